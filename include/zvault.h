@@ -0,0 +1,52 @@
+/*
+ * C interface of zVault
+ *
+ * Memory ownership:
+ *  - Strings passed to zvault must be null-terminated UTF-8 and are only borrowed
+ *    for the duration of the call.
+ *  - Strings returned via output parameters are owned by the caller and must be
+ *    released with zvault_free_string().
+ *  - The string returned by zvault_last_error_message() is owned by zvault and
+ *    stays valid until the next failing call on the same thread.
+ *  - Repository handles must be released with zvault_close().
+ *
+ * All functions returning int return ZVAULT_OK on success and one of the error
+ * codes below on failure.
+ */
+
+#ifndef ZVAULT_H
+#define ZVAULT_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define ZVAULT_OK                   0
+#define ZVAULT_ERR_INVALID_ARGUMENT 1
+#define ZVAULT_ERR_LOAD_REPOSITORY  2
+#define ZVAULT_ERR_NO_SUCH_BACKUP   3
+#define ZVAULT_ERR_BACKUP_EXISTS    4
+#define ZVAULT_ERR_BACKUP           5
+#define ZVAULT_ERR_RESTORE          6
+#define ZVAULT_ERR_LIST             7
+#define ZVAULT_ERR_CHECK            8
+#define ZVAULT_ERR_PANIC            99
+
+typedef struct ZvaultRepository ZvaultRepository;
+
+const char *zvault_last_error_message(void);
+void zvault_free_string(char *str);
+
+int zvault_open(const char *path, ZvaultRepository **repo);
+void zvault_close(ZvaultRepository *repo);
+
+int zvault_backup(ZvaultRepository *repo, const char *src_path, const char *name);
+int zvault_restore(ZvaultRepository *repo, const char *name, const char *dst_path);
+int zvault_list(ZvaultRepository *repo, char **names);
+int zvault_check(ZvaultRepository *repo);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -62,19 +62,6 @@ impl StoredBundle {
         self.info.id.clone()
     }
 
-    pub fn copy_to<P: AsRef<Path>>(
-        &self,
-        base_path: &Path,
-        path: P,
-    ) -> Result<Self, BundleDbError> {
-        let src_path = base_path.join(&self.path);
-        let dst_path = path.as_ref();
-        try!(fs::copy(&src_path, dst_path).context(dst_path));
-        let mut bundle = self.clone();
-        bundle.path = dst_path.strip_prefix(base_path).unwrap().to_path_buf();
-        Ok(bundle)
-    }
-
     pub fn move_to<P: AsRef<Path>>(
         &mut self,
         base_path: &Path,
@@ -2,12 +2,22 @@ use prelude::*;
 use super::*;
 
 use std::path::{Path, PathBuf};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
 use std::sync::{Arc, Mutex};
 use std::io;
 use std::mem;
 use std::cmp::min;
+use std::panic;
+use std::thread;
+use std::sync::mpsc::{self, Sender, Receiver};
+
+use super::uploader::copy_file;
+
+
+/// Default size in bytes of the decoded bundles that are kept in memory
+pub const DEFAULT_CACHE_SIZE: usize = 256 * 1024 * 1024;
+
 
 quick_error!{
     #[derive(Debug)]
@@ -23,6 +33,11 @@ quick_error!{
             description(tr!("Failed to read bundle"))
             display("{}", tr_format!("Bundle db error: failed to read bundle\n\tcaused by: {}", err))
         }
+        ReadChunk(err: BundleReaderError, bundle: BundleId, hash: Hash, offset: usize) {
+            cause(err)
+            description(tr!("Failed to read chunk"))
+            display("{}", tr_format!("Bundle db error: {} failed for chunk {} in bundle {} at offset {}\n\tcaused by: {}", err.step(), hash, bundle, offset, err))
+        }
         Writer(err: BundleWriterError) {
             from()
             cause(err)
@@ -53,24 +68,62 @@ quick_error!{
             description(tr!("Failed to remove bundle"))
             display("{}", tr_format!("Bundle db error: failed to remove bundle {}\n\tcaused by: {}", bundle, err))
         }
+        Parity(err: ParityError) {
+            from()
+            cause(err)
+            description(tr!("Failed to read/write parity"))
+            display("{}", tr_format!("Bundle db error: failed to read/write parity\n\tcaused by: {}", err))
+        }
+        AppendOnly(bundle: BundleId) {
+            description(tr!("Bundle storage is append-only"))
+            display("{}", tr_format!("Bundle db error: refusing to remove bundle {} from append-only storage", bundle))
+        }
+        Thread(err: io::Error) {
+            cause(err)
+            description(tr!("Failed to start bundle writer thread"))
+            display("{}", tr_format!("Bundle db error: failed to start bundle writer thread\n\tcaused by: {}", err))
+        }
     }
 }
 
 
-#[allow(needless_pass_by_value)]
-fn load_bundles(
+/// Checks whether reading the bundle failed with an error that might go away on a retry
+fn is_retriable_read(err: &BundleReaderError) -> bool {
+    match *err {
+        BundleReaderError::Read(ref err, _) => is_retriable(err),
+        _ => false
+    }
+}
+
+/// Returns the paths in the folder and whether they are folders themselves
+fn list_folder(path: &Path, activity: &Activity) -> Result<Vec<(PathBuf, bool)>, io::Error> {
+    let mut entries = vec![];
+    for entry in try!(fs::read_dir(path)) {
+        let path = try!(entry).path();
+        let is_dir = path.is_dir();
+        entries.push((path, is_dir));
+        activity.touch();
+    }
+    Ok(entries)
+}
+
+/// Returns the paths of all bundle files below `path` relative to `base`
+///
+/// The listing of each folder is retried with `retry` if given.
+fn list_bundle_files(
     path: &Path,
     base: &Path,
-    bundles: &mut HashMap<BundleId, StoredBundle>,
-    crypto: Arc<Mutex<Crypto>>,
-) -> Result<(Vec<StoredBundle>, Vec<StoredBundle>), BundleDbError> {
+    retry: Option<&RetryPolicy>,
+) -> Result<HashSet<PathBuf>, BundleDbError> {
     let mut paths = vec![path.to_path_buf()];
     let mut bundle_paths = HashSet::new();
     while let Some(path) = paths.pop() {
-        for entry in try!(fs::read_dir(path).map_err(BundleDbError::ListBundles)) {
-            let entry = try!(entry.map_err(BundleDbError::ListBundles));
-            let path = entry.path();
-            if path.is_dir() {
+        let entries = match retry {
+            Some(retry) => retry.run(move |activity| list_folder(&path, activity)),
+            None => list_folder(&path, &Activity::default())
+        };
+        for (path, is_dir) in try!(entries.map_err(BundleDbError::ListBundles)) {
+            if is_dir {
                 paths.push(path);
             } else {
                 if path.extension() != Some("bundle".as_ref()) {
@@ -80,6 +133,19 @@ fn load_bundles(
             }
         }
     }
+    Ok(bundle_paths)
+}
+
+/// Lists the bundles below `path`, retrying the listing of each folder with `retry` if given
+#[allow(needless_pass_by_value)]
+fn load_bundles(
+    path: &Path,
+    base: &Path,
+    bundles: &mut HashMap<BundleId, StoredBundle>,
+    crypto: Arc<Mutex<Crypto>>,
+    retry: Option<&RetryPolicy>,
+) -> Result<(Vec<StoredBundle>, Vec<StoredBundle>), BundleDbError> {
+    let mut bundle_paths = try!(list_bundle_files(path, base, retry));
     let mut gone = HashSet::new();
     for (id, bundle) in bundles.iter() {
         if !bundle_paths.contains(&bundle.path) {
@@ -93,7 +159,7 @@ fn load_bundles(
         let info = match BundleReader::load_info(base.join(&path), crypto.clone()) {
             Ok(info) => info,
             Err(err) => {
-                warn!("Failed to read bundle {:?}\n\tcaused by: {}", path, err);
+                warn!("Failed to read bundle {}\n\tcaused by: {}", escape(&path), err);
                 info!("Ignoring unreadable bundle");
                 continue;
             }
@@ -115,6 +181,56 @@ fn load_bundles(
 }
 
 
+type WriteResult = thread::Result<Result<(StoredBundle, PipelineTimes), BundleWriterError>>;
+type WriteJob = (BundleWriter, Sender<WriteResult>);
+
+/// Worker threads that finish and write new bundles in the background
+///
+/// Every bundle gets its own channel for the result, so the bundles can be stored in the order
+/// they were added. A panic of a worker is passed on with the result of its bundle. When the
+/// pool is dropped, the workers finish the queued bundles and stop.
+struct BundleWriterPool {
+    jobs: Sender<WriteJob>
+}
+
+impl BundleWriterPool {
+    fn new(threads: usize, layout: &RepositoryLayout) -> Result<Self, io::Error> {
+        let (jobs, queue) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        for num in 0..threads {
+            let queue = queue.clone();
+            let layout = layout.clone();
+            try!(thread::Builder::new()
+                .name(format!("bundle-writer-{}", num))
+                .spawn(move || BundleWriterPool::worker_thread(&queue, &layout)));
+        }
+        Ok(BundleWriterPool { jobs })
+    }
+
+    fn queue(&self, bundle: BundleWriter) -> Receiver<WriteResult> {
+        let (result, receiver) = mpsc::channel();
+        // The workers only stop once the pool is dropped
+        self.jobs.send((bundle, result)).unwrap();
+        receiver
+    }
+
+    fn worker_thread(queue: &Mutex<Receiver<WriteJob>>, layout: &RepositoryLayout) {
+        loop {
+            let job = queue.lock().unwrap().recv();
+            let (bundle, result) = match job {
+                Ok(job) => job,
+                Err(_) => break
+            };
+            let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                let mut times = PipelineTimes::default();
+                bundle.finish(layout, &mut times).map(|bundle| (bundle, times))
+            }));
+            let _ = result.send(res);
+        }
+    }
+}
+
+
 
 pub struct BundleDb {
     pub layout: RepositoryLayout,
@@ -122,7 +238,22 @@ pub struct BundleDb {
     crypto: Arc<Mutex<Crypto>>,
     local_bundles: HashMap<BundleId, StoredBundle>,
     remote_bundles: HashMap<BundleId, StoredBundle>,
-    bundle_cache: LruCache<BundleId, (BundleReader, Vec<u8>)>
+    bundle_cache: DecodedBundleCache,
+    append_only: bool,
+    redundancy: Option<Redundancy>,
+    /// Length of the folder names of new remote bundles, see `Config::bundle_shard`
+    bundle_shard: Option<usize>,
+    /// Maximal number of bytes per second that are uploaded to the remote storage
+    rate_limit: Option<u64>,
+    /// Retries and timeout of listing, uploading, downloading and removing remote bundles
+    retry: RetryPolicy,
+    /// Parity groups, loaded when they are needed for the first time
+    parity_groups: Option<Vec<ParityGroup>>,
+    times: PipelineTimes,
+    threads: usize,
+    /// Worker threads for new bundles, started when the first bundle is added
+    writers: Option<BundleWriterPool>,
+    pending: VecDeque<(BundleId, Receiver<WriteResult>)>
 }
 
 
@@ -134,7 +265,17 @@ impl BundleDb {
             uploader: None,
             local_bundles: HashMap::new(),
             remote_bundles: HashMap::new(),
-            bundle_cache: LruCache::new(5, 10)
+            bundle_cache: DecodedBundleCache::new(DEFAULT_CACHE_SIZE),
+            append_only: false,
+            redundancy: None,
+            bundle_shard: None,
+            rate_limit: None,
+            retry: RetryPolicy::default(),
+            parity_groups: None,
+            times: PipelineTimes::default(),
+            threads: 1,
+            writers: None,
+            pending: VecDeque::new()
         }
     }
 
@@ -161,7 +302,8 @@ impl BundleDb {
             &self.layout.local_bundles_path(),
             base_path,
             &mut self.local_bundles,
-            self.crypto.clone()
+            self.crypto.clone(),
+            None
         ));
         if !new.is_empty() || !gone.is_empty() {
             let bundles: Vec<_> = self.local_bundles.values().cloned().collect();
@@ -177,7 +319,8 @@ impl BundleDb {
             &self.layout.remote_bundles_path(),
             base_path,
             &mut self.remote_bundles,
-            self.crypto.clone()
+            self.crypto.clone(),
+            Some(&self.retry)
         ));
         if !new.is_empty() || !gone.is_empty() {
             let bundles: Vec<_> = self.remote_bundles.values().cloned().collect();
@@ -190,7 +333,62 @@ impl BundleDb {
     }
 
     pub fn flush(&mut self) -> Result<(), BundleDbError> {
-        self.finish_uploads().and_then(|()| self.save_cache())
+        try!(self.finish_pending());
+        try!(self.finish_uploads());
+        try!(self.save_cache());
+        self.write_parity()
+    }
+
+    /// Sets the number of threads used to compress and write new bundles
+    ///
+    /// With more than one thread, every bundle is compressed in its own thread and new
+    /// bundles are written in the background by this many worker threads, so up to this many
+    /// bundles are kept in memory.
+    #[inline]
+    pub fn set_threads(&mut self, threads: usize) {
+        if threads != self.threads {
+            self.writers = None;
+        }
+        self.threads = threads;
+    }
+
+    #[inline]
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Limits the upload of new bundles to the given number of bytes per second
+    #[inline]
+    pub fn set_rate_limit(&mut self, rate: Option<u64>) {
+        self.rate_limit = rate;
+    }
+
+    /// Sets the retries and timeout of the operations on the remote bundles
+    ///
+    /// The remote bundles are listed when the database is opened, so this listing always uses
+    /// the default policy.
+    #[inline]
+    pub fn set_remote_retry(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    /// Sets the maximal size in bytes of the decoded bundles that are kept in memory
+    ///
+    /// The most recently used bundle is always kept, even if it is larger.
+    #[inline]
+    pub fn set_cache_size(&mut self, size: usize) {
+        self.bundle_cache.set_max_weight(size);
+    }
+
+    #[inline]
+    pub fn cache_size(&self) -> usize {
+        self.bundle_cache.max_weight()
+    }
+
+    /// Returns the cache of decoded bundles so that other threads can read through it
+    #[inline]
+    pub fn decoded_bundles(&self) -> DecodedBundleCache {
+        self.bundle_cache.clone()
     }
 
     fn save_cache(&self) -> Result<(), BundleDbError> {
@@ -288,7 +486,8 @@ impl BundleDb {
             hash_method,
             compression,
             encryption,
-            self.crypto.clone()
+            self.crypto.clone(),
+            self.threads > 1
         )))
     }
 
@@ -303,51 +502,123 @@ impl BundleDb {
         }
     }
 
-    #[inline]
+    /// Opens the bundle, retrying reads that fail with errors of the remote storage
     fn get_bundle(&self, stored: &StoredBundle) -> Result<BundleReader, BundleDbError> {
-        let base_path = self.layout.base_path();
-        Ok(try!(BundleReader::load(
-            base_path.join(&stored.path),
-            self.crypto.clone()
+        let path = self.layout.base_path().join(&stored.path);
+        Ok(try!(self.retry.repeat(
+            || BundleReader::load(path.clone(), self.crypto.clone()),
+            is_retriable_read
         )))
     }
 
+    pub fn get_bundle_path(&self, bundle_id: &BundleId) -> Result<PathBuf, BundleDbError> {
+        let stored = try!(self.get_stored_bundle(bundle_id));
+        Ok(self.layout.base_path().join(&stored.path))
+    }
+
     pub fn get_chunk(&mut self, bundle_id: &BundleId, id: usize) -> Result<Vec<u8>, BundleDbError> {
-        if let Some(&mut (ref mut bundle, ref data)) = self.bundle_cache.get_mut(bundle_id) {
-            let (pos, len) = try!(bundle.get_chunk_position(id));
-            let mut chunk = Vec::with_capacity(len);
-            chunk.extend_from_slice(&data[pos..pos + len]);
-            return Ok(chunk);
+        if self.pending.iter().any(|&(ref pending, _)| pending == bundle_id) {
+            try!(self.finish_pending());
+        }
+        let load = || -> Result<_, BundleDbError> {
+            let mut bundle = try!(self.get_stored_bundle(bundle_id).and_then(
+                |s| self.get_bundle(s)
+            ));
+            let (pos, _len) = try!(bundle.get_chunk_position(id));
+            let hash = bundle.chunks.as_ref().unwrap()[id].0;
+            let data = try!(
+                self.retry.repeat(|| bundle.load_contents(), is_retriable_read).map_err(|err| {
+                    BundleDbError::ReadChunk(err, bundle_id.clone(), hash, pos)
+                })
+            );
+            Ok((bundle, data))
+        };
+        let bundle = try!(self.bundle_cache.get_or_load(bundle_id, load));
+        Ok(try!(bundle.chunk(id)).to_vec())
+    }
+
+    /// Returns the position and length of the chunk in the decoded contents of the bundle
+    pub fn get_chunk_position(
+        &mut self,
+        bundle_id: &BundleId,
+        id: usize,
+    ) -> Result<(usize, usize), BundleDbError> {
+        if let Some(bundle) = self.bundle_cache.get(bundle_id) {
+            return Ok(try!(bundle.chunk_position(id)));
         }
         let mut bundle = try!(self.get_stored_bundle(bundle_id).and_then(
             |s| self.get_bundle(s)
         ));
-        let (pos, len) = try!(bundle.get_chunk_position(id));
-        let mut chunk = Vec::with_capacity(len);
-        let data = try!(bundle.load_contents());
-        chunk.extend_from_slice(&data[pos..pos + len]);
-        self.bundle_cache.put(bundle_id.clone(), (bundle, data));
-        Ok(chunk)
+        Ok(try!(bundle.get_chunk_position(id)))
     }
 
     fn copy_remote_bundle_to_cache(&mut self, bundle: &StoredBundle) -> Result<(), BundleDbError> {
         let id = bundle.id();
         let (folder, filename) = self.layout.local_bundle_path(&id, self.local_bundles.len());
         try!(fs::create_dir_all(&folder).context(&folder as &Path));
-        let bundle = try!(bundle.copy_to(
-            self.layout.base_path(),
-            folder.join(filename)
+        let dst_path = folder.join(filename);
+        let (src, dst) = (self.layout.base_path().join(&bundle.path), dst_path.clone());
+        try!(self.retry.run(move |activity| copy_file(&src, &dst, None, activity)).context(
+            &dst_path as &Path
         ));
+        let mut bundle = bundle.clone();
+        bundle.path = dst_path.strip_prefix(self.layout.base_path()).unwrap().to_path_buf();
         self.local_bundles.insert(id, bundle);
         Ok(())
     }
 
-    pub fn add_bundle(&mut self, bundle: BundleWriter) -> Result<BundleInfo, BundleDbError> {
-        let mut bundle = try!(bundle.finish(self));
+    /// Writes the bundle and queues it for upload, returning its id
+    ///
+    /// With more than one thread, the bundle is written in the background. It becomes visible
+    /// when one of the next bundles is added or when the database is flushed.
+    pub fn add_bundle(&mut self, bundle: BundleWriter) -> Result<BundleId, BundleDbError> {
+        let id = bundle.id();
+        if self.threads <= 1 {
+            let mut times = PipelineTimes::default();
+            let bundle = try!(bundle.finish(&self.layout, &mut times));
+            try!(self.store_bundle(bundle, times));
+            return Ok(id);
+        }
+        while self.pending.len() >= self.threads {
+            try!(self.finish_oldest_pending());
+        }
+        if self.writers.is_none() {
+            let writers = try!(BundleWriterPool::new(self.threads, &self.layout).map_err(
+                BundleDbError::Thread
+            ));
+            self.writers = Some(writers);
+        }
+        let result = self.writers.as_ref().unwrap().queue(bundle);
+        self.pending.push_back((id.clone(), result));
+        Ok(id)
+    }
+
+    fn finish_oldest_pending(&mut self) -> Result<(), BundleDbError> {
+        if let Some((_id, result)) = self.pending.pop_front() {
+            // The workers send a result for every bundle, even if they panic
+            let (bundle, times) = match result.recv().unwrap() {
+                Ok(res) => try!(res),
+                Err(err) => panic::resume_unwind(err)
+            };
+            try!(self.store_bundle(bundle, times));
+        }
+        Ok(())
+    }
+
+    /// Waits until all bundles that are written in the background are stored
+    fn finish_pending(&mut self) -> Result<(), BundleDbError> {
+        while !self.pending.is_empty() {
+            try!(self.finish_oldest_pending());
+        }
+        Ok(())
+    }
+
+    fn store_bundle(&mut self, mut bundle: StoredBundle, times: PipelineTimes) -> Result<(), BundleDbError> {
+        self.times.add(&times);
         if bundle.info.mode == BundleMode::Meta {
             try!(self.copy_remote_bundle_to_cache(&bundle))
         }
-        let (folder, filename) = self.layout.remote_bundle_path(self.remote_bundles.len());
+        let (folder, filename) = self.layout.remote_bundle_path(self.remote_bundles.len(), self.bundle_shard);
         let dst_path = folder.join(filename);
         let src_path = self.layout.base_path().join(bundle.path);
         bundle.path = dst_path
@@ -355,9 +626,36 @@ impl BundleDb {
             .unwrap()
             .to_path_buf();
         if self.uploader.is_none() {
-            self.uploader = Some(BundleUploader::new(5));
+            self.uploader = Some(BundleUploader::new(5, self.rate_limit, self.retry));
         }
         try!(self.uploader.as_ref().unwrap().queue(src_path, dst_path));
+        self.remote_bundles.insert(bundle.id(), bundle);
+        Ok(())
+    }
+
+    /// Copies the bundle file at the given path into the remote storage
+    ///
+    /// The bundle is taken as it is, so it must have been written with a configuration that
+    /// can be read with the keys of this repository.
+    pub fn import_bundle(&mut self, src_path: &Path) -> Result<BundleInfo, BundleDbError> {
+        let info = try!(BundleReader::load_info(src_path, self.crypto.clone()));
+        let (folder, filename) = self.layout.remote_bundle_path(self.remote_bundles.len(), self.bundle_shard);
+        let dst_path = folder.join(filename);
+        let (src, dst) = (src_path.to_path_buf(), dst_path.clone());
+        try!(self.retry.run(move |activity| {
+            try!(fs::create_dir_all(dst.parent().unwrap()));
+            copy_file(&src, &dst, None, activity)
+        }).context(&dst_path as &Path));
+        let bundle = StoredBundle {
+            info,
+            path: dst_path
+                .strip_prefix(self.layout.base_path())
+                .unwrap()
+                .to_path_buf()
+        };
+        if bundle.info.mode == BundleMode::Meta {
+            try!(self.copy_remote_bundle_to_cache(&bundle))
+        }
         self.remote_bundles.insert(bundle.id(), bundle.clone());
         Ok(bundle.info)
     }
@@ -389,7 +687,21 @@ impl BundleDb {
         self.remote_bundles.values().map(|b| &b.info).collect()
     }
 
+    /// Lists all remote bundle files, including those that are missing in the bundle list
+    pub fn list_bundle_files(&self) -> Result<Vec<PathBuf>, BundleDbError> {
+        let base = self.layout.base_path();
+        let paths = try!(list_bundle_files(
+            &self.layout.remote_bundles_path(),
+            base,
+            Some(&self.retry)
+        ));
+        let mut paths: Vec<_> = paths.into_iter().map(|path| base.join(path)).collect();
+        paths.sort();
+        Ok(paths)
+    }
+
     pub fn delete_local_bundle(&mut self, bundle: &BundleId) -> Result<(), BundleDbError> {
+        self.bundle_cache.remove(bundle);
         if let Some(bundle) = self.local_bundles.remove(bundle) {
             let path = self.layout.base_path().join(&bundle.path);
             try!(fs::remove_file(path).map_err(|e| {
@@ -399,17 +711,34 @@ impl BundleDb {
         Ok(())
     }
 
+    #[inline]
+    pub fn times(&self) -> &PipelineTimes {
+        &self.times
+    }
+
+    #[inline]
+    pub fn set_append_only(&mut self, append_only: bool) {
+        self.append_only = append_only;
+    }
+
     pub fn delete_bundle(&mut self, bundle: &BundleId) -> Result<(), BundleDbError> {
+        if self.append_only {
+            return Err(BundleDbError::AppendOnly(bundle.clone()));
+        }
+        try!(self.remove_parity(bundle));
         try!(self.delete_local_bundle(bundle));
         if let Some(bundle) = self.remote_bundles.remove(bundle) {
             let path = self.layout.base_path().join(&bundle.path);
-            fs::remove_file(path).map_err(|e| BundleDbError::Remove(e, bundle.id()))
+            self.retry.run(move |_| fs::remove_file(&path)).map_err(|e| {
+                BundleDbError::Remove(e, bundle.id())
+            })
         } else {
             Err(BundleDbError::NoSuchBundle(bundle.clone()))
         }
     }
 
     pub fn check(&mut self, full: bool, repair: bool) -> Result<bool, BundleDbError> {
+        let missing = repair && try!(self.restore_missing_bundles());
         let mut to_repair = vec![];
         for (id, stored) in ProgressIter::new(
             tr!("checking bundles"),
@@ -438,12 +767,18 @@ impl BundleDb {
             }
         }
         if !to_repair.is_empty() {
+            let mut restored = HashSet::new();
             for id in ProgressIter::new(tr!("repairing bundles"), to_repair.len(), to_repair.iter()) {
-                try!(self.repair_bundle(id));
+                if !restored.contains(id) {
+                    restored.extend(self.try_restore_from_parity(id));
+                }
+                if !restored.contains(id) {
+                    try!(self.repair_bundle(id));
+                }
             }
             try!(self.flush());
         }
-        Ok(!to_repair.is_empty())
+        Ok(missing || !to_repair.is_empty())
     }
 
     fn evacuate_broken_bundle(&mut self, mut bundle: StoredBundle) -> Result<(), BundleDbError> {
@@ -454,12 +789,189 @@ impl BundleDb {
             dst = src.with_extension(&format!("bundle.{}.broken", num));
             num += 1;
         }
-        warn!("Moving bundle to {:?}", dst);
+        warn!("Moving bundle to {}", escape(&dst));
         try!(bundle.move_to(self.layout.base_path(), dst));
         self.remote_bundles.remove(&bundle.info.id);
         Ok(())
     }
 
+    /// Sets the number of parity bundles that are written for groups of bundles, if any
+    #[inline]
+    pub fn set_redundancy(&mut self, redundancy: Option<Redundancy>) {
+        self.redundancy = redundancy;
+    }
+
+    /// Sets the length of the folder names of new remote bundles
+    #[inline]
+    pub fn set_bundle_shard(&mut self, shard: Option<usize>) {
+        self.bundle_shard = shard;
+    }
+
+    /// Guesses the length of the folder names from the existing remote bundles
+    ///
+    /// This only succeeds if all bundles are stored in folders named after the same number of
+    /// leading characters of their file names, or if there are many bundles and all of them
+    /// are stored directly in the bundle folder.
+    pub fn detect_bundle_shard(&self) -> Option<usize> {
+        let base = self.layout.remote_bundles_path()
+            .strip_prefix(self.layout.base_path())
+            .unwrap()
+            .to_path_buf();
+        let mut shard = None;
+        for bundle in self.remote_bundles.values() {
+            let folder = match bundle.path.parent().and_then(|p| p.strip_prefix(&base).ok()) {
+                Some(folder) => folder.to_string_lossy().to_string(),
+                None => return None
+            };
+            let file = bundle.path.file_name().unwrap().to_string_lossy().to_string();
+            if folder.contains('/') || !file.starts_with(&folder as &str) {
+                return None;
+            }
+            if shard.is_some() && shard != Some(folder.len()) {
+                return None;
+            }
+            shard = Some(folder.len());
+        }
+        // Automatically created folders only appear with more than 100 bundles
+        if shard == Some(0) && self.remote_bundles.len() <= 100 {
+            return None;
+        }
+        shard
+    }
+
+    fn load_parity_groups(&mut self) -> Result<(), BundleDbError> {
+        if self.parity_groups.is_none() {
+            self.parity_groups = Some(try!(ParityGroup::load_all(&self.layout)));
+        }
+        Ok(())
+    }
+
+    /// Writes parity files for all bundles that are not protected yet
+    ///
+    /// New bundles are protected by a group of their own, even if it is smaller than
+    /// configured. Such small groups are only replaced once they can be merged with the new
+    /// bundles into a complete group, so that the bundles are not encoded again with every
+    /// flush. Groups are never replaced if the storage is append-only or some of their bundles
+    /// are missing.
+    fn write_parity(&mut self) -> Result<(), BundleDbError> {
+        let redundancy = match self.redundancy {
+            Some(redundancy) => redundancy,
+            None => return Ok(())
+        };
+        try!(self.load_parity_groups());
+        let mut groups = self.parity_groups.take().unwrap();
+        let covered: HashSet<BundleId> = groups.iter()
+            .flat_map(|group| group.members.iter().map(|m| m.id.clone()))
+            .collect();
+        let mut pending: Vec<&StoredBundle> = self.remote_bundles.values()
+            .filter(|bundle| !covered.contains(&bundle.info.id))
+            .collect();
+        if pending.is_empty() {
+            self.parity_groups = Some(groups);
+            return Ok(());
+        }
+        let mut replaced = vec![];
+        if !self.append_only {
+            let remote_bundles = &self.remote_bundles;
+            let (partial, full) = groups.into_iter().partition(|group: &ParityGroup| {
+                group.members.len() < redundancy.data &&
+                    group.members.iter().all(|m| remote_bundles.contains_key(&m.id))
+            });
+            groups = full;
+            let members: usize = partial.iter().map(|group| group.members.len()).sum();
+            if pending.len() + members >= redundancy.data {
+                for group in &partial {
+                    pending.extend(group.members.iter().filter_map(|m| self.remote_bundles.get(&m.id)));
+                }
+                replaced = partial;
+            } else {
+                groups.extend(partial);
+            }
+        }
+        pending.sort_by_key(|bundle| (bundle.info.timestamp, bundle.info.id.clone()));
+        tr_debug!("Writing parity for {} bundles", pending.len());
+        for bundles in pending.chunks(redundancy.data) {
+            groups.push(try!(ParityGroup::write(&self.layout, bundles, redundancy.parity)));
+        }
+        for group in replaced {
+            try!(group.remove());
+        }
+        self.parity_groups = Some(groups);
+        Ok(())
+    }
+
+    /// Removes the parity groups of a bundle that is deleted
+    ///
+    /// The other bundles of the groups will be protected by new groups with the next flush.
+    fn remove_parity(&mut self, bundle: &BundleId) -> Result<(), BundleDbError> {
+        try!(self.load_parity_groups());
+        let groups = self.parity_groups.take().unwrap();
+        let (affected, groups): (Vec<ParityGroup>, _) = groups.into_iter().partition(|group| {
+            group.contains(bundle)
+        });
+        self.parity_groups = Some(groups);
+        for group in affected {
+            try!(group.remove());
+        }
+        Ok(())
+    }
+
+    /// Restores the damaged bundles of the parity group of this bundle
+    ///
+    /// Returns the ids of the restored bundles, failures are only reported as warnings so
+    /// that the bundle can be repaired otherwise.
+    fn try_restore_from_parity(&mut self, id: &BundleId) -> Vec<BundleId> {
+        if let Err(err) = self.load_parity_groups() {
+            tr_warn!("Failed to load parity files\n\tcaused by: {}", err);
+            return vec![];
+        }
+        let restored = match self.parity_groups.as_ref().unwrap().iter().find(|g| g.contains(id)) {
+            Some(group) => {
+                match group.restore(self.layout.base_path()) {
+                    Ok(restored) => restored,
+                    Err(err) => {
+                        tr_warn!("Failed to restore bundle {} from parity\n\tcaused by: {}", id, err);
+                        return vec![];
+                    }
+                }
+            }
+            None => return vec![]
+        };
+        for id in &restored {
+            tr_info!("Restored bundle {} from parity", id);
+            self.bundle_cache.remove(id);
+        }
+        restored
+    }
+
+    /// Restores bundles that are protected by parity but are missing or unreadable
+    fn restore_missing_bundles(&mut self) -> Result<bool, BundleDbError> {
+        try!(self.load_parity_groups());
+        let missing: Vec<ParityMember> = self.parity_groups.as_ref().unwrap().iter()
+            .flat_map(|group| group.members.iter())
+            .filter(|m| !self.remote_bundles.contains_key(&m.id))
+            .cloned()
+            .collect();
+        let mut restored = HashSet::new();
+        for member in &missing {
+            if restored.contains(&member.id) {
+                continue;
+            }
+            tr_warn!("Problem detected: bundle {} is missing or unreadable", member.id);
+            restored.extend(self.try_restore_from_parity(&member.id));
+            if !restored.contains(&member.id) {
+                continue;
+            }
+            let path = self.layout.base_path().join(&member.path);
+            let info = try!(BundleReader::load_info(&path, self.crypto.clone()));
+            self.remote_bundles.insert(member.id.clone(), StoredBundle {
+                info,
+                path: member.path.clone()
+            });
+        }
+        Ok(!restored.is_empty())
+    }
+
     fn repair_bundle(&mut self, id: &BundleId) -> Result<(), BundleDbError> {
         let stored = self.remote_bundles[id].clone();
         let mut bundle = match self.get_bundle(&stored) {
@@ -513,8 +1025,8 @@ impl BundleDb {
             try!(new_bundle.add(&data[pos..pos + len as usize], hash));
             pos += len as usize;
         }
-        let bundle = try!(self.add_bundle(new_bundle));
-        tr_info!("New bundle id is {}", bundle.id);
+        let id = try!(self.add_bundle(new_bundle));
+        tr_info!("New bundle id is {}", id);
         self.evacuate_broken_bundle(stored)
     }
 
@@ -549,3 +1061,48 @@ impl BundleDb {
         }
     }
 }
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    /// Returns a bundle db with a bundle for each folder returned by `folder` for its file name
+    #[allow(dead_code)]
+    fn bundle_db<F: Fn(&str) -> String>(count: usize, folder: F) -> BundleDb {
+        let layout = RepositoryLayout::new("/repo");
+        let mut db = BundleDb::new(layout, Arc::new(Mutex::new(Crypto::dummy())));
+        for _ in 0..count {
+            let mut info = BundleInfo::default();
+            info.id = BundleId::random();
+            let file = format!("{}.bundle", info.id);
+            let path = Path::new("remote/bundles").join(folder(&file)).join(&file);
+            db.remote_bundles.insert(info.id.clone(), StoredBundle { info, path });
+        }
+        db
+    }
+
+    #[test]
+    fn test_detect_bundle_shard() {
+        assert_eq!(bundle_db(0, |_| String::new()).detect_bundle_shard(), None);
+        assert_eq!(bundle_db(10, |file| file[..2].to_string()).detect_bundle_shard(), Some(2));
+        assert_eq!(bundle_db(10, |file| file[..3].to_string()).detect_bundle_shard(), Some(3));
+        // Bundles directly in the bundle folder are only detected if there are many of them
+        assert_eq!(bundle_db(10, |_| String::new()).detect_bundle_shard(), None);
+        assert_eq!(bundle_db(150, |_| String::new()).detect_bundle_shard(), Some(0));
+        // Folders that do not match the file names or are nested are not shards
+        assert_eq!(bundle_db(10, |_| "xx".to_string()).detect_bundle_shard(), None);
+        assert_eq!(
+            bundle_db(10, |file| format!("{}/{}", &file[..2], &file[2..4])).detect_bundle_shard(),
+            None
+        );
+        let mut db = bundle_db(10, |file| file[..2].to_string());
+        let mixed = bundle_db(1, |file| file[..3].to_string());
+        db.remote_bundles.extend(mixed.remote_bundles);
+        assert_eq!(db.detect_bundle_shard(), None);
+    }
+
+}
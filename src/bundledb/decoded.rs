@@ -0,0 +1,101 @@
+use prelude::*;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, Condvar};
+
+
+/// A bundle whose contents have been decoded
+pub struct DecodedBundle {
+    reader: Mutex<BundleReader>,
+    pub data: Vec<u8>
+}
+
+impl DecodedBundle {
+    /// Returns the position and length of the chunk in the decoded contents
+    #[inline]
+    pub fn chunk_position(&self, id: usize) -> Result<(usize, usize), BundleReaderError> {
+        self.reader.lock().unwrap().get_chunk_position(id)
+    }
+
+    #[inline]
+    pub fn chunk(&self, id: usize) -> Result<&[u8], BundleReaderError> {
+        let (pos, len) = try!(self.chunk_position(id));
+        Ok(&self.data[pos..pos + len])
+    }
+}
+
+
+struct DecodedBundleCacheInner {
+    bundles: Mutex<LruCache<BundleId, Arc<DecodedBundle>>>,
+    loading: Mutex<HashSet<BundleId>>,
+    loaded: Condvar
+}
+
+/// Decoded bundles that are kept in memory, shared by all threads reading the repository
+///
+/// Bundles are loaded outside of the cache lock so that a slow bundle does not block readers
+/// of other bundles. A bundle that is being loaded is not loaded a second time, readers of
+/// that bundle wait for it instead.
+#[derive(Clone)]
+pub struct DecodedBundleCache(Arc<DecodedBundleCacheInner>);
+
+impl DecodedBundleCache {
+    pub fn new(max_weight: usize) -> Self {
+        DecodedBundleCache(Arc::new(DecodedBundleCacheInner {
+            bundles: Mutex::new(LruCache::with_max_weight(max_weight)),
+            loading: Mutex::new(HashSet::new()),
+            loaded: Condvar::new()
+        }))
+    }
+
+    #[inline]
+    pub fn max_weight(&self) -> usize {
+        self.0.bundles.lock().unwrap().max_weight()
+    }
+
+    #[inline]
+    pub fn set_max_weight(&self, max_weight: usize) {
+        self.0.bundles.lock().unwrap().set_max_weight(max_weight)
+    }
+
+    #[inline]
+    pub fn get(&self, id: &BundleId) -> Option<Arc<DecodedBundle>> {
+        self.0.bundles.lock().unwrap().get(id).cloned()
+    }
+
+    #[inline]
+    pub fn remove(&self, id: &BundleId) {
+        self.0.bundles.lock().unwrap().remove(id);
+    }
+
+    /// Returns the bundle from the cache or decodes it with `load` and adds it
+    pub fn get_or_load<E, F>(&self, id: &BundleId, load: F) -> Result<Arc<DecodedBundle>, E>
+    where
+        F: FnOnce() -> Result<(BundleReader, Vec<u8>), E>,
+    {
+        let mut loading = self.0.loading.lock().unwrap();
+        loop {
+            if let Some(bundle) = self.get(id) {
+                return Ok(bundle);
+            }
+            if !loading.contains(id) {
+                break;
+            }
+            loading = self.0.loaded.wait(loading).unwrap();
+        }
+        loading.insert(id.clone());
+        drop(loading);
+        let res = load().map(|(reader, data)| {
+            let bundle = Arc::new(DecodedBundle {
+                reader: Mutex::new(reader),
+                data
+            });
+            let size = bundle.data.len();
+            self.0.bundles.lock().unwrap().put_weighted(id.clone(), bundle.clone(), size);
+            bundle
+        });
+        self.0.loading.lock().unwrap().remove(id);
+        self.0.loaded.notify_all();
+        res
+    }
+}
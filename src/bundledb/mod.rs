@@ -3,12 +3,16 @@ mod reader;
 mod db;
 mod cache;
 mod uploader;
+mod parity;
+mod decoded;
 
 pub use self::cache::{StoredBundle, BundleCacheError};
 pub use self::writer::{BundleWriter, BundleWriterError};
 pub use self::reader::{BundleReader, BundleReaderError};
 pub use self::db::*;
 pub use self::uploader::BundleUploader;
+pub use self::parity::{Redundancy, ParityGroup, ParityMember, ParityError};
+pub use self::decoded::{DecodedBundle, DecodedBundleCache};
 
 use prelude::*;
 
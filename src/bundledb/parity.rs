@@ -0,0 +1,478 @@
+use prelude::*;
+use super::*;
+
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader, BufWriter};
+use std::collections::HashMap;
+use std::cmp::min;
+use std::fmt;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+
+pub static PARITY_HEADER_STRING: [u8; 7] = *b"zvault\x05";
+pub static PARITY_HEADER_VERSION: u8 = 1;
+
+/// Size of the parts of the bundles that are encoded together
+const STRIPE_SIZE: usize = 64 * 1024;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum ParityError {
+        Io(err: io::Error, path: PathBuf) {
+            cause(err)
+            context(path: &'a Path, err: io::Error) -> (err, path.to_path_buf())
+            description(tr!("Failed to read/write parity file"))
+            display("{}", tr_format!("Parity error: failed to read/write parity file {:?}\n\tcaused by: {}", path, err))
+        }
+        WrongHeader(path: PathBuf) {
+            description(tr!("Wrong header"))
+            display("{}", tr_format!("Parity error: wrong header on parity file {:?}", path))
+        }
+        UnsupportedVersion(path: PathBuf, version: u8) {
+            description(tr!("Wrong version"))
+            display("{}", tr_format!("Parity error: unsupported version on parity file {:?}: {}", path, version))
+        }
+        Decode(err: msgpack::DecodeError, path: PathBuf) {
+            cause(err)
+            context(path: &'a Path, err: msgpack::DecodeError) -> (err, path.to_path_buf())
+            description(tr!("Failed to decode parity header"))
+            display("{}", tr_format!("Parity error: failed to decode header of parity file {:?}\n\tcaused by: {}", path, err))
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to encode parity header"))
+            display("{}", tr_format!("Parity error: failed to encode parity header\n\tcaused by: {}", err))
+        }
+        ReedSolomon(err: ReedSolomonError) {
+            from()
+            cause(err)
+            description(tr!("Failed to calculate parity"))
+            display("{}", tr_format!("Parity error: failed to calculate parity\n\tcaused by: {}", err))
+        }
+        Unrecoverable(damaged: usize, available: usize) {
+            description(tr!("Too many damaged bundles"))
+            display("{}", tr_format!("Parity error: {} bundles of the group are damaged but only {} intact parity files exist", damaged, available))
+        }
+        RestoredMismatch(bundle: BundleId) {
+            description(tr!("Restored bundle does not match"))
+            display("{}", tr_format!("Parity error: restored bundle {} does not match its hash", bundle))
+        }
+    }
+}
+
+
+/// Number of parity bundles that are calculated for a group of data bundles
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub struct Redundancy {
+    pub data: usize,
+    pub parity: usize
+}
+serde_impl!(Redundancy(u8) {
+    data: usize => 0,
+    parity: usize => 1
+});
+
+impl Redundancy {
+    /// Parses the parameters in the form `DATA/PARITY`, e.g. `10/2`
+    pub fn from_string(val: &str) -> Result<Self, &'static str> {
+        let mut parts = val.splitn(2, '/');
+        let data = parts.next().and_then(|v| v.parse::<usize>().ok());
+        let parity = parts.next().and_then(|v| v.parse::<usize>().ok());
+        match (data, parity) {
+            (Some(data), Some(parity)) => {
+                if ReedSolomon::new(data, parity).is_err() {
+                    return Err(tr!("Both numbers must be at least 1 and their sum at most 256"));
+                }
+                Ok(Redundancy { data, parity })
+            }
+            _ => Err(tr!("Redundancy must be given as DATA/PARITY"))
+        }
+    }
+}
+
+impl fmt::Display for Redundancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.data, self.parity)
+    }
+}
+
+
+/// Bundle that is protected by a parity group
+#[derive(Clone, Default)]
+pub struct ParityMember {
+    pub id: BundleId,
+    /// Path of the bundle file relative to the repository
+    pub path: PathBuf,
+    pub size: u64,
+    /// Blake2 hash of the whole bundle file
+    pub hash: Hash
+}
+serde_impl!(ParityMember(u8) {
+    id: BundleId => 0,
+    path: PathBuf => 1,
+    size: u64 => 2,
+    hash: Hash => 3
+});
+
+
+/// Header of a parity file, it is stored after the parity data
+#[derive(Default)]
+struct ParityHeader {
+    group: BundleId,
+    members: Vec<ParityMember>,
+    parity: usize,
+    index: usize,
+    size: u64,
+    hash: Hash
+}
+serde_impl!(ParityHeader(u8) {
+    group: BundleId => 0,
+    members: Vec<ParityMember> => 1,
+    parity: usize => 2,
+    index: usize => 3,
+    size: u64 => 4,
+    hash: Hash => 5
+});
+
+impl ParityHeader {
+    fn load(path: &Path) -> Result<Self, ParityError> {
+        let mut file = try!(File::open(path).context(path));
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header).context(path));
+        if header[..PARITY_HEADER_STRING.len()] != PARITY_HEADER_STRING {
+            return Err(ParityError::WrongHeader(path.to_path_buf()));
+        }
+        let version = header[PARITY_HEADER_STRING.len()];
+        if version != PARITY_HEADER_VERSION {
+            return Err(ParityError::UnsupportedVersion(path.to_path_buf(), version));
+        }
+        try!(file.seek(SeekFrom::End(-4)).context(path));
+        let len = try!(file.read_u32::<LittleEndian>().context(path)) as i64;
+        try!(file.seek(SeekFrom::End(-4 - len)).context(path));
+        let mut data = vec![0; len as usize];
+        try!(file.read_exact(&mut data).context(path));
+        Ok(try!(msgpack::decode(&data).context(path)))
+    }
+}
+
+
+/// Reads until the buffer is full or the end of the file is reached
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<usize, io::Error> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match r.read(&mut buf[pos..]) {
+            Ok(0) => break,
+            Ok(n) => pos += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(pos)
+}
+
+/// Returns whether the file has the given size and hash
+fn file_matches(path: &Path, size: u64, hash: Hash) -> bool {
+    let mut file = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(_) => return false
+    };
+    let mut stream = Blake2Stream::new();
+    let mut buf = vec![0; STRIPE_SIZE];
+    let mut len = 0;
+    loop {
+        match read_full(&mut file, &mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                stream.update(&buf[..n]);
+                len += n as u64;
+            }
+            Err(_) => return false
+        }
+    }
+    len == size && stream.finish() == hash
+}
+
+
+/// Group of bundles and the parity files that have been calculated from them
+///
+/// Each parity file contains one parity shard of the Reed-Solomon code over the bundle files,
+/// the shorter bundles are padded with zeros. As long as at most as many files of the group
+/// are damaged as there are intact parity files, all bundles can be restored.
+pub struct ParityGroup {
+    pub id: BundleId,
+    pub members: Vec<ParityMember>,
+    pub parity: usize,
+    /// The existing parity files with their shard index
+    pub files: Vec<(usize, PathBuf)>
+}
+
+impl ParityGroup {
+    /// Loads the headers of all parity files
+    ///
+    /// Unreadable parity files are ignored, they can not be used for restoring anyway.
+    pub fn load_all(layout: &RepositoryLayout) -> Result<Vec<Self>, ParityError> {
+        let folder = layout.remote_parity_path();
+        if !folder.exists() {
+            return Ok(vec![]);
+        }
+        let mut groups: HashMap<BundleId, ParityGroup> = HashMap::new();
+        for entry in try!(fs::read_dir(&folder).context(&folder as &Path)) {
+            let path = try!(entry.context(&folder as &Path)).path();
+            if path.extension() != Some("parity".as_ref()) {
+                continue;
+            }
+            let header = match ParityHeader::load(&path) {
+                Ok(header) => header,
+                Err(err) => {
+                    tr_warn!("Ignoring unreadable parity file: {}", err);
+                    continue;
+                }
+            };
+            let index = header.index;
+            groups
+                .entry(header.group.clone())
+                .or_insert_with(|| {
+                    ParityGroup {
+                        id: header.group,
+                        members: header.members,
+                        parity: header.parity,
+                        files: vec![]
+                    }
+                })
+                .files
+                .push((index, path));
+        }
+        Ok(groups.into_iter().map(|(_, group)| group).collect())
+    }
+
+    /// Calculates the parity files for the bundles
+    pub fn write(
+        layout: &RepositoryLayout,
+        bundles: &[&StoredBundle],
+        parity: usize,
+    ) -> Result<Self, ParityError> {
+        let rs = try!(ReedSolomon::new(bundles.len(), parity));
+        let mut inputs = Vec::with_capacity(bundles.len());
+        let mut members = Vec::with_capacity(bundles.len());
+        for bundle in bundles {
+            let path = layout.base_path().join(&bundle.path);
+            let file = try!(File::open(&path).context(&path as &Path));
+            let size = try!(file.metadata().context(&path as &Path)).len();
+            inputs.push((BufReader::new(file), Blake2Stream::new()));
+            members.push(ParityMember {
+                id: bundle.id(),
+                path: bundle.path.clone(),
+                size,
+                hash: Hash::empty()
+            });
+        }
+        let size = members.iter().map(|m| m.size).max().unwrap_or(0);
+        let id = BundleId::random();
+        let folder = layout.remote_parity_path();
+        try!(fs::create_dir_all(&folder).context(&folder as &Path));
+        let mut outputs = Vec::with_capacity(parity);
+        for index in 0..parity {
+            let path = layout.parity_path(&id, index).with_extension("parity.tmp");
+            let mut file = BufWriter::new(try!(File::create(&path).context(&path as &Path)));
+            try!(file.write_all(&PARITY_HEADER_STRING).context(&path as &Path));
+            try!(file.write_all(&[PARITY_HEADER_VERSION]).context(&path as &Path));
+            outputs.push((file, Blake2Stream::new(), path));
+        }
+        let mut data = vec![vec![0; STRIPE_SIZE]; bundles.len()];
+        let mut shards = vec![vec![]; parity];
+        let mut pos = 0;
+        while pos < size {
+            let len = min(STRIPE_SIZE as u64, size - pos) as usize;
+            for (buf, &mut (ref mut file, ref mut stream)) in data.iter_mut().zip(inputs.iter_mut()) {
+                buf.resize(len, 0);
+                let read = try!(read_full(file, buf).context(layout.base_path()));
+                stream.update(&buf[..read]);
+                for b in &mut buf[read..] {
+                    *b = 0;
+                }
+            }
+            {
+                let refs: Vec<&[u8]> = data.iter().map(|d| &d[..]).collect();
+                try!(rs.encode(&refs, &mut shards));
+            }
+            for (shard, &mut (ref mut file, ref mut stream, ref path)) in shards.iter().zip(outputs.iter_mut()) {
+                stream.update(shard);
+                try!(file.write_all(shard).context(path as &Path));
+            }
+            pos += len as u64;
+        }
+        for (member, (_, stream)) in members.iter_mut().zip(inputs) {
+            member.hash = stream.finish();
+        }
+        let mut files = Vec::with_capacity(parity);
+        for (index, (mut file, stream, path)) in outputs.into_iter().enumerate() {
+            let header = ParityHeader {
+                group: id.clone(),
+                members: members.clone(),
+                parity,
+                index,
+                size,
+                hash: stream.finish()
+            };
+            let header = try!(msgpack::encode(&header));
+            try!(file.write_all(&header).context(&path as &Path));
+            try!(file.write_u32::<LittleEndian>(header.len() as u32).context(&path as &Path));
+            try!(file.flush().context(&path as &Path));
+            let dst = layout.parity_path(&id, index);
+            try!(fs::rename(&path, &dst).context(&dst as &Path));
+            files.push((index, dst));
+        }
+        Ok(ParityGroup {
+            id,
+            members,
+            parity,
+            files
+        })
+    }
+
+    #[inline]
+    pub fn contains(&self, bundle: &BundleId) -> bool {
+        self.members.iter().any(|m| m.id == *bundle)
+    }
+
+    /// Removes the parity files of the group
+    pub fn remove(&self) -> Result<(), ParityError> {
+        for &(_, ref path) in &self.files {
+            try!(fs::remove_file(path).context(path as &Path));
+        }
+        Ok(())
+    }
+
+    /// Restores all damaged or missing bundles of the group and returns their ids
+    ///
+    /// The bundle files and parity files are checked against their hashes first. The damaged
+    /// bundle files are kept with the extension `.bundle.broken`.
+    pub fn restore(&self, base: &Path) -> Result<Vec<BundleId>, ParityError> {
+        let size = self.members.iter().map(|m| m.size).max().unwrap_or(0);
+        let damaged: Vec<usize> = (0..self.members.len())
+            .filter(|&i| {
+                let member = &self.members[i];
+                !file_matches(&base.join(&member.path), member.size, member.hash)
+            })
+            .collect();
+        if damaged.is_empty() {
+            return Ok(vec![]);
+        }
+        // Shards are opened in the order of the code: bundles first, then parity files
+        let mut inputs: Vec<Option<BufReader<File>>> = Vec::with_capacity(self.members.len() + self.parity);
+        for (i, member) in self.members.iter().enumerate() {
+            inputs.push(if damaged.contains(&i) {
+                None
+            } else {
+                let path = base.join(&member.path);
+                let file = try!(File::open(&path).context(&path as &Path));
+                Some(BufReader::new(file))
+            });
+        }
+        inputs.extend((0..self.parity).map(|_| None));
+        let mut intact = 0;
+        for &(index, ref path) in &self.files {
+            match ParityHeader::load(path) {
+                // Files that belong to another group can not be used for this code
+                Ok(ref header) if header.group == self.id && header.size == size && index < self.parity => {
+                    let mut file = try!(File::open(path).context(path as &Path));
+                    let mut stream = Blake2Stream::new();
+                    try!(file.seek(SeekFrom::Start(8)).context(path as &Path));
+                    let mut reader = BufReader::new(file).take(size);
+                    let mut buf = vec![0; STRIPE_SIZE];
+                    loop {
+                        match try!(read_full(&mut reader, &mut buf).context(path as &Path)) {
+                            0 => break,
+                            n => stream.update(&buf[..n])
+                        }
+                    }
+                    if stream.finish() != header.hash {
+                        tr_warn!("Parity file {} is damaged", escape(&path));
+                        continue;
+                    }
+                    let mut file = reader.into_inner();
+                    try!(file.seek(SeekFrom::Start(8)).context(path as &Path));
+                    inputs[self.members.len() + index] = Some(file);
+                    intact += 1;
+                }
+                _ => tr_warn!("Parity file {} is damaged", escape(&path))
+            }
+        }
+        if intact < damaged.len() {
+            return Err(ParityError::Unrecoverable(damaged.len(), intact));
+        }
+        let rs = try!(ReedSolomon::new(self.members.len(), self.parity));
+        let mut outputs = Vec::with_capacity(damaged.len());
+        for &i in &damaged {
+            let path = base.join(&self.members[i].path).with_extension("bundle.restored");
+            if let Some(folder) = path.parent() {
+                try!(fs::create_dir_all(folder).context(folder));
+            }
+            let file = BufWriter::new(try!(File::create(&path).context(&path as &Path)));
+            outputs.push((file, Blake2Stream::new(), path));
+        }
+        let mut pos = 0;
+        while pos < size {
+            let len = min(STRIPE_SIZE as u64, size - pos) as usize;
+            let mut shards = Vec::with_capacity(inputs.len());
+            for input in &mut inputs {
+                shards.push(match *input {
+                    Some(ref mut file) => {
+                        let mut buf = vec![0; len];
+                        try!(read_full(file, &mut buf).context(base));
+                        Some(buf)
+                    }
+                    None => None
+                });
+            }
+            try!(rs.reconstruct(&mut shards));
+            for (&i, &mut (ref mut file, ref mut stream, ref path)) in damaged.iter().zip(outputs.iter_mut()) {
+                let member_size = self.members[i].size;
+                if pos < member_size {
+                    let end = min(len as u64, member_size - pos) as usize;
+                    let data = &shards[i].as_ref().unwrap()[..end];
+                    stream.update(data);
+                    try!(file.write_all(data).context(path as &Path));
+                }
+            }
+            pos += len as u64;
+        }
+        // Nothing is replaced unless all restored bundles match the hashes of the originals
+        let mut paths = Vec::with_capacity(damaged.len());
+        let mut mismatch = None;
+        for (&i, (mut file, stream, path)) in damaged.iter().zip(outputs) {
+            try!(file.flush().context(&path as &Path));
+            if mismatch.is_none() && stream.finish() != self.members[i].hash {
+                mismatch = Some(self.members[i].id.clone());
+            }
+            paths.push(path);
+        }
+        if let Some(id) = mismatch {
+            for path in &paths {
+                try!(fs::remove_file(path).context(path as &Path));
+            }
+            return Err(ParityError::RestoredMismatch(id));
+        }
+        let mut restored = Vec::with_capacity(damaged.len());
+        for (&i, path) in damaged.iter().zip(paths) {
+            let member = &self.members[i];
+            let dst = base.join(&member.path);
+            if dst.exists() {
+                let mut broken = dst.with_extension("bundle.broken");
+                let mut num = 1;
+                while broken.exists() {
+                    broken = dst.with_extension(&format!("bundle.{}.broken", num));
+                    num += 1;
+                }
+                try!(fs::rename(&dst, &broken).context(&broken as &Path));
+            }
+            try!(fs::rename(&path, &dst).context(&dst as &Path));
+            restored.push(member.id.clone());
+        }
+        Ok(restored)
+    }
+}
@@ -3,11 +3,13 @@ use super::*;
 
 use std::path::{Path, PathBuf};
 use std::fs::{self, File};
-use std::io::{self, Read, Seek, SeekFrom, BufReader};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader};
 use std::cmp::max;
 use std::fmt::{self, Debug};
 use std::sync::{Arc, Mutex};
 
+const CHECK_BUFFER_SIZE: usize = 64 * 1024;
+
 
 quick_error!{
     #[derive(Debug)]
@@ -52,6 +54,27 @@ quick_error!{
             description(tr!("Bundle has an integrity error"))
             display("{}", tr_format!("Bundle reader error: bundle {:?} has an integrity error: {}", bundle, reason))
         }
+        CorruptChunk(bundle: BundleId, hash: Hash, id: usize, offset: usize) {
+            description(tr!("Chunk does not match its hash"))
+            display("{}", tr_format!("Bundle reader error: checksum failed for chunk {} in bundle {} (chunk {} at offset {})", hash, bundle, id, offset))
+        }
+    }
+}
+
+impl BundleReaderError {
+    /// Returns the name of the step of reading a bundle that failed
+    pub fn step(&self) -> &'static str {
+        match *self {
+            BundleReaderError::Read(..) => tr!("read"),
+            BundleReaderError::WrongHeader(..) |
+            BundleReaderError::UnsupportedVersion(..) |
+            BundleReaderError::Decode(..) => tr!("header"),
+            BundleReaderError::NoSuchChunk(..) => tr!("lookup"),
+            BundleReaderError::Decompression(..) => tr!("decompression"),
+            BundleReaderError::Decryption(..) => tr!("decryption"),
+            BundleReaderError::Integrity(..) |
+            BundleReaderError::CorruptChunk(..) => tr!("checksum")
+        }
     }
 }
 
@@ -281,30 +304,80 @@ impl BundleReader {
             }
             return Ok(());
         }
-        let encoded_contents = try!(self.load_encoded_contents());
-        if self.info.encoded_size != encoded_contents.len() {
+        let size = try!(fs::metadata(&self.path).context(&self.path as &Path)).len();
+        if size as usize != self.info.encoded_size + self.content_start {
             return Err(BundleReaderError::Integrity(
                 self.id(),
                 tr!("Encoded data size does not match size in header, truncated bundle")
             ));
         }
-        let contents = try!(self.decode_contents(encoded_contents));
-        if self.info.raw_size != contents.len() {
+        // Stream the contents through the verifier to avoid holding the whole
+        // decoded bundle in memory
+        let chunks = self.chunks.as_ref().unwrap();
+        let mut verifier = ChunkVerifier::new(chunks, self.info.hash_method);
+        let mut stream = match self.info.compression {
+            Some(ref compression) => {
+                Some(try!(compression.decompress_stream().context(&self.path as &Path)))
+            }
+            None => None,
+        };
+        if let Some(ref encryption) = self.info.encryption {
+            // Sealed boxes can only be decrypted as a whole
+            let encoded_contents = try!(self.load_encoded_contents());
+            if self.info.encoded_size != encoded_contents.len() {
+                return Err(BundleReaderError::Integrity(
+                    self.id(),
+                    tr!("Encoded data size does not match size in header, truncated bundle")
+                ));
+            }
+            let data = try!(
+                self.crypto
+                    .lock()
+                    .unwrap()
+                    .decrypt(encryption, &encoded_contents)
+                    .context(&self.path as &Path)
+            );
+            try!(self.verify_data(&data, stream.as_mut(), &mut verifier));
+        } else {
+            let mut file = BufReader::new(try!(File::open(&self.path).context(&self.path as &Path)));
+            try!(
+                file.seek(SeekFrom::Start(self.content_start as u64))
+                    .context(&self.path as &Path)
+            );
+            let mut buffer = vec![0; CHECK_BUFFER_SIZE];
+            loop {
+                let len = try!(file.read(&mut buffer).context(&self.path as &Path));
+                if len == 0 {
+                    break;
+                }
+                try!(self.verify_data(&buffer[..len], stream.as_mut(), &mut verifier));
+            }
+        }
+        if let Some(stream) = stream {
+            try!(stream.finish(&mut verifier).context(&self.path as &Path));
+        }
+        if self.info.raw_size != verifier.size() || !verifier.is_complete() {
             return Err(BundleReaderError::Integrity(
                 self.id(),
                 tr!("Raw data size does not match size in header, truncated bundle")
             ));
         }
-        let mut pos = 0;
-        for chunk in self.chunks.as_ref().unwrap().as_ref() {
-            let data = &contents[pos..pos+chunk.1 as usize];
-            if self.info.hash_method.hash(data) != chunk.0 {
-                return Err(BundleReaderError::Integrity(
-                    self.id(),
-                    tr!("Stored hash does not match hash in header, modified data")
-                ));
-            }
-            pos += chunk.1 as usize;
+        if let Some((id, offset)) = verifier.first_invalid() {
+            return Err(BundleReaderError::CorruptChunk(self.id(), chunks[id].0, id, offset));
+        }
+        Ok(())
+    }
+
+    fn verify_data(
+        &self,
+        data: &[u8],
+        stream: Option<&mut CompressionStream>,
+        verifier: &mut ChunkVerifier,
+    ) -> Result<(), BundleReaderError> {
+        if let Some(stream) = stream {
+            try!(stream.process(data, verifier).context(&self.path as &Path));
+        } else {
+            try!(verifier.write_all(data).context(&self.path as &Path));
         }
         Ok(())
     }
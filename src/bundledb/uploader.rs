@@ -1,15 +1,91 @@
 use prelude::*;
 
-use std::sync::atomic::{Ordering, AtomicBool, AtomicUsize};
+use std::sync::atomic::{Ordering, AtomicBool, AtomicUsize, ATOMIC_USIZE_INIT};
 use std::sync::{Mutex, Condvar, Arc};
 use std::{mem, fs, thread};
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::cmp::min;
+use std::process;
 
 use crossbeam::sync::MsQueue;
 
 
+/// Seconds of data that may be written at full speed after a pause
+const RATE_LIMIT_BURST: f64 = 0.5;
+
+const COPY_BLOCK_SIZE: usize = 64 * 1024;
+
+static TEMP_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+
+/// Copies the file in blocks, marking the progress after each block
+///
+/// With a rate limiter, the limiter is waited for before writing each block and the blocks are
+/// small enough to take at most a second, so a slow copy is not mistaken for a stalled one.
+///
+/// Every call writes to its own temporary file next to `dst` that is only renamed to `dst`
+/// when it is complete. So an attempt that has been abandoned by the retry policy but is still
+/// running can never overwrite or truncate the file written by a later attempt.
+pub fn copy_file(
+    src: &Path,
+    dst: &Path,
+    limiter: Option<&Mutex<RateLimiter>>,
+    activity: &Activity,
+) -> Result<(), io::Error> {
+    let mut tmp_name = dst.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(
+        ".{}-{}.tmp",
+        process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    let tmp = dst.with_file_name(tmp_name);
+    let res = copy_file_contents(src, &tmp, limiter, activity).and_then(|()| {
+        if activity.is_abandoned() {
+            Err(io::Error::new(io::ErrorKind::TimedOut, tr!("the copy has been abandoned")))
+        } else {
+            fs::rename(&tmp, dst)
+        }
+    });
+    if res.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    res
+}
+
+fn copy_file_contents(
+    src: &Path,
+    dst: &Path,
+    limiter: Option<&Mutex<RateLimiter>>,
+    activity: &Activity,
+) -> Result<(), io::Error> {
+    let mut input = try!(File::open(src));
+    let mut output = try!(File::create(dst));
+    let block_size = limiter.map_or(COPY_BLOCK_SIZE, |limiter| {
+        min(COPY_BLOCK_SIZE, limiter.lock().unwrap().rate() as usize)
+    });
+    let mut buf = vec![0; block_size];
+    loop {
+        let len = try!(input.read(&mut buf));
+        if len == 0 {
+            break;
+        }
+        if let Some(limiter) = limiter {
+            limiter.lock().unwrap().acquire(len);
+        }
+        try!(output.write_all(&buf[..len]));
+        activity.touch();
+    }
+    output.sync_all()
+}
+
+
 pub struct BundleUploader {
     capacity: usize,
+    /// Maximal number of bytes per second that are written to the remote storage
+    rate_limit: Option<u64>,
+    retry: RetryPolicy,
     error_present: AtomicBool,
     error: Mutex<Option<BundleDbError>>,
     waiting: AtomicUsize,
@@ -18,9 +94,11 @@ pub struct BundleUploader {
 }
 
 impl BundleUploader {
-    pub fn new(capacity: usize) -> Arc<Self> {
+    pub fn new(capacity: usize, rate_limit: Option<u64>, retry: RetryPolicy) -> Arc<Self> {
         let self_ = Arc::new(BundleUploader {
             capacity,
+            rate_limit,
+            retry,
             error_present: AtomicBool::new(false),
             error: Mutex::new(None),
             waiting: AtomicUsize::new(0),
@@ -52,7 +130,7 @@ impl BundleUploader {
     pub fn queue(&self, local_path: PathBuf, remote_path: PathBuf) -> Result<(), BundleDbError> {
         while self.waiting.load(Ordering::SeqCst) >= self.capacity {
             tr_debug!("Upload queue is full, waiting for slots");
-            let _ = self.wait.0.wait(self.wait.1.lock().unwrap()).unwrap();
+            drop(self.wait.0.wait(self.wait.1.lock().unwrap()).unwrap());
         }
         tr_trace!("Adding to upload queue: {:?}", local_path);
         if !self.error_present.load(Ordering::SeqCst) {
@@ -68,19 +146,25 @@ impl BundleUploader {
             self.queue.push(None);
         }
         while self.waiting.load(Ordering::SeqCst) > 0 {
-            let _ = self.wait.0.wait(self.wait.1.lock().unwrap());
+            drop(self.wait.0.wait(self.wait.1.lock().unwrap()));
         }
         self.get_status()
     }
 
     fn worker_thread_inner(&self) -> Result<(), BundleDbError> {
+        // The limiter is only used by the uploads, so it never blocks the writers of the bundles
+        let limiter = Arc::new(self.rate_limit.map(|rate| {
+            Mutex::new(RateLimiter::new(rate, RATE_LIMIT_BURST))
+        }));
         while let Some((src_path, dst_path)) = self.queue.pop() {
             tr_trace!("Uploading {:?} to {:?}", src_path, dst_path);
             self.waiting.fetch_sub(1, Ordering::SeqCst);
             self.wait.0.notify_all();
-            let folder = dst_path.parent().unwrap();
-            try!(fs::create_dir_all(&folder).context(folder as &Path));
-            try!(fs::copy(&src_path, &dst_path).context(&dst_path as &Path));
+            let (src, dst, limiter) = (src_path.clone(), dst_path.clone(), limiter.clone());
+            try!(self.retry.run(move |activity| {
+                try!(fs::create_dir_all(dst.parent().unwrap()));
+                copy_file(&src, &dst, (*limiter).as_ref(), activity)
+            }).context(&dst_path as &Path));
             try!(fs::remove_file(&src_path).context(&src_path as &Path));
             tr_debug!("Uploaded {:?} to {:?}", src_path, dst_path);
         }
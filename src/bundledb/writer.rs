@@ -5,6 +5,10 @@ use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::{self, Write, BufWriter};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
 
 use chrono::prelude::*;
 
@@ -40,6 +44,72 @@ quick_error!{
             description(tr!("Failed to write data to file"))
             display("{}", tr_format!("Bundle writer error: failed to write data to file {:?}\n\tcaused by: {}", path, err))
         }
+        Thread(err: io::Error) {
+            cause(err)
+            description(tr!("Failed to start compression thread"))
+            display("{}", tr_format!("Bundle writer error: failed to start compression thread\n\tcaused by: {}", err))
+        }
+    }
+}
+
+
+/// Compresses the chunks of one bundle in a separate thread, keeping their order
+struct CompressionWorker {
+    input: Sender<Vec<u8>>,
+    thread: JoinHandle<Result<(Vec<u8>, f64), CompressionError>>,
+    // Raw bytes that have been compressed and size of the compressed output so far
+    progress: Arc<(AtomicUsize, AtomicUsize)>
+}
+
+impl CompressionWorker {
+    fn new(mut stream: CompressionStream) -> Result<Self, io::Error> {
+        let (input, chunks) = mpsc::channel::<Vec<u8>>();
+        let progress = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let progress2 = progress.clone();
+        let thread = try!(thread::Builder::new()
+            .name("compression".to_string())
+            .spawn(move || {
+                let mut data = vec![];
+                let mut time = 0.0;
+                for chunk in chunks {
+                    try!(timed(&mut time, || stream.process(&chunk, &mut data)));
+                    progress2.0.fetch_add(chunk.len(), Ordering::SeqCst);
+                    progress2.1.store(data.len(), Ordering::SeqCst);
+                }
+                try!(timed(&mut time, || stream.finish(&mut data)));
+                Ok((data, time))
+            }));
+        Ok(CompressionWorker {
+            input,
+            thread,
+            progress
+        })
+    }
+
+    fn add(&self, chunk: &[u8]) {
+        // The thread only stops on errors which are reported by finish
+        let _ = self.input.send(chunk.to_vec());
+    }
+
+    /// Estimates the compressed size, assuming the queued data compresses like the data so far
+    fn estimate_size(&self, raw_size: usize) -> usize {
+        let processed = self.progress.0.load(Ordering::SeqCst);
+        let compressed = self.progress.1.load(Ordering::SeqCst);
+        let queued = raw_size.saturating_sub(processed);
+        if processed == 0 {
+            return compressed + queued;
+        }
+        compressed + (queued as f64 * compressed as f64 / processed as f64) as usize
+    }
+
+    fn finish(self) -> Result<(Vec<u8>, f64), BundleWriterError> {
+        drop(self.input);
+        match self.thread.join() {
+            Ok(res) => res.map_err(BundleWriterError::Compression),
+            Err(_) => Err(BundleWriterError::Compression(
+                CompressionError::Operation(tr!("Compression thread panicked"))
+            ))
+        }
     }
 }
 
@@ -50,6 +120,7 @@ pub struct BundleWriter {
     data: Vec<u8>,
     compression: Option<Compression>,
     compression_stream: Option<CompressionStream>,
+    compression_worker: Option<CompressionWorker>,
     encryption: Option<Encryption>,
     crypto: Arc<Mutex<Crypto>>,
     raw_size: usize,
@@ -64,19 +135,31 @@ impl BundleWriter {
         compression: Option<Compression>,
         encryption: Option<Encryption>,
         crypto: Arc<Mutex<Crypto>>,
+        threaded: bool,
     ) -> Result<Self, BundleWriterError> {
-        let compression_stream = match compression {
+        let mut compression_stream = match compression {
             Some(ref compression) => Some(try!(compression.compress_stream().map_err(
                 BundleWriterError::CompressionSetup
             ))),
             None => None,
         };
+        let compression_worker = if threaded {
+            match compression_stream.take() {
+                Some(stream) => Some(try!(CompressionWorker::new(stream).map_err(
+                    BundleWriterError::Thread
+                ))),
+                None => None
+            }
+        } else {
+            None
+        };
         Ok(BundleWriter {
             mode,
             hash_method,
             data: vec![],
             compression,
             compression_stream,
+            compression_worker,
             encryption,
             crypto,
             raw_size: 0,
@@ -86,7 +169,9 @@ impl BundleWriter {
     }
 
     pub fn add(&mut self, chunk: &[u8], hash: Hash) -> Result<usize, BundleWriterError> {
-        if let Some(ref mut stream) = self.compression_stream {
+        if let Some(ref worker) = self.compression_worker {
+            worker.add(chunk)
+        } else if let Some(ref mut stream) = self.compression_stream {
             try!(stream.process(chunk, &mut self.data).map_err(
                 BundleWriterError::Compression
             ))
@@ -99,14 +184,34 @@ impl BundleWriter {
         Ok(self.chunk_count - 1)
     }
 
-    pub fn finish(mut self, db: &BundleDb) -> Result<StoredBundle, BundleWriterError> {
-        if let Some(stream) = self.compression_stream {
-            try!(stream.finish(&mut self.data).map_err(
+    /// Returns the id the bundle will have, which only depends on its chunks
+    pub fn id(&self) -> BundleId {
+        let mut chunk_data = Vec::with_capacity(self.chunks.encoded_size());
+        self.chunks.write_to(&mut chunk_data).unwrap();
+        BundleId(self.hash_method.hash(&chunk_data))
+    }
+
+    pub fn finish(
+        mut self,
+        layout: &RepositoryLayout,
+        times: &mut PipelineTimes,
+    ) -> Result<StoredBundle, BundleWriterError> {
+        if let Some(worker) = self.compression_worker.take() {
+            let (data, time) = try!(worker.finish());
+            self.data = data;
+            times.compressing += time;
+        }
+        if let Some(stream) = self.compression_stream.take() {
+            let data = &mut self.data;
+            try!(timed(&mut times.compressing, || stream.finish(data)).map_err(
                 BundleWriterError::Compression
             ))
         }
         if let Some(ref encryption) = self.encryption {
-            self.data = try!(self.crypto.lock().unwrap().encrypt(encryption, &self.data));
+            let data = try!(timed(&mut times.encrypting, || {
+                self.crypto.lock().unwrap().encrypt(encryption, &self.data)
+            }));
+            self.data = data;
         }
         let encoded_size = self.data.len();
         let mut chunk_data = Vec::with_capacity(self.chunks.encoded_size());
@@ -115,7 +220,8 @@ impl BundleWriter {
         if let Some(ref encryption) = self.encryption {
             chunk_data = try!(self.crypto.lock().unwrap().encrypt(encryption, &chunk_data));
         }
-        let mut path = db.layout.temp_bundle_path();
+        let write_start = Instant::now();
+        let mut path = layout.temp_bundle_path();
         let mut file = BufWriter::new(try!(File::create(&path).context(&path as &Path)));
         try!(file.write_all(&HEADER_STRING).context(&path as &Path));
         try!(file.write_all(&[HEADER_VERSION]).context(&path as &Path));
@@ -145,7 +251,9 @@ impl BundleWriter {
         try!(file.write_all(&info_data).context(&path as &Path));
         try!(file.write_all(&chunk_data).context(&path as &Path));
         try!(file.write_all(&self.data).context(&path as &Path));
-        path = path.strip_prefix(db.layout.base_path())
+        try!(file.flush().context(&path as &Path));
+        add_elapsed(&mut times.writing, write_start);
+        path = path.strip_prefix(layout.base_path())
             .unwrap()
             .to_path_buf();
         Ok(StoredBundle {
@@ -161,6 +269,10 @@ impl BundleWriter {
 
     #[inline]
     pub fn estimate_final_size(&self) -> usize {
-        self.data.len() + self.chunk_count * 20 + 500
+        let data_size = match self.compression_worker {
+            Some(ref worker) => worker.estimate_size(self.raw_size),
+            None => self.data.len()
+        };
+        data_size + self.chunk_count * 20 + 500
     }
 }
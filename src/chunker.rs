@@ -8,15 +8,28 @@ pub enum ChunkerType {
     Ae(usize),
     Rabin((usize, u32)),
     FastCdc((usize, u64)),
-    Fixed(usize)
+    Fixed(usize),
+    // Rabin with a custom window size, kept separate to stay compatible with existing backups
+    RabinWindow((usize, u32, usize)),
+    Buzhash((usize, u32, usize, usize, usize)),
+    // Chunkers with custom minimum and maximum chunk sizes, kept separate for the same reason
+    RabinBounds((usize, u32, usize, usize, usize)),
+    FastCdcBounds((usize, u64, usize, usize))
 }
 serde_impl!(ChunkerType(u64) {
     Ae(usize) => 1,
     Rabin((usize, u32)) => 2,
     FastCdc((usize, u64)) => 3,
-    Fixed(usize) => 4
+    Fixed(usize) => 4,
+    RabinWindow((usize, u32, usize)) => 5,
+    Buzhash((usize, u32, usize, usize, usize)) => 6,
+    RabinBounds((usize, u32, usize, usize, usize)) => 7,
+    FastCdcBounds((usize, u64, usize, usize)) => 8
 });
 
+/// The smallest supported window size of the rabin and buzhash chunkers
+pub const MIN_RABIN_WINDOW_SIZE: usize = 16;
+
 
 impl ChunkerType {
     pub fn from(name: &str, avg_size: usize, seed: u64) -> Result<Self, &'static str> {
@@ -25,23 +38,143 @@ impl ChunkerType {
             "rabin" => Ok(ChunkerType::Rabin((avg_size, seed as u32))),
             "fastcdc" => Ok(ChunkerType::FastCdc((avg_size, seed))),
             "fixed" => Ok(ChunkerType::Fixed(avg_size)),
+            "buzhash" => {
+                let (min, max) = BuzhashChunker::default_bounds(avg_size);
+                Ok(ChunkerType::Buzhash((avg_size, seed as u32, DEFAULT_BUZHASH_WINDOW_SIZE, min, max)))
+            }
             _ => Err(tr!("Unsupported chunker type")),
         }
     }
 
+    /// Parses a chunker in the form `name/avg[:min:max][/window]` with sizes in KiB
     pub fn from_string(name: &str) -> Result<Self, &'static str> {
-        let (name, size) = if let Some(pos) = name.find('/') {
-            let size = try!(usize::from_str(&name[pos + 1..]).map_err(
+        let mut parts = name.splitn(3, '/');
+        let name = parts.next().unwrap();
+        let (size, bounds) = if let Some(sizes) = parts.next() {
+            let mut sizes = sizes.split(':');
+            let size = try!(usize::from_str(sizes.next().unwrap()).map_err(
                 |_| tr!("Chunk size must be a number")
             ));
-            let name = &name[..pos];
-            (name, size)
+            let bounds = match (sizes.next(), sizes.next(), sizes.next()) {
+                (None, _, _) => None,
+                (Some(min), Some(max), None) => Some((
+                    try!(usize::from_str(min).map_err(|_| tr!("Chunk size must be a number"))),
+                    try!(usize::from_str(max).map_err(|_| tr!("Chunk size must be a number")))
+                )),
+                _ => return Err(tr!("Chunk sizes must be given as avg:min:max"))
+            };
+            (size, bounds)
         } else {
-            (name, 8)
+            (8, None)
         };
-        Self::from(name, size * 1024, 0)
+        let mut chunker = try!(Self::from(name, size * 1024, 0));
+        if let Some((min, max)) = bounds {
+            chunker = try!(chunker.with_bounds(min * 1024, max * 1024));
+        }
+        if let Some(window) = parts.next() {
+            let window = try!(usize::from_str(window).map_err(
+                |_| tr!("Window size must be a number")
+            ));
+            chunker.with_window_size(window)
+        } else {
+            Ok(chunker)
+        }
+    }
+
+    /// Selects the most compact variant for the given rabin parameters
+    fn rabin(size: usize, seed: u32, window: usize, min: usize, max: usize) -> Self {
+        if (min, max) != RabinChunker::default_bounds(size) {
+            ChunkerType::RabinBounds((size, seed, window, min, max))
+        } else if window != RabinChunker::default_window_size(size) {
+            ChunkerType::RabinWindow((size, seed, window))
+        } else {
+            ChunkerType::Rabin((size, seed))
+        }
+    }
+
+    /// Sets the window size of the rabin or buzhash chunker in bytes
+    ///
+    /// For the rabin chunker, the window must be between `MIN_RABIN_WINDOW_SIZE` and the
+    /// default window size (a quarter of the average chunk size), so it can only be used to
+    /// reduce the memory usage. The buzhash window can be anywhere between
+    /// `MIN_RABIN_WINDOW_SIZE` and a quarter of the average chunk size.
+    pub fn with_window_size(self, window: usize) -> Result<Self, &'static str> {
+        match self {
+            ChunkerType::Rabin((size, seed)) |
+            ChunkerType::RabinWindow((size, seed, _)) |
+            ChunkerType::RabinBounds((size, seed, _, _, _)) => {
+                let default = RabinChunker::default_window_size(size);
+                if window != default && (window < MIN_RABIN_WINDOW_SIZE || window > default) {
+                    Err(tr!("Window size must be between 16 bytes and a quarter of the chunk size"))
+                } else {
+                    let (min, max) = self.bounds().unwrap_or_else(|| RabinChunker::default_bounds(size));
+                    Ok(Self::rabin(size, seed, window, min, max))
+                }
+            }
+            ChunkerType::Buzhash((size, seed, _, min, max)) => {
+                if window < MIN_RABIN_WINDOW_SIZE || window > size / 4 {
+                    Err(tr!("Window size must be between 16 bytes and a quarter of the chunk size"))
+                } else {
+                    Ok(ChunkerType::Buzhash((size, seed, window, min, max)))
+                }
+            }
+            _ => Err(tr!("Window size is only supported by the rabin and buzhash chunkers"))
+        }
     }
 
+    /// Sets the minimum and maximum chunk size of the rabin, fastcdc or buzhash chunker in bytes
+    ///
+    /// The minimum must not be larger and the maximum not smaller than the average chunk size.
+    pub fn with_bounds(self, min: usize, max: usize) -> Result<Self, &'static str> {
+        let size = self.avg_size();
+        if min == 0 || min > size || max < size {
+            return Err(tr!("Chunk sizes must satisfy 0 < min <= avg <= max"))
+        }
+        match self {
+            ChunkerType::Rabin((size, seed)) => {
+                Ok(Self::rabin(size, seed, RabinChunker::default_window_size(size), min, max))
+            }
+            ChunkerType::RabinWindow((size, seed, window)) |
+            ChunkerType::RabinBounds((size, seed, window, _, _)) => {
+                Ok(Self::rabin(size, seed, window, min, max))
+            }
+            ChunkerType::FastCdc((size, seed)) |
+            ChunkerType::FastCdcBounds((size, seed, _, _)) => {
+                if (min, max) == FastCdcChunker::default_bounds(size) {
+                    Ok(ChunkerType::FastCdc((size, seed)))
+                } else {
+                    Ok(ChunkerType::FastCdcBounds((size, seed, min, max)))
+                }
+            }
+            ChunkerType::Buzhash((size, seed, window, _, _)) => {
+                Ok(ChunkerType::Buzhash((size, seed, window, min, max)))
+            }
+            _ => Err(tr!("Chunk size bounds are only supported by the rabin, fastcdc and buzhash chunkers"))
+        }
+    }
+
+    /// Sets the seed of the rabin, fastcdc or buzhash chunker
+    ///
+    /// The rabin and buzhash chunkers only use the lower 32 bits of the seed.
+    pub fn with_seed(self, seed: u64) -> Result<Self, &'static str> {
+        match self {
+            ChunkerType::Rabin((size, _)) => Ok(ChunkerType::Rabin((size, seed as u32))),
+            ChunkerType::FastCdc((size, _)) => Ok(ChunkerType::FastCdc((size, seed))),
+            ChunkerType::RabinWindow((size, _, window)) => {
+                Ok(ChunkerType::RabinWindow((size, seed as u32, window)))
+            }
+            ChunkerType::Buzhash((size, _, window, min, max)) => {
+                Ok(ChunkerType::Buzhash((size, seed as u32, window, min, max)))
+            }
+            ChunkerType::RabinBounds((size, _, window, min, max)) => {
+                Ok(ChunkerType::RabinBounds((size, seed as u32, window, min, max)))
+            }
+            ChunkerType::FastCdcBounds((size, _, min, max)) => {
+                Ok(ChunkerType::FastCdcBounds((size, seed, min, max)))
+            }
+            _ => Err(tr!("A seed is only supported by the rabin, fastcdc and buzhash chunkers"))
+        }
+    }
 
     #[inline]
     pub fn create(&self) -> Box<Chunker> {
@@ -50,6 +183,16 @@ impl ChunkerType {
             ChunkerType::Rabin((size, seed)) => Box::new(RabinChunker::new(size, seed)),
             ChunkerType::FastCdc((size, seed)) => Box::new(FastCdcChunker::new(size, seed)),
             ChunkerType::Fixed(size) => Box::new(FixedChunker::new(size)),
+            ChunkerType::RabinWindow((size, seed, window)) => Box::new(RabinChunker::with_window(size, seed, window)),
+            ChunkerType::Buzhash((size, seed, window, min, max)) => {
+                Box::new(BuzhashChunker::with_bounds(size, seed, window, min, max))
+            }
+            ChunkerType::RabinBounds((size, seed, window, min, max)) => {
+                Box::new(RabinChunker::with_bounds(size, seed, window, min, max))
+            }
+            ChunkerType::FastCdcBounds((size, seed, min, max)) => {
+                Box::new(FastCdcChunker::with_bounds(size, seed, min, max))
+            }
         }
     }
 
@@ -59,6 +202,10 @@ impl ChunkerType {
             ChunkerType::Rabin((_size, _seed)) => "rabin",
             ChunkerType::FastCdc((_size, _seed)) => "fastcdc",
             ChunkerType::Fixed(_size) => "fixed",
+            ChunkerType::RabinWindow((_size, _seed, _window)) => "rabin",
+            ChunkerType::Buzhash((_size, _seed, _window, _min, _max)) => "buzhash",
+            ChunkerType::RabinBounds((_size, _seed, _window, _min, _max)) => "rabin",
+            ChunkerType::FastCdcBounds((_size, _seed, _min, _max)) => "fastcdc",
         }
     }
 
@@ -68,11 +215,62 @@ impl ChunkerType {
             ChunkerType::Fixed(size) => size,
             ChunkerType::Rabin((size, _seed)) => size,
             ChunkerType::FastCdc((size, _seed)) => size,
+            ChunkerType::RabinWindow((size, _seed, _window)) => size,
+            ChunkerType::Buzhash((size, _seed, _window, _min, _max)) => size,
+            ChunkerType::RabinBounds((size, _seed, _window, _min, _max)) => size,
+            ChunkerType::FastCdcBounds((size, _seed, _min, _max)) => size,
+        }
+    }
+
+    /// Returns the window size if it differs from the default
+    pub fn window_size(&self) -> Option<usize> {
+        match *self {
+            ChunkerType::RabinWindow((_size, _seed, window)) => Some(window),
+            ChunkerType::RabinBounds((size, _seed, window, _min, _max))
+                if window != RabinChunker::default_window_size(size) => Some(window),
+            ChunkerType::Buzhash((_size, _seed, window, _min, _max))
+                if window != DEFAULT_BUZHASH_WINDOW_SIZE => Some(window),
+            _ => None
+        }
+    }
+
+    /// Returns the minimum and maximum chunk size if they differ from the defaults
+    pub fn bounds(&self) -> Option<(usize, usize)> {
+        match *self {
+            ChunkerType::RabinBounds((_size, _seed, _window, min, max)) => Some((min, max)),
+            ChunkerType::FastCdcBounds((_size, _seed, min, max)) => Some((min, max)),
+            ChunkerType::Buzhash((size, _seed, _window, min, max))
+                if (min, max) != BuzhashChunker::default_bounds(size) => Some((min, max)),
+            _ => None
+        }
+    }
+
+    /// Returns the size at which the chunker cuts a chunk without finding a cut point
+    ///
+    /// The ae chunker has no such limit and all chunks of the fixed chunker have this size, so
+    /// `None` is returned for both.
+    pub fn max_size(&self) -> Option<usize> {
+        match *self {
+            ChunkerType::Ae(_size) |
+            ChunkerType::Fixed(_size) => None,
+            ChunkerType::Rabin((size, _seed)) => Some(RabinChunker::default_bounds(size).1),
+            ChunkerType::RabinWindow((size, _seed, _window)) => Some(RabinChunker::default_bounds(size).1),
+            ChunkerType::FastCdc((size, _seed)) => Some(FastCdcChunker::default_bounds(size).1),
+            ChunkerType::Buzhash((_size, _seed, _window, _min, max)) => Some(max),
+            ChunkerType::RabinBounds((_size, _seed, _window, _min, max)) => Some(max),
+            ChunkerType::FastCdcBounds((_size, _seed, _min, max)) => Some(max),
         }
     }
 
     pub fn to_string(&self) -> String {
-        format!("{}/{}", self.name(), self.avg_size() / 1024)
+        let mut name = format!("{}/{}", self.name(), self.avg_size() / 1024);
+        if let Some((min, max)) = self.bounds() {
+            name.push_str(&format!(":{}:{}", min / 1024, max / 1024));
+        }
+        if let Some(window) = self.window_size() {
+            name.push_str(&format!("/{}", window));
+        }
+        name
     }
 
     pub fn seed(&self) -> u64 {
@@ -81,6 +279,51 @@ impl ChunkerType {
             ChunkerType::Fixed(_size) => 0,
             ChunkerType::Rabin((_size, seed)) => u64::from(seed),
             ChunkerType::FastCdc((_size, seed)) => seed,
+            ChunkerType::RabinWindow((_size, seed, _window)) => u64::from(seed),
+            ChunkerType::Buzhash((_size, seed, _window, _min, _max)) => u64::from(seed),
+            ChunkerType::RabinBounds((_size, seed, _window, _min, _max)) => u64::from(seed),
+            ChunkerType::FastCdcBounds((_size, seed, _min, _max)) => seed,
         }
     }
 }
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_with_seed() {
+        let seed = 0x1234_5678_9abc_def0;
+        let cases = vec![
+            (ChunkerType::Rabin((16 * 1024, 0)), ChunkerType::Rabin((16 * 1024, 0x9abc_def0))),
+            (ChunkerType::FastCdc((16 * 1024, 0)), ChunkerType::FastCdc((16 * 1024, seed))),
+            (
+                ChunkerType::RabinWindow((16 * 1024, 0, 64)),
+                ChunkerType::RabinWindow((16 * 1024, 0x9abc_def0, 64))
+            ),
+            (
+                ChunkerType::Buzhash((16 * 1024, 0, 64, 4096, 65536)),
+                ChunkerType::Buzhash((16 * 1024, 0x9abc_def0, 64, 4096, 65536))
+            ),
+            (
+                ChunkerType::RabinBounds((16 * 1024, 0, 64, 4096, 65536)),
+                ChunkerType::RabinBounds((16 * 1024, 0x9abc_def0, 64, 4096, 65536))
+            ),
+            (
+                ChunkerType::FastCdcBounds((16 * 1024, 0, 4096, 65536)),
+                ChunkerType::FastCdcBounds((16 * 1024, seed, 4096, 65536))
+            )
+        ];
+        for (chunker, expected) in cases {
+            assert_eq!(chunker.with_seed(seed), Ok(expected));
+            assert_eq!(expected.seed() as u32, seed as u32);
+        }
+        assert!(ChunkerType::Ae(16 * 1024).with_seed(seed).is_err());
+        assert!(ChunkerType::Fixed(16 * 1024).with_seed(seed).is_err());
+    }
+
+}
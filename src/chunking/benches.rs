@@ -128,3 +128,24 @@ fn test_fastcdc_8192(b: &mut Bencher) {
         test::black_box(sink.positions().len())
     })
 }
+
+
+#[bench]
+fn test_buzhash_init(b: &mut Bencher) {
+    b.iter(|| {
+        BuzhashChunker::new(8*1024, 0);
+    })
+}
+
+#[bench]
+fn test_buzhash_8192(b: &mut Bencher) {
+    let data = random_data(0, 1024*1024);
+    b.bytes = data.len() as u64;
+    b.iter(|| {
+        let mut chunker = BuzhashChunker::new(8*1024, 0);
+        let mut cursor = Cursor::new(&data);
+        let mut sink = CutPositions::new();
+        while chunker.chunk(&mut cursor, &mut sink).unwrap() == ChunkerStatus::Continue {};
+        test::black_box(sink.positions().len())
+    })
+}
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::ptr;
+
+use super::*;
+
+// Buzhash Chunker (cyclic polynomial rolling hash)
+// Paper: "Recursive Hashing Functions for n-Grams"
+// Paper-URL: https://dl.acm.org/citation.cfm?id=256168
+// Wikipedia: https://en.wikipedia.org/wiki/Rolling_hash#Cyclic_polynomial
+
+
+// Creating 256 pseudo-random values (based on Knuth's MMIX)
+fn create_table(seed: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let a = 6_364_136_223_846_793_005u64;
+    let c = 1_442_695_040_888_963_407u64;
+    let mut v = u64::from(seed);
+    for t in &mut table.iter_mut() {
+        v = v.wrapping_mul(a).wrapping_add(c);
+        *t = (v >> 32) as u32;
+    }
+    table
+}
+
+
+/// The window size used if none is given
+pub const DEFAULT_BUZHASH_WINDOW_SIZE: usize = 48;
+
+
+pub struct BuzhashChunker {
+    buffer: [u8; 0x1000],
+    window: VecDeque<u8>,
+    buffered: usize,
+    table: [u32; 256],
+    min_size: usize,
+    max_size: usize,
+    window_size: usize,
+    chunk_mask: u32,
+}
+
+
+impl BuzhashChunker {
+    pub fn new(avg_size: usize, seed: u32) -> Self {
+        Self::with_window(avg_size, seed, DEFAULT_BUZHASH_WINDOW_SIZE)
+    }
+
+    /// The minimum and maximum chunk size used if none are given
+    #[inline]
+    pub fn default_bounds(avg_size: usize) -> (usize, usize) {
+        (avg_size/4, avg_size*4)
+    }
+
+    /// Creates a chunker with a custom window size
+    ///
+    /// As the chunk borders depend on the window size, changing it prevents deduplication
+    /// with chunks created using a different window size.
+    pub fn with_window(avg_size: usize, seed: u32, window_size: usize) -> Self {
+        let (min_size, max_size) = Self::default_bounds(avg_size);
+        Self::with_bounds(avg_size, seed, window_size, min_size, max_size)
+    }
+
+    /// Creates a chunker with a custom window size and custom minimum and maximum chunk sizes
+    pub fn with_bounds(avg_size: usize, seed: u32, window_size: usize, min_size: usize, max_size: usize) -> Self {
+        assert!(window_size > 0 && window_size <= avg_size/4);
+        assert!(min_size <= avg_size && avg_size <= max_size);
+        let chunk_mask = (avg_size as u32).next_power_of_two() - 1;
+        BuzhashChunker {
+            buffer: [0; 0x1000],
+            window: VecDeque::with_capacity(window_size),
+            buffered: 0,
+            table: create_table(seed),
+            min_size,
+            max_size,
+            window_size,
+            chunk_mask,
+        }
+    }
+}
+
+impl Chunker for BuzhashChunker {
+    #[allow(unknown_lints,explicit_counter_loop)]
+    fn chunk(&mut self, r: &mut Read, w: &mut Write) -> Result<ChunkerStatus, ChunkerError> {
+        let mut max;
+        let mut hash = 0u32;
+        let mut pos = 0;
+        // Rotation that removes a byte leaving the window
+        let shift = (self.window_size % 32) as u32;
+        let window = &mut self.window;
+        window.clear();
+        loop {
+            // Fill the buffer, there might be some bytes still in there from last chunk
+            max = try!(r.read(&mut self.buffer[self.buffered..]).map_err(ChunkerError::Read)) + self.buffered;
+            // If nothing to do, finish
+            if max == 0 {
+                return Ok(ChunkerStatus::Finished)
+            }
+            for i in 0..max {
+                let val = self.buffer[i];
+                if pos >= self.max_size {
+                    try!(w.write_all(&self.buffer[..i+1]).map_err(ChunkerError::Write));
+                    unsafe { ptr::copy(self.buffer[i+1..].as_ptr(), self.buffer.as_mut_ptr(), max-i-1) };
+                    self.buffered = max-i-1;
+                    return Ok(ChunkerStatus::Continue);
+                }
+                // Hash update
+                hash = hash.rotate_left(1) ^ self.table[val as usize];
+                if pos >= self.window_size {
+                    let take = window.pop_front().unwrap();
+                    hash ^= self.table[take as usize].rotate_left(shift);
+                    if pos >= self.min_size && (hash & self.chunk_mask) == 0 {
+                        try!(w.write_all(&self.buffer[..i+1]).map_err(ChunkerError::Write));
+                        unsafe { ptr::copy(self.buffer[i+1..].as_ptr(), self.buffer.as_mut_ptr(), max-i-1) };
+                        self.buffered = max-i-1;
+                        return Ok(ChunkerStatus::Continue);
+                    }
+                }
+                pos += 1;
+                window.push_back(val);
+            }
+            try!(w.write_all(&self.buffer[..max]).map_err(ChunkerError::Write));
+            self.buffered = 0;
+        }
+    }
+}
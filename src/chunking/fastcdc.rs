@@ -59,13 +59,26 @@ pub struct FastCdcChunker {
 
 impl FastCdcChunker {
     pub fn new(avg_size: usize, seed: u64) -> Self {
+        let (min_size, max_size) = Self::default_bounds(avg_size);
+        Self::with_bounds(avg_size, seed, min_size, max_size)
+    }
+
+    /// The minimum and maximum chunk size used if none are given
+    #[inline]
+    pub fn default_bounds(avg_size: usize) -> (usize, usize) {
+        (avg_size/4, avg_size*8)
+    }
+
+    /// Creates a chunker with custom minimum and maximum chunk sizes
+    pub fn with_bounds(avg_size: usize, seed: u64, min_size: usize, max_size: usize) -> Self {
+        assert!(min_size <= avg_size && avg_size <= max_size);
         let (mask_short, mask_long) = get_masks(avg_size, 2, seed);
         FastCdcChunker {
             buffer: [0; 0x1000],
             buffered: 0,
             gear: create_gear(seed),
-            min_size: avg_size/4,
-            max_size: avg_size*8,
+            min_size,
+            max_size,
             avg_size,
             mask_long,
             mask_short,
@@ -4,6 +4,7 @@ mod fixed;
 mod ae;
 mod rabin;
 mod fastcdc;
+mod buzhash;
 #[cfg(test)] mod test;
 #[cfg(feature = "bench")] mod benches;
 
@@ -11,6 +12,7 @@ pub use self::fixed::FixedChunker;
 pub use self::ae::AeChunker;
 pub use self::rabin::RabinChunker;
 pub use self::fastcdc::FastCdcChunker;
+pub use self::buzhash::{BuzhashChunker, DEFAULT_BUZHASH_WINDOW_SIZE};
 
 // https://moinakg.wordpress.com/2013/06/22/high-performance-content-defined-chunking/
 
@@ -35,6 +35,7 @@ fn create_table(alpha: u32, window_size: usize) -> [u32; 256] {
 
 pub struct RabinChunker {
     buffer: [u8; 0x1000],
+    window: VecDeque<u8>,
     buffered: usize,
     seed: u32,
     alpha: u32,
@@ -48,17 +49,46 @@ pub struct RabinChunker {
 
 impl RabinChunker {
     pub fn new(avg_size: usize, seed: u32) -> Self {
+        Self::with_window(avg_size, seed, Self::default_window_size(avg_size))
+    }
+
+    /// The window size used if none is given, this is also the maximal window size
+    #[inline]
+    pub fn default_window_size(avg_size: usize) -> usize {
+        avg_size/4-1
+    }
+
+    /// The minimum and maximum chunk size used if none are given
+    #[inline]
+    pub fn default_bounds(avg_size: usize) -> (usize, usize) {
+        (avg_size/4, avg_size*4)
+    }
+
+    /// Creates a chunker with a custom window size
+    ///
+    /// The chunker keeps the window in memory, so smaller windows need less memory. But as
+    /// the chunk borders depend on the window size, changing it prevents deduplication with
+    /// chunks created using a different window size.
+    pub fn with_window(avg_size: usize, seed: u32, window_size: usize) -> Self {
+        let (min_size, max_size) = Self::default_bounds(avg_size);
+        Self::with_bounds(avg_size, seed, window_size, min_size, max_size)
+    }
+
+    /// Creates a chunker with a custom window size and custom minimum and maximum chunk sizes
+    pub fn with_bounds(avg_size: usize, seed: u32, window_size: usize, min_size: usize, max_size: usize) -> Self {
+        assert!(window_size > 0 && window_size <= Self::default_window_size(avg_size));
+        assert!(min_size <= avg_size && avg_size <= max_size);
         let chunk_mask = (avg_size as u32).next_power_of_two() - 1;
-        let window_size = avg_size/4-1;
         let alpha = 1_664_525;//153191;
         RabinChunker {
             buffer: [0; 0x1000],
+            window: VecDeque::with_capacity(window_size),
             buffered: 0,
             table: create_table(alpha, window_size),
             alpha,
             seed,
-            min_size: avg_size/4,
-            max_size: avg_size*4,
+            min_size,
+            max_size,
             window_size,
             chunk_mask,
         }
@@ -71,7 +101,8 @@ impl Chunker for RabinChunker {
         let mut max;
         let mut hash = 0u32;
         let mut pos = 0;
-        let mut window = VecDeque::with_capacity(self.window_size);
+        let window = &mut self.window;
+        window.clear();
         loop {
             // Fill the buffer, there might be some bytes still in there from last chunk
             max = try!(r.read(&mut self.buffer[self.buffered..]).map_err(ChunkerError::Read)) + self.buffered;
@@ -1,6 +1,7 @@
 use super::*;
 
 use std::io::Cursor;
+use std::collections::HashSet;
 
 
 fn random_data(seed: u64, size: usize) -> Vec<u8> {
@@ -87,6 +88,20 @@ fn test_rabin() {
     }
 }
 
+#[test]
+fn test_rabin_window() {
+    let data = random_data(0, 1024*1024);
+    for window in &[16usize, 64, 256, 1024, 2047] {
+        let mut chunker = RabinChunker::with_window(8192, 0, *window);
+        let len = test_chunking(&mut chunker, &data, None);
+        assert!(len >= data.len()/8192/4);
+        assert!(len <= data.len()/8192*4);
+    }
+    let mut default = RabinChunker::new(8192, 0);
+    let mut full = RabinChunker::with_window(8192, 0, RabinChunker::default_window_size(8192));
+    assert_eq!(test_chunking(&mut default, &data, None), test_chunking(&mut full, &data, None));
+}
+
 #[test]
 fn test_fastcdc() {
     test_chunking(&mut FastCdcChunker::new(8192, 0), &random_data(0, 128*1024),
@@ -100,3 +115,75 @@ fn test_fastcdc() {
         assert!(len <= data.len()/n/1024*4);
     }
 }
+
+#[test]
+fn test_buzhash() {
+    let data = random_data(0, 10*1024*1024);
+    for n in &[1usize,2,4,8,16,32,64,128,256,512,1024] {
+        let mut chunker = BuzhashChunker::new(1024*n, 0);
+        let len = test_chunking(&mut chunker, &data, None);
+        assert!(len >= data.len()/n/1024/4);
+        assert!(len <= data.len()/n/1024*4);
+    }
+    let data = random_data(0, 1024*1024);
+    for window in &[16usize, 32, 256, 2048] {
+        let mut chunker = BuzhashChunker::with_window(8192, 0, *window);
+        let len = test_chunking(&mut chunker, &data, None);
+        assert!(len >= data.len()/8192/4);
+        assert!(len <= data.len()/8192*4);
+    }
+}
+
+fn test_bounds(chunker: &mut Chunker, min_size: usize, max_size: usize) {
+    let data = random_data(0, 4*1024*1024);
+    let chunks = collect_chunks(chunker, &data);
+    for chunk in &chunks[..chunks.len()-2] {
+        assert!(chunk.len() >= min_size);
+        // Rabin and buzhash include the byte that reached the maximum in the chunk
+        assert!(chunk.len() <= max_size + 1);
+    }
+}
+
+#[test]
+fn test_custom_bounds() {
+    test_bounds(&mut RabinChunker::with_bounds(8192, 0, 2047, 4096, 16384), 4096, 16384);
+    test_bounds(&mut FastCdcChunker::with_bounds(8192, 0, 2048, 65536), 2048, 65536);
+    test_bounds(&mut FastCdcChunker::with_bounds(8192, 0, 8192, 8192), 8192, 8192);
+    test_bounds(&mut BuzhashChunker::with_bounds(8192, 0, 48, 6144, 12288), 6144, 12288);
+}
+
+fn collect_chunks(chunker: &mut Chunker, data: &[u8]) -> Vec<Vec<u8>> {
+    let mut cursor = Cursor::new(&data);
+    let mut chunks = vec![];
+    let mut chunk = vec![];
+    while chunker.chunk(&mut cursor, &mut chunk).unwrap() == ChunkerStatus::Continue {
+        chunks.push(chunk);
+        chunk = vec![];
+    }
+    chunks.push(chunk);
+    chunks
+}
+
+fn test_append(chunker: &mut Chunker) {
+    let data = random_data(0, 4*1024*1024);
+    let mut appended = data.clone();
+    appended.extend_from_slice(&random_data(1, 64*1024));
+    let old = collect_chunks(chunker, &data);
+    let new = collect_chunks(chunker, &appended);
+    // Only the chunk cut by the end of the data and the final empty chunk may change
+    assert!(old.len() > 2);
+    assert_eq!(&old[..old.len()-2], &new[..old.len()-2]);
+    let known: HashSet<&[u8]> = old.iter().map(|c| c as &[u8]).collect();
+    let deduplicated: usize = new.iter().filter(|c| known.contains(c as &[u8])).map(|c| c.len()).sum();
+    let last = old[old.len()-2].len() + old[old.len()-1].len();
+    assert!(deduplicated + last >= data.len());
+}
+
+#[test]
+fn test_append_stability() {
+    test_append(&mut FixedChunker::new(8192));
+    test_append(&mut AeChunker::new(8192));
+    test_append(&mut RabinChunker::new(8192, 0));
+    test_append(&mut FastCdcChunker::new(8192, 0));
+    test_append(&mut BuzhashChunker::new(8192, 0));
+}
@@ -1,10 +1,14 @@
 use prelude::*;
+use super::print_pipeline_times;
 
 use std::io::{self, Cursor, Read, Write};
 use std::fs::File;
 use std::collections::HashSet;
+use std::cmp;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use chrono::Duration;
+use crossbeam;
 
 
 struct ChunkSink {
@@ -32,6 +36,51 @@ impl Write for ChunkSink {
     }
 }
 
+/// Prints the distribution of the chunk sizes in buckets of powers of two
+///
+/// Many chunks of the maximal size mean that the chunker often fails to find a cut point.
+fn print_chunk_sizes(chunks: &[(usize, usize)], max_size: Option<usize>) {
+    let mut buckets = vec![0usize; 64];
+    let mut min = usize::max_value();
+    let mut max = 0;
+    for &(_, len) in chunks {
+        // The last chunk can be empty
+        let bucket = if len == 0 { 0 } else { 63 - (len as u64).leading_zeros() as usize };
+        buckets[bucket] += 1;
+        min = cmp::min(min, len);
+        max = cmp::max(max, len);
+    }
+    tr_println!(
+        "- chunk sizes: min {}, max {}",
+        to_file_size(min as u64),
+        to_file_size(max as u64)
+    );
+    let first = buckets.iter().position(|&c| c > 0).unwrap_or(0);
+    let last = buckets.iter().rposition(|&c| c > 0).unwrap_or(0);
+    let most = buckets.iter().cloned().max().unwrap_or(0);
+    for (i, &count) in buckets.iter().enumerate().take(last + 1).skip(first) {
+        let share = count as f32 / chunks.len() as f32;
+        let bar = if most > 0 { count * 40 / most } else { 0 };
+        println!(
+            "  {:>10} - {:>10}: {:>8} {:>5.1}% {}",
+            to_file_size(1u64 << i),
+            to_file_size(1u64 << (i + 1)),
+            count,
+            share * 100.0,
+            "#".repeat(bar)
+        );
+    }
+    if let Some(max_size) = max_size {
+        let at_max = chunks.iter().filter(|c| c.1 >= max_size).count();
+        tr_println!(
+            "- {} chunks ({:.1}%) cut at the maximal size of {}",
+            at_max,
+            at_max as f32 / chunks.len() as f32 * 100.0,
+            to_file_size(max_size as u64)
+        );
+    }
+}
+
 fn chunk(data: &[u8], mut chunker: Box<Chunker>, sink: &mut ChunkSink) {
     let mut cursor = Cursor::new(data);
     while chunker.chunk(&mut cursor, sink).unwrap() == ChunkerStatus::Continue {
@@ -40,7 +89,26 @@ fn chunk(data: &[u8], mut chunker: Box<Chunker>, sink: &mut ChunkSink) {
     sink.end_chunk();
 }
 
-#[allow(dead_code)]
+/// Compresses the bundles using the given number of threads and returns the time it took
+fn compress_parallel(data: &[u8], bundles: &[Vec<(usize, usize)>], compression: &Compression, threads: usize) -> f32 {
+    let next = AtomicUsize::new(0);
+    Duration::span(|| crossbeam::scope(|scope| for _ in 0..threads {
+        scope.spawn(|| loop {
+            let i = next.fetch_add(1, Ordering::SeqCst);
+            if i >= bundles.len() {
+                break;
+            }
+            let mut bundle = Vec::new();
+            let mut c = compression.compress_stream().unwrap();
+            for &(pos, len) in &bundles[i] {
+                c.process(&data[pos..pos + len], &mut bundle).unwrap();
+            }
+            c.finish(&mut bundle).unwrap();
+        });
+    })).num_milliseconds() as f32 / 1_000.0
+}
+
+#[allow(dead_code, unknown_lints, too_many_arguments)]
 pub fn run(
     path: &str,
     bundle_size: usize,
@@ -48,9 +116,11 @@ pub fn run(
     compression: Option<Compression>,
     encrypt: bool,
     hash: HashMethod,
+    threads: usize,
 ) {
     let mut total_write_time = 0.0;
     let mut total_read_time = 0.0;
+    let mut times = PipelineTimes::default();
 
     tr_println!("Reading input file ...");
     let mut file = File::open(path).unwrap();
@@ -77,10 +147,12 @@ pub fn run(
         written: 0,
         pos: 0
     };
+    let max_chunk_size = chunker.max_size();
     let chunker = chunker.create();
     let chunk_time = Duration::span(|| chunk(&data, chunker, &mut chunk_sink))
         .num_milliseconds() as f32 / 1_000.0;
     total_write_time += chunk_time;
+    times.chunking = f64::from(chunk_time);
     println!(
         "- {}, {}",
         to_duration(chunk_time),
@@ -101,15 +173,17 @@ pub fn run(
         to_file_size(chunk_size_avg as u64),
         to_file_size(chunk_size_stddev as u64)
     );
+    print_chunk_sizes(&chunks, max_chunk_size);
 
     println!();
 
-    tr_println!("Hashing chunks with {} ...", hash.name());
+    tr_println!("Hashing chunks with {} ({}) ...", hash.name(), hash.implementation());
     let mut hashes = Vec::with_capacity(chunks.len());
     let hash_time = Duration::span(|| for &(pos, len) in &chunks {
         hashes.push(hash.hash(&data[pos..pos + len]))
     }).num_milliseconds() as f32 / 1_000.0;
     total_write_time += hash_time;
+    times.hashing = f64::from(hash_time);
     println!(
         "- {}, {}",
         to_duration(hash_time),
@@ -158,6 +232,7 @@ pub fn run(
             bundles.push(bundle);
         }).num_milliseconds() as f32 / 1_000.0;
         total_write_time += compress_time;
+        times.compressing = f64::from(compress_time);
         println!(
             "- {}, {}",
             to_duration(compress_time),
@@ -170,6 +245,78 @@ pub fn run(
             to_file_size(compressed_size as u64),
             (size as f32 - compressed_size as f32) / size as f32 * 100.0
         );
+
+        println!();
+
+        tr_println!(
+            "Compressing only compressible chunks with {} (auto) ...",
+            compression.to_string()
+        );
+        let mut auto_size = 0;
+        let mut raw_chunks = 0;
+        let mut raw_size = 0;
+        let auto_time = Duration::span(|| {
+            let mut bundle = Vec::with_capacity(bundle_size + 2 * chunk_size_avg as usize);
+            let mut c = compression.compress_stream().unwrap();
+            for &(pos, len) in &chunks {
+                let chunk = &data[pos..pos + len];
+                if !is_compressible(chunk) {
+                    raw_chunks += 1;
+                    raw_size += len;
+                    continue;
+                }
+                c.process(chunk, &mut bundle).unwrap();
+                if bundle.len() >= bundle_size {
+                    c.finish(&mut bundle).unwrap();
+                    auto_size += bundle.len();
+                    bundle.clear();
+                    c = compression.compress_stream().unwrap();
+                }
+            }
+            c.finish(&mut bundle).unwrap();
+            auto_size += bundle.len();
+        }).num_milliseconds() as f32 / 1_000.0;
+        auto_size += raw_size;
+        println!(
+            "- {}, {}",
+            to_duration(auto_time),
+            to_speed(size, auto_time)
+        );
+        tr_println!(
+            "- {} incompressible chunks, {} stored uncompressed",
+            raw_chunks,
+            to_file_size(raw_size as u64)
+        );
+        tr_println!(
+            "- {}, {:.1}% saved, {:.1}% less CPU time than compressing all chunks",
+            to_file_size(auto_size as u64),
+            (size as f32 - auto_size as f32) / size as f32 * 100.0,
+            (compress_time - auto_time) / compress_time * 100.0
+        );
+        if threads > 1 {
+            println!();
+
+            tr_println!("Compressing bundles in parallel with {} ...", compression.to_string());
+            let mut raw_bundles = vec![vec![]];
+            let mut raw_size = 0;
+            for &chunk in &chunks {
+                if raw_size >= bundle_size {
+                    raw_bundles.push(vec![]);
+                    raw_size = 0;
+                }
+                raw_bundles.last_mut().unwrap().push(chunk);
+                raw_size += chunk.1;
+            }
+            let mut n = 1;
+            loop {
+                let time = compress_parallel(&data, &raw_bundles, &compression, n);
+                tr_println!("- {} threads: {}, {}", n, to_duration(time), to_speed(size, time));
+                if n >= threads {
+                    break;
+                }
+                n = cmp::min(2 * n, threads);
+            }
+        }
         size = compressed_size as u64;
     } else {
         let mut bundle = Vec::with_capacity(bundle_size + 2 * chunk_size_avg as usize);
@@ -203,6 +350,7 @@ pub fn run(
             to_speed(size, encrypt_time)
         );
         total_write_time += encrypt_time;
+        times.encrypting = f64::from(encrypt_time);
 
         println!();
 
@@ -257,4 +405,8 @@ pub fn run(
         "Total read speed: {}",
         to_speed(total_size, total_read_time)
     );
+
+    println!();
+
+    print_pipeline_times(&times);
 }
@@ -2,8 +2,14 @@ use prelude::*;
 use super::*;
 
 use std::path::{Path, PathBuf};
+use std::ffi::OsString;
+use std::fs::File;
+use std::env;
+use std::time::Duration;
 use log;
-use clap::{App, AppSettings, Arg, SubCommand};
+use rand;
+use serde_yaml;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 #[allow(option_option)]
 pub enum Arguments {
@@ -13,8 +19,13 @@ pub enum Arguments {
         chunker: ChunkerType,
         compression: Option<Compression>,
         encryption: bool,
+        cipher: EncryptionMethod,
+        password: bool,
         hash: HashMethod,
-        remote_path: String
+        remote_path: String,
+        append_only: bool,
+        redundancy: Option<Redundancy>,
+        bundle_shard: Option<usize>
     },
     Backup {
         repo_path: PathBuf,
@@ -26,20 +37,56 @@ pub enum Arguments {
         excludes: Vec<String>,
         excludes_from: Option<String>,
         no_default_excludes: bool,
-        tar: bool
+        tar: bool,
+        inline_size: u64,
+        metadata: Vec<(String, String)>,
+        exclude_older_than: Option<u64>,
+        only_older_than: Option<u64>,
+        exclude_larger_than: Option<u64>,
+        exclude_caches: bool,
+        atime: bool,
+        checkpoint_interval: Option<u64>,
+        checkpoint_bundles: Option<usize>,
+        resume: bool,
+        files_from: Option<(String, u8)>,
+        dry_run: bool,
+        stdin_name: Option<String>,
+        chunker: Option<ChunkerType>,
+        compression: Option<Option<Compression>>,
+        auto_compression: bool,
+        verify: bool,
+        fail_on_error: bool,
+        hardlinks_threshold: Option<usize>
     },
     Restore {
         repo_path: PathBuf,
         backup_name: String,
         inode: Option<String>,
         dst_path: String,
-        tar: bool
+        tar: bool,
+        numeric_owner: bool,
+        chown: Option<(u32, u32)>,
+        decrypt: bool,
+        verify_chunks: bool,
+        skip_corrupt: bool,
+        atomic: bool,
+        force: bool
+    },
+    TestRestore {
+        repo_path: PathBuf,
+        backup_name: String,
+        inode: Option<String>,
+        sample: usize
     },
     Remove {
         repo_path: PathBuf,
         backup_name: String,
         inode: Option<String>,
-        force: bool
+        force: bool,
+        yes: bool,
+        dry_run: bool,
+        remove_empty: bool,
+        admin: bool
     },
     Duplicates {
         repo_path: PathBuf,
@@ -54,13 +101,24 @@ pub enum Arguments {
         weekly: usize,
         monthly: usize,
         yearly: usize,
-        force: bool
+        keep_last: usize,
+        keep_within: Option<u64>,
+        max_size: Option<u64>,
+        force: bool,
+        admin: bool
     },
     Vacuum {
         repo_path: PathBuf,
         ratio: f32,
         force: bool,
-        combine: bool
+        combine: bool,
+        admin: bool
+    },
+    Repack {
+        repo_path: PathBuf,
+        bundle_size: Option<usize>,
+        force: bool,
+        admin: bool
     },
     Check {
         repo_path: PathBuf,
@@ -69,17 +127,28 @@ pub enum Arguments {
         bundles: bool,
         bundle_data: bool,
         index: bool,
-        repair: bool
+        index_only: bool,
+        bundles_only: bool,
+        full: bool,
+        repair: bool,
+        repair_index: bool,
+        admin: bool
     },
     List {
         repo_path: PathBuf,
         backup_name: Option<String>,
-        inode: Option<String>
+        inode: Option<String>,
+        meta_filter: Vec<(String, String)>,
+        recursive: bool,
+        filters: Vec<String>,
+        json: bool
     },
     Info {
         repo_path: PathBuf,
         backup_name: Option<String>,
-        inode: Option<String>
+        inode: Option<String>,
+        tree: bool,
+        json: bool
     },
     Statistics {
         repo_path: PathBuf
@@ -90,11 +159,23 @@ pub enum Arguments {
         repo_path_dst: PathBuf,
         backup_name_dst: String
     },
+    Clone {
+        repo_path: PathBuf,
+        remote_path: String,
+        dst_repo_path: PathBuf
+    },
+    Rename {
+        repo_path: PathBuf,
+        old_name: String,
+        new_name: String,
+        admin: bool
+    },
     Mount {
         repo_path: PathBuf,
         backup_name: Option<String>,
         inode: Option<String>,
-        mount_point: String
+        mount_point: String,
+        threads: usize
     },
     Versions { repo_path: PathBuf, path: String },
     Diff {
@@ -103,26 +184,64 @@ pub enum Arguments {
         inode_old: Option<String>,
         repo_path_new: PathBuf,
         backup_name_new: String,
-        inode_new: Option<String>
+        inode_new: Option<String>,
+        json: bool
+    },
+    VerifySignature {
+        repo_path: PathBuf,
+        backup_name: Option<String>,
+        verify_key: Option<Vec<u8>>,
+        allow_unsigned: bool
     },
-    Analyze { repo_path: PathBuf },
-    BundleList { repo_path: PathBuf },
+    Compare {
+        repo_path: PathBuf,
+        backup_name: String,
+        inode: Option<String>,
+        path: String,
+        json: bool
+    },
+    Analyze {
+        repo_path: PathBuf,
+        dedup: bool,
+        top: usize
+    },
+    BundleList { repo_path: PathBuf, json: bool },
     BundleInfo {
         repo_path: PathBuf,
-        bundle_id: BundleId
+        bundle_id: BundleId,
+        chunks: bool,
+        json: bool
+    },
+    DumpIndex {
+        repo_path: PathBuf,
+        json: bool
     },
     Import {
         repo_path: PathBuf,
         remote_path: String,
         key_files: Vec<String>
     },
+    ExportArchive {
+        repo_path: PathBuf,
+        backup_name: String,
+        archive_path: String
+    },
+    ImportArchive {
+        repo_path: PathBuf,
+        remote_path: String,
+        archive_path: String,
+        key_files: Vec<String>
+    },
     Config {
         repo_path: PathBuf,
         bundle_size: Option<usize>,
         chunker: Option<ChunkerType>,
         compression: Option<Option<Compression>>,
         encryption: Option<Option<PublicKey>>,
-        hash: Option<HashMethod>
+        hash: Option<HashMethod>,
+        redundancy: Option<Option<Redundancy>>,
+        admin: bool,
+        recover: bool
     },
     GenKey {
         file: Option<String>,
@@ -132,7 +251,8 @@ pub enum Arguments {
         repo_path: PathBuf,
         file: Option<String>,
         password: Option<String>,
-        set_default: bool
+        set_default: bool,
+        admin: bool
     },
     AlgoTest {
         file: String,
@@ -145,6 +265,21 @@ pub enum Arguments {
 }
 
 
+/// Options that apply to all subcommands
+pub struct GlobalOptions {
+    pub log_level: log::Level,
+    pub audit_log: Option<PathBuf>,
+    pub escape_mode: EscapeMode,
+    pub threads: usize,
+    pub cache_size: Option<usize>,
+    pub rate_limit: Option<u64>,
+    pub remote_retry: RetryPolicy,
+    pub progress: bool,
+    /// Shell command that prints the password of the repository
+    pub password_command: Option<String>
+}
+
+
 fn convert_repo_path(mut path_str: &str) -> PathBuf {
     if path_str.is_empty() {
         path_str = "default";
@@ -237,6 +372,30 @@ fn validate_filesize(val: String) -> Result<(), String> {
 }
 
 
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_inline_size(val: String) -> Result<(), String> {
+    match parse_filesize(&val) {
+        Ok(size) if size > MAX_INLINE_SIZE => {
+            Err(tr_format!("Must be at most {}", to_file_size(MAX_INLINE_SIZE)))
+        }
+        res => res.map(|_| ())
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_duration(val: String) -> Result<(), String> {
+    parse_duration(&val).map(|_| ())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_timeout(val: String) -> Result<(), String> {
+    match parse_duration(&val) {
+        Ok(0) => Err(tr!("Must be at least 1s").to_string()),
+        res => res.map(|_| ())
+    }
+}
+
+
 fn parse_num(num: &str) -> Result<u64, String> {
     if let Ok(num) = num.parse::<u64>() {
         Ok(num)
@@ -250,6 +409,27 @@ fn validate_num(val: String) -> Result<(), String> {
     parse_num(&val).map(|_| ())
 }
 
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_bundle_shard(val: String) -> Result<(), String> {
+    match parse_num(&val) {
+        Ok(num) if num > 4 => Err(tr!("Must be at most 4").to_string()),
+        res => res.map(|_| ())
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_threads(val: String) -> Result<(), String> {
+    validate_positive_num(val)
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_positive_num(val: String) -> Result<(), String> {
+    match parse_num(&val) {
+        Ok(0) => Err(tr!("Must be at least 1").to_string()),
+        res => res.map(|_| ())
+    }
+}
+
 fn parse_chunker(val: &str) -> Result<ChunkerType, String> {
     if let Ok(chunker) = ChunkerType::from_string(val) {
         Ok(chunker)
@@ -279,6 +459,14 @@ fn validate_compression(val: String) -> Result<(), String> {
     parse_compression(&val).map(|_| ())
 }
 
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_backup_compression(val: String) -> Result<(), String> {
+    if val == "auto" {
+        return Ok(());
+    }
+    validate_compression(val)
+}
+
 fn parse_public_key(val: &str) -> Result<Option<PublicKey>, String> {
     if val.to_lowercase() == "none" {
         return Ok(None);
@@ -296,11 +484,41 @@ fn parse_public_key(val: &str) -> Result<Option<PublicKey>, String> {
     }
 }
 
+fn parse_redundancy(val: &str) -> Result<Option<Redundancy>, String> {
+    if val.to_lowercase() == "none" {
+        return Ok(None);
+    }
+    Redundancy::from_string(val).map(Some).map_err(|e| e.to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_redundancy(val: String) -> Result<(), String> {
+    parse_redundancy(&val).map(|_| ())
+}
+
 #[allow(unknown_lints, needless_pass_by_value)]
 fn validate_public_key(val: String) -> Result<(), String> {
     parse_public_key(&val).map(|_| ())
 }
 
+fn parse_verify_key(val: &str) -> Result<Vec<u8>, String> {
+    let bytes = match parse_hex(val) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            return Err(tr!("Invalid hexadecimal").to_string());
+        }
+    };
+    if bytes.len() != 32 {
+        return Err(tr!("Invalid key").to_string());
+    }
+    Ok(bytes)
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_verify_key(val: String) -> Result<(), String> {
+    parse_verify_key(&val).map(|_| ())
+}
+
 fn parse_hash(val: &str) -> Result<HashMethod, String> {
     if let Ok(hash) = HashMethod::from(val) {
         Ok(hash)
@@ -314,6 +532,43 @@ fn validate_hash(val: String) -> Result<(), String> {
     parse_hash(&val).map(|_| ())
 }
 
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_cipher(val: String) -> Result<(), String> {
+    EncryptionMethod::from_string(&val).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn parse_meta(val: &str) -> Result<(String, String), String> {
+    let mut parts = val.splitn(2, '=');
+    let key = parts.next().unwrap();
+    if let Some(value) = parts.next() {
+        if !key.is_empty() {
+            return Ok((key.to_string(), value.to_string()));
+        }
+    }
+    Err(tr!("Must be in the form KEY=VALUE").to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_meta(val: String) -> Result<(), String> {
+    parse_meta(&val).map(|_| ())
+}
+
+fn parse_owner(val: &str) -> Result<(u32, u32), String> {
+    let mut parts = val.splitn(2, ':');
+    let user = parts.next().unwrap();
+    if let Some(group) = parts.next() {
+        if let (Ok(user), Ok(group)) = (user.parse(), group.parse()) {
+            return Ok((user, group));
+        }
+    }
+    Err(tr!("Must be in the form UID:GID").to_string())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_owner(val: String) -> Result<(), String> {
+    parse_owner(&val).map(|_| ())
+}
+
 fn parse_bundle_id(val: &str) -> Result<BundleId, ErrorCode> {
     if let Ok(hash) = Hash::from_string(val) {
         Ok(BundleId(hash))
@@ -332,6 +587,37 @@ fn validate_existing_path(val: String) -> Result<(), String> {
     }
 }
 
+/// Checks the path of the remote storage which must be a mounted filesystem
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_remote_path(val: String) -> Result<(), String> {
+    if val.starts_with("s3://") {
+        Err(tr!("S3 buckets are not supported directly, mount the bucket (e.g. via rclone) and use the mount point").to_string())
+    } else if val.starts_with("sftp://") || val.starts_with("ssh://") {
+        Err(tr!("SFTP servers are not supported directly, mount the server (e.g. via sshfs) and use the mount point").to_string())
+    } else {
+        validate_existing_path(val)
+    }
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_backup_name(val: String) -> Result<(), String> {
+    if val.contains("::") {
+        return Err(tr!("The backup name must not contain ::").to_string());
+    }
+    if val.starts_with('/') || val.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+        return Err(tr!("Invalid backup name").to_string());
+    }
+    Ok(())
+}
+
+#[allow(unknown_lints, needless_pass_by_value)]
+fn validate_file_name(val: String) -> Result<(), String> {
+    if val.is_empty() || val == "." || val == ".." || val.contains('/') {
+        return Err(tr!("Invalid file name").to_string());
+    }
+    Ok(())
+}
+
 #[allow(unknown_lints, needless_pass_by_value)]
 fn validate_existing_path_or_stdio(val: String) -> Result<(), String> {
     if val != "-" && !Path::new(&val).exists() {
@@ -341,12 +627,124 @@ fn validate_existing_path_or_stdio(val: String) -> Result<(), String> {
     }
 }
 
+/// Default options from the user configuration file
+#[derive(Default)]
+struct UserDefaults {
+    chunker: Option<String>,
+    compression: Option<String>,
+    hash: Option<String>,
+    bundle_size: Option<usize>,
+    threads: Option<usize>
+}
+serde_impl!(UserDefaults(String?) {
+    chunker: Option<String> => "chunker",
+    compression: Option<String> => "compression",
+    hash: Option<String> => "hash",
+    bundle_size: Option<usize> => "bundle_size",
+    threads: Option<usize> => "threads"
+});
+
+impl UserDefaults {
+    fn check(&self, path: &Path) -> Result<(), ErrorCode> {
+        let values: [(&str, Option<String>, fn(String) -> Result<(), String>); 5] = [
+            ("chunker", self.chunker.clone(), validate_chunker),
+            ("compression", self.compression.clone(), validate_compression),
+            ("hash", self.hash.clone(), validate_hash),
+            ("bundle_size", self.bundle_size.map(|v| v.to_string()), validate_num),
+            ("threads", self.threads.map(|v| v.to_string()), validate_threads)
+        ];
+        for &(name, ref val, validator) in &values {
+            if let Some(ref val) = *val {
+                if let Err(err) = validator(val.clone()) {
+                    tr_error!("Invalid value for {} in {}: {}", name, escape(&path), err);
+                    return Err(ErrorCode::InvalidArgs);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loads the default options from `~/.config/zvault/config.yaml`
+///
+/// The values in this file replace the built-in defaults but are overridden by flags given on
+/// the command line. A missing file is not an error, without a home folder it is skipped.
+fn load_user_defaults() -> Result<UserDefaults, ErrorCode> {
+    match env::home_dir() {
+        Some(home) => load_user_defaults_from(&home.join(".config/zvault/config.yaml")),
+        None => {
+            tr_debug!("No home folder found, skipping the user configuration");
+            Ok(UserDefaults::default())
+        }
+    }
+}
+
+fn load_user_defaults_from(path: &Path) -> Result<UserDefaults, ErrorCode> {
+    if !path.exists() {
+        return Ok(UserDefaults::default());
+    }
+    let defaults: UserDefaults = match File::open(path).map_err(|e| e.to_string()).and_then(
+        |f| serde_yaml::from_reader(f).map_err(|e| e.to_string())
+    ) {
+        Ok(defaults) => defaults,
+        Err(err) => {
+            tr_error!("Failed to load the user configuration {}: {}", escape(&path), err);
+            return Err(ErrorCode::InvalidArgs);
+        }
+    };
+    try!(defaults.check(path));
+    Ok(defaults)
+}
+
+/// Returns the value of an option, using the environment variable `var` if it is not given
+///
+/// The precedence is: explicit flag, environment variable, default value of the option.
+/// Values from the environment are checked with the same validator as the flag.
+fn value_or_env(
+    args: &ArgMatches,
+    name: &str,
+    var: &str,
+    validator: fn(String) -> Result<(), String>,
+) -> Result<Option<String>, ErrorCode> {
+    if args.occurrences_of(name) == 0 {
+        if let Ok(val) = env::var(var) {
+            if let Err(err) = validator(val.clone()) {
+                tr_error!("Invalid value in environment variable {}: {}", var, err);
+                return Err(ErrorCode::InvalidArgs);
+            }
+            return Ok(Some(val));
+        }
+    }
+    Ok(args.value_of(name).map(|v| v.to_string()))
+}
+
+
+pub fn parse() -> Result<(GlobalOptions, Arguments), ErrorCode> {
+    let user_defaults = try!(load_user_defaults());
+    parse_from(env::args_os(), user_defaults)
+}
 
 #[allow(unknown_lints, cyclomatic_complexity)]
-pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
+fn parse_from<I, T>(
+    cmd_args: I,
+    user_defaults: UserDefaults,
+) -> Result<(GlobalOptions, Arguments), ErrorCode>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let default_bundle_size = user_defaults.bundle_size.map_or_else(
+        || DEFAULT_BUNDLE_SIZE_STR.to_string(),
+        |v| v.to_string()
+    );
+    let default_chunker = user_defaults.chunker.unwrap_or_else(|| DEFAULT_CHUNKER.to_string());
+    let default_compression = user_defaults.compression.unwrap_or_else(
+        || DEFAULT_COMPRESSION.to_string()
+    );
+    let default_hash = user_defaults.hash.unwrap_or_else(|| DEFAULT_HASH.to_string());
     let args = App::new("zvault")
         .version(crate_version!())
-        .author(crate_authors!(",\n"))
+        .author(env!("CARGO_PKG_AUTHORS"))
         .about(crate_description!())
         .settings(&[AppSettings::VersionlessSubcommands, AppSettings::SubcommandRequiredElseHelp])
         .global_settings(&[AppSettings::AllowMissingPositional, AppSettings::UnifiedHelpMessage, AppSettings::ColoredHelp, AppSettings::ColorAuto])
@@ -360,29 +758,87 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .help(tr!("Print less information"))
             .global(true)
             .conflicts_with("verbose"))
+        .arg(Arg::from_usage("[audit_log] --audit-log [FILE]")
+            .help(tr!("Append a record of all modifying operations to this file"))
+            .global(true))
+        .arg(Arg::from_usage("[escape] --escape [MODE]")
+            .help(tr!("Escape mode for names and paths in the output"))
+            .possible_values(&["c", "shell", "none"])
+            .default_value(DEFAULT_ESCAPE_MODE)
+            .global(true))
+        .arg(Arg::from_usage("[threads] --threads [NUM]")
+            .help(tr!("Number of threads compressing and writing bundles or restoring files (default: number of CPUs)"))
+            .validator(validate_threads)
+            .global(true))
+        .arg(Arg::from_usage("[cache_size] --cache-size [SIZE]")
+            .help(tr!("Size of the cache for decoded bundles in MiB (default: 256)"))
+            .validator(validate_num)
+            .global(true))
+        .arg(Arg::from_usage("[limit_rate] --limit-rate [KIB]")
+            .help(tr!("Limit the upload of bundles to the remote storage to this many KiB/s"))
+            .validator(validate_positive_num)
+            .global(true))
+        .arg(Arg::from_usage("[remote_timeout] --remote-timeout [DURATION]")
+            .help(tr!("Retry operations on the remote storage that make no progress for this time, e.g. 1m (default: 30s)"))
+            .validator(validate_timeout)
+            .global(true))
+        .arg(Arg::from_usage("[remote_retries] --remote-retries [NUM]")
+            .help(tr!("Number of retries of failed operations on the remote storage (default: 3)"))
+            .validator(validate_num)
+            .global(true))
+        .arg(Arg::from_usage("[password_command] --password-command [COMMAND]")
+            .help(tr!("Run this shell command and use its output as the repository password"))
+            .global(true))
+        .arg(Arg::from_usage("--progress")
+            .help(tr!("Show the progress of backups and restores (default: if stderr is a terminal)"))
+            .global(true)
+            .conflicts_with("no-progress"))
+        .arg(Arg::from_usage("--no-progress")
+            .help(tr!("Do not show the progress of backups and restores"))
+            .global(true))
+        .arg(Arg::from_usage("--json")
+            .help(tr!("Output machine-readable JSON instead of formatted text where supported"))
+            .global(true))
         .subcommand(SubCommand::with_name("init")
             .about(tr!("Initialize a new repository"))
             .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE]")
                 .help(tr!("Set the target bundle size in MiB"))
-                .default_value(DEFAULT_BUNDLE_SIZE_STR)
+                .default_value(&default_bundle_size)
                 .validator(validate_num))
             .arg(Arg::from_usage("--chunker [CHUNKER]")
                 .help(tr!("Set the chunker algorithm and target chunk size"))
-                .default_value(DEFAULT_CHUNKER)
+                .default_value(&default_chunker)
                 .validator(validate_chunker))
+            .arg(Arg::from_usage("[random_seed] --random-seed")
+                .help(tr!("Use a random chunker seed to make the chunk boundaries specific to this repository")))
             .arg(Arg::from_usage("-c --compression [COMPRESSION]")
                 .help(tr!("Set the compression method and level"))
-                .default_value(DEFAULT_COMPRESSION)
+                .default_value(&default_compression)
                 .validator(validate_compression))
             .arg(Arg::from_usage("-e --encrypt")
                 .help(tr!("Generate a keypair and enable encryption")))
+            .arg(Arg::from_usage("-p --password")
+                .help(tr!("Derive the keypair from a password instead of storing it"))
+                .requires("encrypt"))
+            .arg(Arg::from_usage("--cipher [CIPHER]")
+                .help(tr!("Set the cipher used for encryption, sodium or aes256gcm (default: sodium)"))
+                .requires("encrypt")
+                .validator(validate_cipher))
             .arg(Arg::from_usage("--hash [HASH]")
                 .help(tr!("Set the hash method'"))
-                .default_value(DEFAULT_HASH)
+                .default_value(&default_hash)
                 .validator(validate_hash))
-            .arg(Arg::from_usage("-r --remote <REMOTE>")
+            .arg(Arg::from_usage("-r --remote [REMOTE]")
                 .help(tr!("Set the path to the mounted remote storage"))
-                .validator(validate_existing_path))
+                .validator(validate_remote_path))
+            .arg(Arg::from_usage("[append_only] --append-only")
+                .help(tr!("Never remove or modify data once it has been written")))
+            .arg(Arg::from_usage("--redundancy [DATA/PARITY]")
+                .help(tr!("Protect groups of DATA bundles with PARITY parity files (default: none)"))
+                .validator(validate_redundancy))
+            .arg(Arg::from_usage("--bundle-shard [CHARS]")
+                .help(tr!("Store the remote bundles in folders named after their first CHARS characters"))
+                .validator(validate_bundle_shard))
             .arg(Arg::from_usage("<REPO>")
                 .help(tr!("The path for the new repository"))
                 .validator(|val| validate_repo_path(val, false, Some(false), Some(false)))))
@@ -391,7 +847,7 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .arg(Arg::from_usage("--full")
                 .help(tr!("Create a full backup without using a reference")))
             .arg(Arg::from_usage("[reference] --ref [REF]")
-                .help(tr!("Base the new backup on this reference"))
+                .help(tr!("Base the new backup on this reference, 'auto' selects the latest matching backup"))
                 .conflicts_with("full"))
             .arg(Arg::from_usage("[cross_device] -x --xdev")
                 .help(tr!("Allow to cross filesystem boundaries")))
@@ -401,9 +857,70 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 .help(tr!("Read the list of excludes from this file")))
             .arg(Arg::from_usage("[no_default_excludes] --no-default-excludes")
                 .help(tr!("Do not load the default excludes file")))
+            .arg(Arg::from_usage("[exclude_older_than] --exclude-older-than [AGE]")
+                .help(tr!("Exclude files that have not been modified within this time, e.g. 30d"))
+                .validator(validate_duration))
+            .arg(Arg::from_usage("[only_older_than] --only-older-than [AGE]")
+                .help(tr!("Exclude files that have been modified within this time, e.g. 1y"))
+                .validator(validate_duration))
+            .arg(Arg::from_usage("[exclude_larger_than] --exclude-larger-than [SIZE]")
+                .help(tr!("Exclude files that are larger than this size, e.g. 100M"))
+                .validator(validate_filesize))
+            .arg(Arg::from_usage("[exclude_caches] --exclude-caches")
+                .help(tr!("Exclude the contents of folders that contain a CACHEDIR.TAG file")))
+            .arg(Arg::from_usage("--atime")
+                .help(tr!("Use the access time instead of the modification time for the file age")))
+            .arg(Arg::from_usage("[files_from] --files-from [FILE]")
+                .help(tr!("Only back up the paths listed in this file, one per line, - for stdin"))
+                .validator(validate_existing_path_or_stdio))
+            .arg(Arg::from_usage("[files_from0] --files-from0 [FILE]")
+                .help(tr!("Like --files-from but with paths separated by null bytes"))
+                .conflicts_with("files_from")
+                .validator(validate_existing_path_or_stdio))
+            .arg(Arg::from_usage("[null] -0 --null")
+                .help(tr!("The paths in the --files-from list are separated by null bytes"))
+                .requires("files_from"))
+            .arg(Arg::from_usage("[checkpoint_interval] --checkpoint-interval [INTERVAL]")
+                .help(tr!("Finish all open bundles in this interval, e.g. 30m"))
+                .validator(validate_duration))
+            .arg(Arg::from_usage("[checkpoint_bundles] --checkpoint-bundles [COUNT]")
+                .help(tr!("Finish all open bundles after this many new bundles"))
+                .validator(validate_positive_num))
+            .arg(Arg::from_usage("--resume")
+                .help(tr!("Continue an interrupted backup with the same name from its last checkpoint")))
+            .arg(Arg::from_usage("[dry_run] -n --dry-run")
+                .help(tr!("Only list the files that would be backed up without storing anything")))
             .arg(Arg::from_usage("--tar")
                 .help(tr!("Read the source data from a tar file"))
-                .conflicts_with_all(&["reference", "exclude", "excludes_from"]))
+                .conflicts_with_all(&["reference", "exclude", "excludes_from", "exclude_older_than",
+                    "only_older_than", "exclude_larger_than", "exclude_caches", "files_from",
+                    "files_from0", "resume", "dry_run"]))
+            .arg(Arg::from_usage("--chunker [CHUNKER]")
+                .help(tr!("Use this chunker algorithm and target chunk size for this backup only"))
+                .validator(validate_chunker))
+            .arg(Arg::from_usage("-c --compression [COMPRESSION]")
+                .help(tr!("Use this compression method and level for this backup only or 'auto'"))
+                .validator(validate_backup_compression))
+            .arg(Arg::from_usage("--verify")
+                .help(tr!("Read back all new chunks after the backup and check them"))
+                .conflicts_with("dry_run"))
+            .arg(Arg::from_usage("--fail-on-error")
+                .help(tr!("Abort the backup if a file can not be read instead of skipping it")))
+            .arg(Arg::from_usage("[hardlinks_threshold] --dereference-hardlinks-threshold [COUNT]")
+                .help(tr!("Store hard links as copies once this many hard linked files are tracked"))
+                .validator(validate_positive_num))
+            .arg(Arg::from_usage("[stdin_name] --stdin-name [NAME]")
+                .help(tr!("Name of the file that stores the data read from stdin (default: backup name)"))
+                .conflicts_with("tar")
+                .validator(validate_file_name))
+            .arg(Arg::from_usage("[inline_size] --inline-size [SIZE]")
+                .help(tr!("Store files smaller than this inline without chunking"))
+                .default_value(DEFAULT_INLINE_SIZE_STR)
+                .validator(validate_inline_size))
+            .arg(Arg::from_usage("[meta] --meta [KEY=VALUE]...")
+                .help(tr!("Attach this metadata to the backup"))
+                .number_of_values(1)
+                .validator(validate_meta))
             .arg(Arg::from_usage("<SRC>")
                 .help(tr!("Source path to backup"))
                 .validator(validate_existing_path_or_stdio))
@@ -414,18 +931,57 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .about(tr!("Restore a backup or subtree"))
             .arg(Arg::from_usage("--tar")
                 .help(tr!("Restore in form of a tar file")))
+            .arg(Arg::from_usage("[numeric_owner] --numeric-owner")
+                .help(tr!("Restore the exact numeric user and group ids and fail if that is not permitted"))
+                .conflicts_with("tar"))
+            .arg(Arg::from_usage("--chown [UID:GID]")
+                .help(tr!("Restore all files with this user and group id"))
+                .validator(validate_owner)
+                .conflicts_with_all(&["tar", "numeric_owner"]))
+            .arg(Arg::from_usage("--decrypt")
+                .help(tr!("Confirm to export an encrypted backup as an unencrypted tar file"))
+                .requires("tar"))
+            .arg(Arg::from_usage("[verify_chunks] --verify-chunks")
+                .help(tr!("Check the hash of every chunk before writing its contents")))
+            .arg(Arg::from_usage("[skip_corrupt] --skip-corrupt")
+                .help(tr!("Skip files with corrupt chunks instead of aborting"))
+                .requires("verify_chunks")
+                .conflicts_with("tar"))
+            .arg(Arg::from_usage("--atomic")
+                .help(tr!("Restore into a staging folder that is renamed to the destination when done"))
+                .conflicts_with("tar"))
+            .arg(Arg::from_usage("-f --force")
+                .help(tr!("Replace an existing destination with --atomic"))
+                .requires("atomic"))
             .arg(Arg::from_usage("<BACKUP>")
                 .help(tr!("The backup/subtree path, [repository]::backup[::subtree]"))
                 .validator(|val| validate_repo_path(val, true, Some(true), None)))
             .arg(Arg::from_usage("<DST>")
-                .help(tr!("Destination path for backup"))))
+                .help(tr!("Destination path for backup, `-` writes a single file to stdout"))))
+        .subcommand(SubCommand::with_name("test-restore")
+            .about(tr!("Test the restore of some random files"))
+            .arg(Arg::from_usage("-s --sample [NUM]")
+                .help(tr!("Number of random files to restore"))
+                .default_value(DEFAULT_TEST_RESTORE_SAMPLE_STR)
+                .validator(validate_num))
+            .arg(Arg::from_usage("<BACKUP>")
+                .help(tr!("The backup/subtree path, [repository]::backup[::subtree]"))
+                .validator(|val| validate_repo_path(val, true, Some(true), None))))
         .subcommand(SubCommand::with_name("remove")
             .aliases(&["rm", "delete", "del"])
             .about(tr!("Remove a backup or a subtree"))
             .arg(Arg::from_usage("-f --force")
                 .help(tr!("Remove multiple backups in a backup folder")))
+            .arg(Arg::from_usage("-y --yes")
+                .help(tr!("Do not ask for confirmation before removing matching backups")))
+            .arg(Arg::from_usage("[dry_run] -n --dry-run")
+                .help(tr!("Only list the backups that would be removed")))
+            .arg(Arg::from_usage("[remove_empty] --remove-empty")
+                .help(tr!("Remove the whole backup if removing the subpath leaves it empty")))
+            .arg(Arg::from_usage("--admin")
+                .help(tr!("Allow this operation on an append-only repository")))
             .arg(Arg::from_usage("<BACKUP>")
-                .help(tr!("The backup/subtree path, [repository]::backup[::subtree]"))
+                .help(tr!("The backup/subtree path, [repository]::backup[::subtree], the backup name may contain wildcards"))
                 .validator(|val| validate_repo_path(val, true, Some(true), None))))
         .subcommand(SubCommand::with_name("prune")
             .about(tr!("Remove backups based on age"))
@@ -447,8 +1003,20 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 .help(tr!("Keep this number of yearly backups"))
                 .default_value("0")
                 .validator(validate_num))
+            .arg(Arg::from_usage("[keep_last] --keep-last [NUM]")
+                .help(tr!("Keep this number of most recent backups"))
+                .default_value("0")
+                .validator(validate_num))
+            .arg(Arg::from_usage("[keep_within] --keep-within [DURATION]")
+                .help(tr!("Keep all backups newer than this duration, e.g. 7d or 2w"))
+                .validator(validate_duration))
+            .arg(Arg::from_usage("[max_size] --max-size [SIZE]")
+                .help(tr!("Remove the oldest backups until the used data fits this size, overriding the other rules"))
+                .validator(validate_filesize))
             .arg(Arg::from_usage("-f --force")
                 .help(tr!("Actually run the prune instead of simulating it")))
+            .arg(Arg::from_usage("--admin")
+                .help(tr!("Allow this operation on an append-only repository")))
             .arg(Arg::from_usage("<REPO>")
                 .help(tr!("Path of the repository"))
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
@@ -461,6 +1029,20 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 .help(tr!("Combine small bundles into larger ones")))
             .arg(Arg::from_usage("-f --force")
                 .help(tr!("Actually run the vacuum instead of simulating it")))
+            .arg(Arg::from_usage("--admin")
+                .help(tr!("Allow this operation on an append-only repository")))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("repack")
+            .about(tr!("Rewrite bundles to match the target bundle size"))
+            .arg(Arg::from_usage("[bundle_size] --target-bundle-size [SIZE]")
+                .help(tr!("Set the target bundle size in MiB (default: configured size)"))
+                .validator(validate_num))
+            .arg(Arg::from_usage("-f --force")
+                .help(tr!("Actually run the repack instead of simulating it")))
+            .arg(Arg::from_usage("--admin")
+                .help(tr!("Allow this operation on an append-only repository")))
             .arg(Arg::from_usage("<REPO>")
                 .help(tr!("Path of the repository"))
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
@@ -470,18 +1052,40 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 .help(tr!("Check the bundles")))
             .arg(Arg::from_usage("[bundle_data] --bundle-data")
                 .help(tr!("Check bundle contents (slow)"))
-                .requires("bundles")
                 .alias("data"))
             .arg(Arg::from_usage("-i --index")
                 .help(tr!("Check the chunk index")))
+            .arg(Arg::from_usage("[index_only] --index-only")
+                .help(tr!("Only check the chunk index"))
+                .conflicts_with_all(&["bundles", "bundle_data", "bundles_only", "full"]))
+            .arg(Arg::from_usage("[bundles_only] --bundles-only")
+                .help(tr!("Only check the bundles"))
+                .conflicts_with_all(&["index", "full"]))
+            .arg(Arg::from_usage("--full")
+                .help(tr!("Verify the backup data by re-hashing all its chunks (slow)"))
+                .conflicts_with("repair"))
             .arg(Arg::from_usage("-r --repair")
                 .help(tr!("Try to repair errors")))
+            .arg(Arg::from_usage("[repair_index] --repair-index")
+                .help(tr!("Only rebuild the chunk index from the chunk lists in the bundles"))
+                .conflicts_with_all(&["bundles", "index", "index_only", "bundles_only", "full"]))
+            .arg(Arg::from_usage("--admin")
+                .help(tr!("Allow this operation on an append-only repository")))
             .arg(Arg::from_usage("<PATH>")
                 .help(tr!("Path of the repository/backup/subtree, [repository][::backup[::subtree]]"))
                 .validator(|val| validate_repo_path(val, true, None, None))))
         .subcommand(SubCommand::with_name("list")
             .alias("ls")
             .about(tr!("List backups or backup contents"))
+            .arg(Arg::from_usage("[where] --where [KEY=VALUE]...")
+                .help(tr!("Only list backups with this metadata"))
+                .number_of_values(1)
+                .validator(validate_meta))
+            .arg(Arg::from_usage("-R --recursive")
+                .help(tr!("List the whole tree below the backup or subtree")))
+            .arg(Arg::from_usage("[filter] --filter [PATTERN]...")
+                .help(tr!("Only list paths that match this pattern, implies --recursive"))
+                .number_of_values(1))
             .arg(Arg::from_usage("<PATH>")
                 .help(tr!("Path of the repository/backup/subtree, [repository][::backup[::subtree]]"))
                 .validator(|val| validate_repo_path(val, true, None, None))))
@@ -490,6 +1094,10 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .arg(Arg::from_usage("<PATH>")
                 .help(tr!("Path of the repository/backup/subtree, [repository][::backup[::subtree]]"))
                 .validator(|val| validate_repo_path(val, true, None, None)))
+            .arg(Arg::from_usage("--fuse-threads [NUM]")
+                .help(tr!("Number of threads serving file reads"))
+                .default_value(DEFAULT_FUSE_THREADS_STR)
+                .validator(validate_num))
             .arg(Arg::from_usage("<MOUNTPOINT>")
                 .help(tr!("Existing mount point"))
                 .validator(validate_existing_path)))
@@ -510,24 +1118,59 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 .help(tr!("Path of the repository"))
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
             .arg(Arg::from_usage("<BUNDLE>")
-                .help(tr!("Id of the bundle"))))
+                .help(tr!("Id of the bundle")))
+            .arg(Arg::from_usage("-c --chunks")
+                .help(tr!("List all chunks of the bundle with their offsets and sizes"))))
+        .subcommand(SubCommand::with_name("dump-index")
+            .about(tr!("Dump the locations of all chunks in the index"))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("import")
             .about(tr!("Reconstruct a repository from the remote storage"))
             .arg(Arg::from_usage("-k --key [FILE]...")
                 .help(tr!("Key file needed to read the bundles")))
             .arg(Arg::from_usage("<REMOTE>")
                 .help(tr!("Remote repository path"))
+                .validator(validate_remote_path))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("The path for the new repository"))
+                .validator(|val| validate_repo_path(val, false, Some(false), Some(false)))))
+        .subcommand(SubCommand::with_name("export-archive")
+            .about(tr!("Write a backup and all its bundles into a single archive file"))
+            .arg(Arg::from_usage("<BACKUP>")
+                .help(tr!("The backup to export, [repository]::backup"))
+                .validator(|val| validate_repo_path(val, true, Some(true), Some(false))))
+            .arg(Arg::from_usage("<FILE>")
+                .help(tr!("Path of the archive file to create"))))
+        .subcommand(SubCommand::with_name("import-archive")
+            .about(tr!("Create a new repository from an archive file"))
+            .arg(Arg::from_usage("-k --key [FILE]...")
+                .help(tr!("Key file needed to read the bundles")))
+            .arg(Arg::from_usage("<FILE>")
+                .help(tr!("Path of the archive file"))
                 .validator(validate_existing_path))
+            .arg(Arg::from_usage("<REMOTE>")
+                .help(tr!("Path to the mounted remote storage of the new repository"))
+                .validator(validate_remote_path))
             .arg(Arg::from_usage("<REPO>")
                 .help(tr!("The path for the new repository"))
                 .validator(|val| validate_repo_path(val, false, Some(false), Some(false)))))
         .subcommand(SubCommand::with_name("info")
             .about(tr!("Display information on a repository, a backup or a subtree"))
+            .arg(Arg::from_usage("--tree")
+                .help(tr!("Show the total and deduplicated size of every directory in the backup")))
             .arg(Arg::from_usage("<PATH>")
                 .help(tr!("Path of the repository/backup/subtree, [repository][::backup[::subtree]]"))
                 .validator(|val| validate_repo_path(val, true, None, None))))
         .subcommand(SubCommand::with_name("analyze")
             .about(tr!("Analyze the used and reclaimable space of bundles"))
+            .arg(Arg::from_usage("--dedup")
+                .help(tr!("Analyze the deduplication of chunks instead")))
+            .arg(Arg::from_usage("--top [NUM]")
+                .help(tr!("Number of most referenced chunks to list with --dedup"))
+                .default_value("10")
+                .validator(validate_num))
             .arg(Arg::from_usage("<REPO>")
                 .help(tr!("Path of the repository"))
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
@@ -546,6 +1189,24 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .arg(Arg::from_usage("<NEW>")
                 .help(tr!("New version, [repository]::backup[::subpath]"))
                 .validator(|val| validate_repo_path(val, true, Some(true), None))))
+        .subcommand(SubCommand::with_name("compare")
+            .about(tr!("Display differences between a backup and the filesystem"))
+            .arg(Arg::from_usage("<BACKUP>")
+                .help(tr!("The backup/subtree path, [repository]::backup[::subtree]"))
+                .validator(|val| validate_repo_path(val, true, Some(true), None)))
+            .arg(Arg::from_usage("<PATH>")
+                .help(tr!("Path on the filesystem to compare with"))
+                .validator(validate_existing_path)))
+        .subcommand(SubCommand::with_name("verify-signature")
+            .about(tr!("Check the signatures of backups"))
+            .arg(Arg::from_usage("[verify_key] --verify-key [KEY]")
+                .help(tr!("Only accept signatures that can be checked with this verify key"))
+                .validator(validate_verify_key))
+            .arg(Arg::from_usage("--allow-unsigned")
+                .help(tr!("Do not fail for backups without a signature")))
+            .arg(Arg::from_usage("<BACKUP>")
+                .help(tr!("The backup or repository path, [repository][::backup]"))
+                .validator(|val| validate_repo_path(val, true, None, Some(false)))))
         .subcommand(SubCommand::with_name("duplicates")
             .aliases(&["dups"])
             .about(tr!("Find duplicate files in a backup"))
@@ -565,6 +1226,31 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .arg(Arg::from_usage("<DST>")
                 .help(tr!("Destination backup, [repository]::backup"))
                 .validator(|val| validate_repo_path(val, true, Some(true), Some(false)))))
+        .subcommand(SubCommand::with_name("clone")
+            .about(tr!("Copy a whole repository to a new remote storage"))
+            .arg(Arg::from_usage("<REPO>")
+                .help(tr!("Path of the existing repository"))
+                .validator(|val| validate_repo_path(val, true, Some(false), Some(false))))
+            .arg(Arg::from_usage("<REMOTE>")
+                .help(tr!("Path to the mounted remote storage of the new repository"))
+                .validator(validate_remote_path))
+            .arg(Arg::from_usage("<DST>")
+                .help(tr!("The path for the new repository, an existing one continues an interrupted clone"))
+                .validator(|val| {
+                    validate_repo_path(val.clone(), false, Some(false), Some(false))
+                        .or_else(|_| validate_repo_path(val, true, Some(false), Some(false)))
+                })))
+        .subcommand(SubCommand::with_name("rename")
+            .alias("mv")
+            .about(tr!("Rename a backup"))
+            .arg(Arg::from_usage("--admin")
+                .help(tr!("Allow this operation on an append-only repository")))
+            .arg(Arg::from_usage("<BACKUP>")
+                .help(tr!("Existing backup, [repository]::backup"))
+                .validator(|val| validate_repo_path(val, true, Some(true), Some(false))))
+            .arg(Arg::from_usage("<NAME>")
+                .help(tr!("The new name of the backup"))
+                .validator(validate_backup_name)))
         .subcommand(SubCommand::with_name("config")
             .about(tr!("Display or change the configuration"))
             .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE]")
@@ -582,6 +1268,13 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .arg(Arg::from_usage("--hash [HASH]")
                 .help(tr!("Set the hash method"))
                 .validator(validate_hash))
+            .arg(Arg::from_usage("--redundancy [DATA/PARITY]")
+                .help(tr!("Protect groups of DATA bundles with PARITY parity files or 'none'"))
+                .validator(validate_redundancy))
+            .arg(Arg::from_usage("--admin")
+                .help(tr!("Allow this operation on an append-only repository")))
+            .arg(Arg::from_usage("[recover] --config-recover")
+                .help(tr!("Rebuild a missing or corrupt configuration from the backups and bundles")))
             .arg(Arg::from_usage("<REPO>")
                 .help(tr!("Path of the repository"))
                 .validator(|val| validate_repo_path(val, true, Some(false), Some(false)))))
@@ -598,6 +1291,8 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 .conflicts_with("FILE"))
             .arg(Arg::from_usage("[set_default] --default -d")
                 .help(tr!("Set the key pair as default")))
+            .arg(Arg::from_usage("--admin")
+                .help(tr!("Allow this operation on an append-only repository")))
             .arg(Arg::from_usage("-p --password [PASSWORD]")
                 .help(tr!("Derive the key pair from the given password"))
                 .requires("generate"))
@@ -611,25 +1306,25 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             .about(tr!("Test a specific algorithm combination"))
             .arg(Arg::from_usage("[bundle_size] --bundle-size [SIZE]")
                 .help(tr!("Set the target bundle size in MiB"))
-                .default_value(DEFAULT_BUNDLE_SIZE_STR)
+                .default_value(&default_bundle_size)
                 .validator(validate_num))
             .arg(Arg::from_usage("--chunker [CHUNKER]")
                 .help(tr!("Set the chunker algorithm and target chunk size"))
-                .default_value(DEFAULT_CHUNKER)
+                .default_value(&default_chunker)
                 .validator(validate_chunker))
             .arg(Arg::from_usage("-c --compression [COMPRESSION]")
                 .help(tr!("Set the compression method and level"))
-                .default_value(DEFAULT_COMPRESSION)
+                .default_value(&default_compression)
                 .validator(validate_compression))
             .arg(Arg::from_usage("-e --encrypt")
                 .help(tr!("Generate a keypair and enable encryption")))
             .arg(Arg::from_usage("--hash [HASH]")
                 .help(tr!("Set the hash method"))
-                .default_value(DEFAULT_HASH)
+                .default_value(&default_hash)
                 .validator(validate_hash))
             .arg(Arg::from_usage("<FILE>")
                 .help(tr!("File with test data"))
-                .validator(validate_existing_path))).get_matches();
+                .validator(validate_existing_path))).get_matches_from(cmd_args);
     let verbose_count = args.subcommand()
         .1
         .map(|m| m.occurrences_of("verbose"))
@@ -638,6 +1333,63 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
         .1
         .map(|m| m.occurrences_of("quiet"))
         .unwrap_or(0) + args.occurrences_of("quiet");
+    let audit_log = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("audit_log"))
+        .or_else(|| args.value_of("audit_log"))
+        .map(PathBuf::from);
+    // The default value is set in all subcommands, so the explicit value has to be found
+    let escape_mode = match args.subcommand().1 {
+        Some(m) if m.occurrences_of("escape") > 0 => m.value_of("escape"),
+        _ => args.value_of("escape"),
+    }.map(|v| EscapeMode::from_string(v).unwrap())
+        .unwrap();
+    let threads = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("threads"))
+        .or_else(|| args.value_of("threads"))
+        .map(|v| parse_num(v).unwrap() as usize)
+        .or(user_defaults.threads)
+        .unwrap_or_else(get_cpu_count);
+    let cache_size = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("cache_size"))
+        .or_else(|| args.value_of("cache_size"))
+        .map(|v| parse_num(v).unwrap() as usize * 1024 * 1024);
+    let rate_limit = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("limit_rate"))
+        .or_else(|| args.value_of("limit_rate"))
+        .map(|v| parse_num(v).unwrap() * 1024);
+    let remote_retry = RetryPolicy::new(
+        args.subcommand()
+            .1
+            .and_then(|m| m.value_of("remote_retries"))
+            .or_else(|| args.value_of("remote_retries"))
+            .map_or(DEFAULT_REMOTE_RETRIES, |v| parse_num(v).unwrap() as usize),
+        Duration::from_secs(args.subcommand()
+            .1
+            .and_then(|m| m.value_of("remote_timeout"))
+            .or_else(|| args.value_of("remote_timeout"))
+            .map_or(DEFAULT_REMOTE_TIMEOUT, |v| parse_duration(v).unwrap()))
+    );
+    let password_command = args.subcommand()
+        .1
+        .and_then(|m| m.value_of("password_command"))
+        .or_else(|| args.value_of("password_command"))
+        .map(|v| v.to_string());
+    let is_present = |name: &str| {
+        args.is_present(name) || args.subcommand().1.map_or(false, |m| m.is_present(name))
+    };
+    let json = is_present("json");
+    // The progress line is written to stderr and never mixed into JSON output
+    let progress = if is_present("progress") {
+        true
+    } else if is_present("no-progress") {
+        false
+    } else {
+        stderr_is_terminal() && !json
+    };
     let log_level = match 1 + verbose_count - quiet_count {
         0 => log::Level::Warn,
         1 => log::Level::Info,
@@ -652,15 +1404,58 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 Some(false),
                 Some(false)
             ).unwrap();
+            let bundle_size = try!(value_or_env(
+                args,
+                "bundle_size",
+                "ZVAULT_BUNDLE_SIZE",
+                validate_num
+            ));
+            let chunker = try!(value_or_env(args, "chunker", "ZVAULT_CHUNKER", validate_chunker));
+            let compression = try!(value_or_env(
+                args,
+                "compression",
+                "ZVAULT_COMPRESSION",
+                validate_compression
+            ));
+            let hash = try!(value_or_env(args, "hash", "ZVAULT_HASH", validate_hash));
+            let remote_path = try!(value_or_env(
+                args,
+                "remote",
+                "ZVAULT_REMOTE",
+                validate_remote_path
+            ));
+            let remote_path = match remote_path {
+                Some(remote_path) => remote_path,
+                None => {
+                    tr_error!("The remote storage path must be given via --remote or ZVAULT_REMOTE");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+            };
+            let mut chunker = parse_chunker(&chunker.unwrap()).unwrap();
+            if args.is_present("random_seed") {
+                chunker = match chunker.with_seed(rand::random()) {
+                    Ok(chunker) => chunker,
+                    Err(err) => {
+                        tr_error!("Can not use a random seed: {}", err);
+                        return Err(ErrorCode::InvalidArgs);
+                    }
+                };
+            }
             Arguments::Init {
-                bundle_size: (parse_num(args.value_of("bundle_size").unwrap()).unwrap() *
-                                  1024 * 1024) as usize,
-                chunker: parse_chunker(args.value_of("chunker").unwrap()).unwrap(),
-                compression: parse_compression(args.value_of("compression").unwrap()).unwrap(),
+                bundle_size: (parse_num(&bundle_size.unwrap()).unwrap() * 1024 * 1024) as usize,
+                chunker,
+                compression: parse_compression(&compression.unwrap()).unwrap(),
                 encryption: args.is_present("encrypt"),
-                hash: parse_hash(args.value_of("hash").unwrap()).unwrap(),
+                cipher: args.value_of("cipher")
+                    .map(|v| EncryptionMethod::from_string(v).unwrap())
+                    .unwrap_or(EncryptionMethod::Sodium),
+                password: args.is_present("password"),
+                hash: parse_hash(&hash.unwrap()).unwrap(),
                 repo_path: repository,
-                remote_path: args.value_of("remote").unwrap().to_string()
+                remote_path,
+                append_only: args.is_present("append_only"),
+                redundancy: args.value_of("redundancy").and_then(|v| parse_redundancy(v).unwrap()),
+                bundle_shard: args.value_of("bundle_shard").map(|v| parse_num(v).unwrap() as usize)
             }
         }
         ("backup", Some(args)) => {
@@ -682,18 +1477,80 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 src_path: args.value_of("SRC").unwrap().to_string(),
                 reference: args.value_of("reference").map(|v| v.to_string()),
                 no_default_excludes: args.is_present("no_default_excludes"),
-                tar: args.is_present("tar")
+                tar: args.is_present("tar"),
+                inline_size: args.value_of("inline_size").map(|v| {
+                    parse_filesize(v).unwrap()
+                }).unwrap(),
+                metadata: args.values_of("meta")
+                    .map(|v| v.map(|m| parse_meta(m).unwrap()).collect())
+                    .unwrap_or_else(|| vec![]),
+                exclude_older_than: args.value_of("exclude_older_than").map(|v| {
+                    parse_duration(v).unwrap()
+                }),
+                only_older_than: args.value_of("only_older_than").map(|v| {
+                    parse_duration(v).unwrap()
+                }),
+                exclude_larger_than: args.value_of("exclude_larger_than").map(|v| {
+                    parse_filesize(v).unwrap()
+                }),
+                exclude_caches: args.is_present("exclude_caches"),
+                atime: args.is_present("atime"),
+                checkpoint_interval: args.value_of("checkpoint_interval").map(|v| {
+                    parse_duration(v).unwrap()
+                }),
+                checkpoint_bundles: args.value_of("checkpoint_bundles").map(|v| {
+                    parse_num(v).unwrap() as usize
+                }),
+                resume: args.is_present("resume"),
+                files_from: args.value_of("files_from")
+                    .map(|v| (v.to_string(), if args.is_present("null") { 0 } else { b'\n' }))
+                    .or_else(|| args.value_of("files_from0").map(|v| (v.to_string(), 0))),
+                dry_run: args.is_present("dry_run"),
+                stdin_name: args.value_of("stdin_name").map(|v| v.to_string()),
+                chunker: args.value_of("chunker").map(|v| parse_chunker(v).unwrap()),
+                compression: args.value_of("compression").and_then(|v| if v == "auto" {
+                    None
+                } else {
+                    Some(parse_compression(v).unwrap())
+                }),
+                auto_compression: args.value_of("compression") == Some("auto"),
+                verify: args.is_present("verify"),
+                fail_on_error: args.is_present("fail_on_error"),
+                hardlinks_threshold: args.value_of("hardlinks_threshold").map(|v| {
+                    parse_num(v).unwrap() as usize
+                })
             }
         }
         ("restore", Some(args)) => {
             let (repository, backup, inode) =
                 parse_repo_path(args.value_of("BACKUP").unwrap(), true, Some(true), None).unwrap();
+            if args.is_present("atomic") && args.value_of("DST") == Some("-") {
+                tr_error!("Restoring to stdout can not be atomic");
+                return Err(ErrorCode::InvalidArgs);
+            }
             Arguments::Restore {
                 repo_path: repository,
                 backup_name: backup.unwrap().to_string(),
                 inode: inode.map(|v| v.to_string()),
                 dst_path: args.value_of("DST").unwrap().to_string(),
-                tar: args.is_present("tar")
+                tar: args.is_present("tar"),
+                numeric_owner: args.is_present("numeric_owner"),
+                chown: args.value_of("chown").map(|v| parse_owner(v).unwrap()),
+                decrypt: args.is_present("decrypt"),
+                verify_chunks: args.is_present("verify_chunks"),
+                skip_corrupt: args.is_present("skip_corrupt"),
+                atomic: args.is_present("atomic"),
+                force: args.is_present("force")
+            }
+        }
+        ("test-restore", Some(args)) => {
+            let (repository, backup, inode) =
+                parse_repo_path(args.value_of("BACKUP").unwrap(), true, Some(true), None).unwrap();
+            Arguments::TestRestore {
+                repo_path: repository,
+                backup_name: backup.unwrap().to_string(),
+                inode: inode.map(|v| v.to_string()),
+                sample: parse_num(args.value_of("sample").unwrap()).unwrap() as usize
             }
         }
         ("remove", Some(args)) => {
@@ -703,7 +1560,11 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 repo_path: repository,
                 backup_name: backup.unwrap().to_string(),
                 inode: inode.map(|v| v.to_string()),
-                force: args.is_present("force")
+                force: args.is_present("force"),
+                yes: args.is_present("yes"),
+                dry_run: args.is_present("dry_run"),
+                remove_empty: args.is_present("remove_empty"),
+                admin: args.is_present("admin")
             }
         }
         ("prune", Some(args)) => {
@@ -720,7 +1581,11 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 daily: parse_num(args.value_of("daily").unwrap()).unwrap() as usize,
                 weekly: parse_num(args.value_of("weekly").unwrap()).unwrap() as usize,
                 monthly: parse_num(args.value_of("monthly").unwrap()).unwrap() as usize,
-                yearly: parse_num(args.value_of("yearly").unwrap()).unwrap() as usize
+                yearly: parse_num(args.value_of("yearly").unwrap()).unwrap() as usize,
+                keep_last: parse_num(args.value_of("keep_last").unwrap()).unwrap() as usize,
+                keep_within: args.value_of("keep_within").map(|v| parse_duration(v).unwrap()),
+                max_size: args.value_of("max_size").map(|v| parse_filesize(v).unwrap()),
+                admin: args.is_present("admin")
             }
         }
         ("vacuum", Some(args)) => {
@@ -734,12 +1599,44 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 repo_path: repository,
                 force: args.is_present("force"),
                 combine: args.is_present("combine"),
-                ratio: parse_num(args.value_of("ratio").unwrap()).unwrap() as f32 / 100.0
+                ratio: parse_num(args.value_of("ratio").unwrap()).unwrap() as f32 / 100.0,
+                admin: args.is_present("admin")
+            }
+        }
+        ("repack", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::Repack {
+                repo_path: repository,
+                bundle_size: args.value_of("bundle_size").map(|v| {
+                    parse_num(v).unwrap() as usize * 1024 * 1024
+                }),
+                force: args.is_present("force"),
+                admin: args.is_present("admin")
             }
         }
         ("check", Some(args)) => {
             let (repository, backup, inode) =
                 parse_repo_path(args.value_of("PATH").unwrap(), true, None, None).unwrap();
+            let index_only = args.is_present("index_only");
+            let bundles_only = args.is_present("bundles_only");
+            let repair_index = args.is_present("repair_index");
+            if (index_only || bundles_only) && backup.is_some() {
+                tr_error!("Only the repository can be given when checking only the index or the bundles");
+                return Err(ErrorCode::InvalidArgs);
+            }
+            if repair_index && backup.is_some() {
+                tr_error!("Only the repository can be given when rebuilding the index");
+                return Err(ErrorCode::InvalidArgs);
+            }
+            if args.is_present("bundle_data") && !args.is_present("bundles") && !bundles_only {
+                tr_error!("Checking the bundle contents requires --bundles or --bundles-only");
+                return Err(ErrorCode::InvalidArgs);
+            }
             Arguments::Check {
                 repo_path: repository,
                 backup_name: backup.map(|v| v.to_string()),
@@ -747,16 +1644,35 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 bundles: args.is_present("bundles"),
                 bundle_data: args.is_present("bundle_data"),
                 index: args.is_present("index"),
-                repair: args.is_present("repair")
+                index_only,
+                bundles_only,
+                full: args.is_present("full"),
+                repair: args.is_present("repair"),
+                repair_index,
+                admin: args.is_present("admin")
             }
         }
         ("list", Some(args)) => {
             let (repository, backup, inode) =
                 parse_repo_path(args.value_of("PATH").unwrap(), true, None, None).unwrap();
+            let filters: Vec<String> = args.values_of("filter")
+                .map(|v| v.map(|p| p.to_string()).collect())
+                .unwrap_or_else(|| vec![]);
+            let recursive = args.is_present("recursive") || !filters.is_empty();
+            if recursive && backup.is_none() {
+                tr_error!("Listing recursively requires a backup");
+                return Err(ErrorCode::InvalidArgs);
+            }
             Arguments::List {
                 repo_path: repository,
                 backup_name: backup.map(|v| v.to_string()),
-                inode: inode.map(|v| v.to_string())
+                inode: inode.map(|v| v.to_string()),
+                meta_filter: args.values_of("where")
+                    .map(|v| v.map(|m| parse_meta(m).unwrap()).collect())
+                    .unwrap_or_else(|| vec![]),
+                recursive,
+                filters,
+                json
             }
         }
         ("bundlelist", Some(args)) => {
@@ -766,7 +1682,10 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 Some(false),
                 Some(false)
             ).unwrap();
-            Arguments::BundleList { repo_path: repository }
+            Arguments::BundleList {
+                repo_path: repository,
+                json
+            }
         }
         ("bundleinfo", Some(args)) => {
             let (repository, _backup, _inode) = parse_repo_path(
@@ -777,16 +1696,37 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             ).unwrap();
             Arguments::BundleInfo {
                 repo_path: repository,
-                bundle_id: try!(parse_bundle_id(args.value_of("BUNDLE").unwrap()))
+                bundle_id: try!(parse_bundle_id(args.value_of("BUNDLE").unwrap())),
+                chunks: args.is_present("chunks"),
+                json
+            }
+        }
+        ("dump-index", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                true,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::DumpIndex {
+                repo_path: repository,
+                json
             }
         }
         ("info", Some(args)) => {
             let (repository, backup, inode) =
                 parse_repo_path(args.value_of("PATH").unwrap(), true, None, None).unwrap();
+            let tree = args.is_present("tree");
+            if tree && backup.is_none() {
+                tr_error!("--tree needs a backup");
+                return Err(ErrorCode::InvalidArgs);
+            }
             Arguments::Info {
                 repo_path: repository,
                 backup_name: backup.map(|v| v.to_string()),
-                inode: inode.map(|v| v.to_string())
+                inode: inode.map(|v| v.to_string()),
+                tree,
+                json
             }
         }
         ("statistics", Some(args)) => {
@@ -812,6 +1752,31 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 backup_name_dst: backup_dst.unwrap().to_string()
             }
         }
+        ("clone", Some(args)) => {
+            let (repository, _backup, _inode) =
+                parse_repo_path(args.value_of("REPO").unwrap(), true, Some(false), Some(false))
+                    .unwrap();
+            let dst = args.value_of("DST").unwrap();
+            let (dst_repository, _backup, _inode) = parse_repo_path(dst, false, Some(false), Some(false))
+                .or_else(|_| parse_repo_path(dst, true, Some(false), Some(false)))
+                .unwrap();
+            Arguments::Clone {
+                repo_path: repository,
+                remote_path: args.value_of("REMOTE").unwrap().to_string(),
+                dst_repo_path: dst_repository
+            }
+        }
+        ("rename", Some(args)) => {
+            let (repository, backup, _inode) =
+                parse_repo_path(args.value_of("BACKUP").unwrap(), true, Some(true), Some(false))
+                    .unwrap();
+            Arguments::Rename {
+                repo_path: repository,
+                old_name: backup.unwrap().to_string(),
+                new_name: args.value_of("NAME").unwrap().to_string(),
+                admin: args.is_present("admin")
+            }
+        }
         ("mount", Some(args)) => {
             let (repository, backup, inode) =
                 parse_repo_path(args.value_of("PATH").unwrap(), true, None, None).unwrap();
@@ -819,7 +1784,8 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 repo_path: repository,
                 backup_name: backup.map(|v| v.to_string()),
                 inode: inode.map(|v| v.to_string()),
-                mount_point: args.value_of("MOUNTPOINT").unwrap().to_string()
+                mount_point: args.value_of("MOUNTPOINT").unwrap().to_string(),
+                threads: parse_num(args.value_of("fuse-threads").unwrap()).unwrap() as usize
             }
         }
         ("versions", Some(args)) => {
@@ -845,7 +1811,33 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 inode_old: inode_old.map(|v| v.to_string()),
                 repo_path_new: repository_new,
                 backup_name_new: backup_new.unwrap().to_string(),
-                inode_new: inode_new.map(|v| v.to_string())
+                inode_new: inode_new.map(|v| v.to_string()),
+                json
+            }
+        }
+        ("compare", Some(args)) => {
+            let (repository, backup, inode) =
+                parse_repo_path(args.value_of("BACKUP").unwrap(), true, Some(true), None).unwrap();
+            Arguments::Compare {
+                repo_path: repository,
+                backup_name: backup.unwrap().to_string(),
+                inode: inode.map(|v| v.to_string()),
+                path: args.value_of("PATH").unwrap().to_string(),
+                json
+            }
+        }
+        ("verify-signature", Some(args)) => {
+            let (repository, backup, _inode) = parse_repo_path(
+                args.value_of("BACKUP").unwrap(),
+                true,
+                None,
+                Some(false)
+            ).unwrap();
+            Arguments::VerifySignature {
+                repo_path: repository,
+                backup_name: backup.map(|v| v.to_string()),
+                verify_key: args.value_of("verify_key").map(|v| parse_verify_key(v).unwrap()),
+                allow_unsigned: args.is_present("allow-unsigned")
             }
         }
         ("analyze", Some(args)) => {
@@ -855,7 +1847,11 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 Some(false),
                 Some(false)
             ).unwrap();
-            Arguments::Analyze { repo_path: repository }
+            Arguments::Analyze {
+                repo_path: repository,
+                dedup: args.is_present("dedup"),
+                top: parse_num(args.value_of("top").unwrap()).unwrap() as usize
+            }
         }
         ("import", Some(args)) => {
             let (repository, _backup, _inode) = parse_repo_path(
@@ -872,6 +1868,32 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                     .unwrap_or_else(|| vec![])
             }
         }
+        ("export-archive", Some(args)) => {
+            let (repository, backup, _inode) =
+                parse_repo_path(args.value_of("BACKUP").unwrap(), true, Some(true), Some(false))
+                    .unwrap();
+            Arguments::ExportArchive {
+                repo_path: repository,
+                backup_name: backup.unwrap().to_string(),
+                archive_path: args.value_of("FILE").unwrap().to_string()
+            }
+        }
+        ("import-archive", Some(args)) => {
+            let (repository, _backup, _inode) = parse_repo_path(
+                args.value_of("REPO").unwrap(),
+                false,
+                Some(false),
+                Some(false)
+            ).unwrap();
+            Arguments::ImportArchive {
+                repo_path: repository,
+                remote_path: args.value_of("REMOTE").unwrap().to_string(),
+                archive_path: args.value_of("FILE").unwrap().to_string(),
+                key_files: args.values_of("key")
+                    .map(|v| v.map(|k| k.to_string()).collect())
+                    .unwrap_or_else(|| vec![])
+            }
+        }
         ("duplicates", Some(args)) => {
             let (repository, backup, inode) =
                 parse_repo_path(args.value_of("BACKUP").unwrap(), true, Some(true), None).unwrap();
@@ -903,7 +1925,10 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                     |v| parse_public_key(v).unwrap()
                 ),
                 hash: args.value_of("hash").map(|v| parse_hash(v).unwrap()),
-                repo_path: repository
+                redundancy: args.value_of("redundancy").map(|v| parse_redundancy(v).unwrap()),
+                repo_path: repository,
+                admin: args.is_present("admin"),
+                recover: args.is_present("recover")
             }
         }
         ("genkey", Some(args)) => {
@@ -923,7 +1948,8 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
                 repo_path: repository,
                 set_default: args.is_present("set_default"),
                 password: args.value_of("password").map(|v| v.to_string()),
-                file: args.value_of("FILE").map(|v| v.to_string())
+                file: args.value_of("FILE").map(|v| v.to_string()),
+                admin: args.is_present("admin")
             }
         }
         ("algotest", Some(args)) => {
@@ -942,5 +1968,106 @@ pub fn parse() -> Result<(log::Level, Arguments), ErrorCode> {
             return Err(ErrorCode::InvalidArgs);
         }
     };
-    Ok((log_level, args))
+    Ok((
+        GlobalOptions {
+            log_level,
+            audit_log,
+            escape_mode,
+            threads,
+            cache_size,
+            rate_limit,
+            remote_retry,
+            progress,
+            password_command
+        },
+        args
+    ))
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::fs;
+
+    #[allow(unused_imports)]
+    use std::io::Write;
+
+
+    #[test]
+    fn test_user_defaults() {
+        let base = ::std::env::temp_dir().join(format!("zvault-{}-{}", "user-defaults", ::std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("remote")).unwrap();
+        let path = base.join("config.yaml");
+        File::create(&path).unwrap().write_all(b"chunker: rabin/8\ncompression: zstd/5\nthreads: 3\n").unwrap();
+        let defaults = match load_user_defaults_from(&path) {
+            Ok(defaults) => defaults,
+            Err(_) => panic!("failed to load the defaults")
+        };
+        assert_eq!(defaults.chunker, Some("rabin/8".to_string()));
+        assert_eq!(defaults.compression, Some("zstd/5".to_string()));
+        assert_eq!(defaults.threads, Some(3));
+        assert_eq!(defaults.hash, None);
+        assert!(load_user_defaults_from(&base.join("missing.yaml")).ok().unwrap().chunker.is_none());
+        let invalid = base.join("invalid.yaml");
+        File::create(&invalid).unwrap().write_all(b"compression: nonsense/5\n").unwrap();
+        assert!(load_user_defaults_from(&invalid).is_err());
+        // Flags given on the command line override the defaults file
+        let cmd_args = vec![
+            "zvault".to_string(),
+            "init".to_string(),
+            "--remote".to_string(),
+            base.join("remote").to_string_lossy().to_string(),
+            "--compression".to_string(),
+            "lz4/1".to_string(),
+            base.join("repo").to_string_lossy().to_string()
+        ];
+        let (global, args) = match parse_from(cmd_args, defaults) {
+            Ok(parsed) => parsed,
+            Err(_) => panic!("failed to parse the arguments")
+        };
+        assert_eq!(global.threads, 3);
+        match args {
+            Arguments::Init { chunker, compression, hash, .. } => {
+                assert_eq!(chunker, parse_chunker("rabin/8").unwrap());
+                assert_eq!(compression, parse_compression("lz4/1").unwrap());
+                assert_eq!(hash, parse_hash(DEFAULT_HASH).unwrap());
+            }
+            _ => panic!("wrong subcommand")
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_validate_inline_size() {
+        assert!(validate_inline_size("0b".to_string()).is_ok());
+        assert!(validate_inline_size("4k".to_string()).is_ok());
+        assert!(validate_inline_size(format!("{}b", MAX_INLINE_SIZE)).is_ok());
+        assert!(validate_inline_size(format!("{}b", MAX_INLINE_SIZE + 1)).is_err());
+        assert!(validate_inline_size("1m".to_string()).is_err());
+        assert!(validate_inline_size("10x".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_default_inline_size() {
+        // Backups from the command line and the library use the same inline size by default
+        assert_eq!(parse_filesize(DEFAULT_INLINE_SIZE_STR), Ok(DEFAULT_INLINE_SIZE));
+        assert_eq!(BackupOptions::default().inline_size, DEFAULT_INLINE_SIZE);
+    }
+
+    #[test]
+    fn test_parse_owner() {
+        assert_eq!(parse_owner("1000:100"), Ok((1000, 100)));
+        assert_eq!(parse_owner("0:0"), Ok((0, 0)));
+        assert!(parse_owner("1000").is_err());
+        assert!(parse_owner("1000:").is_err());
+        assert!(parse_owner("user:group").is_err());
+        assert!(parse_owner("1000:100:10").is_err());
+        assert!(parse_owner("").is_err());
+    }
 }
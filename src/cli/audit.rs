@@ -0,0 +1,304 @@
+use prelude::*;
+
+use super::args::Arguments;
+use super::ErrorCode;
+
+use chrono::prelude::*;
+use serde_json::Value;
+use users;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+
+/// Returns the name and a summary of the arguments if the operation modifies a repository
+#[allow(unknown_lints, cyclomatic_complexity)]
+pub fn describe(args: &Arguments) -> Option<(&'static str, Value)> {
+    match *args {
+        Arguments::Init {
+            ref repo_path,
+            bundle_size,
+            ref chunker,
+            ref compression,
+            encryption,
+            ref cipher,
+            password,
+            hash,
+            ref remote_path,
+            append_only,
+            redundancy,
+            bundle_shard
+        } => Some(("init", json!({
+            "repository": repo_path.to_string_lossy(),
+            "remote": remote_path,
+            "bundle_size": bundle_size,
+            "chunker": chunker.to_string(),
+            "compression": compression.as_ref().map(|c| c.to_string()),
+            "encryption": encryption,
+            "cipher": cipher.to_string(),
+            "password": password,
+            "hash": hash.name(),
+            "append_only": append_only,
+            "redundancy": redundancy.map(|r| r.to_string()),
+            "bundle_shard": bundle_shard
+        }))),
+        Arguments::Backup {
+            ref repo_path,
+            ref backup_name,
+            ref src_path,
+            full,
+            ref reference,
+            tar,
+            resume,
+            dry_run,
+            ref chunker,
+            ref compression,
+            auto_compression,
+            verify,
+            fail_on_error,
+            hardlinks_threshold,
+            ..
+        } => Some(("backup", json!({
+            "repository": repo_path.to_string_lossy(),
+            "backup": backup_name,
+            "source": src_path,
+            "full": full,
+            "reference": reference,
+            "tar": tar,
+            "resume": resume,
+            "dry_run": dry_run,
+            "chunker": chunker.as_ref().map(|c| c.to_string()),
+            "compression": compression.as_ref().map(|c| c.as_ref().map(|c| c.to_string())),
+            "auto_compression": auto_compression,
+            "verify": verify,
+            "fail_on_error": fail_on_error,
+            "hardlinks_threshold": hardlinks_threshold
+        }))),
+        Arguments::Remove {
+            ref repo_path,
+            ref backup_name,
+            ref inode,
+            force,
+            dry_run,
+            remove_empty,
+            admin,
+            ..
+        } => Some(("remove", json!({
+            "repository": repo_path.to_string_lossy(),
+            "backup": backup_name,
+            "subtree": inode,
+            "force": force,
+            "dry_run": dry_run,
+            "remove_empty": remove_empty,
+            "admin": admin
+        }))),
+        Arguments::Prune {
+            ref repo_path,
+            ref prefix,
+            daily,
+            weekly,
+            monthly,
+            yearly,
+            keep_last,
+            keep_within,
+            max_size,
+            force,
+            admin
+        } => Some(("prune", json!({
+            "repository": repo_path.to_string_lossy(),
+            "prefix": prefix,
+            "daily": daily,
+            "weekly": weekly,
+            "monthly": monthly,
+            "yearly": yearly,
+            "keep_last": keep_last,
+            "keep_within": keep_within,
+            "max_size": max_size,
+            "force": force,
+            "admin": admin
+        }))),
+        Arguments::Vacuum {
+            ref repo_path,
+            ratio,
+            force,
+            combine,
+            admin
+        } => Some(("vacuum", json!({
+            "repository": repo_path.to_string_lossy(),
+            "ratio": ratio,
+            "force": force,
+            "combine": combine,
+            "admin": admin
+        }))),
+        Arguments::Repack {
+            ref repo_path,
+            bundle_size,
+            force,
+            admin
+        } => Some(("repack", json!({
+            "repository": repo_path.to_string_lossy(),
+            "bundle_size": bundle_size,
+            "force": force,
+            "admin": admin
+        }))),
+        Arguments::Check {
+            ref repo_path,
+            ref backup_name,
+            repair: true,
+            admin,
+            ..
+        } => Some(("repair", json!({
+            "repository": repo_path.to_string_lossy(),
+            "backup": backup_name,
+            "admin": admin
+        }))),
+        Arguments::Check {
+            ref repo_path,
+            repair_index: true,
+            admin,
+            ..
+        } => Some(("repair_index", json!({
+            "repository": repo_path.to_string_lossy(),
+            "admin": admin
+        }))),
+        Arguments::Copy {
+            ref repo_path_src,
+            ref backup_name_src,
+            ref repo_path_dst,
+            ref backup_name_dst
+        } => Some(("copy", json!({
+            "repository": repo_path_dst.to_string_lossy(),
+            "backup": backup_name_dst,
+            "source_repository": repo_path_src.to_string_lossy(),
+            "source_backup": backup_name_src
+        }))),
+        Arguments::Clone {
+            ref repo_path,
+            ref remote_path,
+            ref dst_repo_path
+        } => Some(("clone", json!({
+            "repository": dst_repo_path.to_string_lossy(),
+            "remote": remote_path,
+            "source_repository": repo_path.to_string_lossy()
+        }))),
+        Arguments::Rename {
+            ref repo_path,
+            ref old_name,
+            ref new_name,
+            admin
+        } => Some(("rename", json!({
+            "repository": repo_path.to_string_lossy(),
+            "backup": old_name,
+            "new_name": new_name,
+            "admin": admin
+        }))),
+        Arguments::Import {
+            ref repo_path,
+            ref remote_path,
+            ..
+        } => Some(("import", json!({
+            "repository": repo_path.to_string_lossy(),
+            "remote": remote_path
+        }))),
+        Arguments::ImportArchive {
+            ref repo_path,
+            ref remote_path,
+            ref archive_path,
+            ..
+        } => Some(("import_archive", json!({
+            "repository": repo_path.to_string_lossy(),
+            "remote": remote_path,
+            "archive": archive_path
+        }))),
+        Arguments::Config {
+            ref repo_path,
+            bundle_size,
+            ref chunker,
+            ref compression,
+            ref encryption,
+            hash,
+            redundancy,
+            admin,
+            recover
+        } => {
+            if bundle_size.is_none() && chunker.is_none() && compression.is_none() &&
+                encryption.is_none() && hash.is_none() && redundancy.is_none() && !recover
+            {
+                return None;
+            }
+            Some(("config", json!({
+                "repository": repo_path.to_string_lossy(),
+                "bundle_size": bundle_size,
+                "chunker": chunker.as_ref().map(|c| c.to_string()),
+                "compression": compression.as_ref().map(|c| c.as_ref().map(|c| c.to_string())),
+                "encryption": encryption.as_ref().map(|k| k.as_ref().map(|k| to_hex(&k[..]))),
+                "hash": hash.map(|h| h.name()),
+                "redundancy": redundancy.map(|r| r.map(|r| r.to_string())),
+                "admin": admin,
+                "recover": recover
+            })))
+        }
+        Arguments::AddKey {
+            ref repo_path,
+            ref file,
+            set_default,
+            admin,
+            ..
+        } => Some(("addkey", json!({
+            "repository": repo_path.to_string_lossy(),
+            "file": file,
+            "set_default": set_default,
+            "admin": admin
+        }))),
+        _ => None
+    }
+}
+
+
+/// Returns the hash of the last record in the log
+fn last_record_hash(path: &Path) -> Result<Option<String>, io::Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err)
+    };
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        let line = try!(line);
+        if !line.is_empty() {
+            last = Some(line);
+        }
+    }
+    Ok(last.map(|line| HashMethod::Blake2.hash(line.as_bytes()).to_string()))
+}
+
+/// Appends a record of the operation to the audit log and syncs it to disk
+///
+/// Every record contains the hash of the previous record, so records that have been removed
+/// or modified later break the chain.
+pub fn write_record(
+    path: &Path,
+    operation: &str,
+    args: Value,
+    result: &Result<(), ErrorCode>,
+) -> Result<(), io::Error> {
+    let prev = try!(last_record_hash(path));
+    let record = json!({
+        "time": Local::now().to_rfc3339(),
+        "user": users::get_current_username(),
+        "uid": users::get_current_uid(),
+        "host": get_hostname().ok(),
+        "operation": operation,
+        "args": args,
+        "outcome": if result.is_ok() { "success" } else { "failure" },
+        "exit_code": match *result {
+            Ok(()) => 0,
+            Err(ref code) => code.code()
+        },
+        "prev": prev
+    });
+    let mut file = try!(OpenOptions::new().create(true).append(true).open(path));
+    try!(writeln!(file, "{}", record));
+    file.sync_data()
+}
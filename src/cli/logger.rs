@@ -3,6 +3,8 @@ pub use log::SetLoggerError;
 
 use ansi_term::{Color, Style};
 
+use util::clear_progress_line;
+
 
 struct Logger(log::Level);
 
@@ -15,6 +17,7 @@ impl log::Log for Logger {
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
+            clear_progress_line();
             match record.level() {
                 log::Level::Error => {
                     eprintln!("{}: {}", Color::Red.bold().paint("error"), record.args())
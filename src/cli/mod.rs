@@ -1,18 +1,27 @@
 mod args;
 mod logger;
 mod algotest;
+mod audit;
 
 use prelude::*;
 
 use chrono::prelude::*;
-use regex::{self, RegexSet};
+use regex;
+use serde_json;
+use libc;
 
-use std::collections::HashMap;
-use std::io::{BufReader, BufRead};
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, BufRead, Read, Write};
+use std::fs::{self, File};
 use std::env;
+use std::process::{self, Command, Stdio};
+use std::{mem, ptr};
+use std::sync::RwLock;
 use std::str;
 use std::path::{Path, PathBuf};
+use std::ffi::OsStr;
+use std::time::Instant;
+use std::os::unix::ffi::OsStrExt;
 
 use self::args::Arguments;
 
@@ -46,7 +55,20 @@ pub enum ErrorCode {
     VersionsRun,
     ImportRun,
     FuseMount,
-    DuplicatesRun
+    DuplicatesRun,
+    RepackRun,
+    TestRestoreRun,
+    CompareRun,
+    VerifySignatureRun,
+    CopyRun,
+    DumpIndexRun,
+    WriteAuditLog,
+    LoadFileList,
+    RenameRun,
+    BackupIncomplete,
+    CloneRun,
+    ExportRun,
+    BackupInterrupted
 }
 impl ErrorCode {
     pub fn code(&self) -> i32 {
@@ -64,6 +86,7 @@ impl ErrorCode {
             ErrorCode::LoadBundle => 6,
             ErrorCode::LoadKey => 7,
             ErrorCode::LoadExcludes => 8,
+            ErrorCode::LoadFileList => 35,
             // Minor operations
             ErrorCode::SaveBackup => 9,
             ErrorCode::AddKey => 10,
@@ -83,6 +106,18 @@ impl ErrorCode {
             ErrorCode::ImportRun => 23,
             ErrorCode::FuseMount => 24,
             ErrorCode::DuplicatesRun => 27,
+            ErrorCode::RepackRun => 28,
+            ErrorCode::TestRestoreRun => 29,
+            ErrorCode::CompareRun => 30,
+            ErrorCode::VerifySignatureRun => 31,
+            ErrorCode::CopyRun => 32,
+            ErrorCode::DumpIndexRun => 33,
+            ErrorCode::WriteAuditLog => 34,
+            ErrorCode::RenameRun => 36,
+            ErrorCode::BackupIncomplete => 37,
+            ErrorCode::CloneRun => 38,
+            ErrorCode::ExportRun => 39,
+            ErrorCode::BackupInterrupted => 40,
             //
             ErrorCode::NoSuchBackup => 25,
             ErrorCode::BackupAlreadyExists => 26,
@@ -97,10 +132,21 @@ pub const DEFAULT_COMPRESSION: &str = "brotli/3";
 pub const DEFAULT_BUNDLE_SIZE_STR: &str = "25";
 pub const DEFAULT_VACUUM_RATIO_STR: &str = "0";
 pub const DEFAULT_DUPLICATES_MIN_SIZE_STR: &str = "1b";
+pub const DEFAULT_INLINE_SIZE_STR: &str = "100b";
+pub const DEFAULT_TEST_RESTORE_SAMPLE_STR: &str = "50";
+pub const DEFAULT_FUSE_THREADS_STR: &str = "1";
+pub const DEFAULT_ESCAPE_MODE: &str = "c";
+pub const LARGE_INDEX_ENTRIES: usize = 10_000_000;
 lazy_static! {
     pub static ref ZVAULT_FOLDER: PathBuf = {
         env::home_dir().unwrap().join(".zvault")
     };
+    static ref THREADS: RwLock<usize> = RwLock::new(1);
+    static ref CACHE_SIZE: RwLock<Option<usize>> = RwLock::new(None);
+    static ref RATE_LIMIT: RwLock<Option<u64>> = RwLock::new(None);
+    static ref REMOTE_RETRY: RwLock<RetryPolicy> = RwLock::new(RetryPolicy::default());
+    static ref PROGRESS: RwLock<bool> = RwLock::new(false);
+    static ref PASSWORD_COMMAND: RwLock<Option<String>> = RwLock::new(None);
 }
 
 macro_rules! checked {
@@ -116,11 +162,31 @@ macro_rules! checked {
 }
 
 fn open_repository(path: &Path, online: bool) -> Result<Repository, ErrorCode> {
-    Ok(checked!(
-        Repository::open(path, online),
-        "load repository",
-        ErrorCode::LoadRepository
-    ))
+    let mut result = Repository::open(path, online);
+    if let Err(RepositoryError::PasswordRequired) = result {
+        let mut password = try!(get_password());
+        result = Repository::open_with_password(path, online, Some(&password));
+        wipe_password(&mut password);
+    }
+    match result {
+        Ok(mut repo) => {
+            repo.set_threads(*THREADS.read().unwrap());
+            if let Some(size) = *CACHE_SIZE.read().unwrap() {
+                repo.set_cache_size(size);
+            }
+            repo.set_rate_limit(*RATE_LIMIT.read().unwrap());
+            repo.set_remote_retry(*REMOTE_RETRY.read().unwrap());
+            repo.set_progress(*PROGRESS.read().unwrap());
+            Ok(repo)
+        }
+        Err(err) => {
+            tr_error!("Failed to load repository\n\tcaused by: {}", err);
+            if let RepositoryError::Config(_) = err {
+                tr_info!("The configuration can be recovered with `zvault config --config-recover`");
+            }
+            Err(ErrorCode::LoadRepository)
+        }
+    }
 }
 
 fn get_backup(repo: &Repository, backup_name: &str) -> Result<Backup, ErrorCode> {
@@ -151,9 +217,167 @@ fn get_inode(repo: &mut Repository, backup: &Backup, inode: Option<&String>) ->
     })
 }
 
+/// Chooses how the owners of restored files are set
+///
+/// Only root can give files to other users, so everybody else keeps the files by default.
+fn restore_owner(numeric_owner: bool, chown: Option<(u32, u32)>) -> RestoreOwner {
+    if numeric_owner {
+        RestoreOwner::Numeric
+    } else if let Some((user, group)) = chown {
+        RestoreOwner::Fixed(user, group)
+    } else if unsafe { libc::geteuid() } == 0 {
+        RestoreOwner::Names
+    } else {
+        tr_warn!("Not running as root, the restored files will be owned by the current user");
+        RestoreOwner::Current
+    }
+}
+
+/// Reads a list of paths separated by `separator` from a file or from stdin if `file` is `-`
+fn read_path_list(file: &str, separator: u8) -> Result<Vec<PathBuf>, io::Error> {
+    let mut data = vec![];
+    if file == "-" {
+        try!(io::stdin().read_to_end(&mut data));
+    } else {
+        try!(try!(File::open(file)).read_to_end(&mut data));
+    }
+    Ok(data.split(|&b| b == separator)
+        .filter(|path| !path.is_empty())
+        .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+        .collect())
+}
+
+/// Reads a password from stdin, switching off the echo if stdin is a terminal
+fn read_password(prompt: &str) -> Result<String, io::Error> {
+    eprint!("{}: ", prompt);
+    try!(io::stderr().flush());
+    let fd = libc::STDIN_FILENO;
+    let mut term: libc::termios = unsafe { mem::zeroed() };
+    let is_terminal = unsafe { libc::isatty(fd) == 1 && libc::tcgetattr(fd, &mut term) == 0 };
+    if is_terminal {
+        let mut silent = term;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+    }
+    let mut line = String::new();
+    let res = io::stdin().read_line(&mut line);
+    if is_terminal {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+        eprintln!();
+    }
+    try!(res);
+    // Truncating instead of copying leaves no other copy of the password in memory
+    let len = line.trim_right_matches(|c| c == '\n' || c == '\r').len();
+    line.truncate(len);
+    Ok(line)
+}
+
+/// Runs the password command in a shell and returns its output without surrounding whitespace
+fn run_password_command(command: &str) -> Result<String, String> {
+    let output = try!(Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| err.to_string()));
+    let mut stdout = output.stdout;
+    let password = if output.status.success() {
+        str::from_utf8(&stdout)
+            .map(|password| password.trim().to_string())
+            .map_err(|_| tr!("the output is not valid UTF-8").to_string())
+    } else {
+        Err(tr_format!("the command failed with {}", output.status))
+    };
+    for byte in &mut stdout {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    password
+}
+
+/// Returns the password of the repository
+///
+/// The password is taken from the output of `--password-command`, the environment variable
+/// `ZVAULT_PASSWORD` or the terminal, in this order.
+fn get_password() -> Result<String, ErrorCode> {
+    if let Some(ref command) = *PASSWORD_COMMAND.read().unwrap() {
+        return Ok(checked!(
+            run_password_command(command),
+            "run password command",
+            ErrorCode::LoadKey
+        ));
+    }
+    if let Ok(password) = env::var("ZVAULT_PASSWORD") {
+        return Ok(password);
+    }
+    Ok(checked!(
+        read_password(tr!("Repository password")),
+        "read password",
+        ErrorCode::LoadKey
+    ))
+}
+
+/// Asks for the password if the keys of the repository at the remote path need one
+fn get_remote_password(remote_path: &Path) -> Result<Option<String>, ErrorCode> {
+    if Repository::remote_uses_password(remote_path) {
+        Ok(Some(try!(get_password())))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Overwrites the password in memory once the key has been derived from it
+fn wipe_password(password: &mut String) {
+    // Zero bytes are valid UTF-8, so the string stays valid
+    for byte in unsafe { password.as_mut_vec() } {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    password.clear();
+}
+
+/// Asks for a new password twice to rule out typos
+///
+/// With `--password-command`, the output of the command is used instead. All copies of the
+/// password except the returned one are overwritten.
+fn read_new_password() -> Result<String, ErrorCode> {
+    let mut password = if let Some(ref command) = *PASSWORD_COMMAND.read().unwrap() {
+        checked!(run_password_command(command), "run password command", ErrorCode::InvalidArgs)
+    } else {
+        checked!(read_password(tr!("Password")), "read password", ErrorCode::InvalidArgs)
+    };
+    if password.is_empty() {
+        tr_error!("The password must not be empty");
+        return Err(ErrorCode::InvalidArgs);
+    }
+    if PASSWORD_COMMAND.read().unwrap().is_some() {
+        return Ok(password);
+    }
+    let mut repeated = match read_password(tr!("Repeat password")) {
+        Ok(repeated) => repeated,
+        Err(err) => {
+            wipe_password(&mut password);
+            tr_error!("Failed to {}\n\tcaused by: {}", tr!("read password"), err);
+            return Err(ErrorCode::InvalidArgs);
+        }
+    };
+    let matches = password == repeated;
+    wipe_password(&mut repeated);
+    if !matches {
+        wipe_password(&mut password);
+        tr_error!("The passwords do not match");
+        return Err(ErrorCode::InvalidArgs);
+    }
+    Ok(password)
+}
+
+/// Finds the latest backup of `path` from this machine
+///
+/// Backups in the same folder as `backup_name` are preferred, so e.g. `daily/...` backups are
+/// based on the last daily backup even if a weekly backup of the same path is more recent.
 fn find_reference_backup(
     repo: &Repository,
     path: &str,
+    backup_name: &str,
 ) -> Result<Option<(String, Backup)>, ErrorCode> {
     let mut matching = Vec::new();
     let hostname = match get_hostname() {
@@ -177,6 +401,12 @@ fn find_reference_backup(
             matching.push((name, backup));
         }
     }
+    if let Some(pos) = backup_name.rfind('/') {
+        let folder = &backup_name[..pos + 1];
+        if matching.iter().any(|&(ref name, _)| name.starts_with(folder)) {
+            matching.retain(|&(ref name, _)| name.starts_with(folder));
+        }
+    }
     matching.sort_by_key(|&(_, ref b)| b.timestamp);
     Ok(matching.pop())
 }
@@ -189,7 +419,14 @@ fn print_backup(backup: &Backup) {
         "Date: {}",
         Local.timestamp(backup.timestamp, 0).to_rfc2822()
     );
+    tr_println!(
+        "Finished: {}",
+        Local.timestamp(backup.end_timestamp(), 0).to_rfc2822()
+    );
     tr_println!("Source: {}:{}", backup.host, backup.path);
+    if let Some(ref version) = backup.version {
+        tr_println!("Created by: zVault {}", version);
+    }
     tr_println!("Duration: {}", to_duration(backup.duration));
     tr_println!(
         "Entries: {} files, {} dirs",
@@ -222,6 +459,32 @@ fn print_backup(backup: &Backup) {
         backup.chunk_count,
         to_file_size(backup.avg_chunk_size as u64)
     );
+    if !backup.metadata.is_empty() {
+        tr_println!("Metadata:");
+        for (key, value) in &backup.metadata {
+            println!("  - {}: {}", key, value);
+        }
+    }
+}
+
+/// Formats the inode as a single line with mode, type and size in front of its full path
+pub fn format_inode_path_line(path: &Path, inode: &Inode) -> String {
+    let mut name = escape(path);
+    match inode.file_type {
+        FileType::Directory if path != Path::new("/") => name.push('/'),
+        FileType::Symlink => {
+            name.push_str(" -> ");
+            name.push_str(&inode.symlink_target.as_ref().map(escape).unwrap_or_else(|| "?".to_string()));
+        }
+        _ => ()
+    }
+    format!(
+        "{:04o}\t{:12}\t{:>10}\t{}",
+        inode.mode & 0o7777,
+        inode.file_type.to_string(),
+        to_file_size(inode.size),
+        name
+    )
 }
 
 pub fn format_inode_one_line(inode: &Inode) -> String {
@@ -229,14 +492,14 @@ pub fn format_inode_one_line(inode: &Inode) -> String {
         FileType::Directory => {
             format!(
                 "{:25}\t{} entries",
-                format!("{}/", inode.name),
+                format!("{}/", escape(&inode.name)),
                 inode.children.as_ref().map(|c| c.len()).unwrap_or(0)
             )
         }
-        FileType::File => {
+        FileType::File | FileType::HardLink => {
             format!(
                 "{:25}\t{:>10}\t{}",
-                inode.name,
+                escape(&inode.name),
                 to_file_size(inode.size),
                 Local.timestamp(inode.timestamp, 0).to_rfc2822()
             )
@@ -244,28 +507,26 @@ pub fn format_inode_one_line(inode: &Inode) -> String {
         FileType::Symlink => {
             format!(
                 "{:25}\t -> {}",
-                inode.name,
-                inode.symlink_target.as_ref().map(|s| s as &str).unwrap_or(
-                    "?"
-                )
+                escape(&inode.name),
+                inode.symlink_target.as_ref().map(escape).unwrap_or_else(|| "?".to_string())
             )
         }
         FileType::BlockDevice | FileType::CharDevice => {
             let device = inode.device.unwrap_or((0, 0));
             format!(
                 "{:25}\t{:12}\t{}:{}",
-                inode.name,
+                escape(&inode.name),
                 inode.file_type,
                 device.0,
                 device.1
             )
         }
-        FileType::NamedPipe => format!("{:25}\t fifo", inode.name),
+        FileType::NamedPipe => format!("{:25}\t fifo", escape(&inode.name)),
     }
 }
 
 fn print_inode(inode: &Inode) {
-    tr_println!("Name: {}", inode.name);
+    tr_println!("Name: {}", escape(&inode.name));
     tr_println!("Type: {}", inode.file_type);
     tr_println!("Size: {}", to_file_size(inode.size));
     tr_println!("Permissions: {:3o}", inode.mode);
@@ -275,8 +536,15 @@ fn print_inode(inode: &Inode) {
         "Timestamp: {}",
         Local.timestamp(inode.timestamp, 0).to_rfc2822()
     );
+    if let Some(birth_time) = inode.birth_time {
+        tr_println!("Birth time: {}", Local.timestamp(birth_time, 0).to_rfc2822());
+    }
     if let Some(ref target) = inode.symlink_target {
-        tr_println!("Symlink target: {}", target);
+        if inode.file_type == FileType::HardLink {
+            tr_println!("Link target: {}", escape(target));
+        } else {
+            tr_println!("Symlink target: {}", escape(target));
+        }
     }
     tr_println!("Cumulative size: {}", to_file_size(inode.cum_size));
     tr_println!("Cumulative file count: {}", inode.cum_files);
@@ -284,7 +552,7 @@ fn print_inode(inode: &Inode) {
     if let Some(ref children) = inode.children {
         tr_println!("Children:");
         for name in children.keys() {
-            println!("  - {}", name);
+            println!("  - {}", escape(name));
         }
     }
     if !inode.xattrs.is_empty() {
@@ -299,13 +567,111 @@ fn print_inode(inode: &Inode) {
     }
 }
 
+fn glob_to_regex(glob: &str) -> String {
+    regex::escape(glob)
+        .replace('?', ".")
+        .replace(r"\*\*", ".*")
+        .replace(r"\*", "[^/]*")
+}
+
+fn is_glob(name: &str) -> bool {
+    name.contains('*') || name.contains('?')
+}
+
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().ok();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    let answer = answer.trim().to_lowercase();
+    answer == "y" || answer == "yes"
+}
+
+fn diff_type_name(diff: &DiffType) -> &'static str {
+    match *diff {
+        DiffType::Add => "add",
+        DiffType::Mod => "mod",
+        DiffType::Del => "del",
+    }
+}
+
+fn diff_type_symbol(diff: &DiffType) -> char {
+    match *diff {
+        DiffType::Add => '+',
+        DiffType::Mod => '*',
+        DiffType::Del => '-',
+    }
+}
+
+fn print_backup_plan(entries: &[PlannedEntry]) {
+    let (mut new, mut modified, mut unchanged) = (0, 0, 0);
+    let (mut total_size, mut changed_size) = (0, 0);
+    for entry in entries {
+        let symbol = match entry.change {
+            Some(ref diff) => diff_type_symbol(diff),
+            None => ' '
+        };
+        println!("{} {:>10} {}", symbol, to_file_size(entry.size), escape(&entry.path));
+        match entry.change {
+            Some(DiffType::Add) => new += 1,
+            Some(_) => modified += 1,
+            None => unchanged += 1
+        }
+        total_size += entry.size;
+        if entry.change.is_some() {
+            changed_size += entry.size;
+        }
+    }
+    println!();
+    tr_println!("Entries: {} new, {} modified, {} unchanged", new, modified, unchanged);
+    tr_println!("Total size: {}", to_file_size(total_size));
+    tr_println!("Changed size: {}", to_file_size(changed_size));
+}
+
+fn print_cache_dirs(paths: &[PathBuf]) {
+    if !paths.is_empty() {
+        tr_info!("Skipped the contents of {} cache folders:", paths.len());
+        for path in paths {
+            tr_info!("  - {}", escape(path));
+        }
+    }
+}
+
+fn print_diffs(diffs: &[(DiffType, PathBuf)]) {
+    for diff in diffs {
+        println!("{} {}", diff_type_symbol(&diff.0), escape(&diff.1));
+    }
+    if diffs.is_empty() {
+        tr_info!("No differences found");
+    }
+}
+
+fn print_diffs_json(diffs: &[(DiffType, PathBuf)]) {
+    let diffs: Vec<_> = diffs
+        .iter()
+        .map(|&(ref diff, ref path)| {
+            json!({"type": diff_type_name(diff), "path": path.to_string_lossy()})
+        })
+        .collect();
+    println!("{}", serde_json::Value::Array(diffs));
+}
+
+fn print_pipeline_times(times: &PipelineTimes) {
+    tr_println!("Time breakdown:");
+    for (stage, percent) in times.breakdown() {
+        println!("  - {}: {:.1}%", stage, percent);
+    }
+}
+
 fn print_backups(backup_map: &HashMap<String, Backup>) {
     let mut backups: Vec<_> = backup_map.into_iter().collect();
     backups.sort_by_key(|b| b.0);
     for (name, backup) in backups {
         println!(
             "{:40}  {:>32}  {:7} files, {:6} dirs, {:>10}",
-            name,
+            escape(name),
             Local.timestamp(backup.timestamp, 0).to_rfc2822(),
             backup.file_count,
             backup.dir_count,
@@ -314,11 +680,124 @@ fn print_backups(backup_map: &HashMap<String, Backup>) {
     }
 }
 
+fn backup_to_json(name: &str, backup: &Backup) -> serde_json::Value {
+    json!({
+        "name": name,
+        "timestamp": backup.timestamp,
+        "date": Local.timestamp(backup.timestamp, 0).to_rfc3339(),
+        "host": backup.host,
+        "path": backup.path,
+        "duration": backup.duration,
+        "end_timestamp": backup.end_timestamp(),
+        "version": backup.version,
+        "file_count": backup.file_count,
+        "dir_count": backup.dir_count,
+        "total_data_size": backup.total_data_size,
+        "changed_data_size": backup.changed_data_size,
+        "deduplicated_data_size": backup.deduplicated_data_size,
+        "encoded_data_size": backup.encoded_data_size,
+        "bundle_count": backup.bundle_count,
+        "chunk_count": backup.chunk_count,
+        "avg_chunk_size": backup.avg_chunk_size,
+        "modified": backup.modified,
+        "signed": backup.signature.is_some(),
+        "metadata": backup.metadata
+    })
+}
+
+fn inode_to_json(inode: &Inode) -> serde_json::Value {
+    let xattrs: BTreeMap<&String, String> = inode.xattrs
+        .iter()
+        .map(|(key, value)| (key, to_hex(value)))
+        .collect();
+    json!({
+        "name": inode.name,
+        "type": inode.file_type.to_string(),
+        "size": inode.size,
+        "mode": inode.mode,
+        "user": inode.user,
+        "group": inode.group,
+        "timestamp": inode.timestamp,
+        "birth_time": inode.birth_time,
+        "symlink_target": inode.symlink_target,
+        "cum_size": inode.cum_size,
+        "cum_files": inode.cum_files,
+        "cum_dirs": inode.cum_dirs,
+        "children": inode.children.as_ref().map(|c| c.keys().collect::<Vec<_>>()),
+        "device": inode.device,
+        "xattrs": xattrs
+    })
+}
+
+fn repoinfo_to_json(info: &RepositoryInfo) -> serde_json::Value {
+    let bundle_keys: Vec<_> = info.bundle_keys
+        .iter()
+        .map(|&(ref key, available)| json!({"key": key, "secret_key_available": available}))
+        .collect();
+    let compressions: Vec<_> = info.compressions
+        .iter()
+        .map(|usage| json!({
+            "compression": usage.compression,
+            "bundle_count": usage.bundle_count,
+            "raw_size": usage.raw_size,
+            "encoded_size": usage.encoded_size,
+            "compression_ratio": usage.compression_ratio()
+        }))
+        .collect();
+    json!({
+        "bundle_count": info.bundle_count,
+        "encoded_data_size": info.encoded_data_size,
+        "raw_data_size": info.raw_data_size,
+        "compression_ratio": info.compression_ratio,
+        "chunk_count": info.chunk_count,
+        "avg_chunk_size": info.avg_chunk_size,
+        "index_size": info.index_size,
+        "index_capacity": info.index_capacity,
+        "index_entries": info.index_entries,
+        "encryption_method": info.encryption_method,
+        "default_key": info.default_key,
+        "bundle_keys": bundle_keys,
+        "can_decrypt": info.can_decrypt,
+        "compressions": compressions
+    })
+}
+
+fn bundle_to_json(info: &BundleInfo) -> serde_json::Value {
+    json!({
+        "id": info.id.to_string(),
+        "mode": format!("{:?}", info.mode).to_lowercase(),
+        "timestamp": info.timestamp,
+        "hash_method": info.hash_method.name(),
+        "compression": info.compression.as_ref().map(|c| c.to_string()),
+        "encryption": info.encryption.as_ref().map(|e| to_hex(&e.1[..])),
+        "chunk_count": info.chunk_count,
+        "raw_size": info.raw_size,
+        "encoded_size": info.encoded_size
+    })
+}
+
 fn print_repoinfo(info: &RepositoryInfo) {
     tr_println!("Bundles: {}", info.bundle_count);
     tr_println!("Total size: {}", to_file_size(info.encoded_data_size));
     tr_println!("Uncompressed size: {}", to_file_size(info.raw_data_size));
     tr_println!("Compression ratio: {:.1}%", (info.compression_ratio - 1.0) * 100.0);
+    if !info.compressions.is_empty() {
+        tr_println!("Bundles by compression:");
+        for usage in &info.compressions {
+            let name = match usage.compression {
+                Some(ref name) => name.as_str(),
+                None => tr!("none")
+            };
+            tr_println!(
+                "  - {:12} {:>6} bundles, {:>10} raw, {:>10} encoded, ratio {:5.1}%",
+                name,
+                usage.bundle_count,
+                to_file_size(usage.raw_size),
+                to_file_size(usage.encoded_size),
+                (usage.compression_ratio() - 1.0) * 100.0
+            );
+        }
+    }
     tr_println!("Chunk count: {}", info.chunk_count);
     tr_println!(
         "Average chunk size: {}",
@@ -330,6 +809,29 @@ fn print_repoinfo(info: &RepositoryInfo) {
         to_file_size(info.index_size as u64),
         index_usage * 100.0
     );
+    if let Some(ref method) = info.encryption_method {
+        tr_println!("Encryption: {}", method);
+    } else {
+        tr_println!("Encryption: none");
+    }
+    if let Some(ref key) = info.default_key {
+        tr_println!("Default key: {}", key);
+    }
+    if !info.bundle_keys.is_empty() {
+        tr_println!("Keys used by bundles:");
+        for &(ref key, available) in &info.bundle_keys {
+            if available {
+                tr_println!("  - {} (secret key available)", key);
+            } else {
+                tr_println!("  - {} (secret key missing)", key);
+            }
+        }
+    }
+    if info.can_decrypt {
+        tr_println!("Decryptable: yes");
+    } else {
+        tr_println!("Decryptable: no, secret keys are missing");
+    }
 }
 
 fn print_repostats(stats: &RepositoryStatistics) {
@@ -395,14 +897,14 @@ fn print_repostats(stats: &RepositoryStatistics) {
 fn print_bundle(bundle: &StoredBundle) {
     tr_println!("Bundle {}", bundle.info.id);
     tr_println!("  - Mode: {:?}", bundle.info.mode);
-    tr_println!("  - Path: {:?}", bundle.path);
+    tr_println!("  - Path: {}", escape(&bundle.path));
     tr_println!(
         "  - Date: {}",
         Local.timestamp(bundle.info.timestamp, 0).to_rfc2822()
     );
     tr_println!("  - Hash method: {:?}", bundle.info.hash_method);
-    let encryption = if let Some((_, ref key)) = bundle.info.encryption {
-        to_hex(key)
+    let encryption = if let Some((ref method, ref key)) = bundle.info.encryption {
+        format!("{} ({})", to_hex(key), method.to_string())
     } else {
         "none".to_string()
     };
@@ -429,6 +931,31 @@ fn print_bundle(bundle: &StoredBundle) {
     );
 }
 
+fn print_bundle_chunks(chunks: &[IndexEntry]) {
+    tr_println!("  - Chunk list:");
+    for chunk in chunks {
+        tr_println!(
+            "    {:5}: {}, offset: {}, size: {}",
+            chunk.chunk,
+            chunk.hash,
+            chunk.offset,
+            chunk.size
+        );
+    }
+}
+
+fn print_directory_sizes(sizes: &[DirectorySize]) {
+    println!("{:>10}  {:>10}  {}", tr!("Size"), tr!("Dedup size"), tr!("Path"));
+    for dir in sizes {
+        println!(
+            "{:>10}  {:>10}  {}",
+            to_file_size(dir.size),
+            to_file_size(dir.dedup_size),
+            escape(&dir.path)
+        );
+    }
+}
+
 fn print_bundle_one_line(bundle: &BundleInfo) {
     tr_println!(
         "{}: {:8?}, {:5} chunks, {:8}",
@@ -448,11 +975,23 @@ fn print_config(config: &Config) {
         tr_println!("Compression: none");
     }
     if let Some(ref encryption) = config.encryption {
-        tr_println!("Encryption: {}", to_hex(&encryption.1[..]));
+        tr_println!("Encryption: {} ({})", to_hex(&encryption.1[..]), encryption.0.to_string());
+        if config.key_derivation.is_some() {
+            tr_println!("Key source: password");
+        }
     } else {
         tr_println!("Encryption: none");
     }
     tr_println!("Hash method: {}", config.hash.name());
+    if config.append_only {
+        tr_println!("Append-only: yes");
+    }
+    if let Some(redundancy) = config.redundancy {
+        tr_println!("Redundancy: {}", redundancy);
+    }
+    if let Some(shard) = config.bundle_shard {
+        tr_println!("Bundle shard: {} characters", shard);
+    }
 }
 
 fn print_analysis(analysis: &HashMap<u32, BundleAnalysis>) {
@@ -489,11 +1028,35 @@ fn print_analysis(analysis: &HashMap<u32, BundleAnalysis>) {
     }
 }
 
+fn print_dedup_analysis(analysis: &DedupAnalysis) {
+    tr_println!(
+        "Unique chunks: {}, {}",
+        analysis.unique_chunks,
+        to_file_size(analysis.unique_size)
+    );
+    tr_println!(
+        "Chunk references: {}, {}",
+        analysis.references,
+        to_file_size(analysis.referenced_size)
+    );
+    tr_println!("Deduplication factor: {:.2}", analysis.get_dedup_factor());
+    tr_println!(
+        "Chunks in index: {} (including metadata and unused chunks)",
+        analysis.index_chunks
+    );
+    if !analysis.top_chunks.is_empty() {
+        tr_println!("Most referenced chunks");
+        for &(ref hash, len, refs) in &analysis.top_chunks {
+            tr_println!("  - {}: {} references, {}", hash, refs, to_file_size(u64::from(len)));
+        }
+    }
+}
+
 fn print_duplicates(dups: Vec<(Vec<PathBuf>, u64)>) {
     for (group, size) in dups {
         tr_println!("{} duplicates found, size: {}", group.len(), to_file_size(size));
         for dup in group {
-            println!("  - {}", dup.to_string_lossy());
+            println!("  - {}", escape(dup));
         }
         println!();
     }
@@ -501,13 +1064,37 @@ fn print_duplicates(dups: Vec<(Vec<PathBuf>, u64)>) {
 
 
 
-#[allow(unknown_lints, cyclomatic_complexity)]
 pub fn run() -> Result<(), ErrorCode> {
-    let (log_level, args) = try!(args::parse());
-    if let Err(err) = logger::init(log_level) {
+    let (global, args) = try!(args::parse());
+    if let Err(err) = logger::init(global.log_level) {
         tr_println!("Failed to initialize the logger: {}", err);
         return Err(ErrorCode::InitializeLogger);
     }
+    set_escape_mode(global.escape_mode);
+    *THREADS.write().unwrap() = global.threads;
+    *CACHE_SIZE.write().unwrap() = global.cache_size;
+    *RATE_LIMIT.write().unwrap() = global.rate_limit;
+    *REMOTE_RETRY.write().unwrap() = global.remote_retry;
+    *PROGRESS.write().unwrap() = global.progress;
+    *PASSWORD_COMMAND.write().unwrap() = global.password_command;
+    let audit = match global.audit_log {
+        Some(path) => audit::describe(&args).map(|(operation, summary)| (path, operation, summary)),
+        None => None
+    };
+    let result = run_command(args);
+    if let Some((path, operation, summary)) = audit {
+        if let Err(err) = audit::write_record(&path, operation, summary, &result) {
+            tr_error!("Failed to write the audit log\n\tcaused by: {}", err);
+            if result.is_ok() {
+                return Err(ErrorCode::WriteAuditLog);
+            }
+        }
+    }
+    result
+}
+
+#[allow(unknown_lints, cyclomatic_complexity)]
+fn run_command(args: Arguments) -> Result<(), ErrorCode> {
     match args {
         Arguments::Init {
             repo_path,
@@ -515,34 +1102,68 @@ pub fn run() -> Result<(), ErrorCode> {
             chunker,
             compression,
             encryption,
+            cipher,
+            password,
             hash,
-            remote_path
+            remote_path,
+            append_only,
+            redundancy,
+            bundle_shard
         } => {
             if !Path::new(&remote_path).is_absolute() {
                 tr_error!("The remote path of a repository must be absolute.");
                 return Err(ErrorCode::InvalidArgs);
             }
-            let mut repo = checked!(
-                Repository::create(
-                    repo_path,
-                    &Config {
-                        bundle_size,
-                        chunker,
-                        compression,
-                        encryption: None,
-                        hash
-                    },
-                    remote_path
-                ),
-                "create repository",
-                ErrorCode::CreateRepository
+            if encryption && !cipher.is_available() {
+                tr_error!("The cipher {} is not supported on this machine", cipher.to_string());
+                return Err(ErrorCode::InvalidArgs);
+            }
+            // The password is confirmed first so that a mistyped password does not leave an
+            // unencrypted repository behind
+            let mut new_password = if encryption && password {
+                Some(try!(read_new_password()))
+            } else {
+                None
+            };
+            let repo = Repository::create(
+                repo_path,
+                &Config {
+                    bundle_size,
+                    chunker,
+                    compression,
+                    encryption: None,
+                    hash,
+                    append_only,
+                    redundancy,
+                    bundle_shard,
+                    key_derivation: None
+                },
+                remote_path
             );
-            if encryption {
+            if repo.is_err() {
+                if let Some(ref mut password) = new_password {
+                    wipe_password(password);
+                }
+            }
+            let mut repo = checked!(repo, "create repository", ErrorCode::CreateRepository);
+            if let Some(mut password) = new_password {
+                let public = repo.set_password_encryption(&password);
+                wipe_password(&mut password);
+                let public = checked!(public, "derive key", ErrorCode::AddKey);
+                repo.set_encryption_method(cipher);
+                checked!(repo.save_config(), "save config", ErrorCode::SaveConfig);
+                tr_info!("Derived the key pair with public key {} from the password", to_hex(&public[..]));
+                tr_warn!(
+                    "The password can not be recovered, data can not be restored without it"
+                );
+                println!();
+            } else if encryption {
                 let (public, secret) = Crypto::gen_keypair();
                 tr_info!("Created the following key pair");
                 tr_println!("public: {}", to_hex(&public[..]));
                 tr_println!("secret: {}", to_hex(&secret[..]));
                 repo.set_encryption(Some(&public));
+                repo.set_encryption_method(cipher);
                 checked!(
                     repo.register_key(public, secret),
                     "add key",
@@ -566,28 +1187,58 @@ pub fn run() -> Result<(), ErrorCode> {
             mut excludes,
             excludes_from,
             no_default_excludes,
-            tar
+            tar,
+            inline_size,
+            metadata,
+            exclude_older_than,
+            only_older_than,
+            exclude_larger_than,
+            exclude_caches,
+            atime,
+            checkpoint_interval,
+            checkpoint_bundles,
+            resume,
+            files_from,
+            dry_run,
+            stdin_name,
+            chunker,
+            compression,
+            auto_compression,
+            verify,
+            fail_on_error,
+            hardlinks_threshold
         } => {
+            if !tar && !dry_run && src_path != "-" {
+                // This has to happen before the repository starts any threads
+                checked!(catch_interrupts(), "set up signal handling", ErrorCode::BackupRun);
+            }
             let mut repo = try!(open_repository(&repo_path, true));
             if repo.has_backup(&backup_name) {
                 tr_error!("A backup with that name already exists");
                 return Err(ErrorCode::BackupAlreadyExists);
             }
-            if src_path == "-" && !tar {
-                tr_error!("Reading from stdin requires --tar");
+            // Without --tar, the data from stdin is stored as a single file
+            let stream = src_path == "-" && !tar;
+            if stream && (dry_run || resume || files_from.is_some()) {
+                tr_error!("Reading a single file from stdin does not support --dry-run, --resume or --files-from");
+                return Err(ErrorCode::InvalidArgs);
+            }
+            if stdin_name.is_some() && !stream {
+                tr_error!("A file name for stdin can only be given when reading from stdin");
                 return Err(ErrorCode::InvalidArgs);
             }
             let mut reference_backup = None;
-            if !full && !tar {
+            if !full && !tar && !stream {
                 reference_backup = match reference {
-                    Some(r) => {
-                        let b = try!(get_backup(&repo, &r));
-                        Some((r, b))
+                    Some(ref r) if r != "auto" => {
+                        let b = try!(get_backup(&repo, r));
+                        Some((r.clone(), b))
                     }
-                    None => None,
+                    _ => None,
                 };
                 if reference_backup.is_none() {
-                    reference_backup = try!(find_reference_backup(&repo, &src_path));
+                    reference_backup =
+                        try!(find_reference_backup(&repo, &src_path, &backup_name));
                 }
                 if let Some(&(ref name, _)) = reference_backup.as_ref() {
                     tr_info!("Using backup {} as reference", name);
@@ -596,7 +1247,7 @@ pub fn run() -> Result<(), ErrorCode> {
                 }
             }
             let reference_backup = reference_backup.map(|(_, backup)| backup);
-            if !no_default_excludes && !tar {
+            if !no_default_excludes && !tar && !stream {
                 for line in BufReader::new(checked!(
                     File::open(&repo.layout.excludes_path()),
                     "open default excludes file",
@@ -624,69 +1275,148 @@ pub fn run() -> Result<(), ErrorCode> {
                     ));
                 }
             }
-            let mut excludes_parsed = Vec::with_capacity(excludes.len());
-            for mut exclude in excludes {
-                if exclude.starts_with('#') || exclude.is_empty() {
-                    continue;
-                }
-                exclude = regex::escape(&exclude)
-                    .replace('?', ".")
-                    .replace(r"\*\*", ".*")
-                    .replace(r"\*", "[^/]*");
-                excludes_parsed.push(if exclude.starts_with('/') {
-                    format!(r"^{}($|/)", exclude)
-                } else {
-                    format!(r"/{}($|/)", exclude)
-                });
-            }
-            let excludes = if excludes_parsed.is_empty() {
+            let excludes = checked!(
+                ExcludeMatcher::new(excludes),
+                "parse exclude patterns",
+                ErrorCode::InvalidExcludes
+            );
+            let excludes = if excludes.is_empty() {
                 None
             } else {
-                Some(checked!(
-                    RegexSet::new(excludes_parsed),
-                    "parse exclude patterns",
-                    ErrorCode::InvalidExcludes
-                ))
+                Some(excludes)
             };
+            let selection = match files_from {
+                Some((file, separator)) => {
+                    let paths = checked!(
+                        read_path_list(&file, separator),
+                        "read file list",
+                        ErrorCode::LoadFileList
+                    );
+                    let (selection, outside) = PathSelection::new(&src_path, paths);
+                    if !outside.is_empty() {
+                        for path in outside {
+                            tr_error!("Path is outside of the source folder: {}", escape(&path));
+                        }
+                        return Err(ErrorCode::LoadFileList);
+                    }
+                    Some(selection)
+                }
+                None => None
+            };
+            let now = Local::now().timestamp();
             let options = BackupOptions {
                 same_device,
-                excludes
+                excludes,
+                inline_size,
+                min_time: exclude_older_than.map(|age| now - age as i64),
+                max_time: only_older_than.map(|age| now - age as i64),
+                use_atime: atime,
+                max_file_size: exclude_larger_than,
+                exclude_caches,
+                checkpoint_interval,
+                checkpoint_bundles,
+                selection,
+                journal: if tar || stream { None } else { Some(backup_name.clone()) },
+                resume,
+                chunker,
+                compression,
+                auto_compression,
+                verify,
+                fail_on_error,
+                hard_link_threshold: hardlinks_threshold
             };
-            let result = if tar {
-                repo.import_tarfile(&src_path)
+            if dry_run {
+                let (entries, failed_paths) = checked!(
+                    repo.plan_backup(&src_path, reference_backup.as_ref(), &options),
+                    "plan backup",
+                    ErrorCode::BackupRun
+                );
+                print_backup_plan(&entries);
+                print_cache_dirs(repo.cache_dirs());
+                if !failed_paths.is_empty() {
+                    tr_warn!("Some files could not be read and would be missing from the backup:");
+                    for path in &failed_paths {
+                        tr_warn!("  - {}", escape(path));
+                    }
+                }
+                tr_info!("Dry run, no data has been stored");
+                return Ok(());
+            }
+            let result = if stream {
+                let name = stdin_name.unwrap_or_else(|| {
+                    Path::new(&backup_name).file_name()
+                        .map_or_else(|| backup_name.clone(), |n| n.to_string_lossy().to_string())
+                });
+                repo.backup_stream(io::stdin(), &name, &options)
+            } else if tar {
+                repo.import_tarfile(&src_path, &options)
             } else {
                 repo.create_backup_recursively(&src_path, reference_backup.as_ref(), &options)
             };
-            let backup = match result {
+            let (mut backup, failed_paths) = match result {
                 Ok(backup) => {
                     tr_info!("Backup finished");
-                    backup
+                    (backup, vec![])
                 }
-                Err(RepositoryError::Backup(BackupError::FailedPaths(backup, _failed_paths))) => {
+                Err(RepositoryError::Backup(BackupError::FailedPaths(backup, failed_paths))) => {
                     tr_warn!("Some files are missing from the backup");
-                    backup
+                    (backup, failed_paths)
+                }
+                Err(RepositoryError::Backup(BackupError::Interrupted)) => {
+                    tr_warn!("The backup has been interrupted, the data stored so far has been kept");
+                    tr_info!("Run the backup again with --resume to continue it");
+                    return Err(ErrorCode::BackupInterrupted);
                 }
                 Err(err) => {
                     tr_error!("Backup failed: {}", err);
                     return Err(ErrorCode::BackupRun);
                 }
             };
+            backup.metadata.extend(metadata);
             checked!(
                 repo.save_backup(&backup, &backup_name),
                 "save backup file",
                 ErrorCode::SaveBackup
             );
             print_backup(&backup);
+            if log_enabled!(::log::Level::Debug) {
+                print_pipeline_times(&repo.pipeline_times());
+            }
+            print_cache_dirs(repo.cache_dirs());
+            if !failed_paths.is_empty() {
+                tr_warn!("{} paths could not be read and are missing from the backup:", failed_paths.len());
+                for path in &failed_paths {
+                    tr_warn!("  - {}", escape(path));
+                }
+                return Err(ErrorCode::BackupIncomplete);
+            }
         }
         Arguments::Restore {
             repo_path,
             backup_name,
             inode,
             dst_path,
-            tar
+            tar,
+            numeric_owner,
+            chown,
+            decrypt,
+            verify_chunks,
+            skip_corrupt,
+            atomic,
+            force
         } => {
+            if atomic && !force && fs::symlink_metadata(&dst_path).is_ok() {
+                tr_error!("The destination {} already exists, use --force to replace it", dst_path);
+                return Err(ErrorCode::InvalidArgs);
+            }
             let mut repo = try!(open_repository(&repo_path, true));
+            repo.set_verify_chunks(verify_chunks);
             let backup = try!(get_backup(&repo, &backup_name));
+            if tar && backup.config.encryption.is_some() && !decrypt {
+                tr_error!("The backup is encrypted but the tar file would contain the plain data");
+                tr_info!("Use --decrypt to export it anyway");
+                return Err(ErrorCode::UnsafeArgs);
+            }
             let inode = try!(get_inode(&mut repo, &backup, inode.as_ref()));
             if tar {
                 checked!(
@@ -694,57 +1424,263 @@ pub fn run() -> Result<(), ErrorCode> {
                     "restore backup",
                     ErrorCode::RestoreRun
                 );
+            } else if dst_path == "-" {
+                // The contents are written chunk by chunk, the file is never held in memory
+                if inode.file_type != FileType::File && inode.file_type != FileType::HardLink {
+                    tr_error!(
+                        "Only regular files can be restored to stdout, use --tar for a {}",
+                        inode.file_type
+                    );
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                checked!(
+                    repo.get_inode_contents(&inode, &mut stdout),
+                    "restore file",
+                    ErrorCode::RestoreRun
+                );
+                checked!(stdout.flush(), "restore file", ErrorCode::RestoreRun);
+            } else if atomic {
+                checked!(
+                    repo.restore_inode_tree_atomic(
+                        &backup,
+                        inode,
+                        &dst_path,
+                        restore_owner(numeric_owner, chown),
+                        skip_corrupt
+                    ),
+                    "restore backup",
+                    ErrorCode::RestoreRun
+                );
             } else {
                 checked!(
-                    repo.restore_inode_tree(&backup, inode, &dst_path),
+                    repo.restore_inode_tree(
+                        &backup,
+                        inode,
+                        &dst_path,
+                        restore_owner(numeric_owner, chown),
+                        skip_corrupt
+                    ),
                     "restore backup",
                     ErrorCode::RestoreRun
                 );
             }
             tr_info!("Restore finished");
         }
+        Arguments::TestRestore {
+            repo_path,
+            backup_name,
+            inode,
+            sample
+        } => {
+            let mut repo = try!(open_repository(&repo_path, true));
+            let backup = try!(get_backup(&repo, &backup_name));
+            let inode = try!(get_inode(&mut repo, &backup, inode.as_ref()));
+            let tmp_path = env::temp_dir().join(format!("zvault-test-restore-{}", process::id()));
+            let (tested, failed) = checked!(
+                repo.test_restore(&backup, inode, sample, &tmp_path),
+                "test restore",
+                ErrorCode::TestRestoreRun
+            );
+            if failed.is_empty() {
+                tr_info!("Successfully restored {} files", tested);
+            } else {
+                tr_error!("Failed to restore {} of {} files", failed.len(), tested);
+                return Err(ErrorCode::TestRestoreRun);
+            }
+        }
         Arguments::Copy {
             repo_path_src,
             backup_name_src,
             repo_path_dst,
             backup_name_dst
         } => {
-            if repo_path_src != repo_path_dst {
-                tr_error!("Can only run copy on same repository");
-                return Err(ErrorCode::InvalidArgs);
+            if repo_path_src == repo_path_dst {
+                let mut repo = try!(open_repository(&repo_path_src, false));
+                if repo.has_backup(&backup_name_dst) {
+                    tr_error!("A backup with that name already exists");
+                    return Err(ErrorCode::BackupAlreadyExists);
+                }
+                let backup = try!(get_backup(&repo, &backup_name_src));
+                checked!(
+                    repo.save_backup(&backup, &backup_name_dst),
+                    "save backup file",
+                    ErrorCode::SaveBackup
+                );
+                return Ok(());
             }
-            let mut repo = try!(open_repository(&repo_path_src, false));
-            if repo.has_backup(&backup_name_dst) {
+            let mut src_repo = try!(open_repository(&repo_path_src, true));
+            let mut dst_repo = try!(open_repository(&repo_path_dst, true));
+            if dst_repo.has_backup(&backup_name_dst) {
                 tr_error!("A backup with that name already exists");
                 return Err(ErrorCode::BackupAlreadyExists);
             }
-            let backup = try!(get_backup(&repo, &backup_name_src));
+            let backup = try!(get_backup(&src_repo, &backup_name_src));
+            if src_repo.config.chunker != dst_repo.config.chunker {
+                tr_warn!(
+                    "The repositories use different chunkers, the copied data will not deduplicate well with new backups"
+                );
+            }
+            let stats = checked!(
+                src_repo.copy_backup_chunks(&backup, &mut dst_repo),
+                "copy backup data",
+                ErrorCode::CopyRun
+            );
+            tr_info!(
+                "Copied {} chunks as-is in {} bundles, transcoded {} chunks, {} chunks were already present",
+                stats.passed_chunks,
+                stats.passed_bundles,
+                stats.transcoded_chunks,
+                stats.existing_chunks
+            );
             checked!(
-                repo.save_backup(&backup, &backup_name_dst),
+                dst_repo.save_backup(&backup, &backup_name_dst),
                 "save backup file",
                 ErrorCode::SaveBackup
             );
         }
+        Arguments::Clone {
+            repo_path,
+            remote_path,
+            dst_repo_path
+        } => {
+            let repo = try!(open_repository(&repo_path, true));
+            let same_remote = match (repo.layout.remote_path().canonicalize(), Path::new(&remote_path).canonicalize()) {
+                (Ok(src), Ok(dst)) => src == dst,
+                _ => false
+            };
+            if same_remote {
+                tr_error!("The new repository must use a different remote storage");
+                return Err(ErrorCode::InvalidArgs);
+            }
+            let stats = checked!(
+                repo.clone_to(&dst_repo_path, &remote_path),
+                "clone repository",
+                ErrorCode::CloneRun
+            );
+            tr_info!(
+                "Copied {} files with {}, {} files were already present",
+                stats.copied_files,
+                to_file_size(stats.copied_size),
+                stats.existing_files
+            );
+            drop(repo);
+            // Opening the new repository adds all bundles to its index
+            try!(open_repository(&dst_repo_path, true));
+            tr_info!("Clone finished");
+        }
+        Arguments::Rename {
+            repo_path,
+            old_name,
+            new_name,
+            admin
+        } => {
+            let mut repo = try!(open_repository(&repo_path, false));
+            repo.set_admin(admin);
+            if !repo.has_backup(&old_name) {
+                tr_error!("A backup with that name does not exist");
+                return Err(ErrorCode::NoSuchBackup);
+            }
+            if repo.has_backup(&new_name) {
+                tr_error!("A backup with that name already exists");
+                return Err(ErrorCode::BackupAlreadyExists);
+            }
+            checked!(
+                repo.rename_backup(&old_name, &new_name),
+                "rename backup",
+                ErrorCode::RenameRun
+            );
+            tr_info!("The backup {} has been renamed to {}", old_name, new_name);
+        }
         Arguments::Remove {
             repo_path,
             backup_name,
             inode,
-            force
+            force,
+            yes,
+            dry_run,
+            remove_empty,
+            admin
         } => {
             let mut repo = try!(open_repository(&repo_path, true));
-            if let Some(inode) = inode {
+            repo.set_admin(admin);
+            if is_glob(&backup_name) {
+                if inode.is_some() {
+                    tr_error!("Subpaths can not be removed from multiple backups");
+                    return Err(ErrorCode::InvalidArgs);
+                }
+                let pattern = checked!(
+                    regex::Regex::new(&format!("^{}$", glob_to_regex(&backup_name))),
+                    "parse backup pattern",
+                    ErrorCode::InvalidArgs
+                );
+                let backups = match repo.get_all_backups() {
+                    Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backups, _failed))) => {
+                        tr_warn!("Some backups could not be read, ignoring them");
+                        Ok(backups)
+                    }
+                    result => result
+                };
+                let backups = checked!(backups, "retrieve backups", ErrorCode::RemoveRun);
+                let mut names: Vec<_> = backups.keys().filter(|name| pattern.is_match(name)).collect();
+                names.sort();
+                if names.is_empty() {
+                    tr_info!("No backups match the pattern");
+                    return Ok(());
+                }
+                tr_println!("The following backups match the pattern:");
+                for name in &names {
+                    println!("  - {}", escape(name));
+                }
+                if dry_run {
+                    tr_info!("Dry run, no backups have been removed");
+                    return Ok(());
+                }
+                if !yes && !confirm(&tr_format!("Remove these {} backups?", names.len())) {
+                    tr_info!("No backups have been removed");
+                    return Ok(());
+                }
+                for name in names {
+                    checked!(
+                        repo.delete_backup(name),
+                        "delete backup",
+                        ErrorCode::RemoveRun
+                    );
+                }
+                tr_info!("The backups have been deleted, run vacuum to reclaim space");
+            } else if dry_run {
+                match inode {
+                    Some(inode) => tr_println!("Would remove {} from backup {}", inode, backup_name),
+                    None => tr_println!("Would remove backup {}", backup_name),
+                }
+            } else if let Some(inode) = inode {
                 let mut backup = try!(get_backup(&repo, &backup_name));
                 checked!(
                     repo.remove_backup_path(&mut backup, inode),
                     "remove backup subpath",
                     ErrorCode::RemoveRun
                 );
-                checked!(
-                    repo.save_backup(&backup, &backup_name),
-                    "save backup file",
-                    ErrorCode::SaveBackup
+                let root = checked!(
+                    repo.get_inode(&backup.root),
+                    "load root inode",
+                    ErrorCode::RemoveRun
                 );
-                tr_info!("The backup subpath has been deleted, run vacuum to reclaim space");
+                if remove_empty && root.children.as_ref().map_or(false, |c| c.is_empty()) {
+                    checked!(
+                        repo.delete_backup(&backup_name),
+                        "delete backup",
+                        ErrorCode::RemoveRun
+                    );
+                    tr_info!("The backup is empty and has been deleted, run vacuum to reclaim space");
+                } else {
+                    checked!(
+                        repo.save_backup(&backup, &backup_name),
+                        "save backup file",
+                        ErrorCode::SaveBackup
+                    );
+                    tr_info!("The backup subpath has been deleted, run vacuum to reclaim space");
+                }
             } else if repo.layout.backups_path().join(&backup_name).is_dir() {
                 let backups = checked!(
                     repo.get_backups(&backup_name),
@@ -762,7 +1698,7 @@ pub fn run() -> Result<(), ErrorCode> {
                 } else {
                     tr_error!("Denying to remove multiple backups (use --force):");
                     for name in backups.keys() {
-                        println!("  - {}/{}", backup_name, name);
+                        println!("  - {}", escape(format!("{}/{}", backup_name, name)));
                     }
                 }
             } else {
@@ -781,15 +1717,28 @@ pub fn run() -> Result<(), ErrorCode> {
             weekly,
             monthly,
             yearly,
-            force
+            keep_last,
+            keep_within,
+            max_size,
+            force,
+            admin
         } => {
             let mut repo = try!(open_repository(&repo_path, true));
-            if daily + weekly + monthly + yearly == 0 {
+            repo.set_admin(admin);
+            let policy = RetentionPolicy {
+                daily,
+                weekly,
+                monthly,
+                yearly,
+                keep_last,
+                keep_within
+            };
+            if policy.is_empty() && max_size.is_none() {
                 tr_error!("This would remove all those backups");
                 return Err(ErrorCode::UnsafeArgs);
             }
             checked!(
-                repo.prune_backups(&prefix, daily, weekly, monthly, yearly, force),
+                repo.prune_backups(&prefix, &policy, max_size, force),
                 "prune backups",
                 ErrorCode::PruneRun
             );
@@ -801,9 +1750,11 @@ pub fn run() -> Result<(), ErrorCode> {
             repo_path,
             ratio,
             force,
-            combine
+            combine,
+            admin
         } => {
             let mut repo = try!(open_repository(&repo_path, true));
+            repo.set_admin(admin);
             let info_before = repo.info();
             checked!(
                 repo.vacuum(ratio, combine, force),
@@ -820,16 +1771,79 @@ pub fn run() -> Result<(), ErrorCode> {
                 );
             }
         }
+        Arguments::Repack {
+            repo_path,
+            bundle_size,
+            force,
+            admin
+        } => {
+            let mut repo = try!(open_repository(&repo_path, true));
+            repo.set_admin(admin);
+            let bundle_size = bundle_size.unwrap_or(repo.config.bundle_size);
+            let info_before = repo.info();
+            checked!(
+                repo.repack(bundle_size, force),
+                "repack",
+                ErrorCode::RepackRun
+            );
+            if !force {
+                tr_info!("Run with --force to actually execute this command");
+            } else {
+                let info_after = repo.info();
+                tr_info!(
+                    "Bundle count changed from {} to {}",
+                    info_before.bundle_count,
+                    info_after.bundle_count
+                );
+            }
+        }
         Arguments::Check {
             repo_path,
             backup_name,
             inode,
             bundles,
             index,
+            index_only,
+            bundles_only,
             bundle_data,
-            repair
+            full,
+            repair,
+            repair_index,
+            admin
         } => {
+            if full && backup_name.is_none() {
+                tr_error!("A backup must be given to verify its data");
+                return Err(ErrorCode::InvalidArgs);
+            }
             let mut repo = try!(open_repository(&repo_path, true));
+            repo.set_admin(admin);
+            if repair_index {
+                let start = Instant::now();
+                let count = checked!(
+                    repo.rebuild_index_from_bundles(),
+                    "rebuild index",
+                    ErrorCode::CheckRun
+                );
+                let elapsed = start.elapsed();
+                let secs = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1e9;
+                tr_info!("Indexed {} chunks in {}", count, to_duration(secs));
+                repo.set_clean();
+                return Ok(());
+            }
+            if index_only || bundles_only {
+                if index_only {
+                    checked!(repo.check_index(repair), "check index", ErrorCode::CheckRun);
+                } else {
+                    checked!(
+                        repo.check_bundles(bundle_data, repair),
+                        "check bundles",
+                        ErrorCode::CheckRun
+                    );
+                }
+                repo.set_clean();
+                tr_info!("Integrity verified");
+                return Ok(());
+            }
             checked!(
                 repo.check_repository(repair),
                 "check repository",
@@ -847,9 +1861,9 @@ pub fn run() -> Result<(), ErrorCode> {
             }
             if let Some(backup_name) = backup_name {
                 let mut backup = try!(get_backup(&repo, &backup_name));
-                if let Some(path) = inode {
+                if let Some(ref path) = inode {
                     checked!(
-                        repo.check_backup_inode(&backup_name, &mut backup, Path::new(&path), repair),
+                        repo.check_backup_inode(&backup_name, &mut backup, Path::new(path), repair),
                         "check inode",
                         ErrorCode::CheckRun
                     )
@@ -860,6 +1874,13 @@ pub fn run() -> Result<(), ErrorCode> {
                         ErrorCode::CheckRun
                     )
                 }
+                if full {
+                    checked!(
+                        repo.verify_backup(&backup, inode.as_ref().map(Path::new)),
+                        "verify backup data",
+                        ErrorCode::CheckRun
+                    )
+                }
             } else {
                 checked!(
                     repo.check_backups(repair),
@@ -873,31 +1894,77 @@ pub fn run() -> Result<(), ErrorCode> {
         Arguments::List {
             repo_path,
             backup_name,
-            inode
+            inode,
+            meta_filter,
+            recursive,
+            filters,
+            json
         } => {
+            // The paths that the patterns would exclude from a backup are listed
+            let filter = checked!(
+                ExcludeMatcher::new(&filters),
+                "parse filter patterns",
+                ErrorCode::InvalidArgs
+            );
             let mut repo = try!(open_repository(&repo_path, false));
             let backup_map = if let Some(backup_name) = backup_name {
                 if repo.layout.backups_path().join(&backup_name).is_dir() {
+                    if recursive {
+                        tr_error!("{} is a folder of backups, listing recursively requires a backup", backup_name);
+                        return Err(ErrorCode::InvalidArgs);
+                    }
                     repo.get_backups(&backup_name)
                 } else {
                     let backup = try!(get_backup(&repo, &backup_name));
+                    let path = inode.as_ref().map(|v| v as &str).unwrap_or("/");
                     let inode = checked!(
-                        repo.get_backup_inode(
-                            &backup,
-                            inode.as_ref().map(|v| v as &str).unwrap_or("/")
-                        ),
+                        repo.get_backup_inode(&backup, path),
                         "load subpath inode",
                         ErrorCode::LoadInode
                     );
-                    println!("{}", format_inode_one_line(&inode));
-                    if let Some(children) = inode.children {
-                        for chunks in children.values() {
-                            let inode = checked!(
+                    if recursive {
+                        let stdout = io::stdout();
+                        let mut out = stdout.lock();
+                        checked!(
+                            repo.walk_inodes(&inode, &Path::new("/").join(path), |path, inode| {
+                                let is_dir = inode.file_type == FileType::Directory;
+                                if !filter.is_empty() && !filter.is_excluded(path, is_dir) {
+                                    return Ok(());
+                                }
+                                if json {
+                                    let mut value = inode_to_json(inode);
+                                    value["path"] = json!(path.to_string_lossy());
+                                    try!(writeln!(out, "{}", value));
+                                } else {
+                                    try!(writeln!(out, "{}", format_inode_path_line(path, inode)));
+                                }
+                                Ok(())
+                            }),
+                            "list backup contents",
+                            ErrorCode::LoadInode
+                        );
+                        return Ok(());
+                    }
+                    let mut children = vec![];
+                    if let Some(ref chunks) = inode.children {
+                        for chunks in chunks.values() {
+                            children.push(checked!(
                                 repo.get_inode(chunks),
                                 "load child inode",
                                 ErrorCode::LoadInode
-                            );
-                            println!("- {}", format_inode_one_line(&inode));
+                            ));
+                        }
+                    }
+                    if json {
+                        let mut value = inode_to_json(&inode);
+                        value["children"] = serde_json::Value::Array(
+                            children.iter().map(inode_to_json).collect()
+                        );
+                        println!("{}", value);
+                    } else {
+                        println!("{}", format_inode_one_line(&inode));
+                        for child in &children {
+                            println!("- {}", format_inode_one_line(child));
                         }
                     }
                     return Ok(());
@@ -905,7 +1972,7 @@ pub fn run() -> Result<(), ErrorCode> {
             } else {
                 repo.get_all_backups()
             };
-            let backup_map = match backup_map {
+            let mut backup_map = match backup_map {
                 Ok(backup_map) => backup_map,
                 Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map, _failed))) => {
                     tr_warn!("Some backups could not be read, ignoring them");
@@ -916,26 +1983,70 @@ pub fn run() -> Result<(), ErrorCode> {
                     return Err(ErrorCode::LoadBackup);
                 }
             };
-            print_backups(&backup_map);
+            backup_map.retain(|_, backup| {
+                meta_filter.iter().all(|&(ref key, ref value)| {
+                    backup.metadata.get(key) == Some(value)
+                })
+            });
+            if json {
+                let mut backups: Vec<_> = backup_map.iter().collect();
+                backups.sort_by_key(|b| b.0);
+                let backups: Vec<_> = backups
+                    .into_iter()
+                    .map(|(name, backup)| backup_to_json(name, backup))
+                    .collect();
+                println!("{}", serde_json::Value::Array(backups));
+            } else {
+                print_backups(&backup_map);
+            }
         }
         Arguments::Info {
             repo_path,
             backup_name,
-            inode
+            inode,
+            tree,
+            json
         } => {
             let mut repo = try!(open_repository(&repo_path, false));
             if let Some(backup_name) = backup_name {
                 let backup = try!(get_backup(&repo, &backup_name));
-                if let Some(inode) = inode {
+                if tree {
+                    let path = Path::new("/").join(inode.as_ref().map_or("", |v| v.as_str()));
+                    let inode = try!(get_inode(&mut repo, &backup, inode.as_ref()));
+                    let sizes = checked!(
+                        repo.directory_sizes(&inode, &path),
+                        "calculate directory sizes",
+                        ErrorCode::LoadInode
+                    );
+                    if json {
+                        println!("{}", serde_json::Value::Array(
+                            sizes.iter().map(|dir| json!({
+                                "path": dir.path.to_string_lossy(),
+                                "size": dir.size,
+                                "dedup_size": dir.dedup_size
+                            })).collect()
+                        ));
+                    } else {
+                        print_directory_sizes(&sizes);
+                    }
+                } else if let Some(inode) = inode {
                     let inode = checked!(
                         repo.get_backup_inode(&backup, inode),
                         "load subpath inode",
                         ErrorCode::LoadInode
                     );
-                    print_inode(&inode);
+                    if json {
+                        println!("{}", inode_to_json(&inode));
+                    } else {
+                        print_inode(&inode);
+                    }
+                } else if json {
+                    println!("{}", backup_to_json(&backup_name, &backup));
                 } else {
                     print_backup(&backup);
                 }
+            } else if json {
+                println!("{}", repoinfo_to_json(&repo.info()));
             } else {
                 print_repoinfo(&repo.info());
             }
@@ -966,10 +2077,24 @@ pub fn run() -> Result<(), ErrorCode> {
             repo_path,
             backup_name,
             inode,
-            mount_point
+            mount_point,
+            threads
         } => {
+            if !Path::new("/dev/fuse").exists() {
+                tr_error!("FUSE is not available, please install it and load the fuse kernel module");
+                return Err(ErrorCode::FuseMount);
+            }
+            let not_empty = checked!(
+                fs::read_dir(&mount_point).map(|mut entries| entries.next().is_some()),
+                "read mount point",
+                ErrorCode::FuseMount
+            );
+            if not_empty {
+                tr_error!("The mount point must be an empty directory");
+                return Err(ErrorCode::InvalidArgs);
+            }
             let mut repo = try!(open_repository(&repo_path, true));
-            let fs = if let Some(backup_name) = backup_name {
+            let mut fs = if let Some(backup_name) = backup_name {
                 if repo.layout.backups_path().join(&backup_name).is_dir() {
                     checked!(
                         FuseFilesystem::from_repository(&mut repo, Some(&backup_name)),
@@ -1004,9 +2129,17 @@ pub fn run() -> Result<(), ErrorCode> {
                     ErrorCode::FuseMount
                 )
             };
+            // Installed only now so that Ctrl-C still aborts a password prompt or a slow open,
+            // but before the read threads start so that they inherit the signal mask
+            checked!(
+                unmount_on_signal(&mount_point),
+                "set up signal handling",
+                ErrorCode::FuseMount
+            );
+            fs.set_read_threads(threads);
             tr_info!("Mounting the filesystem...");
             tr_info!(
-                "Please unmount the filesystem via 'fusermount -u {}' when done.",
+                "Press Ctrl-C or run 'fusermount -u {}' to unmount the filesystem when done.",
                 mount_point
             );
             checked!(
@@ -1015,44 +2148,188 @@ pub fn run() -> Result<(), ErrorCode> {
                 ErrorCode::FuseMount
             );
         }
-        Arguments::Analyze { repo_path } => {
+        Arguments::Analyze {
+            repo_path,
+            dedup,
+            top
+        } => {
             let mut repo = try!(open_repository(&repo_path, true));
-            print_analysis(&checked!(
-                repo.analyze_usage(),
-                "analyze repository",
-                ErrorCode::AnalyzeRun
-            ));
+            if dedup {
+                print_dedup_analysis(&checked!(
+                    repo.find_duplicate_chunks(top),
+                    "analyze repository",
+                    ErrorCode::AnalyzeRun
+                ));
+            } else {
+                print_analysis(&checked!(
+                    repo.analyze_usage(),
+                    "analyze repository",
+                    ErrorCode::AnalyzeRun
+                ));
+            }
         }
-        Arguments::BundleList { repo_path } => {
+        Arguments::BundleList { repo_path, json } => {
             let repo = try!(open_repository(&repo_path, true));
-            for bundle in repo.list_bundles() {
-                print_bundle_one_line(bundle);
+            if json {
+                let bundles: Vec<_> = repo.list_bundles().into_iter().map(bundle_to_json).collect();
+                println!("{}", serde_json::Value::Array(bundles));
+            } else {
+                for bundle in repo.list_bundles() {
+                    print_bundle_one_line(bundle);
+                }
             }
         }
         Arguments::BundleInfo {
             repo_path,
-            bundle_id
+            bundle_id,
+            chunks,
+            json
         } => {
             let repo = try!(open_repository(&repo_path, true));
             if let Some(bundle) = repo.get_bundle(&bundle_id) {
-                print_bundle(bundle);
+                let chunks = if chunks {
+                    Some(checked!(
+                        repo.get_bundle_chunks(&bundle_id),
+                        "load chunk list",
+                        ErrorCode::LoadBundle
+                    ))
+                } else {
+                    None
+                };
+                if json {
+                    let mut value = bundle_to_json(&bundle.info);
+                    value["path"] = json!(bundle.path.to_string_lossy());
+                    if let Some(chunks) = chunks {
+                        value["chunks"] = serde_json::Value::Array(
+                            chunks.iter().map(|chunk| json!({
+                                "hash": chunk.hash.to_string(),
+                                "offset": chunk.offset,
+                                "size": chunk.size
+                            })).collect()
+                        );
+                    }
+                    println!("{}", value);
+                } else {
+                    print_bundle(bundle);
+                    if let Some(chunks) = chunks {
+                        print_bundle_chunks(&chunks);
+                    }
+                }
             } else {
                 tr_error!("No such bundle");
                 return Err(ErrorCode::LoadBundle);
             }
         }
+        Arguments::DumpIndex { repo_path, json } => {
+            let repo = try!(open_repository(&repo_path, true));
+            let entries = repo.info().index_entries;
+            if entries > LARGE_INDEX_ENTRIES {
+                tr_warn!("The index contains {} entries, the dump will be large", entries);
+            }
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            if !json {
+                checked!(
+                    writeln!(out, "hash,bundle,chunk,offset,size"),
+                    "write index dump",
+                    ErrorCode::DumpIndexRun
+                );
+            }
+            let count = checked!(
+                repo.dump_index(|entry| {
+                    if json {
+                        try!(writeln!(out, "{}", json!({
+                            "hash": entry.hash.to_string(),
+                            "bundle": entry.bundle.to_string(),
+                            "chunk": entry.chunk,
+                            "offset": entry.offset,
+                            "size": entry.size
+                        })));
+                    } else {
+                        try!(writeln!(
+                            out,
+                            "{},{},{},{},{}",
+                            entry.hash,
+                            entry.bundle,
+                            entry.chunk,
+                            entry.offset,
+                            entry.size
+                        ));
+                    }
+                    Ok(())
+                }),
+                "dump index",
+                ErrorCode::DumpIndexRun
+            );
+            tr_info!("Dumped {} index entries", count);
+        }
         Arguments::Import {
             repo_path,
             remote_path,
             key_files
         } => {
-            checked!(
-                Repository::import(repo_path, remote_path, key_files),
-                "import repository",
-                ErrorCode::ImportRun
+            let mut password = try!(get_remote_password(Path::new(&remote_path)));
+            let result = Repository::import(
+                repo_path,
+                remote_path,
+                key_files,
+                password.as_ref().map(|p| p as &str)
             );
+            if let Some(ref mut password) = password {
+                wipe_password(password);
+            }
+            checked!(result, "import repository", ErrorCode::ImportRun);
             tr_info!("Import finished");
         }
+        Arguments::ExportArchive {
+            repo_path,
+            backup_name,
+            archive_path
+        } => {
+            let mut repo = try!(open_repository(&repo_path, true));
+            if !repo.has_backup(&backup_name) {
+                tr_error!("A backup with that name does not exist");
+                return Err(ErrorCode::NoSuchBackup);
+            }
+            let header = checked!(
+                repo.export_backup(&backup_name, &archive_path),
+                "export backup",
+                ErrorCode::ExportRun
+            );
+            tr_info!(
+                "Exported the backup with {} bundles, {}",
+                header.bundles.len(),
+                to_file_size(header.contents_size())
+            );
+        }
+        Arguments::ImportArchive {
+            repo_path,
+            remote_path,
+            archive_path,
+            key_files
+        } => {
+            let mut result = Repository::import_archive(
+                &repo_path,
+                &remote_path,
+                &archive_path,
+                key_files.clone(),
+                None
+            );
+            // The failed import has been removed, so it can be started again with the password
+            if let Err(RepositoryError::PasswordRequired) = result {
+                let mut password = try!(get_password());
+                result = Repository::import_archive(
+                    &repo_path,
+                    &remote_path,
+                    &archive_path,
+                    key_files,
+                    Some(&password)
+                );
+                wipe_password(&mut password);
+            }
+            let (_repo, backup_name) = checked!(result, "import archive", ErrorCode::ImportRun);
+            tr_info!("Imported the backup '{}'", backup_name);
+        }
         Arguments::Versions { repo_path, path } => {
             let mut repo = try!(open_repository(&repo_path, true));
             let mut found = false;
@@ -1077,7 +2354,8 @@ pub fn run() -> Result<(), ErrorCode> {
             inode_old,
             repo_path_new,
             backup_name_new,
-            inode_new
+            inode_new,
+            json
         } => {
             if repo_path_old != repo_path_new {
                 tr_error!("Can only run diff on same repository");
@@ -1103,19 +2381,76 @@ pub fn run() -> Result<(), ErrorCode> {
                 "find differences",
                 ErrorCode::DiffRun
             );
-            for diff in &diffs {
-                println!(
-                    "{} {:?}",
-                    match diff.0 {
-                        DiffType::Add => "add",
-                        DiffType::Mod => "mod",
-                        DiffType::Del => "del",
-                    },
-                    diff.1
-                );
+            if json {
+                print_diffs_json(&diffs);
+            } else {
+                print_diffs(&diffs);
             }
-            if diffs.is_empty() {
-                tr_info!("No differences found");
+        }
+        Arguments::Compare {
+            repo_path,
+            backup_name,
+            inode,
+            path,
+            json
+        } => {
+            let mut repo = try!(open_repository(&repo_path, true));
+            let backup = try!(get_backup(&repo, &backup_name));
+            let inode = try!(get_inode(&mut repo, &backup, inode.as_ref()));
+            let diffs = checked!(
+                repo.compare_with_path(&backup, &inode, &path),
+                "compare with filesystem",
+                ErrorCode::CompareRun
+            );
+            if json {
+                print_diffs_json(&diffs);
+            } else {
+                print_diffs(&diffs);
+            }
+        }
+        Arguments::VerifySignature {
+            repo_path,
+            backup_name,
+            verify_key,
+            allow_unsigned
+        } => {
+            let repo = try!(open_repository(&repo_path, false));
+            let mut backups = if let Some(backup_name) = backup_name {
+                vec![(backup_name.clone(), try!(get_backup(&repo, &backup_name)))]
+            } else {
+                checked!(repo.get_all_backups(), "load backups", ErrorCode::LoadBackup)
+                    .into_iter()
+                    .collect()
+            };
+            backups.sort_by(|a, b| a.0.cmp(&b.0));
+            let mut invalid = 0;
+            for (name, backup) in backups {
+                match repo.verify_backup_signature(&backup, verify_key.as_ref().map(|k| &k[..])) {
+                    Ok(true) => {
+                        let key = &backup.signature.as_ref().unwrap().verify_key;
+                        tr_println!("{}: signature valid, verify key {}", name, to_hex(key))
+                    }
+                    Ok(false) => {
+                        tr_error!("{}: signature does not match", name);
+                        invalid += 1;
+                    }
+                    Err(RepositoryError::BackupFile(BackupFileError::NotSigned)) => {
+                        if allow_unsigned {
+                            tr_warn!("{}: not signed", name)
+                        } else {
+                            tr_error!("{}: not signed", name);
+                            invalid += 1;
+                        }
+                    }
+                    Err(err) => {
+                        tr_error!("{}: failed to check signature\n\tcaused by: {}", name, err);
+                        invalid += 1;
+                    }
+                }
+            }
+            if invalid > 0 {
+                tr_error!("{} backups could not be verified", invalid);
+                return Err(ErrorCode::VerifySignatureRun);
             }
         }
         Arguments::Config {
@@ -1124,9 +2459,27 @@ pub fn run() -> Result<(), ErrorCode> {
             chunker,
             compression,
             encryption,
-            hash
+            hash,
+            redundancy,
+            admin,
+            recover
         } => {
+            if recover {
+                let remote_path = RepositoryLayout::new(&repo_path).remote_path();
+                let mut password = try!(get_remote_password(&remote_path));
+                let result = Repository::recover_config(
+                    &repo_path,
+                    password.as_ref().map(|p| p as &str),
+                    admin
+                );
+                if let Some(ref mut password) = password {
+                    wipe_password(password);
+                }
+                checked!(result, "recover config", ErrorCode::SaveConfig);
+                tr_info!("The configuration has been recovered.");
+            }
             let mut repo = try!(open_repository(&repo_path, false));
+            repo.set_admin(admin);
             let mut changed = false;
             if let Some(bundle_size) = bundle_size {
                 repo.config.bundle_size = bundle_size;
@@ -1136,7 +2489,9 @@ pub fn run() -> Result<(), ErrorCode> {
                 tr_warn!(
                     "Changing the chunker makes it impossible to use existing data for deduplication"
                 );
-                repo.config.chunker = chunker;
+                // A random seed of the repository is kept so that the boundaries stay private
+                let seed = repo.config.chunker.seed();
+                repo.config.chunker = chunker.with_seed(seed).unwrap_or(chunker);
                 changed = true;
             }
             if let Some(compression) = compression {
@@ -1148,13 +2503,23 @@ pub fn run() -> Result<(), ErrorCode> {
                 changed = true;
             }
             if let Some(hash) = hash {
-                tr_warn!(
-                    "Changing the hash makes it impossible to use existing data for deduplication"
-                );
+                if hash != repo.config.hash && repo.info().bundle_count > 0 {
+                    tr_error!("The hash method can not be changed once the repository contains data");
+                    return Err(ErrorCode::InvalidArgs);
+                }
                 repo.config.hash = hash;
                 changed = true;
             }
+            if let Some(redundancy) = redundancy {
+                repo.config.redundancy = redundancy;
+                changed = true;
+            }
             if changed {
+                checked!(
+                    repo.check_append_only(),
+                    "change config",
+                    ErrorCode::SaveConfig
+                );
                 checked!(repo.save_config(), "save config", ErrorCode::SaveConfig);
                 tr_info!("The configuration has been updated.");
             } else {
@@ -1181,9 +2546,18 @@ pub fn run() -> Result<(), ErrorCode> {
             repo_path,
             set_default,
             password,
-            file
+            file,
+            admin
         } => {
             let mut repo = try!(open_repository(&repo_path, false));
+            repo.set_admin(admin);
+            if set_default {
+                checked!(
+                    repo.check_append_only(),
+                    "change default key",
+                    ErrorCode::SaveConfig
+                );
+            }
             let (public, secret) = if let Some(file) = file {
                 checked!(
                     Crypto::load_keypair_from_file(file),
@@ -1221,7 +2595,8 @@ pub fn run() -> Result<(), ErrorCode> {
             hash,
             file
         } => {
-            algotest::run(&file, bundle_size, chunker, compression, encrypt, hash);
+            let threads = *THREADS.read().unwrap();
+            algotest::run(&file, bundle_size, chunker, compression, encrypt, hash, threads);
         }
     }
     Ok(())
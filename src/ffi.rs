@@ -0,0 +1,259 @@
+//! C interface for the core operations
+//!
+//! All functions take and return only C types. A repository is represented by an opaque
+//! handle that is created by `zvault_open` and must be released by `zvault_close`.
+//! Strings passed in must be valid, null-terminated UTF-8 and are only borrowed for the
+//! duration of the call. Strings returned by zvault are owned by the caller and must be
+//! released with `zvault_free_string`, except for `zvault_last_error_message`.
+//!
+//! All functions except `zvault_close` and `zvault_free_string` return `ZVAULT_OK` on
+//! success and one of the other error codes on failure. The message describing the last
+//! failure of the calling thread can be obtained via `zvault_last_error_message`.
+//!
+//! See `include/zvault.h` for the matching C declarations.
+
+use prelude::*;
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+
+
+pub const ZVAULT_OK: c_int = 0;
+pub const ZVAULT_ERR_INVALID_ARGUMENT: c_int = 1;
+pub const ZVAULT_ERR_LOAD_REPOSITORY: c_int = 2;
+pub const ZVAULT_ERR_NO_SUCH_BACKUP: c_int = 3;
+pub const ZVAULT_ERR_BACKUP_EXISTS: c_int = 4;
+pub const ZVAULT_ERR_BACKUP: c_int = 5;
+pub const ZVAULT_ERR_RESTORE: c_int = 6;
+pub const ZVAULT_ERR_LIST: c_int = 7;
+pub const ZVAULT_ERR_CHECK: c_int = 8;
+pub const ZVAULT_ERR_PANIC: c_int = 99;
+
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error<T: ToString>(msg: T) {
+    let msg = CString::new(msg.to_string().replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+/// Runs the closure, storing any error message and catching panics
+fn guarded<F: FnOnce() -> Result<(), (c_int, String)>>(f: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => ZVAULT_OK,
+        Ok(Err((code, msg))) => {
+            set_last_error(msg);
+            code
+        }
+        Err(_) => {
+            set_last_error(tr!("Internal error"));
+            ZVAULT_ERR_PANIC
+        }
+    }
+}
+
+unsafe fn get_str<'a>(val: *const c_char) -> Result<&'a str, (c_int, String)> {
+    if val.is_null() {
+        return Err((ZVAULT_ERR_INVALID_ARGUMENT, tr!("Null pointer given").to_string()));
+    }
+    CStr::from_ptr(val).to_str().map_err(|_| {
+        (ZVAULT_ERR_INVALID_ARGUMENT, tr!("String is not valid UTF-8").to_string())
+    })
+}
+
+unsafe fn get_repo<'a>(repo: *mut ZvaultRepository) -> Result<&'a mut Repository, (c_int, String)> {
+    if repo.is_null() {
+        return Err((ZVAULT_ERR_INVALID_ARGUMENT, tr!("Null pointer given").to_string()));
+    }
+    Ok(&mut (*repo).repo)
+}
+
+fn get_backup(repo: &Repository, name: &str) -> Result<Backup, (c_int, String)> {
+    if !repo.has_backup(name) {
+        return Err((ZVAULT_ERR_NO_SUCH_BACKUP, tr!("A backup with that name does not exist").to_string()));
+    }
+    repo.get_backup(name).map_err(|err| (ZVAULT_ERR_NO_SUCH_BACKUP, err.to_string()))
+}
+
+
+/// Opaque repository handle
+pub struct ZvaultRepository {
+    repo: Repository
+}
+
+
+/// Returns the message of the last error on this thread or NULL if there was none
+///
+/// The string is owned by zvault and stays valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn zvault_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| match *last.borrow() {
+        Some(ref msg) => msg.as_ptr(),
+        None => ptr::null()
+    })
+}
+
+/// Releases a string that has been returned by zvault
+///
+/// # Safety
+///
+/// `val` must be NULL or a string returned by zvault that has not been released yet.
+#[no_mangle]
+pub unsafe extern "C" fn zvault_free_string(val: *mut c_char) {
+    if !val.is_null() {
+        drop(CString::from_raw(val));
+    }
+}
+
+/// Opens the repository at `path` and stores the handle in `repo`
+///
+/// # Safety
+///
+/// `path` must be NULL or a valid null-terminated string and `repo` must be NULL or point
+/// to writable memory for a handle.
+#[no_mangle]
+pub unsafe extern "C" fn zvault_open(path: *const c_char, repo: *mut *mut ZvaultRepository) -> c_int {
+    guarded(|| {
+        let path = try!(get_str(path));
+        if repo.is_null() {
+            return Err((ZVAULT_ERR_INVALID_ARGUMENT, tr!("Null pointer given").to_string()));
+        }
+        let handle = try!(Repository::open(path, true).map_err(|err| {
+            (ZVAULT_ERR_LOAD_REPOSITORY, err.to_string())
+        }));
+        *repo = Box::into_raw(Box::new(ZvaultRepository { repo: handle }));
+        Ok(())
+    })
+}
+
+/// Closes the repository and releases the handle, passing NULL is allowed
+///
+/// # Safety
+///
+/// `repo` must be NULL or a handle returned by `zvault_open` that has not been closed yet.
+/// The handle must not be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn zvault_close(repo: *mut ZvaultRepository) {
+    if !repo.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(repo))));
+    }
+}
+
+/// Creates the backup `name` from the files at `src_path`
+///
+/// The latest backup of the same path on this host is used as reference and all other options
+/// are the defaults of `BackupOptions`. If some files could not be read, the backup is still
+/// saved and `ZVAULT_ERR_BACKUP` is returned.
+///
+/// # Safety
+///
+/// `repo` must be NULL or an open handle that is not used by another thread at the same time.
+/// `src_path` and `name` must be NULL or valid null-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn zvault_backup(
+    repo: *mut ZvaultRepository,
+    src_path: *const c_char,
+    name: *const c_char,
+) -> c_int {
+    guarded(|| {
+        let repo = try!(get_repo(repo));
+        let src_path = try!(get_str(src_path));
+        let name = try!(get_str(name));
+        if repo.has_backup(name) {
+            return Err((ZVAULT_ERR_BACKUP_EXISTS, tr!("A backup with that name already exists").to_string()));
+        }
+        let hostname = get_hostname().unwrap_or_else(|_| "".to_string());
+        let reference = match repo.get_all_backups() {
+            Ok(backups) |
+            Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backups, _))) => {
+                backups.into_iter()
+                    .map(|(_, backup)| backup)
+                    .filter(|backup| backup.host == hostname && backup.path == src_path)
+                    .max_by_key(|backup| backup.timestamp)
+            }
+            Err(err) => return Err((ZVAULT_ERR_BACKUP, err.to_string()))
+        };
+        let options = BackupOptions::default();
+        let (backup, failed) = match repo.create_backup_recursively(src_path, reference.as_ref(), &options) {
+            Ok(backup) => (backup, None),
+            Err(RepositoryError::Backup(BackupError::FailedPaths(backup, failed_paths))) => {
+                (backup, Some(tr_format!("Failed to backup {} paths", failed_paths.len())))
+            }
+            Err(err) => return Err((ZVAULT_ERR_BACKUP, err.to_string()))
+        };
+        try!(repo.save_backup(&backup, name).map_err(|err| (ZVAULT_ERR_BACKUP, err.to_string())));
+        match failed {
+            Some(msg) => Err((ZVAULT_ERR_BACKUP, msg)),
+            None => Ok(())
+        }
+    })
+}
+
+/// Restores the backup `name` into the existing directory `dst_path`
+///
+/// # Safety
+///
+/// `repo` must be NULL or an open handle that is not used by another thread at the same time.
+/// `name` and `dst_path` must be NULL or valid null-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn zvault_restore(
+    repo: *mut ZvaultRepository,
+    name: *const c_char,
+    dst_path: *const c_char,
+) -> c_int {
+    guarded(|| {
+        let repo = try!(get_repo(repo));
+        let name = try!(get_str(name));
+        let dst_path = try!(get_str(dst_path));
+        let backup = try!(get_backup(repo, name));
+        let inode = try!(repo.get_inode(&backup.root).map_err(|err| (ZVAULT_ERR_RESTORE, err.to_string())));
+        repo.restore_inode_tree(&backup, inode, Path::new(dst_path), RestoreOwner::Names, false)
+            .map_err(|err| (ZVAULT_ERR_RESTORE, err.to_string()))
+    })
+}
+
+/// Stores the names of all backups, separated by newlines, in `names`
+///
+/// The string must be released with `zvault_free_string`.
+///
+/// # Safety
+///
+/// `repo` must be NULL or an open handle that is not used by another thread at the same time.
+/// `names` must be NULL or point to writable memory for a string pointer.
+#[no_mangle]
+pub unsafe extern "C" fn zvault_list(repo: *mut ZvaultRepository, names: *mut *mut c_char) -> c_int {
+    guarded(|| {
+        let repo = try!(get_repo(repo));
+        if names.is_null() {
+            return Err((ZVAULT_ERR_INVALID_ARGUMENT, tr!("Null pointer given").to_string()));
+        }
+        let backups = try!(repo.get_all_backups().map_err(|err| (ZVAULT_ERR_LIST, err.to_string())));
+        let mut list: Vec<String> = backups.into_iter().map(|(name, _)| name).collect();
+        list.sort();
+        let list = try!(CString::new(list.join("\n")).map_err(|err| (ZVAULT_ERR_LIST, err.to_string())));
+        *names = list.into_raw();
+        Ok(())
+    })
+}
+
+/// Checks the repository and all backups without repairing anything
+///
+/// # Safety
+///
+/// `repo` must be NULL or an open handle that is not used by another thread at the same time.
+#[no_mangle]
+pub unsafe extern "C" fn zvault_check(repo: *mut ZvaultRepository) -> c_int {
+    guarded(|| {
+        let repo = try!(get_repo(repo));
+        try!(repo.check_repository(false).map_err(|err| (ZVAULT_ERR_CHECK, err.to_string())));
+        try!(repo.check_backups(false).map_err(|err| (ZVAULT_ERR_CHECK, err.to_string())));
+        repo.set_clean();
+        Ok(())
+    })
+}
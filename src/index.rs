@@ -69,7 +69,9 @@ pub trait Key: Eq + Copy + Default {
 pub trait Value: Copy + Default {}
 
 
-#[repr(packed)]
+// Same layout as a packed struct for the hash and location entries of the index, but without
+// unaligned references to the fields
+#[repr(C)]
 #[derive(Default)]
 pub struct Entry<K, V> {
     key: K,
@@ -79,42 +81,42 @@ pub struct Entry<K, V> {
 impl<K: Key, V> Entry<K, V> {
     #[inline]
     fn is_used(&self) -> bool {
-        unsafe { self.key.is_used() }
+        self.key.is_used()
     }
 
     #[inline]
     fn clear(&mut self) {
-        unsafe { self.key.clear() }
+        self.key.clear()
     }
 
     #[inline]
     fn get(&self) -> (&K, &V) {
-        unsafe { (&self.key, &self.data) }
+        (&self.key, &self.data)
     }
 
     #[inline]
     fn get_mut(&mut self) -> (&K, &mut V) {
-        unsafe { (&self.key, &mut self.data) }
+        (&self.key, &mut self.data)
     }
 
     #[inline]
     fn get_key(&self) -> &K {
-        unsafe { &self.key }
+        &self.key
     }
 
     #[inline]
     fn get_mut_key(&mut self) -> &mut K {
-        unsafe { &mut self.key }
+        &mut self.key
     }
 
     #[inline]
     fn get_data(&self) -> &V {
-        unsafe { &self.data }
+        &self.data
     }
 
     #[inline]
     fn get_mut_data(&mut self) -> &mut V {
-        unsafe { &mut self.data }
+        &mut self.data
     }
 }
 
@@ -175,6 +177,12 @@ unsafe fn mmap_as_ref<K, V>(mmap: &MemoryMap, len: usize) -> (&'static mut Heade
     (header, data)
 }
 
+/// Hash table stored in a memory-mapped file
+///
+/// The file is mapped shared, so every `set` and `delete` only touches the affected entries and
+/// the header in place and the kernel writes back the modified pages. The index is never
+/// rewritten as a whole, only growing and shrinking the table moves all entries once. If the
+/// file is lost or broken, the repository rebuilds the index from the bundles.
 pub struct Index<K: 'static, V: 'static> {
     capacity: usize,
     mask: usize,
@@ -578,6 +586,12 @@ impl<K: Key, V: Value> Index<K, V> {
         self.entries = 0;
     }
 
+    /// Writes all changes of the mapped file to disk
+    #[inline]
+    pub fn sync(&self) -> Result<(), IndexError> {
+        self.fd.sync_all().map_err(IndexError::Io)
+    }
+
     #[allow(dead_code)]
     pub fn statistics(&self) -> IndexStatistics {
         IndexStatistics {
@@ -0,0 +1,115 @@
+//! Deduplicating backup tool
+//!
+//! Besides the `zvault` binary, which is a thin wrapper around `cli::run`, this crate can be
+//! used as a library to create and read backups without the command line interface. The main
+//! entry point is `Repository`, the `prelude` module contains all public types at once.
+//!
+//! ```no_run
+//! extern crate zvault;
+//!
+//! use zvault::{Repository, Config, BackupOptions};
+//!
+//! # fn main() {
+//! let mut repo = Repository::create("/var/lib/backups/repo", &Config::default(),
+//!     "/mnt/remote").unwrap();
+//! let backup = repo.create_backup_recursively("/home", None, &BackupOptions::default()).unwrap();
+//! repo.save_backup(&backup, "home-2018-03-01").unwrap();
+//! for (name, backup) in repo.get_all_backups().unwrap() {
+//!     println!("{}: {} files", name, backup.file_count);
+//! }
+//! # }
+//! ```
+//!
+//! All error types are `Send`, so errors can be passed to other threads.
+
+#![recursion_limit="128"]
+#![allow(unknown_lints, float_cmp)]
+#![cfg_attr(feature = "bench", feature(test))]
+#[cfg(feature = "bench")]
+extern crate test;
+extern crate serde;
+extern crate serde_bytes;
+extern crate rmp_serde;
+#[macro_use]
+extern crate serde_utils;
+extern crate squash_sys as squash;
+extern crate blake2_rfc as blake2;
+extern crate murmurhash3;
+extern crate serde_yaml;
+#[macro_use]
+extern crate serde_json;
+#[macro_use]
+extern crate quick_error;
+extern crate chrono;
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate log;
+extern crate byteorder;
+extern crate sodiumoxide;
+extern crate libsodium_sys;
+extern crate ansi_term;
+extern crate filetime;
+extern crate regex;
+#[macro_use]
+extern crate lazy_static;
+extern crate fuse;
+extern crate rand;
+extern crate time;
+extern crate xattr;
+extern crate crossbeam;
+extern crate pbr;
+extern crate users;
+extern crate libc;
+extern crate tar;
+#[macro_use]
+extern crate runtime_fmt;
+extern crate locale_config;
+extern crate mmap;
+
+#[macro_use] mod translation;
+pub mod util;
+mod bundledb;
+mod repository;
+pub mod cli;
+pub mod ffi;
+pub mod prelude;
+mod mount;
+mod chunker;
+mod chunking;
+mod index;
+
+pub use repository::{Repository, Config, Backup, BackupOptions, Inode, FileType, FileData,
+                     RepositoryError, RepositoryInfo, BackupError, BackupFileError, InodeError,
+                     IntegrityError, ConfigError, CheckpointError, VacuumError, BundleMapError};
+pub use chunker::{ChunkerType, Chunker, ChunkerStatus, ChunkerError, AeChunker, RabinChunker,
+                  FastCdcChunker, FixedChunker, BuzhashChunker};
+pub use bundledb::{BundleId, BundleDbError, BundleReaderError, BundleWriterError,
+                   BundleCacheError, ParityError};
+pub use index::IndexError;
+
+
+/// Fails to compile if one of the public error types can not be sent to other threads
+#[allow(dead_code)]
+fn assert_errors_send() {
+    fn is_send<T: Send>() {}
+    is_send::<RepositoryError>();
+    is_send::<BackupError>();
+    is_send::<BackupFileError>();
+    is_send::<InodeError>();
+    is_send::<IntegrityError>();
+    is_send::<ChunkerError>();
+    is_send::<BundleDbError>();
+    is_send::<BundleReaderError>();
+    is_send::<BundleWriterError>();
+    is_send::<BundleCacheError>();
+    is_send::<ParityError>();
+    is_send::<IndexError>();
+    is_send::<ConfigError>();
+    is_send::<CheckpointError>();
+    is_send::<VacuumError>();
+    is_send::<BundleMapError>();
+    is_send::<util::EncryptionError>();
+    is_send::<util::CompressionError>();
+    is_send::<util::LockError>();
+}
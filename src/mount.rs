@@ -1,12 +1,16 @@
 use prelude::*;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::mem;
-use std::cmp::min;
+use std::sync::{Arc, Condvar, Mutex};
+use std::{io, mem, thread};
+use std::cmp::{min, max};
+use std::process::Command;
+
+use bundledb::BundleReaderError;
 
 use fuse;
 use users::{self, Users, Groups};
@@ -14,6 +18,25 @@ use time::Timespec;
 use libc;
 
 
+/// Unmounts the filesystem at `mountpoint` when SIGINT or SIGTERM is received
+///
+/// The signals are handled by `handle_signals`, so this must be called before any other
+/// threads are started to make them inherit the mask.
+/// Unmounting ends the session loop of `FuseFilesystem::mount` which then returns normally.
+pub fn unmount_on_signal<P: AsRef<Path>>(mountpoint: P) -> Result<(), io::Error> {
+    let mountpoint = mountpoint.as_ref().to_path_buf();
+    handle_signals("fuse-signals", move |_| {
+        tr_info!("Unmounting the filesystem...");
+        match Command::new("fusermount").arg("-u").arg(&mountpoint).status() {
+            Ok(ref status) if status.success() => return false,
+            Ok(_) => tr_warn!("Failed to unmount the filesystem, it might still be in use"),
+            Err(err) => tr_warn!("Failed to run fusermount: {}", err)
+        }
+        true
+    })
+}
+
+
 macro_rules! fuse_try(
     ($val:expr, $reply:expr) => {
         match $val {
@@ -67,7 +90,7 @@ macro_rules! lookup(
 fn convert_file_type(kind: FileType) -> fuse::FileType {
     match kind {
         FileType::Directory => fuse::FileType::Directory,
-        FileType::File => fuse::FileType::RegularFile,
+        FileType::File | FileType::HardLink => fuse::FileType::RegularFile,
         FileType::Symlink => fuse::FileType::Symlink,
         FileType::BlockDevice => fuse::FileType::BlockDevice,
         FileType::CharDevice => fuse::FileType::CharDevice,
@@ -83,6 +106,10 @@ pub struct FuseInode {
     parent: Option<FuseInodeRef>,
     children: HashMap<String, FuseInodeRef>,
     chunks: Option<ChunkList>,
+    /// End of the last read, a read starting here continues a sequential read
+    read_end: u64,
+    /// End of the data that has been prefetched for sequential reads
+    readahead_end: u64,
     name_cache: Rc<users::UsersCache>,
     user_names: Rc<HashMap<u32, String>>,
     group_names: Rc<HashMap<u32, String>>
@@ -109,7 +136,7 @@ impl FuseInode {
             atime: Timespec::new(self.inode.timestamp, 0),
             mtime: Timespec::new(self.inode.timestamp, 0),
             ctime: Timespec::new(0, 0),
-            crtime: Timespec::new(0, 0),
+            crtime: Timespec::new(self.inode.birth_time.unwrap_or(0), 0),
             kind: convert_file_type(self.inode.file_type),
             perm: self.inode.mode as u16,
             nlink: 1,
@@ -148,10 +175,182 @@ impl FuseInode {
 }
 
 
+/// A part of a read request: a byte range of a chunk stored in a bundle
+struct ReadSegment {
+    bundle_id: BundleId,
+    path: PathBuf,
+    chunk: usize,
+    start: usize,
+    end: usize
+}
+
+/// Data of a read request that is stored contiguously, `size` bytes at `offset` of the reply
+struct ReadPart {
+    offset: usize,
+    size: usize,
+    segments: Vec<ReadSegment>
+}
+
+/// A read request of `size` bytes, everything that is not covered by a part is zero
+struct ReadJob {
+    parts: Vec<ReadPart>,
+    size: usize,
+    reply: fuse::ReplyData
+}
+
+enum FuseJob {
+    Read(ReadJob),
+    /// Loads the bundle of the segment into the cache without answering a request
+    Prefetch(ReadSegment)
+}
+
+/// Pending jobs of the read pool, read requests are always taken before prefetches
+#[derive(Default)]
+struct FuseJobQueue {
+    reads: VecDeque<ReadJob>,
+    prefetches: VecDeque<ReadSegment>,
+    finished: bool
+}
+
+/// Worker threads that answer read requests in parallel and prefetch bundles
+///
+/// The decoded bundles are kept in the bundle cache of the repository, so the workers and
+/// the main thread share them and no bundle is kept in memory twice.
+pub struct FuseReadPool {
+    crypto: Arc<Mutex<Crypto>>,
+    cache: DecodedBundleCache,
+    jobs: Mutex<FuseJobQueue>,
+    available: Condvar
+}
+
+impl FuseReadPool {
+    pub fn new(
+        threads: usize,
+        crypto: Arc<Mutex<Crypto>>,
+        cache: DecodedBundleCache,
+    ) -> Arc<Self> {
+        let self_ = Arc::new(FuseReadPool {
+            crypto,
+            cache,
+            jobs: Mutex::new(FuseJobQueue::default()),
+            available: Condvar::new()
+        });
+        for num in 0..threads {
+            let self2 = self_.clone();
+            thread::Builder::new()
+                .name(format!("fuse-reader-{}", num))
+                .spawn(move || self2.worker_thread())
+                .unwrap();
+        }
+        self_
+    }
+
+    fn queue(&self, parts: Vec<ReadPart>, size: usize, reply: fuse::ReplyData) {
+        self.jobs.lock().unwrap().reads.push_back(ReadJob { parts, size, reply });
+        self.available.notify_one();
+    }
+
+    fn prefetch(&self, mut segments: Vec<ReadSegment>) {
+        segments.retain(|segment| self.cache.get(&segment.bundle_id).is_none());
+        segments.dedup_by(|a, b| a.bundle_id == b.bundle_id);
+        if segments.is_empty() {
+            return;
+        }
+        // One job per bundle, so that reads never wait for more than one bundle to be prefetched
+        self.jobs.lock().unwrap().prefetches.extend(segments);
+        self.available.notify_all();
+    }
+
+    /// Stops the workers once all read requests are answered, pending prefetches are dropped
+    pub fn finish(&self) {
+        self.jobs.lock().unwrap().finished = true;
+        self.available.notify_all();
+    }
+
+    fn next_job(&self) -> Option<FuseJob> {
+        let mut jobs = self.jobs.lock().unwrap();
+        loop {
+            if let Some(job) = jobs.reads.pop_front() {
+                return Some(FuseJob::Read(job));
+            }
+            if jobs.finished {
+                return None;
+            }
+            if let Some(segment) = jobs.prefetches.pop_front() {
+                return Some(FuseJob::Prefetch(segment));
+            }
+            jobs = self.available.wait(jobs).unwrap();
+        }
+    }
+
+    fn get_bundle(&self, segment: &ReadSegment) -> Result<Arc<DecodedBundle>, BundleReaderError> {
+        self.cache.get_or_load(&segment.bundle_id, || -> Result<_, BundleReaderError> {
+            let reader = try!(BundleReader::load(segment.path.clone(), self.crypto.clone()));
+            let data = try!(reader.load_contents());
+            Ok((reader, data))
+        })
+    }
+
+    fn read_segments(&self, segments: &[ReadSegment]) -> Result<Vec<u8>, BundleReaderError> {
+        let mut data = Vec::new();
+        for segment in segments {
+            let bundle = try!(self.get_bundle(segment));
+            let chunk = try!(bundle.chunk(segment.chunk));
+            data.extend_from_slice(&chunk[segment.start..min(segment.end, chunk.len())]);
+        }
+        Ok(data)
+    }
+
+    fn read_parts(&self, parts: &[ReadPart], size: usize) -> Result<Vec<u8>, RepositoryError> {
+        let mut data = vec![0; size];
+        for part in parts {
+            let stored = try!(self.read_segments(&part.segments).map_err(BundleDbError::from));
+            if stored.len() != part.size || part.offset + part.size > size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    tr!("Stored data does not have the expected size")
+                ).into());
+            }
+            data[part.offset..part.offset + part.size].copy_from_slice(&stored);
+        }
+        Ok(data)
+    }
+
+    fn worker_thread(&self) {
+        while let Some(job) = self.next_job() {
+            match job {
+                FuseJob::Read(job) => match self.read_parts(&job.parts, job.size) {
+                    Ok(data) => job.reply.data(&data),
+                    Err(err) => {
+                        info!("Error: {:?}", err);
+                        job.reply.error(libc::EIO)
+                    }
+                },
+                FuseJob::Prefetch(segment) => {
+                    if let Err(err) = self.get_bundle(&segment) {
+                        info!(
+                            "Error: Failed to prefetch bundle {}: {:?}",
+                            segment.bundle_id,
+                            err
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+/// Amount of data that is prefetched ahead of sequential reads of a file
+const READAHEAD_SIZE: u64 = 16 * 1024 * 1024;
+
+
 pub struct FuseFilesystem<'a> {
     next_id: u64,
     repository: &'a mut Repository,
-    inodes: HashMap<u64, FuseInodeRef>
+    inodes: HashMap<u64, FuseInodeRef>,
+    read_pool: Option<Arc<FuseReadPool>>,
+    read_threads: usize
 }
 
 impl<'a> FuseFilesystem<'a> {
@@ -159,10 +358,29 @@ impl<'a> FuseFilesystem<'a> {
         Ok(FuseFilesystem {
             next_id: 1,
             repository,
-            inodes: HashMap::new()
+            inodes: HashMap::new(),
+            read_pool: None,
+            read_threads: 1
         })
     }
 
+    /// Serves file reads through the bundle cache of the repository and prefetches data of
+    /// sequentially read files
+    ///
+    /// With more than one thread, reads are answered by worker threads, otherwise they are
+    /// answered on the main thread and a single worker only prefetches.
+    pub fn set_read_threads(&mut self, threads: usize) {
+        if let Some(pool) = self.read_pool.take() {
+            pool.finish();
+        }
+        self.read_threads = threads;
+        self.read_pool = Some(FuseReadPool::new(
+            max(threads, 1),
+            self.repository.get_crypto(),
+            self.repository.get_decoded_bundles()
+        ));
+    }
+
     pub fn from_repository(
         repository: &'a mut Repository,
         path: Option<&str>,
@@ -244,6 +462,8 @@ impl<'a> FuseFilesystem<'a> {
             num: self.next_id,
             parent: parent.clone(),
             chunks: None,
+            read_end: 0,
+            readahead_end: 0,
             children: HashMap::new(),
             user_names: Rc::new(user_names),
             group_names: Rc::new(group_names),
@@ -294,6 +514,8 @@ impl<'a> FuseFilesystem<'a> {
                 parent: Some(parent.clone()),
                 children: HashMap::new(),
                 chunks: None,
+                read_end: 0,
+                readahead_end: 0,
                 user_names: parent_mut.user_names.clone(),
                 group_names: parent_mut.group_names.clone(),
                 name_cache: parent_mut.name_cache.clone()
@@ -320,6 +542,8 @@ impl<'a> FuseFilesystem<'a> {
                         parent: Some(parent.clone()),
                         children: HashMap::new(),
                         chunks: None,
+                        read_end: 0,
+                        readahead_end: 0,
                         user_names: parent_mut.user_names.clone(),
                         group_names: parent_mut.group_names.clone(),
                         name_cache: parent_mut.name_cache.clone()
@@ -336,20 +560,197 @@ impl<'a> FuseFilesystem<'a> {
 
     pub fn fetch_chunks(&mut self, inode: &FuseInodeRef) -> Result<(), RepositoryError> {
         let mut inode = inode.borrow_mut();
-        let mut chunks = None;
-        match inode.inode.data {
-            None |
-            Some(FileData::Inline(_)) => (),
-            Some(FileData::ChunkedDirect(ref c)) => {
-                chunks = Some(c.clone());
+        // For sparse files, these are the chunks of the data regions
+        let chunks = match inode.inode.data {
+            Some(ref data) => try!(self.repository.get_data_chunks(data)),
+            None => None
+        };
+        inode.chunks = chunks;
+        inode.read_end = 0;
+        inode.readahead_end = 0;
+        Ok(())
+    }
+
+    /// Reads `size` bytes at `offset` of the data that is stored in the chunks
+    fn read_chunks(
+        &mut self,
+        chunks: &ChunkList,
+        mut offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, RepositoryError> {
+        let mut data = Vec::with_capacity(size as usize);
+        for &(hash, len) in chunks.iter() {
+            let len = u64::from(len);
+            if len <= offset {
+                offset -= len;
+                continue;
             }
-            Some(FileData::ChunkedIndirect(ref c)) => {
-                let chunk_data = try!(self.repository.get_data(c));
-                chunks = Some(ChunkList::read_from(&chunk_data));
+            let chunk = try!(try!(self.repository.get_chunk(hash)).ok_or_else(|| {
+                IntegrityError::MissingChunk(hash)
+            }));
+            let end = min(len, offset + size - data.len() as u64);
+            data.extend_from_slice(&chunk[offset as usize..end as usize]);
+            if data.len() as u64 == size {
+                break;
             }
+            offset = 0;
+        }
+        Ok(data)
+    }
+
+    /// Reads from a sparse file, the holes are filled with zeros
+    fn read_sparse(
+        &mut self,
+        inode: &FuseInode,
+        regions: &[(u64, u64)],
+        offset: i64,
+        size: u32,
+        reply: fuse::ReplyData,
+    ) {
+        let chunks = match inode.chunks {
+            Some(ref chunks) => chunks,
+            None => return reply.error(libc::EBADF)
+        };
+        let start = min(offset as u64, inode.inode.size);
+        let end = min(start + u64::from(size), inode.inode.size);
+        if let Some(pool) = self.read_pool.clone() {
+            let mut parts = vec![];
+            let mut stored_offset = 0;
+            for &(region_start, len) in regions {
+                let region_end = region_start + len;
+                if region_start < end && region_end > start {
+                    let (from, to) = (max(start, region_start), min(end, region_end));
+                    let stored_start = stored_offset + from - region_start;
+                    let segments = fuse_try!(
+                        self.get_segments(chunks, stored_start, to - from),
+                        reply
+                    );
+                    parts.push(ReadPart {
+                        offset: (from - start) as usize,
+                        size: (to - from) as usize,
+                        segments
+                    });
+                }
+                stored_offset += len;
+            }
+            return self.run_read(&pool, parts, (end - start) as usize, reply);
+        }
+        let mut data = vec![0; (end - start) as usize];
+        // Offset of the current region in the stored data
+        let mut stored_offset = 0;
+        for &(region_start, len) in regions {
+            let region_end = region_start + len;
+            if region_start < end && region_end > start {
+                let (from, to) = (max(start, region_start), min(end, region_end));
+                let stored = fuse_try!(
+                    self.read_chunks(chunks, stored_offset + from - region_start, to - from),
+                    reply
+                );
+                if stored.len() as u64 != to - from {
+                    return reply.error(libc::EIO);
+                }
+                data[(from - start) as usize..(to - start) as usize].copy_from_slice(&stored);
+            }
+            stored_offset += len;
+        }
+        reply.data(&data)
+    }
+
+    /// Looks up the bundle locations of `size` bytes at `offset` of the data in the chunks
+    fn get_segments(
+        &self,
+        chunks: &ChunkList,
+        mut offset: u64,
+        mut size: u64,
+    ) -> Result<Vec<ReadSegment>, RepositoryError> {
+        let mut segments = vec![];
+        for &(hash, len) in chunks.iter() {
+            let len = u64::from(len);
+            if len <= offset {
+                offset -= len;
+                continue;
+            }
+            let (bundle_id, path, chunk) =
+                try!(try!(self.repository.get_chunk_source(hash)).ok_or_else(|| {
+                    IntegrityError::MissingChunk(hash)
+                }));
+            segments.push(ReadSegment {
+                bundle_id,
+                path,
+                chunk,
+                start: offset as usize,
+                end: (offset + size) as usize
+            });
+            if len - offset >= size {
+                break;
+            }
+            size -= len - offset;
+            offset = 0;
+        }
+        Ok(segments)
+    }
+
+    /// Queues the data following a sequential read of the file to be loaded in the background
+    ///
+    /// The prefetched range is extended by `READAHEAD_SIZE` whenever less than half of it is
+    /// left ahead of the reader, so that not every read queues a prefetch.
+    fn readahead(&self, pool: &FuseReadPool, inode: &mut FuseInode, offset: u64, end: u64) {
+        let sequential = offset == inode.read_end;
+        inode.read_end = end;
+        if !sequential {
+            inode.readahead_end = end;
+            return;
+        }
+        if end + READAHEAD_SIZE / 2 <= inode.readahead_end {
+            return;
+        }
+        let start = max(end, inode.readahead_end);
+        let stop = min(end + READAHEAD_SIZE, inode.inode.size);
+        if start >= stop {
+            return;
+        }
+        inode.readahead_end = stop;
+        if let Some(ref chunks) = inode.chunks {
+            match self.get_segments(chunks, start, stop - start) {
+                Ok(segments) => pool.prefetch(segments),
+                Err(err) => info!("Error: Failed to prefetch data: {:?}", err)
+            }
+        }
+    }
+
+    fn read_pooled(
+        &mut self,
+        pool: &FuseReadPool,
+        inode: &mut FuseInode,
+        offset: u64,
+        size: u64,
+        reply: fuse::ReplyData,
+    ) {
+        let end = min(offset + size, inode.inode.size);
+        let start = min(offset, end);
+        let segments = match inode.chunks {
+            Some(ref chunks) => fuse_try!(self.get_segments(chunks, start, end - start), reply),
+            None => return reply.error(libc::EBADF)
+        };
+        self.readahead(pool, inode, offset, end);
+        let size = (end - start) as usize;
+        self.run_read(pool, vec![ReadPart { offset: 0, size, segments }], size, reply)
+    }
+
+    /// Answers the read on a worker thread or, with a single thread, right away
+    fn run_read(
+        &self,
+        pool: &FuseReadPool,
+        parts: Vec<ReadPart>,
+        size: usize,
+        reply: fuse::ReplyData,
+    ) {
+        if self.read_threads > 1 {
+            pool.queue(parts, size, reply)
+        } else {
+            let data = fuse_try!(pool.read_parts(&parts, size), reply);
+            reply.data(&data)
         }
-        inode.chunks = chunks;
-        Ok(())
     }
 }
 
@@ -367,6 +768,9 @@ impl<'a> fuse::Filesystem for FuseFilesystem<'a> {
 
     fn destroy(&mut self, _req: &fuse::Request) {
         info!("destroy");
+        if let Some(pool) = self.read_pool.take() {
+            pool.finish();
+        }
     }
 
     /// Forget about an inode
@@ -538,7 +942,7 @@ impl<'a> fuse::Filesystem for FuseFilesystem<'a> {
         reply: fuse::ReplyData,
     ) {
         let inode = inode!(self, ino, reply);
-        let inode = inode.borrow();
+        let mut inode = inode.borrow_mut();
         match inode.inode.data {
             None => return reply.data(&[]),
             Some(FileData::Inline(ref data)) => {
@@ -547,8 +951,14 @@ impl<'a> fuse::Filesystem for FuseFilesystem<'a> {
                               min(offset as usize + size as usize, data.len())]
                 )
             }
+            Some(FileData::Sparse(ref sparse)) => {
+                return self.read_sparse(&inode, &sparse.regions, offset, size, reply);
+            }
             _ => (),
         }
+        if let Some(pool) = self.read_pool.clone() {
+            return self.read_pooled(&pool, &mut inode, offset as u64, u64::from(size), reply);
+        }
         if let Some(ref chunks) = inode.chunks {
             let mut data = Vec::with_capacity(size as usize);
             for &(hash, len) in chunks.iter() {
@@ -1,13 +1,20 @@
 pub use util::*;
 pub use bundledb::{BundleReader, BundleMode, BundleWriter, BundleInfo, BundleId, BundleDbError,
-                   BundleDb, BundleWriterError, StoredBundle, BundleStatistics};
+                   BundleDb, BundleWriterError, StoredBundle, BundleStatistics, Redundancy,
+                   ParityError, BundleReaderError, BundleCacheError, DecodedBundle,
+                   DecodedBundleCache};
 pub use chunker::{ChunkerType, Chunker, ChunkerStatus, ChunkerError};
 pub use repository::{Repository, Backup, Config, RepositoryError, RepositoryInfo, Inode, FileType,
                      IntegrityError, BackupFileError, BackupError, BackupOptions, BundleAnalysis,
                      FileData, DiffType, InodeError, RepositoryLayout, Location,
-                     RepositoryStatistics};
+                     RepositoryStatistics, PathSelection, RetentionPolicy, ExcludeMatcher,
+                     PlannedEntry, DedupAnalysis, ConfigError, CheckpointError, VacuumError,
+                     BundleMapError, IndexEntry, DirectorySize, RestoreOwner, MAX_INLINE_SIZE,
+                     DEFAULT_INLINE_SIZE, SparseData, CompressionUsage, CopyStatistics,
+                     CloneStatistics, ArchiveHeader, ArchiveEntry, ArchiveError, BackupCheckpoint,
+                     RetentionSlot, KeepReason};
 pub use index::{Index, IndexError, IndexStatistics};
-pub use mount::FuseFilesystem;
+pub use mount::{FuseFilesystem, unmount_on_signal};
 pub use translation::CowStr;
 
 pub use serde::{Serialize, Deserialize};
@@ -0,0 +1,468 @@
+use prelude::*;
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::Path;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::fs::{self, File};
+
+
+static HEADER_STRING: [u8; 7] = *b"zvault\x06";
+static HEADER_VERSION: u8 = 1;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum ArchiveError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description(tr!("Failed to read/write archive"))
+            display("{}", tr_format!("Archive error: failed to read/write archive\n\tcaused by: {}", err))
+        }
+        Decode(err: msgpack::DecodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to decode archive header"))
+            display("{}", tr_format!("Archive error: failed to decode archive header\n\tcaused by: {}", err))
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to encode archive header"))
+            display("{}", tr_format!("Archive error: failed to encode archive header\n\tcaused by: {}", err))
+        }
+        WrongHeader {
+            description(tr!("Wrong header"))
+            display("{}", tr_format!("Archive error: the file is not a zVault archive"))
+        }
+        WrongVersion(version: u8) {
+            description(tr!("Wrong version"))
+            display("{}", tr_format!("Archive error: unsupported archive version: {}", version))
+        }
+        Incomplete(expected: u64, size: u64) {
+            description(tr!("Incomplete archive"))
+            display("{}", tr_format!("Archive error: the archive should be {} bytes long but it has {} bytes", expected, size))
+        }
+        InvalidName(name: String) {
+            description(tr!("Invalid backup name"))
+            display("{}", tr_format!("Archive error: invalid backup name in archive: {:?}", name))
+        }
+        Corrupt(name: String) {
+            description(tr!("Corrupt archive"))
+            display("{}", tr_format!("Archive error: the contents of {} do not match the archive header", name))
+        }
+    }
+}
+
+
+/// A file stored in an archive
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    pub size: u64,
+    /// Blake2 hash of the file contents
+    pub hash: Hash
+}
+serde_impl!(ArchiveEntry(u8) {
+    size: u64 => 0,
+    hash: Hash => 1
+});
+
+
+/// Header of an archive with a single backup and all bundles it references
+///
+/// The header is followed by the backup file and the bundle files in the listed order, all of
+/// them byte for byte as they are stored in the repository.
+#[derive(Default, Debug, Clone)]
+pub struct ArchiveHeader {
+    pub backup_name: String,
+    /// Configuration for the repository the archive is imported into
+    pub config: Config,
+    pub backup: ArchiveEntry,
+    /// Bundles in the order they are stored, data bundles come first
+    pub bundles: Vec<(BundleId, ArchiveEntry)>
+}
+serde_impl!(ArchiveHeader(u8?) {
+    backup_name: String => 0,
+    config: Config => 1,
+    backup: ArchiveEntry => 2,
+    bundles: Vec<(BundleId, ArchiveEntry)> => 3
+});
+
+impl ArchiveHeader {
+    /// Returns the size of all files in the archive
+    pub fn contents_size(&self) -> u64 {
+        self.backup.size + self.bundles.iter().map(|&(_, ref entry)| entry.size).sum::<u64>()
+    }
+
+    /// Reads the header and checks that the archive contains all listed files unchanged
+    ///
+    /// Returns the header and the reader, positioned at the start of the backup file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<(Self, BufReader<File>), ArchiveError> {
+        let mut file = BufReader::new(try!(File::open(path.as_ref())));
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header));
+        if header[..HEADER_STRING.len()] != HEADER_STRING {
+            return Err(ArchiveError::WrongHeader);
+        }
+        let version = header[HEADER_STRING.len()];
+        if version != HEADER_VERSION {
+            return Err(ArchiveError::WrongVersion(version));
+        }
+        let header: ArchiveHeader = try!(msgpack::decode_from_stream(&mut file));
+        // The name is used as a path in the new repository
+        let name = &header.backup_name;
+        if name.starts_with('/') || name.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+            return Err(ArchiveError::InvalidName(name.clone()));
+        }
+        let start = try!(file.seek(SeekFrom::Current(0)));
+        let expected = start + header.contents_size();
+        let size = try!(file.get_ref().metadata()).len();
+        if size != expected {
+            return Err(ArchiveError::Incomplete(expected, size));
+        }
+        try!(verify_entry(&mut file, &header.backup, &header.backup_name));
+        for &(ref id, ref entry) in &header.bundles {
+            try!(verify_entry(&mut file, entry, &id.to_string()));
+        }
+        try!(file.seek(SeekFrom::Start(start)));
+        Ok((header, file))
+    }
+}
+
+
+/// Copies `size` bytes from `input` to `output` and returns the hash of the data
+fn copy_hashed<R: Read, W: Write>(input: R, output: &mut W, size: u64) -> Result<Hash, io::Error> {
+    let mut input = input.take(size);
+    let mut hasher = Blake2Stream::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let len = try!(input.read(&mut buf));
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        try!(output.write_all(&buf[..len]));
+        copied += len as u64;
+    }
+    if copied != size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, tr!("file is shorter than expected")));
+    }
+    Ok(hasher.finish())
+}
+
+fn verify_entry<R: Read>(
+    input: &mut R,
+    entry: &ArchiveEntry,
+    name: &str,
+) -> Result<(), ArchiveError> {
+    if try!(copy_hashed(input, &mut io::sink(), entry.size)) != entry.hash {
+        return Err(ArchiveError::Corrupt(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Copies the file into the archive, checking that it has not changed since it was listed
+fn write_entry<W: Write>(
+    path: &Path,
+    output: &mut W,
+    entry: &ArchiveEntry,
+    name: &str,
+) -> Result<(), ArchiveError> {
+    let file = try!(File::open(path));
+    if try!(copy_hashed(file, output, entry.size)) != entry.hash {
+        return Err(ArchiveError::Corrupt(name.to_string()));
+    }
+    Ok(())
+}
+
+fn describe_file(path: &Path) -> Result<ArchiveEntry, io::Error> {
+    let size = try!(fs::metadata(path)).len();
+    let hash = try!(copy_hashed(try!(File::open(path)), &mut io::sink(), size));
+    Ok(ArchiveEntry { size, hash })
+}
+
+
+impl Repository {
+    /// Writes the backup and all bundles it references into a single archive file
+    ///
+    /// The archive can be imported into a new repository with `import_archive`. Bundles are
+    /// stored as they are, so the archive can only be read with the keys of this repository.
+    pub fn export_backup<P: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        path: P,
+    ) -> Result<ArchiveHeader, RepositoryError> {
+        let path = path.as_ref();
+        let backup = try!(self.get_backup(name));
+        let _lock = try!(self.lock(false));
+        let chunks = try!(self.get_backup_chunks(&backup));
+        let mut seen = HashSet::new();
+        let mut bundles = vec![];
+        for (hash, _len) in chunks {
+            let found = try!(self.index.get(&hash).ok_or(IntegrityError::MissingChunk(hash)));
+            if seen.insert(found.bundle) {
+                let bundle_id = try!(self.get_bundle_id(found.bundle));
+                let mode = try!(self.bundles.get_bundle_info(&bundle_id).ok_or_else(|| {
+                    IntegrityError::MissingBundle(bundle_id.clone())
+                })).info.mode;
+                bundles.push((mode == BundleMode::Meta, bundle_id));
+            }
+        }
+        // Data bundles first, as meta data refers to those chunks
+        bundles.sort();
+        let mut files = vec![];
+        for (_, bundle_id) in bundles {
+            let bundle_path = try!(self.bundles.get_bundle_path(&bundle_id));
+            files.push((bundle_id, bundle_path));
+        }
+        let backup_path = self.layout.backup_path(name);
+        let mut header = ArchiveHeader {
+            backup_name: name.to_string(),
+            config: self.config.clone(),
+            backup: try!(describe_file(&backup_path)),
+            bundles: Vec::with_capacity(files.len())
+        };
+        for &(ref bundle_id, ref bundle_path) in &files {
+            header.bundles.push((bundle_id.clone(), try!(describe_file(bundle_path))));
+        }
+        let mut tmp_name = path.file_name().unwrap().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        {
+            let mut file = BufWriter::new(try!(File::create(&tmp_path)));
+            try!(file.write_all(&HEADER_STRING));
+            try!(file.write_all(&[HEADER_VERSION]));
+            try!(msgpack::encode_to_stream(&header, &mut file).map_err(ArchiveError::from));
+            try!(write_entry(&backup_path, &mut file, &header.backup, name));
+            for (&(ref bundle_id, ref bundle_path), &(_, ref entry)) in ProgressIter::new(
+                tr!("exporting bundles"),
+                files.len(),
+                files.iter().zip(&header.bundles)
+            )
+            {
+                try!(write_entry(bundle_path, &mut file, entry, &bundle_id.to_string()));
+            }
+            try!(file.flush());
+            try!(file.get_ref().sync_all());
+        }
+        try!(fs::rename(&tmp_path, path));
+        Ok(header)
+    }
+
+    /// Creates a new repository at `path` from an archive written by `export_backup`
+    ///
+    /// The whole archive is checked against its header before anything is written. The new
+    /// repository gets the configuration of the exported one, the key files are needed to read
+    /// encrypted bundles and the password if its keys are derived from one. If the import
+    /// fails, the new repository and everything it added to the remote storage are removed
+    /// again. Returns the repository and the name of the imported backup.
+    pub fn import_archive<P: AsRef<Path>, R: AsRef<Path>, A: AsRef<Path>>(
+        path: P,
+        remote: R,
+        archive: A,
+        key_files: Vec<String>,
+        password: Option<&str>,
+    ) -> Result<(Self, String), RepositoryError> {
+        let path = path.as_ref();
+        let remote = remote.as_ref();
+        let (header, file) = try!(ArchiveHeader::load(archive));
+        let path_existed = path.exists();
+        let remote_entries = try!(dir_entries(remote));
+        let result = Self::import_archive_contents(path, remote, header, file, key_files, password);
+        if result.is_err() {
+            tr_warn!("Removing the partially imported repository {}", escape(&path));
+            if let Err(err) = remove_new_entries(path, !path_existed, remote, &remote_entries) {
+                tr_error!("Failed to remove the partially imported repository\n\tcaused by: {}", err);
+            }
+        }
+        result
+    }
+
+    fn import_archive_contents(
+        path: &Path,
+        remote: &Path,
+        header: ArchiveHeader,
+        mut file: BufReader<File>,
+        key_files: Vec<String>,
+        password: Option<&str>,
+    ) -> Result<(Self, String), RepositoryError> {
+        let layout = try!(Self::create_layout(path, &header.config, remote));
+        let mut crypto = try!(Crypto::open(layout.keys_path()));
+        for key_file in key_files {
+            try!(crypto.register_keyfile(key_file));
+        }
+        let mut repo = try!(Repository::open_with_password(path, true, password));
+        try!(repo.write_mode());
+        let _lock = try!(repo.lock(false));
+        try!(repo.set_dirty());
+        if repo.config.key_derivation.is_some() {
+            // Stores the copy of the key derivation in the remote storage
+            try!(repo.save_config());
+        }
+        let mut backup_data = Vec::with_capacity(header.backup.size as usize);
+        try!(copy_hashed(&mut file, &mut backup_data, header.backup.size).map_err(ArchiveError::from));
+        try!(fs::create_dir_all(layout.temp_bundles_path()));
+        for &(ref bundle_id, ref entry) in ProgressIter::new(
+            tr!("importing bundles"),
+            header.bundles.len(),
+            header.bundles.iter()
+        )
+        {
+            let tmp_path = layout.temp_bundle_path();
+            {
+                let mut tmp = try!(File::create(&tmp_path));
+                try!(copy_hashed(&mut file, &mut tmp, entry.size).map_err(ArchiveError::from));
+            }
+            let imported = try!(repo.bundles.import_bundle(&tmp_path));
+            try!(fs::remove_file(&tmp_path));
+            if imported.id != *bundle_id {
+                return Err(ArchiveError::Corrupt(bundle_id.to_string()).into());
+            }
+            try!(repo.add_new_remote_bundle(&imported));
+        }
+        try!(repo.flush());
+        repo.dirty = false;
+        // The backup is written last so that it never references missing bundles
+        let backup_path = layout.backup_path(&header.backup_name);
+        try!(fs::create_dir_all(backup_path.parent().unwrap()));
+        try!(File::create(&backup_path).and_then(|mut f| f.write_all(&backup_data)));
+        Ok((repo, header.backup_name))
+    }
+}
+
+
+/// Returns the names of the entries in the folder, or nothing if it does not exist
+fn dir_entries(path: &Path) -> Result<HashSet<OsString>, io::Error> {
+    let mut names = HashSet::new();
+    if path.exists() {
+        for entry in try!(fs::read_dir(path)) {
+            names.insert(try!(entry).file_name());
+        }
+    }
+    Ok(names)
+}
+
+/// Removes the new repository folder and all entries of the remote folder that are not in `keep`
+fn remove_new_entries(
+    path: &Path,
+    remove_path: bool,
+    remote: &Path,
+    keep: &HashSet<OsString>,
+) -> Result<(), io::Error> {
+    if remove_path && path.exists() {
+        try!(fs::remove_dir_all(path));
+    }
+    if !remote.exists() {
+        return Ok(());
+    }
+    for entry in try!(fs::read_dir(remote)) {
+        let entry = try!(entry);
+        if keep.contains(&entry.file_name()) {
+            continue;
+        }
+        if try!(entry.file_type()).is_dir() {
+            try!(fs::remove_dir_all(entry.path()));
+        } else {
+            try!(fs::remove_file(entry.path()));
+        }
+    }
+    Ok(())
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use repository::tests::temp_dir;
+
+    #[allow(unused_imports)]
+    use std::io::Cursor;
+
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = ArchiveHeader {
+            backup_name: "host/backup".to_string(),
+            config: Config::default(),
+            backup: ArchiveEntry { size: 12, hash: Hash { high: 1, low: 2 } },
+            bundles: vec![
+                (BundleId(Hash { high: 3, low: 4 }), ArchiveEntry { size: 34, hash: Hash { high: 5, low: 6 } })
+            ]
+        };
+        let decoded: ArchiveHeader = msgpack::decode(&msgpack::encode(&header).unwrap()).unwrap();
+        assert_eq!(decoded.backup_name, header.backup_name);
+        assert_eq!(decoded.bundles.len(), 1);
+        assert_eq!(decoded.bundles[0].0, header.bundles[0].0);
+        assert_eq!(decoded.contents_size(), 46);
+    }
+
+    #[test]
+    fn test_copy_hashed() {
+        let data = vec![42u8; 100_000];
+        let mut output = vec![];
+        let hash = copy_hashed(Cursor::new(&data), &mut output, 100_000).unwrap();
+        assert_eq!(output, data);
+        let entry = ArchiveEntry { size: 100_000, hash };
+        assert!(verify_entry(&mut Cursor::new(&data), &entry, "test").is_ok());
+        let mut changed = data.clone();
+        changed[500] = 0;
+        assert!(verify_entry(&mut Cursor::new(&changed), &entry, "test").is_err());
+        assert!(copy_hashed(Cursor::new(&data[..10]), &mut io::sink(), 100).is_err());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let base = temp_dir("archive");
+        let src = base.join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        File::create(src.join("sub/file")).unwrap().write_all(&data).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+        repo.save_backup(&backup, "host/test").unwrap();
+        let header = repo.export_backup("host/test", base.join("test.zvault")).unwrap();
+        assert_eq!(header.backup_name, "host/test");
+        drop(repo);
+        // A failed import leaves nothing behind
+        fs::create_dir(base.join("imported-remote")).unwrap();
+        File::create(base.join("imported-remote/keep")).unwrap();
+        let missing_key = base.join("missing.key").to_string_lossy().to_string();
+        assert!(Repository::import_archive(
+            base.join("imported"),
+            base.join("imported-remote"),
+            base.join("test.zvault"),
+            vec![missing_key],
+            None
+        ).is_err());
+        assert!(!base.join("imported").exists());
+        let entries: Vec<_> = fs::read_dir(base.join("imported-remote")).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec![OsString::from("keep")]);
+        let (mut imported, name) = Repository::import_archive(
+            base.join("imported"),
+            base.join("imported-remote"),
+            base.join("test.zvault"),
+            vec![],
+            None
+        ).unwrap();
+        assert_eq!(name, "host/test");
+        let backup = imported.get_backup("host/test").unwrap();
+        let root = imported.get_inode(&backup.root).unwrap();
+        fs::create_dir(base.join("restored")).unwrap();
+        imported.restore_inode_tree(&backup, root, base.join("restored"), RestoreOwner::Current, false).unwrap();
+        let mut restored = vec![];
+        File::open(base.join("restored/sub/file")).unwrap().read_to_end(&mut restored).unwrap();
+        assert!(restored == data);
+        drop(imported);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+}
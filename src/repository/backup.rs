@@ -1,15 +1,23 @@
 use prelude::*;
 
-use std::fs;
-use std::path::{self, Path, PathBuf};
-use std::collections::{HashMap, BTreeMap, VecDeque};
+use super::sparse::RegionReader;
+use super::restore::{RestorePool, RestoreChunk};
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{self, Component, Path, PathBuf};
+use std::collections::{HashMap, HashSet, BTreeMap, VecDeque};
 use std::os::linux::fs::MetadataExt;
+use std::time::Instant;
+use std::process;
+use std::mem;
 
 use chrono::prelude::*;
-use regex::RegexSet;
+use rand::{self, Rng};
 use users::{self, Users, Groups};
 
 
+
 quick_error!{
     #[derive(Debug)]
     #[allow(unknown_lints,large_enum_variant)]
@@ -22,13 +30,266 @@ quick_error!{
             description(tr!("The root of a backup can not be removed"))
             display("{}", tr_format!("Backup error: the root of a backup can not be removed"))
         }
+        NoSuchBackup(name: String) {
+            description(tr!("No such backup"))
+            display("{}", tr_format!("Backup error: there is no backup named {}", name))
+        }
+        AlreadyExists(name: String) {
+            description(tr!("Backup already exists"))
+            display("{}", tr_format!("Backup error: a backup named {} already exists", name))
+        }
+        Interrupted {
+            description(tr!("Backup interrupted"))
+            display("{}", tr_format!("Backup error: the backup has been interrupted"))
+        }
     }
 }
 
 
 pub struct BackupOptions {
     pub same_device: bool,
-    pub excludes: Option<RegexSet>
+    pub excludes: Option<ExcludeMatcher>,
+    pub inline_size: u64,
+    /// Skip all files with a timestamp before this time
+    pub min_time: Option<i64>,
+    /// Skip all files with a timestamp at or after this time
+    pub max_time: Option<i64>,
+    /// Use the access time instead of the modification time for `min_time` and `max_time`
+    pub use_atime: bool,
+    /// Skip all files that are larger than this size
+    pub max_file_size: Option<u64>,
+    /// Skip the contents of folders that are marked with a `CACHEDIR.TAG` file, except the tag
+    pub exclude_caches: bool,
+    /// Finish all open bundles after this many seconds
+    pub checkpoint_interval: Option<u64>,
+    /// Finish all open bundles after this many new bundles have been written
+    pub checkpoint_bundles: Option<usize>,
+    /// Only include these paths instead of the whole source folder
+    pub selection: Option<PathSelection>,
+    /// Write a checkpoint journal for the backup with this name at every checkpoint
+    pub journal: Option<String>,
+    /// Skip the folders that are listed in the checkpoint journal of an interrupted backup
+    pub resume: bool,
+    /// Use this chunker instead of the configured one
+    pub chunker: Option<ChunkerType>,
+    /// Use this compression for the data bundles instead of the configured one
+    pub compression: Option<Option<Compression>>,
+    /// Store data chunks that look incompressible in separate uncompressed bundles
+    pub auto_compression: bool,
+    /// Read back all new chunks after the backup and compare them to their hashes
+    pub verify: bool,
+    /// Abort the backup if a path can not be read instead of leaving it out
+    pub fail_on_error: bool,
+    /// Stop detecting hard links once this many linked files are tracked, store the rest as copies
+    pub hard_link_threshold: Option<usize>
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        BackupOptions {
+            same_device: true,
+            excludes: None,
+            inline_size: DEFAULT_INLINE_SIZE,
+            min_time: None,
+            max_time: None,
+            use_atime: false,
+            max_file_size: None,
+            exclude_caches: false,
+            checkpoint_interval: None,
+            checkpoint_bundles: None,
+            selection: None,
+            journal: None,
+            resume: false,
+            chunker: None,
+            compression: None,
+            auto_compression: false,
+            verify: false,
+            fail_on_error: false,
+            hard_link_threshold: None
+        }
+    }
+}
+
+impl BackupOptions {
+    /// Checks whether the folder entry is included in the backup
+    ///
+    /// Entries are left out if they are outside of the device `boundary` (only given with
+    /// `same_device`), excluded, not selected, outside of the time range or too large.
+    fn includes(
+        &self,
+        entry: &fs::DirEntry,
+        boundary: Option<&DeviceBoundary>,
+    ) -> Result<bool, io::Error> {
+        let path = entry.path();
+        if let Some(boundary) = boundary {
+            if !try!(boundary.includes(&path)) {
+                return Ok(false);
+            }
+        }
+        if let Some(ref excludes) = self.excludes {
+            let is_dir = try!(entry.file_type()).is_dir();
+            if excludes.is_excluded(&path, is_dir) {
+                return Ok(false);
+            }
+        }
+        if let Some(ref selection) = self.selection {
+            if !selection.includes(&path) {
+                return Ok(false);
+            }
+        }
+        if self.min_time.is_some() || self.max_time.is_some() {
+            let meta = try!(entry.metadata());
+            if !meta.is_dir() {
+                let time = if self.use_atime { meta.st_atime() } else { meta.st_mtime() };
+                if self.min_time.map_or(false, |min| time < min) ||
+                    self.max_time.map_or(false, |max| time >= max) {
+                    return Ok(false);
+                }
+            }
+        }
+        if let Some(max_size) = self.max_file_size {
+            let meta = try!(entry.metadata());
+            if meta.is_file() && meta.len() > max_size {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Checks whether the folder contains a `CACHEDIR.TAG` file with the standard signature
+///
+/// The tag has to be a regular file, tags that can not be read are ignored.
+fn is_cache_dir(path: &Path) -> bool {
+    let tag_path = path.join(CACHEDIR_TAG);
+    match fs::symlink_metadata(&tag_path) {
+        Ok(meta) if meta.is_file() => (),
+        _ => return false
+    }
+    let mut signature = Vec::with_capacity(CACHEDIR_SIGNATURE.len());
+    let len = CACHEDIR_SIGNATURE.len() as u64;
+    match File::open(&tag_path) {
+        Ok(file) => file.take(len).read_to_end(&mut signature).is_ok() && signature == CACHEDIR_SIGNATURE,
+        Err(_) => false
+    }
+}
+
+fn device_of(path: &Path) -> Result<u64, io::Error> {
+    Ok(try!(fs::symlink_metadata(path)).st_dev())
+}
+
+/// The device of the backup source and the mount points below it
+///
+/// Bind mounts and mounts of other parts of the same device have the same device id as the
+/// source, so they can only be told apart by their mount point.
+pub struct DeviceBoundary {
+    device: u64,
+    mount_points: HashSet<PathBuf>,
+    device_of: fn(&Path) -> Result<u64, io::Error>
+}
+
+impl DeviceBoundary {
+    pub fn new(root: &Path) -> Result<Self, io::Error> {
+        let mount_points = match mount_points() {
+            Ok(mount_points) => mount_points,
+            Err(err) => {
+                tr_warn!("Failed to read the mount points, only checking devices: {}", err);
+                vec![]
+            }
+        };
+        Self::with_device_of(root, mount_points, device_of)
+    }
+
+    /// Creates a boundary around `root` that uses `device_of` to get the device of a path
+    ///
+    /// The `mount_points` are absolute, all that are below the canonical `root` are converted to
+    /// paths below `root` as given so that they can be compared to the walked paths.
+    fn with_device_of(
+        root: &Path,
+        mount_points: Vec<PathBuf>,
+        device_of: fn(&Path) -> Result<u64, io::Error>,
+    ) -> Result<Self, io::Error> {
+        let device = try!(device_of(root));
+        let canonical_root = try!(fs::canonicalize(root));
+        let mount_points = mount_points
+            .iter()
+            .filter_map(|mount_point| mount_point.strip_prefix(&canonical_root).ok())
+            .filter(|rel_path| !rel_path.as_os_str().is_empty())
+            .map(|rel_path| root.join(rel_path))
+            .collect();
+        Ok(DeviceBoundary {
+            device,
+            mount_points,
+            device_of
+        })
+    }
+
+    /// Checks whether `path` is on the same device and not a mount point itself
+    pub fn includes(&self, path: &Path) -> Result<bool, io::Error> {
+        Ok(!self.mount_points.contains(path) && try!((self.device_of)(path)) == self.device)
+    }
+}
+
+
+/// Explicit list of paths to include in a backup
+///
+/// The listed paths are included with all their contents. Their parent folders are included
+/// as well to keep the structure, but without any other contents.
+pub struct PathSelection {
+    paths: HashSet<PathBuf>,
+    parents: HashSet<PathBuf>
+}
+
+impl PathSelection {
+    /// Creates a selection of the given paths, relative paths are taken relative to `base`
+    ///
+    /// Returns the selection and all paths that are not inside of `base`.
+    pub fn new<P: AsRef<Path>>(base: P, paths: Vec<PathBuf>) -> (Self, Vec<PathBuf>) {
+        let base: PathBuf = base.as_ref().components().collect();
+        let mut selection = PathSelection {
+            paths: HashSet::new(),
+            parents: HashSet::new()
+        };
+        let mut outside = vec![];
+        for path in paths {
+            let full_path: PathBuf = base.join(&path).components().collect();
+            if !full_path.starts_with(&base) ||
+                full_path.components().any(|c| c == Component::ParentDir)
+            {
+                outside.push(path);
+                continue;
+            }
+            let mut parent = full_path.parent();
+            while let Some(path) = parent {
+                if !path.starts_with(&base) || !selection.parents.insert(path.to_path_buf()) {
+                    break;
+                }
+                parent = path.parent();
+            }
+            selection.paths.insert(full_path);
+        }
+        (selection, outside)
+    }
+
+    /// Checks whether the path is part of the selection
+    pub fn includes(&self, path: &Path) -> bool {
+        let path: PathBuf = path.components().collect();
+        if self.parents.contains(&path) {
+            return true;
+        }
+        let mut current = Some(path.as_path());
+        while let Some(path) = current {
+            if self.paths.contains(path) {
+                return true;
+            }
+            current = path.parent();
+        }
+        false
+    }
 }
 
 
@@ -39,6 +300,84 @@ pub enum DiffType {
 }
 
 
+/// Path that would be included in a backup, as determined by `plan_backup`
+pub struct PlannedEntry {
+    pub path: PathBuf,
+    pub file_type: FileType,
+    /// Size of the file data, hard links to files that are already included have no data
+    pub size: u64,
+    /// Change compared to the reference backup, `None` if the entry is unchanged
+    pub change: Option<DiffType>
+}
+
+/// Apparent and deduplicated size of a directory in a backup, as determined by `directory_sizes`
+pub struct DirectorySize {
+    pub path: PathBuf,
+    /// Total size of all files in the directory and its subdirectories
+    pub size: u64,
+    /// Size of the data of all files in the subtree, counting every chunk only once
+    pub dedup_size: u64
+}
+
+
+/// Returns the stored size of the chunks
+fn chunk_list_size(chunks: &[Chunk]) -> u64 {
+    chunks.iter().map(|&(_, len)| u64::from(len)).sum()
+}
+
+/// Returns the device and inode number of files with more than one link
+fn hard_link_id(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    if meta.is_file() && meta.st_nlink() > 1 {
+        Some((meta.st_dev(), meta.st_ino()))
+    } else {
+        None
+    }
+}
+
+/// Maps the owner and group of the inode to the local ids with the same names
+fn map_owner(
+    backup: &Backup,
+    inode: &mut Inode,
+    owner: RestoreOwner,
+    cache: &users::UsersCache,
+    unknown_users: &mut HashSet<u32>,
+    unknown_groups: &mut HashSet<u32>,
+) {
+    match owner {
+        RestoreOwner::Names => (),
+        RestoreOwner::Fixed(user, group) => {
+            inode.user = user;
+            inode.group = group;
+            return;
+        }
+        RestoreOwner::Numeric | RestoreOwner::Current => return
+    }
+    if let Some(name) = backup.user_names.get(&inode.user) {
+        if let Some(user) = cache.get_user_by_name(name) {
+            inode.user = user.uid();
+        } else if unknown_users.insert(inode.user) {
+            tr_warn!(
+                "User {} does not exist, using numeric id {}",
+                name,
+                inode.user
+            );
+        }
+    }
+    if let Some(name) = backup.group_names.get(&inode.group) {
+        if let Some(group) = cache.get_group_by_name(name) {
+            inode.group = group.gid();
+        } else if unknown_groups.insert(inode.group) {
+            tr_warn!(
+                "Group {} does not exist, using numeric id {}",
+                name,
+                inode.group
+            );
+        }
+    }
+}
+
+
+
 impl Repository {
     pub fn get_all_backups(&self) -> Result<HashMap<String, Backup>, RepositoryError> {
         Ok(try!(Backup::get_all_from(
@@ -72,40 +411,113 @@ impl Repository {
     pub fn save_backup(&mut self, backup: &Backup, name: &str) -> Result<(), RepositoryError> {
         try!(self.write_mode());
         let path = self.layout.backup_path(name);
+        if path.exists() {
+            try!(self.check_append_only());
+        }
         try!(fs::create_dir_all(path.parent().unwrap()));
-        try!(backup.save_to(
-            &self.crypto.lock().unwrap(),
-            self.config.encryption.clone(),
-            path
-        ));
-        Ok(())
+        let crypto = self.crypto.lock().unwrap();
+        let mut backup = backup.clone();
+        backup.signature = None;
+        if let Some(ref encryption) = self.config.encryption {
+            if crypto.can_decrypt(encryption) {
+                let key = PublicKey::from_slice(&encryption.1).unwrap();
+                try!(backup.sign(&crypto, &key));
+            } else {
+                tr_info!("The backup is not signed as the secret key is not available");
+            }
+        }
+        try!(backup.save_to(&crypto, self.config.encryption.clone(), path));
+        // The backup is complete, an interrupted earlier attempt does not need to be resumed
+        self.remove_checkpoint(name)
+    }
+
+    /// Checks the signature of the backup, returns false if the signature does not match
+    ///
+    /// If `trusted_key` is given, signatures made with another verify key do not match.
+    #[inline]
+    pub fn verify_backup_signature(
+        &self,
+        backup: &Backup,
+        trusted_key: Option<&[u8]>,
+    ) -> Result<bool, RepositoryError> {
+        Ok(try!(backup.verify_signature(trusted_key)))
     }
 
     pub fn delete_backup(&mut self, name: &str) -> Result<(), RepositoryError> {
+        try!(self.check_append_only());
         try!(self.write_mode());
-        let mut path = self.layout.backup_path(name);
+        let path = self.layout.backup_path(name);
         try!(fs::remove_file(&path));
+        self.remove_empty_backup_folders(path);
+        Ok(())
+    }
+
+    /// Gives a backup a new name
+    ///
+    /// Only the backup file is moved, the bundles and the index are not touched. The file is
+    /// linked under the new name first, so an existing backup is never replaced, even if it
+    /// is created by another client at the same time. On filesystems without hard links, the
+    /// file is renamed after checking for an existing backup while holding the lock.
+    pub fn rename_backup(&mut self, name: &str, new_name: &str) -> Result<(), RepositoryError> {
+        try!(self.check_append_only());
+        try!(self.write_mode());
+        let _lock = try!(self.lock(true));
+        let path = self.layout.backup_path(name);
+        let new_path = self.layout.backup_path(new_name);
+        if !path.exists() {
+            return Err(BackupError::NoSuchBackup(name.to_string()).into());
+        }
+        try!(fs::create_dir_all(new_path.parent().unwrap()));
+        match fs::hard_link(&path, &new_path) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(BackupError::AlreadyExists(new_name.to_string()).into())
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(BackupError::NoSuchBackup(name.to_string()).into())
+            }
+            Err(_) => {
+                if new_path.exists() {
+                    return Err(BackupError::AlreadyExists(new_name.to_string()).into());
+                }
+                try!(fs::rename(&path, &new_path));
+                self.remove_empty_backup_folders(path);
+                return Ok(());
+            }
+        }
+        try!(fs::remove_file(&path));
+        self.remove_empty_backup_folders(path);
+        Ok(())
+    }
+
+    /// Removes the folders of a removed backup file up to the backups folder if they are empty
+    fn remove_empty_backup_folders(&self, mut path: PathBuf) {
         loop {
             path = path.parent().unwrap().to_owned();
             if path == self.layout.backups_path() || fs::remove_dir(&path).is_err() {
                 break;
             }
         }
-        Ok(())
     }
 
 
+    /// Removes the backups starting with `prefix` that are not kept by the retention policy
+    ///
+    /// With `max_size`, the oldest backups are removed until the used data fits into that size,
+    /// even if the policy would keep them. The newest backup is always kept.
     pub fn prune_backups(
         &mut self,
         prefix: &str,
-        daily: usize,
-        weekly: usize,
-        monthly: usize,
-        yearly: usize,
+        policy: &RetentionPolicy,
+        max_size: Option<u64>,
         force: bool,
     ) -> Result<(), RepositoryError> {
+        if force {
+            try!(self.check_append_only());
+        }
         try!(self.write_mode());
         let mut backups = Vec::new();
+        let mut others = Vec::new();
         let backup_map = match self.get_all_backups() {
             Ok(backup_map) => backup_map,
             Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
@@ -119,57 +531,54 @@ impl Repository {
             if name.starts_with(prefix) {
                 let date = Local.timestamp(backup.timestamp, 0);
                 backups.push((name, date, backup));
+            } else {
+                others.push(backup);
             }
         }
         backups.sort_by_key(|backup| -backup.2.timestamp);
+        let dates: Vec<DateTime<Local>> = backups.iter().map(|backup| backup.1).collect();
+        let reasons = policy.select(&dates, Local::now());
+        let no_slots = policy.is_empty();
         let mut keep = Bitmap::new(backups.len());
-
-        fn mark_needed<K: Eq, F: Fn(&DateTime<Local>) -> K>(
-            backups: &[(String, DateTime<Local>, Backup)],
-            keep: &mut Bitmap,
-            max: usize,
-            keyfn: F,
-        ) {
-            let mut kept = 0;
-            let mut last = None;
-            for (i, backup) in backups.iter().enumerate() {
-                let val = keyfn(&backup.1);
-                let cur = Some(val);
-                if cur != last {
-                    if kept >= max {
-                        break;
-                    }
-                    last = cur;
+        for (i, reasons) in reasons.iter().enumerate() {
+            if !reasons.is_empty() {
+                keep.set(i);
+            }
+        }
+        if let Some(max_size) = max_size {
+            if no_slots {
+                for i in 0..backups.len() {
                     keep.set(i);
-                    kept += 1;
                 }
             }
-        }
-        if yearly > 0 {
-            mark_needed(&backups, &mut keep, yearly, |d| d.year());
-        }
-        if monthly > 0 {
-            mark_needed(&backups, &mut keep, monthly, |d| (d.year(), d.month()));
-        }
-        if weekly > 0 {
-            mark_needed(&backups, &mut keep, weekly, |d| {
-                let week = d.iso_week();
-                (week.year(), week.week())
-            });
-        }
-        if daily > 0 {
-            mark_needed(
-                &backups,
-                &mut keep,
-                daily,
-                |d| (d.year(), d.month(), d.day())
-            );
+            try!(self.prune_to_size(&backups, &others, &mut keep, max_size));
         }
         let mut remove = Vec::new();
-        println!("Removing the following backups");
-        for (i, backup) in backups.into_iter().enumerate() {
-            if !keep.get(i) {
-                println!("  - {}", backup.0);
+        for (i, (backup, reasons)) in backups.into_iter().zip(reasons).enumerate() {
+            if keep.get(i) {
+                let reason = if reasons.is_empty() {
+                    tr!("within the size limit").to_string()
+                } else {
+                    reasons.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(", ")
+                };
+                tr_println!("  KEPT     {} (kept: {})", backup.0, reason);
+            } else {
+                // The size limit takes precedence over the retention slots
+                let reason = if reasons.is_empty() && !no_slots {
+                    tr!("not in any retention slot").to_string()
+                } else if reasons.is_empty() {
+                    tr!("exceeds the size limit").to_string()
+                } else {
+                    tr_format!(
+                        "exceeds the size limit, overriding: {}",
+                        reasons.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(", ")
+                    )
+                };
+                if force {
+                    tr_println!("  REMOVED  {} ({})", backup.0, reason);
+                } else {
+                    tr_println!("  WOULD BE REMOVED {} ({})", backup.0, reason);
+                }
                 remove.push(backup.0);
             }
         }
@@ -181,47 +590,596 @@ impl Repository {
         Ok(())
     }
 
+    fn get_backup_chunk_sizes(&mut self, backup: &Backup) -> Result<HashMap<Hash, u32>, RepositoryError> {
+        let mut used = HashMap::new();
+        let mut todo = VecDeque::new();
+        todo.push_back(backup.root.clone());
+        while let Some(chunks) = todo.pop_back() {
+            if chunks.iter().all(|&(hash, _)| used.contains_key(&hash)) {
+                continue;
+            }
+            used.extend(chunks.iter().cloned());
+            let inode = try!(self.get_inode(&chunks));
+            match inode.data.as_ref().map(FileData::stored) {
+                None |
+                Some(&FileData::Inline(_)) |
+                Some(&FileData::Sparse(_)) => (),
+                Some(&FileData::ChunkedDirect(ref chunks)) => {
+                    used.extend(chunks.iter().cloned());
+                }
+                Some(&FileData::ChunkedIndirect(ref chunks)) => {
+                    used.extend(chunks.iter().cloned());
+                    let chunk_data = try!(self.get_data(chunks));
+                    used.extend(ChunkList::read_from(&chunk_data).iter().cloned());
+                }
+            }
+            if let Some(children) = inode.children {
+                todo.extend(children.into_iter().map(|(_, chunks)| chunks));
+            }
+        }
+        Ok(used)
+    }
+
+    fn release_backup_chunks(
+        &mut self,
+        backup: &Backup,
+        refcounts: &mut HashMap<Hash, (usize, u64)>,
+    ) -> Result<u64, RepositoryError> {
+        let mut freed = 0;
+        for hash in try!(self.get_backup_chunk_sizes(backup)).keys() {
+            if let Some(&mut (ref mut count, size)) = refcounts.get_mut(hash) {
+                *count -= 1;
+                if *count == 0 {
+                    freed += size;
+                }
+            }
+        }
+        Ok(freed)
+    }
+
+    fn prune_to_size(
+        &mut self,
+        backups: &[(String, DateTime<Local>, Backup)],
+        others: &[Backup],
+        keep: &mut Bitmap,
+        max_size: u64,
+    ) -> Result<(), RepositoryError> {
+        // Estimate the encoded size of chunks by the ratio of their bundle
+        let mut ratios = HashMap::new();
+        for (id, bundle) in self.bundle_map.bundles() {
+            if let Some(bundle) = self.bundles.get_bundle_info(&bundle) {
+                let info = &bundle.info;
+                ratios.insert(id, info.encoded_size as f64 / info.raw_size.max(1) as f64);
+            }
+        }
+        let mut refcounts = HashMap::<Hash, (usize, u64)>::new();
+        for backup in others.iter().chain(backups.iter().map(|b| &b.2)) {
+            for (hash, len) in try!(self.get_backup_chunk_sizes(backup)) {
+                if let Some(&mut (ref mut count, _)) = refcounts.get_mut(&hash) {
+                    *count += 1;
+                    continue;
+                }
+                let ratio = self.index
+                    .get(&hash)
+                    .and_then(|loc| ratios.get(&loc.bundle).cloned())
+                    .unwrap_or(1.0);
+                refcounts.insert(hash, (1, (f64::from(len) * ratio) as u64));
+            }
+        }
+        let mut size: u64 = refcounts.values().map(|&(_, size)| size).sum();
+        tr_info!("Size of used data: {}", to_file_size(size));
+        for (i, backup) in backups.iter().enumerate() {
+            if !keep.get(i) {
+                size -= try!(self.release_backup_chunks(&backup.2, &mut refcounts));
+            }
+        }
+        // Remove the oldest backups first but never the latest one
+        for i in (1..backups.len()).rev() {
+            if size <= max_size {
+                break;
+            }
+            if keep.get(i) {
+                keep.unset(i);
+                size -= try!(self.release_backup_chunks(&backups[i].2, &mut refcounts));
+            }
+        }
+        tr_info!("Size of used data after pruning: {}", to_file_size(size));
+        if size > max_size {
+            tr_warn!("The size target can not be reached without removing the latest backup");
+        }
+        Ok(())
+    }
+
+    fn restore_inode_at(
+        &mut self,
+        inode: &Inode,
+        path: &Path,
+        owner: RestoreOwner,
+        skip_corrupt: bool,
+        corrupt_paths: &mut Vec<PathBuf>,
+    ) -> Result<(), RepositoryError> {
+        match self.save_inode_at(inode, path, owner) {
+            Ok(()) => Ok(()),
+            Err(RepositoryError::Integrity(err)) => {
+                let hash = match err {
+                    IntegrityError::CorruptChunk(hash) |
+                    IntegrityError::CorruptChunkInBundle(hash, ..) => hash,
+                    _ => return Err(err.into())
+                };
+                let file_path = path.join(&inode.name);
+                tr_error!("File {} contains the corrupt chunk {}", escape(&file_path), hash);
+                if !skip_corrupt {
+                    return Err(err.into());
+                }
+                fs::remove_file(&file_path).ok();
+                corrupt_paths.push(file_path);
+                Ok(())
+            }
+            Err(err) => Err(err)
+        }
+    }
+
     pub fn restore_inode_tree<P: AsRef<Path>>(
         &mut self,
         backup: &Backup,
         inode: Inode,
         path: P,
+        owner: RestoreOwner,
+        skip_corrupt: bool,
     ) -> Result<(), RepositoryError> {
         let _lock = try!(self.lock(false));
-        let mut queue = VecDeque::new();
-        queue.push_back((path.as_ref().to_owned(), inode));
-        let cache = users::UsersCache::new();
-        let mut is_root = true;
-        while let Some((path, mut inode)) = queue.pop_front() {
-            if inode.file_type != FileType::Directory || !is_root {
-                if let Some(name) = backup.user_names.get(&inode.user) {
-                    if let Some(user) = cache.get_user_by_name(name) {
-                        inode.user = user.uid();
+        let mut corrupt_paths = vec![];
+        let root_is_dir = inode.file_type == FileType::Directory;
+        let mut progress = if self.show_progress {
+            Some(ProgressReporter::new(tr!("restore"), inode.cum_size))
+        } else {
+            None
+        };
+        // Regular files are written by the pool while this thread creates the folders
+        let threads = self.threads();
+        let pool = if threads > 1 {
+            let verify = if self.verify_chunks {
+                Some(self.config.hash)
+            } else {
+                None
+            };
+            Some(RestorePool::new(
+                threads,
+                self.get_crypto(),
+                self.get_decoded_bundles(),
+                verify,
+                owner,
+                skip_corrupt
+            ))
+        } else {
+            None
+        };
+        let mut directories = vec![];
+        let mut hard_links = vec![];
+        let result = {
+            let restore_files = || -> Result<(), RepositoryError> {
+                let mut queue = VecDeque::new();
+                queue.push_back((path.as_ref().to_owned(), inode));
+                let cache = users::UsersCache::new();
+                let mut unknown_users = HashSet::new();
+                let mut unknown_groups = HashSet::new();
+                let mut is_root = true;
+                while let Some((path, mut inode)) = queue.pop_front() {
+                    if inode.file_type == FileType::Directory {
+                        let children = inode.children.take().unwrap_or_default();
+                        let child_path = if is_root {
+                            path.to_path_buf()
+                        } else {
+                            path.join(&inode.name)
+                        };
+                        if !is_root {
+                            map_owner(
+                                backup,
+                                &mut inode,
+                                owner,
+                                &cache,
+                                &mut unknown_users,
+                                &mut unknown_groups
+                            );
+                            try!(inode.create_at(&path));
+                            if let Some(ref mut progress) = progress {
+                                progress.add(inode.size, &child_path);
+                            }
+                            directories.push((path, inode));
+                        }
+                        for chunks in children.values() {
+                            let inode = try!(self.get_inode(chunks));
+                            queue.push_back((child_path.clone(), inode));
+                        }
+                        is_root = false;
+                        continue;
+                    }
+                    is_root = false;
+                    map_owner(
+                        backup,
+                        &mut inode,
+                        owner,
+                        &cache,
+                        &mut unknown_users,
+                        &mut unknown_groups
+                    );
+                    if inode.file_type == FileType::HardLink {
+                        // Links are created last so that their targets exist
+                        hard_links.push((path, inode));
+                        continue;
                     }
+                    if let Some(ref mut progress) = progress {
+                        progress.add(inode.size, path.join(&inode.name));
+                    }
+                    if let Some(ref pool) = pool {
+                        let chunks = match inode.data {
+                            Some(ref data) if inode.file_type == FileType::File => {
+                                try!(self.get_data_chunks(data))
+                            }
+                            _ => None
+                        };
+                        if let Some(chunks) = chunks {
+                            let mut sources = Vec::with_capacity(chunks.len());
+                            for &(hash, _) in chunks.iter() {
+                                let source = try!(self.get_chunk_source(hash));
+                                let (bundle_id, bundle_path, chunk) = try!(source.ok_or_else(|| {
+                                    IntegrityError::MissingChunk(hash)
+                                }));
+                                sources.push(RestoreChunk {
+                                    hash,
+                                    bundle_id,
+                                    path: bundle_path,
+                                    chunk
+                                });
+                            }
+                            try!(pool.queue(inode, path, sources));
+                            continue;
+                        }
+                    }
+                    try!(self.restore_inode_at(
+                        &inode,
+                        &path,
+                        owner,
+                        skip_corrupt,
+                        &mut corrupt_paths
+                    ));
                 }
-                if let Some(name) = backup.group_names.get(&inode.group) {
-                    if let Some(group) = cache.get_group_by_name(name) {
-                        inode.group = group.gid();
+                Ok(())
+            };
+            restore_files()
+        };
+        if let Some(pool) = pool {
+            // The pool is stopped in any case so that no worker is left behind
+            let skipped = pool.finish();
+            try!(result);
+            corrupt_paths.extend(try!(skipped));
+        } else {
+            try!(result);
+        }
+        let root = normalize_path(path.as_ref());
+        // Targets outside of the restored tree are restored as regular files by the first link
+        let mut outside_targets = HashMap::new();
+        for (path, inode) in hard_links {
+            let file_path = path.join(&inode.name);
+            if let Some(ref mut progress) = progress {
+                progress.add(inode.size, &file_path);
+            }
+            let target = normalize_path(path.join(inode.symlink_target.clone().unwrap_or_default()));
+            let inside = root_is_dir && target.starts_with(&root) && target != root;
+            let source = if inside {
+                Some(target.clone())
+            } else {
+                outside_targets.get(&target).cloned()
+            };
+            if let Some(source) = source {
+                match fs::hard_link(&source, &file_path) {
+                    Ok(()) => continue,
+                    Err(err) => {
+                        tr_warn!(
+                            "Failed to create hard link {} to {}: {}",
+                            escape(&file_path),
+                            escape(&source),
+                            err
+                        )
                     }
                 }
-                try!(self.save_inode_at(&inode, &path));
             }
-            if inode.file_type == FileType::Directory {
-                let path = if is_root {
-                    path.to_path_buf()
-                } else {
-                    path.join(inode.name)
-                };
-                for chunks in inode.children.unwrap().values() {
-                    let inode = try!(self.get_inode(chunks));
-                    queue.push_back((path.clone(), inode));
+            try!(self.restore_inode_at(&inode, &path, owner, skip_corrupt, &mut corrupt_paths));
+            if !inside && !outside_targets.contains_key(&target) && !corrupt_paths.contains(&file_path) {
+                outside_targets.insert(target, file_path);
+            }
+        }
+        // Folders get their metadata last, children before parents, so that restoring their
+        // contents neither changes their timestamps nor fails on read-only folders
+        for (path, inode) in directories.into_iter().rev() {
+            try!(inode.restore_meta_at(&path, owner));
+        }
+        if corrupt_paths.is_empty() {
+            Ok(())
+        } else {
+            Err(IntegrityError::CorruptFiles(corrupt_paths).into())
+        }
+    }
+
+    /// Restores the tree into a staging folder next to `path` that is renamed to `path` at the end
+    ///
+    /// A failed or interrupted restore leaves `path` untouched and only the staging folder
+    /// remains. An existing `path` is replaced. The staging folder has to be on the same
+    /// filesystem, if `path` is a mount point, the tree is restored directly into it instead.
+    pub fn restore_inode_tree_atomic<P: AsRef<Path>>(
+        &mut self,
+        backup: &Backup,
+        inode: Inode,
+        path: P,
+        owner: RestoreOwner,
+        skip_corrupt: bool,
+    ) -> Result<(), RepositoryError> {
+        let path = path.as_ref();
+        let parent = match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+            Some(parent) => parent,
+            None => Path::new("/")
+        };
+        let name = path.file_name().map(|name| name.to_string_lossy().to_string());
+        let other_device = match fs::symlink_metadata(path) {
+            Ok(meta) => meta.st_dev() != try!(parent.metadata()).st_dev(),
+            Err(_) => false
+        };
+        let name = match name {
+            Some(ref name) if !other_device => name,
+            _ => {
+                tr_warn!("Can not restore atomically to {}, restoring directly into it", escape(&path));
+                return self.restore_inode_tree(backup, inode, path, owner, skip_corrupt);
+            }
+        };
+        let staging = parent.join(format!(".{}.zvault-restore-{}", name, process::id()));
+        try!(fs::create_dir(&staging));
+        let result = self.restore_inode_tree(backup, inode, &staging, owner, skip_corrupt);
+        match result {
+            // The skipped files have been reported, all others are complete
+            Ok(()) | Err(RepositoryError::Integrity(IntegrityError::CorruptFiles(_))) => (),
+            Err(err) => {
+                tr_warn!("The incomplete restore has been left in {}", escape(&staging));
+                return Err(err);
+            }
+        }
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            let old = parent.join(format!(".{}.zvault-old-{}", name, process::id()));
+            try!(fs::rename(path, &old));
+            if let Err(err) = fs::rename(&staging, path) {
+                fs::rename(&old, path).ok();
+                return Err(err.into());
+            }
+            let removed = if meta.is_dir() {
+                fs::remove_dir_all(&old)
+            } else {
+                fs::remove_file(&old)
+            };
+            if let Err(err) = removed {
+                tr_warn!("Failed to remove the replaced {}: {}", escape(&old), err);
+            }
+        } else {
+            try!(fs::rename(&staging, path));
+        }
+        result
+    }
+
+    fn test_restore_file(
+        &mut self,
+        inode: &Inode,
+        hash_method: HashMethod,
+        path: &Path,
+    ) -> Result<bool, RepositoryError> {
+        try!(self.get_inode_contents(inode, &mut try!(File::create(path))));
+        self.file_matches_inode(inode, hash_method, path)
+    }
+
+    fn file_matches_inode(
+        &mut self,
+        inode: &Inode,
+        hash_method: HashMethod,
+        path: &Path,
+    ) -> Result<bool, RepositoryError> {
+        let mut file = try!(File::open(path));
+        if try!(file.metadata()).len() != inode.size {
+            return Ok(false);
+        }
+        let chunks = match inode.data {
+            None => return Ok(true),
+            Some(FileData::Inline(ref data)) => {
+                let mut restored = Vec::with_capacity(data.len());
+                try!(file.read_to_end(&mut restored));
+                return Ok(restored[..] == data[..]);
+            }
+            Some(ref data) => {
+                try!(try!(self.get_data_chunks(data)).ok_or_else(|| {
+                    InodeError::Integrity(tr!("Sparse file with inline data"))
+                }))
+            }
+        };
+        let mut verifier = ChunkVerifier::new(&chunks, hash_method);
+        if let Some(FileData::Sparse(ref sparse)) = inode.data {
+            // Only the data regions are stored, so only those are compared
+            try!(io::copy(&mut RegionReader::new(file, sparse.regions.clone()), &mut verifier));
+        } else {
+            try!(io::copy(&mut file, &mut verifier));
+        }
+        Ok(verifier.is_valid() && verifier.is_complete())
+    }
+
+    pub fn test_restore<P: AsRef<Path>>(
+        &mut self,
+        backup: &Backup,
+        inode: Inode,
+        count: usize,
+        tmp_path: P,
+    ) -> Result<(usize, Vec<PathBuf>), RepositoryError> {
+        let _lock = try!(self.lock(false));
+        // Pick random files using reservoir sampling
+        let mut rng = rand::thread_rng();
+        let mut sample = Vec::with_capacity(count);
+        let mut seen = 0;
+        let mut queue = VecDeque::new();
+        let root_path = if inode.file_type == FileType::Directory {
+            PathBuf::from("/")
+        } else {
+            Path::new("/").join(&inode.name)
+        };
+        queue.push_back((root_path, inode));
+        while let Some((path, mut inode)) = queue.pop_front() {
+            if let Some(children) = inode.children.take() {
+                for chunks in children.values() {
+                    let child = try!(self.get_inode(chunks));
+                    queue.push_back((path.join(&child.name), child));
+                }
+            }
+            // Hard links store the contents of their file as well
+            if inode.file_type != FileType::File && inode.file_type != FileType::HardLink {
+                continue;
+            }
+            seen += 1;
+            if sample.len() < count {
+                sample.push((path, inode));
+            } else {
+                let pos = rng.gen_range(0, seen);
+                if pos < count {
+                    sample[pos] = (path, inode);
+                }
+            }
+        }
+        let tmp_path = tmp_path.as_ref();
+        try!(fs::create_dir_all(tmp_path));
+        let mut failed = vec![];
+        let tested = sample.len();
+        for (i, (path, inode)) in ProgressIter::new(
+            tr!("testing restore"),
+            sample.len(),
+            sample.into_iter()
+        ).enumerate()
+        {
+            let file_path = tmp_path.join(i.to_string());
+            let result = self.test_restore_file(&inode, backup.config.hash, &file_path);
+            fs::remove_file(&file_path).ok();
+            match result {
+                Ok(true) => (),
+                Ok(false) => {
+                    tr_warn!("Restored file {} does not match the backup", escape(&path));
+                    failed.push(path);
+                }
+                Err(err) => {
+                    tr_warn!("Failed to restore file {}: {}", escape(&path), err);
+                    failed.push(path);
                 }
             }
-            is_root = false;
+        }
+        try!(fs::remove_dir(tmp_path));
+        Ok((tested, failed))
+    }
+
+    /// Finishes all open bundles if the checkpoint interval has passed or enough new bundles
+    /// have been written since the last checkpoint
+    ///
+    /// After a checkpoint, all chunks in the index are stored in finished bundles. If the
+    /// backup fails later, a repair keeps those chunks and only the data written since the
+    /// last checkpoint has to be stored again. This is also checked while storing large files.
+    pub fn write_checkpoint_if_due(&mut self) -> Result<(), RepositoryError> {
+        let due_time = self.checkpoint_interval.map_or(false, |interval| {
+            self.last_checkpoint.elapsed().as_secs() >= interval
+        });
+        let due_bundles = self.checkpoint_bundles.map_or(false, |count| {
+            self.bundle_map.len() >= self.last_checkpoint_bundles + count
+        });
+        if due_time || due_bundles {
+            tr_info!("Writing checkpoint");
+            try!(self.flush());
+            self.last_checkpoint = Instant::now();
+            self.last_checkpoint_bundles = self.bundle_map.len();
+            self.checkpoint_written = true;
         }
         Ok(())
     }
 
+    /// Writes a checkpoint if it is due and returns whether a checkpoint has been written since
+    /// the last call, so that the checkpoint journal can be updated
+    pub fn checkpoint_if_needed(&mut self) -> Result<bool, RepositoryError> {
+        try!(self.write_checkpoint_if_due());
+        Ok(mem::replace(&mut self.checkpoint_written, false))
+    }
+
+    /// Lists the paths and names of the entries of the folder that are included in a backup
+    ///
+    /// This is shared by backups and plans so that both walk the same tree. Only the tag file
+    /// of a folder marked as cache is included. Entries that can not be checked are added to
+    /// `failed_paths` unless `fail_on_error` is set.
+    fn included_children(
+        &mut self,
+        path: &Path,
+        options: &BackupOptions,
+        failed_paths: &mut Vec<PathBuf>,
+    ) -> Result<Vec<(PathBuf, String)>, RepositoryError> {
+        let is_cache = options.exclude_caches && is_cache_dir(path);
+        if is_cache {
+            self.cache_dirs.push(path.to_path_buf());
+        }
+        let mut children = vec![];
+        for ch in try!(fs::read_dir(path)) {
+            let child = try!(ch);
+            let child_path = child.path();
+            if is_cache && child.file_name() != CACHEDIR_TAG {
+                continue;
+            }
+            // The entry might have been removed or be inaccessible by now
+            match options.includes(&child, self.device_boundary.as_ref()) {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(err) => {
+                    if options.fail_on_error {
+                        return Err(err.into());
+                    }
+                    info!("Failed to backup {}: {}", escape(&child_path), err);
+                    failed_paths.push(child_path);
+                    continue;
+                }
+            }
+            let name = child.file_name().to_string_lossy().to_string();
+            children.push((child_path, name));
+        }
+        Ok(children)
+    }
+
+    /// Returns the child with the given name of the folder in the reference backup
+    fn reference_child(&mut self, reference: Option<&Inode>, name: &str) -> Option<Inode> {
+        reference
+            .and_then(|inode| inode.children.as_ref())
+            .and_then(|map| map.get(name))
+            .and_then(|chunks| self.get_inode(chunks).ok())
+    }
+
+    /// Remembers the first link of a hard linked file so that later links refer to it
+    ///
+    /// Only files with more than one link are tracked, and none at all once
+    /// `hard_link_threshold` files are tracked, which bounds the memory on huge filesystems.
+    fn track_hard_link(
+        &mut self,
+        id: (u64, u64),
+        path: &Path,
+        data: Option<FileData>,
+        options: &BackupOptions,
+    ) {
+        if let Some(threshold) = options.hard_link_threshold {
+            if self.hard_links.len() >= threshold {
+                return;
+            }
+            if self.hard_links.len() + 1 == threshold {
+                tr_warn!(
+                    "Tracking {} hard linked files, further hard links are stored as copies",
+                    threshold
+                );
+            }
+        }
+        self.hard_links.insert(id, (path.to_path_buf(), data));
+    }
+
     pub fn create_backup_recurse<P: AsRef<Path>>(
         &mut self,
         path: P,
@@ -231,7 +1189,32 @@ impl Repository {
         failed_paths: &mut Vec<PathBuf>,
     ) -> Result<Inode, RepositoryError> {
         let path = path.as_ref();
-        let mut inode = try!(self.create_inode(path, reference));
+        let meta = try!(fs::symlink_metadata(path));
+        let link_id = hard_link_id(&meta);
+        let mut inode = match link_id.and_then(|id| self.hard_links.get(&id).cloned()) {
+            Some((target, data)) => {
+                // The contents have already been stored for the first link
+                let mut inode = try!(Inode::get_from(path));
+                inode.file_type = FileType::HardLink;
+                inode.symlink_target = Some(
+                    relative_path(path.parent().unwrap_or(path), target)
+                        .to_string_lossy()
+                        .to_string()
+                );
+                inode.data = data;
+                inode
+            }
+            None => {
+                let inode = try!(self.create_inode(path, reference, options.inline_size));
+                if let Some(id) = link_id {
+                    self.track_hard_link(id, path, inode.data.clone(), options);
+                }
+                inode
+            }
+        };
+        if let Some(ref mut progress) = self.progress {
+            progress.add(inode.size, path);
+        }
         if !backup.user_names.contains_key(&inode.user) {
             if let Some(user) = users::get_user_by_uid(inode.user) {
                 backup.user_names.insert(
@@ -257,46 +1240,46 @@ impl Repository {
         if inode.file_type == FileType::Directory {
             inode.cum_dirs = 1;
             let mut children = BTreeMap::new();
-            let parent_dev = try!(path.metadata()).st_dev();
-            for ch in try!(fs::read_dir(path)) {
-                let child = try!(ch);
-                let child_path = child.path();
-                if options.same_device {
-                    let child_dev = try!(child.metadata()).st_dev();
-                    if child_dev != parent_dev {
-                        continue;
-                    }
+            for (child_path, name) in try!(self.included_children(path, options, failed_paths)) {
+                if is_interrupted() {
+                    return Err(BackupError::Interrupted.into());
                 }
-                if let Some(ref excludes) = options.excludes {
-                    let child_path_str = child_path.to_string_lossy();
-                    if excludes.is_match(&child_path_str) {
-                        continue;
+                let ref_child = self.reference_child(reference, &name);
+                let (child_inode, chunks) = match self.get_checkpoint_inode(&child_path) {
+                    Some((child_inode, chunks)) => {
+                        if let Some(ref mut progress) = self.progress {
+                            progress.add(child_inode.cum_size, &child_path);
+                        }
+                        (child_inode, chunks)
                     }
-                }
-                let name = child.file_name().to_string_lossy().to_string();
-                let ref_child = reference
-                    .as_ref()
-                    .and_then(|inode| inode.children.as_ref())
-                    .and_then(|map| map.get(&name))
-                    .and_then(|chunks| self.get_inode(chunks).ok());
-                let child_inode = match self.create_backup_recurse(
-                    &child_path,
-                    ref_child.as_ref(),
-                    options,
-                    backup,
-                    failed_paths
-                ) {
-                    Ok(inode) => inode,
-                    Err(RepositoryError::Inode(_)) |
-                    Err(RepositoryError::Chunker(_)) |
-                    Err(RepositoryError::Io(_)) => {
-                        info!("Failed to backup {:?}", child_path);
-                        failed_paths.push(child_path);
-                        continue;
+                    None => {
+                        let child_inode = match self.create_backup_recurse(
+                            &child_path,
+                            ref_child.as_ref(),
+                            options,
+                            backup,
+                            failed_paths
+                        ) {
+                            Ok(inode) => inode,
+                            Err(err @ RepositoryError::Inode(_)) |
+                            Err(err @ RepositoryError::Chunker(_)) |
+                            Err(err @ RepositoryError::Io(_)) => {
+                                if options.fail_on_error {
+                                    return Err(err);
+                                }
+                                info!("Failed to backup {}: {}", escape(&child_path), err);
+                                failed_paths.push(child_path);
+                                continue;
+                            }
+                            Err(err) => return Err(err),
+                        };
+                        let chunks = try!(self.put_inode(&child_inode));
+                        if child_inode.file_type == FileType::Directory {
+                            self.add_checkpoint_folder(&child_path, &chunks);
+                        }
+                        (child_inode, chunks)
                     }
-                    Err(err) => return Err(err),
                 };
-                let chunks = try!(self.put_inode(&child_inode));
                 inode.cum_size += child_inode.cum_size;
                 for &(_, len) in chunks.iter() {
                     meta_size += u64::from(len);
@@ -304,11 +1287,15 @@ impl Repository {
                 inode.cum_dirs += child_inode.cum_dirs;
                 inode.cum_files += child_inode.cum_files;
                 children.insert(name, chunks);
+                if try!(self.checkpoint_if_needed()) {
+                    try!(self.write_checkpoint_journal(backup));
+                }
             }
             inode.children = Some(children);
         } else {
             inode.cum_files = 1;
-            if let Some(FileData::ChunkedIndirect(ref chunks)) = inode.data {
+            let stored = inode.data.as_ref().map(FileData::stored);
+            if let Some(&FileData::ChunkedIndirect(ref chunks)) = stored {
                 for &(_, len) in chunks.iter() {
                     meta_size += u64::from(len);
                 }
@@ -325,6 +1312,131 @@ impl Repository {
         Ok(inode)
     }
 
+    fn plan_backup_recurse(
+        &mut self,
+        path: &Path,
+        reference: Option<&Inode>,
+        options: &BackupOptions,
+        entries: &mut Vec<PlannedEntry>,
+        failed_paths: &mut Vec<PathBuf>,
+    ) -> Result<(), RepositoryError> {
+        let meta = try!(fs::symlink_metadata(path));
+        let inode = try!(Inode::get_from(path));
+        let mut size = inode.size;
+        if let Some(id) = hard_link_id(&meta) {
+            // Only the first link of a file stores the data
+            if self.hard_links.contains_key(&id) {
+                size = 0;
+            } else {
+                self.track_hard_link(id, path, None, options);
+            }
+        }
+        let change = match reference {
+            Some(ref_inode) if ref_inode.is_same_meta_quick(&inode) => None,
+            Some(_) => Some(DiffType::Mod),
+            None => Some(DiffType::Add)
+        };
+        entries.push(PlannedEntry {
+            path: path.to_path_buf(),
+            file_type: inode.file_type,
+            size,
+            change
+        });
+        if inode.file_type != FileType::Directory {
+            return Ok(());
+        }
+        for (child_path, name) in try!(self.included_children(path, options, failed_paths)) {
+            if is_interrupted() {
+                return Err(BackupError::Interrupted.into());
+            }
+            let ref_child = self.reference_child(reference, &name);
+            match self.plan_backup_recurse(
+                &child_path,
+                ref_child.as_ref(),
+                options,
+                entries,
+                failed_paths
+            ) {
+                Ok(()) => (),
+                Err(err @ RepositoryError::Inode(_)) |
+                Err(err @ RepositoryError::Io(_)) => {
+                    if options.fail_on_error {
+                        return Err(err);
+                    }
+                    info!("Failed to read {}: {}", escape(&child_path), err);
+                    failed_paths.push(child_path);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Determines which paths a backup would include without storing any data
+    ///
+    /// The tree is walked with the same options as `create_backup_recursively` and every
+    /// included path is compared to the reference backup to find out whether it is new,
+    /// modified or unchanged. Paths that can not be read are returned separately.
+    pub fn plan_backup<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        reference: Option<&Backup>,
+        options: &BackupOptions,
+    ) -> Result<(Vec<PlannedEntry>, Vec<PathBuf>), RepositoryError> {
+        let _lock = try!(self.lock(false));
+        let reference_inode = reference.and_then(|b| self.get_inode(&b.root).ok());
+        let mut entries = vec![];
+        let mut failed_paths = vec![];
+        self.hard_links.clear();
+        self.cache_dirs.clear();
+        self.device_boundary = if options.same_device {
+            Some(try!(DeviceBoundary::new(path.as_ref())))
+        } else {
+            None
+        };
+        let result = self.plan_backup_recurse(
+            path.as_ref(),
+            reference_inode.as_ref(),
+            options,
+            &mut entries,
+            &mut failed_paths
+        );
+        self.hard_links.clear();
+        self.device_boundary = None;
+        try!(result);
+        Ok((entries, failed_paths))
+    }
+
+    /// Uses the chunker and compression of the options for the data of the running backup
+    ///
+    /// Bundles record their compression, so they can be read regardless of the configuration.
+    pub fn set_overrides(&mut self, options: &BackupOptions) {
+        if let Some(chunker) = options.chunker {
+            self.chunker = chunker.create();
+        }
+        self.data_compression = options.compression.clone();
+        self.auto_compression = options.auto_compression;
+        self.checkpoint_interval = options.checkpoint_interval;
+        self.checkpoint_bundles = options.checkpoint_bundles;
+        self.last_checkpoint = Instant::now();
+        self.last_checkpoint_bundles = self.bundle_map.len();
+        self.checkpoint_written = false;
+    }
+
+    /// Returns the folders whose contents have been skipped by the last backup or plan because
+    /// they are marked as caches
+    pub fn cache_dirs(&self) -> &[PathBuf] {
+        &self.cache_dirs
+    }
+
+    pub fn reset_overrides(&mut self) {
+        self.chunker = self.config.chunker.create();
+        self.data_compression = None;
+        self.auto_compression = false;
+        self.checkpoint_interval = None;
+        self.checkpoint_bundles = None;
+    }
+
     pub fn create_backup_recursively<P: AsRef<Path>>(
         &mut self,
         path: P,
@@ -342,16 +1454,50 @@ impl Repository {
         backup.config = self.config.clone();
         backup.host = get_hostname().unwrap_or_else(|_| "".to_string());
         backup.path = path.as_ref().to_string_lossy().to_string();
+        backup.version = Some(env!("CARGO_PKG_VERSION").to_string());
         let info_before = self.info();
         let start = Local::now();
         let mut failed_paths = vec![];
-        let root_inode = try!(self.create_backup_recurse(
+        self.hard_links.clear();
+        self.cache_dirs.clear();
+        self.device_boundary = if options.same_device {
+            Some(try!(DeviceBoundary::new(path.as_ref())))
+        } else {
+            None
+        };
+        if let Some(ref name) = options.journal {
+            try!(self.start_checkpoint_journal(name, path.as_ref(), options.resume));
+        }
+        self.written_chunks = if options.verify { Some(vec![]) } else { None };
+        // The size of the reference backup is the best guess for the size of this one
+        self.progress = if self.show_progress {
+            Some(ProgressReporter::new(tr!("backup"), reference.map_or(0, |b| b.total_data_size)))
+        } else {
+            None
+        };
+        self.set_overrides(options);
+        let root_inode = self.create_backup_recurse(
             path,
             reference_inode.as_ref(),
             options,
             &mut backup,
             &mut failed_paths
-        ));
+        );
+        if let Err(RepositoryError::Backup(BackupError::Interrupted)) = root_inode {
+            // All chunks in the index are stored in finished bundles after the flush, so the
+            // repository is consistent and the backup can be resumed from the journal
+            tr_info!("Storing the data written so far");
+            self.dirty = false;
+            try!(self.flush());
+            try!(self.write_checkpoint_journal(&backup));
+        }
+        self.reset_overrides();
+        self.hard_links.clear();
+        self.device_boundary = None;
+        self.progress = None;
+        self.restore_checkpoint_names(&mut backup);
+        self.journal = None;
+        let root_inode = try!(root_inode);
         backup.root = try!(self.put_inode(&root_inode));
         try!(self.flush());
         let elapsed = Local::now().signed_duration_since(start);
@@ -368,7 +1514,16 @@ impl Repository {
         backup.encoded_data_size = info_after.encoded_data_size - info_before.encoded_data_size;
         backup.bundle_count = info_after.bundle_count - info_before.bundle_count;
         backup.chunk_count = info_after.chunk_count - info_before.chunk_count;
-        backup.avg_chunk_size = backup.deduplicated_data_size as f32 / backup.chunk_count as f32;
+        // Backups of data that is already stored add no chunks
+        backup.avg_chunk_size = if backup.chunk_count > 0 {
+            backup.deduplicated_data_size as f32 / backup.chunk_count as f32
+        } else {
+            0.0
+        };
+        if options.verify {
+            // A failed verification leaves the repository dirty so that it gets checked
+            try!(self.verify_written_chunks());
+        }
         self.dirty = false;
         if failed_paths.is_empty() {
             Ok(backup)
@@ -377,33 +1532,54 @@ impl Repository {
         }
     }
 
+    /// Removes the file or folder at `path` with all its contents from the backup
+    ///
+    /// The folders above it are stored again with updated sizes and counts, as are the totals of
+    /// the backup. The chunks of the removed inodes are not deleted, they are reclaimed by a
+    /// later vacuum if no other backup uses them. The backup has to be saved afterwards.
     pub fn remove_backup_path<P: AsRef<Path>>(
         &mut self,
         backup: &mut Backup,
         path: P,
     ) -> Result<(), RepositoryError> {
+        try!(self.check_append_only());
         try!(self.write_mode());
         let _lock = try!(self.lock(false));
         let mut inodes = try!(self.get_backup_path(backup, path));
         let to_remove = inodes.pop().unwrap();
-        let mut remove_from = match inodes.pop() {
+        let mut inode = match inodes.pop() {
             Some(inode) => inode,
             None => return Err(BackupError::RemoveRoot.into()),
         };
-        remove_from.children.as_mut().unwrap().remove(
-            &to_remove.name
-        );
-        let mut last_inode_chunks = try!(self.put_inode(&remove_from));
-        let mut last_inode_name = remove_from.name;
-        while let Some(mut inode) = inodes.pop() {
-            inode.children.as_mut().unwrap().insert(
-                last_inode_name,
-                last_inode_chunks
-            );
-            last_inode_chunks = try!(self.put_inode(&inode));
-            last_inode_name = inode.name;
+        let mut old_size = inode.cum_size;
+        let removed_chunks = inode.children.as_mut().unwrap().remove(&to_remove.name);
+        let removed_size = to_remove.cum_size + removed_chunks.map_or(0, |c| chunk_list_size(&c));
+        inode.cum_size = inode.cum_size.saturating_sub(removed_size);
+        inode.cum_dirs = inode.cum_dirs.saturating_sub(to_remove.cum_dirs);
+        inode.cum_files = inode.cum_files.saturating_sub(to_remove.cum_files);
+        let mut chunks = try!(self.put_inode(&inode));
+        while let Some(mut parent) = inodes.pop() {
+            // The size of a folder includes the stored inodes of its children
+            let old_chunks_size = {
+                let children = parent.children.as_ref().unwrap();
+                children.get(&inode.name).map_or(0, |c| chunk_list_size(c))
+            };
+            let parent_size = parent.cum_size;
+            parent.cum_size = (parent.cum_size + inode.cum_size + chunk_list_size(&chunks))
+                .saturating_sub(old_size + old_chunks_size);
+            parent.cum_dirs = parent.cum_dirs.saturating_sub(to_remove.cum_dirs);
+            parent.cum_files = parent.cum_files.saturating_sub(to_remove.cum_files);
+            parent.children.as_mut().unwrap().insert(inode.name, chunks);
+            chunks = try!(self.put_inode(&parent));
+            old_size = parent_size;
+            inode = parent;
         }
-        backup.root = last_inode_chunks;
+        // The new inodes must be stored before the backup refers to them
+        try!(self.flush());
+        backup.total_data_size = inode.cum_size + chunk_list_size(&chunks);
+        backup.file_count = inode.cum_files;
+        backup.dir_count = inode.cum_dirs;
+        backup.root = chunks;
         backup.modified = true;
         Ok(())
     }
@@ -423,8 +1599,8 @@ impl Repository {
                 {
                     return Ok(vec![inode]);
                 }
-                if let Some(chunks) = inode.children.as_mut().and_then(
-                    |c| c.remove(&name as &str)
+                if let Some(chunks) = inode.children.as_ref().and_then(
+                    |c| c.get(&name as &str).cloned()
                 )
                 {
                     inodes.push(inode);
@@ -526,6 +1702,91 @@ impl Repository {
         Ok(())
     }
 
+    fn compare_with_path_recurse(
+        &mut self,
+        inode: &Inode,
+        live_path: &Path,
+        path: PathBuf,
+        hash_method: HashMethod,
+        diffs: &mut Vec<(DiffType, PathBuf)>,
+    ) -> Result<(), RepositoryError> {
+        let mut live = match Inode::get_from(live_path) {
+            Ok(live) => live,
+            Err(err) => {
+                tr_warn!("Failed to read {}: {}", escape(&live_path), err);
+                diffs.push((DiffType::Mod, path));
+                return Ok(());
+            }
+        };
+        // The name of the root may differ, all other names match by construction
+        live.name = inode.name.clone();
+        if inode.file_type == FileType::HardLink && live.file_type == FileType::File {
+            live.file_type = FileType::HardLink;
+            live.symlink_target = inode.symlink_target.clone();
+        }
+        let mut same = inode.is_same_meta(&live);
+        if same && (inode.file_type == FileType::File || inode.file_type == FileType::HardLink) {
+            same = match self.file_matches_inode(inode, hash_method, live_path) {
+                Ok(same) => same,
+                Err(err) => {
+                    tr_warn!("Failed to compare contents of {}: {}", escape(&live_path), err);
+                    false
+                }
+            };
+        }
+        if !same {
+            diffs.push((DiffType::Mod, path.clone()));
+        }
+        if inode.file_type != FileType::Directory || live.file_type != FileType::Directory {
+            return Ok(());
+        }
+        let mut live_names = BTreeMap::new();
+        for entry in try!(fs::read_dir(live_path)) {
+            let entry = try!(entry);
+            live_names.insert(entry.file_name().to_string_lossy().to_string(), entry.path());
+        }
+        if let Some(ref children) = inode.children {
+            for (name, chunks) in children {
+                if let Some(child_path) = live_names.remove(name) {
+                    let child = try!(self.get_inode(chunks));
+                    try!(self.compare_with_path_recurse(
+                        &child,
+                        &child_path,
+                        path.join(name),
+                        hash_method,
+                        diffs
+                    ));
+                } else {
+                    diffs.push((DiffType::Del, path.join(name)));
+                }
+            }
+        }
+        for name in live_names.keys() {
+            diffs.push((DiffType::Add, path.join(name)));
+        }
+        Ok(())
+    }
+
+    /// Compares a backup or subtree with files on the filesystem, reporting entries that are
+    /// new on disk (`Add`), missing on disk (`Del`) or differ in metadata or contents (`Mod`)
+    pub fn compare_with_path<P: AsRef<Path>>(
+        &mut self,
+        backup: &Backup,
+        inode: &Inode,
+        path: P,
+    ) -> Result<Vec<(DiffType, PathBuf)>, RepositoryError> {
+        let _lock = try!(self.lock(false));
+        let mut diffs = vec![];
+        try!(self.compare_with_path_recurse(
+            inode,
+            path.as_ref(),
+            PathBuf::from("/"),
+            backup.config.hash,
+            &mut diffs
+        ));
+        Ok(diffs)
+    }
+
     #[inline]
     pub fn find_differences(
         &mut self,
@@ -543,6 +1804,33 @@ impl Repository {
         Ok(diffs)
     }
 
+    /// Calls `f` with the path of `inode` and of every inode below it
+    ///
+    /// The tree is walked depth-first with the children in the order of their names. Only the
+    /// inodes on the current path are loaded at any time, so this also works on huge backups.
+    /// Returns the number of visited inodes.
+    pub fn walk_inodes<F>(&mut self, inode: &Inode, path: &Path, mut f: F) -> Result<usize, RepositoryError>
+    where
+        F: FnMut(&Path, &Inode) -> Result<(), RepositoryError>,
+    {
+        self.walk_inodes_recurse(inode, path, &mut f)
+    }
+
+    fn walk_inodes_recurse<F>(&mut self, inode: &Inode, path: &Path, f: &mut F) -> Result<usize, RepositoryError>
+    where
+        F: FnMut(&Path, &Inode) -> Result<(), RepositoryError>,
+    {
+        try!(f(path, inode));
+        let mut count = 1;
+        if let Some(ref children) = inode.children {
+            for (name, chunks) in children {
+                let child = try!(self.get_inode(chunks));
+                count += try!(self.walk_inodes_recurse(&child, &path.join(name), f));
+            }
+        }
+        Ok(count)
+    }
+
     fn count_sizes_recursive(&mut self, inode: &Inode, sizes: &mut HashMap<u64, usize>, min_size: u64) -> Result<(), RepositoryError> {
         if inode.size >= min_size {
             *sizes.entry(inode.size).or_insert(0) += 1;
@@ -574,6 +1862,69 @@ impl Repository {
         Ok(())
     }
 
+    fn directory_sizes_recurse(
+        &mut self,
+        inode: &Inode,
+        path: &Path,
+        sizes: &mut Vec<DirectorySize>,
+    ) -> Result<(u64, u64, HashMap<Hash, u32>), RepositoryError> {
+        let mut size = 0;
+        let mut inline_size = 0;
+        let mut chunks = HashMap::new();
+        if inode.file_type == FileType::File || inode.file_type == FileType::HardLink {
+            size = inode.size;
+        }
+        if let Some(ref data) = inode.data {
+            if let FileData::Inline(ref data) = *data.stored() {
+                inline_size = data.len() as u64;
+            } else if let Some(list) = try!(self.get_data_chunks(data)) {
+                chunks.extend(list.iter().cloned());
+            }
+        }
+        if inode.file_type != FileType::Directory {
+            return Ok((size, inline_size, chunks));
+        }
+        let pos = sizes.len();
+        sizes.push(DirectorySize {
+            path: path.to_path_buf(),
+            size: 0,
+            dedup_size: 0
+        });
+        if let Some(ref children) = inode.children {
+            for (name, child) in children {
+                let child = try!(self.get_inode(child));
+                let (child_size, child_inline_size, mut child_chunks) =
+                    try!(self.directory_sizes_recurse(&child, &path.join(name), sizes));
+                size += child_size;
+                inline_size += child_inline_size;
+                // Merge the smaller set into the larger one
+                if child_chunks.len() > chunks.len() {
+                    mem::swap(&mut chunks, &mut child_chunks);
+                }
+                chunks.extend(child_chunks);
+            }
+        }
+        sizes[pos].size = size;
+        let chunk_size: u64 = chunks.values().map(|&len| u64::from(len)).sum();
+        sizes[pos].dedup_size = inline_size + chunk_size;
+        Ok((size, inline_size, chunks))
+    }
+
+    /// Calculates the apparent and deduplicated size of `inode` and all directories below it
+    ///
+    /// The deduplicated size counts every chunk once, even if several files in the subtree
+    /// reference it, so it is the amount of data that this subtree stores in the repository.
+    /// Every directory is listed before its subdirectories.
+    pub fn directory_sizes(
+        &mut self,
+        inode: &Inode,
+        path: &Path,
+    ) -> Result<Vec<DirectorySize>, RepositoryError> {
+        let mut sizes = vec![];
+        try!(self.directory_sizes_recurse(inode, path, &mut sizes));
+        Ok(sizes)
+    }
+
     pub fn find_duplicates(&mut self, inode: &Inode, min_size: u64) -> Result<Vec<(Vec<PathBuf>, u64)>, RepositoryError> {
         let mut sizes = HashMap::new();
         try!(self.count_sizes_recursive(inode, &mut sizes, min_size));
@@ -588,3 +1939,554 @@ impl Repository {
         Ok(dups)
     }
 }
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use repository::tests::temp_dir;
+
+    #[allow(unused_imports)]
+    use std::io::Write;
+
+
+    #[allow(dead_code)]
+    fn write_file(path: &Path, data: &[u8]) {
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+
+    #[test]
+    fn test_path_selection() {
+        let paths = vec!["a/b", "/src/c", "../x", "/other", "d/../../y"];
+        let (selection, outside) = PathSelection::new("/src", paths.into_iter().map(PathBuf::from).collect());
+        assert_eq!(outside, vec![PathBuf::from("../x"), PathBuf::from("/other"), PathBuf::from("d/../../y")]);
+        // Parent folders are included without their other contents
+        for path in &["/src", "/src/a", "/src/a/b", "/src/a/b/deep/file", "/src/./a/b", "/src/c", "/src/c/file"] {
+            assert!(selection.includes(Path::new(path)), "{} should be included", path);
+        }
+        for path in &["/", "/src/a/other", "/src/d", "/src/cc", "/other", "/x"] {
+            assert!(!selection.includes(Path::new(path)), "{} should not be included", path);
+        }
+    }
+
+    #[test]
+    fn test_remove_backup_path() {
+        let base = temp_dir("remove-path");
+        let src = base.join("src");
+        fs::create_dir_all(src.join("keep")).unwrap();
+        fs::create_dir_all(src.join("drop/sub")).unwrap();
+        write_file(&src.join("keep/a"), &[1; 1000]);
+        write_file(&src.join("drop/b"), &[2; 2000]);
+        write_file(&src.join("drop/sub/c"), &[3; 3000]);
+        fs::create_dir(base.join("remote")).unwrap();
+        let config = Config::default();
+        let mut repo = Repository::create(base.join("repo"), &config, base.join("remote")).unwrap();
+        let options = BackupOptions::default();
+        let mut backup = repo.create_backup_recursively(&src, None, &options).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        assert_eq!((backup.file_count, backup.dir_count), (3, 4));
+        repo.remove_backup_path(&mut backup, "drop").unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        let backup = repo.get_backup("test").unwrap();
+        assert_eq!((backup.file_count, backup.dir_count), (1, 2));
+        assert!(repo.get_backup_inode(&backup, "drop/b").is_err());
+        let root = repo.get_inode(&backup.root).unwrap();
+        let mut paths = vec![];
+        repo.walk_inodes(&root, Path::new("/"), |path, _| {
+            paths.push(path.to_path_buf());
+            Ok(())
+        }).unwrap();
+        assert_eq!(paths, vec![PathBuf::from("/"), PathBuf::from("/keep"), PathBuf::from("/keep/a")]);
+        // Only the remaining file and the stored inodes are counted
+        assert_eq!(root.cum_size, backup.total_data_size - chunk_list_size(&backup.root));
+        assert!(root.cum_size > 1000 && root.cum_size < 3000);
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_rename_backup() {
+        let base = temp_dir("rename-backup");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        write_file(&src.join("a"), &[1; 1000]);
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let options = BackupOptions::default();
+        let first = repo.create_backup_recursively(&src, None, &options).unwrap();
+        repo.save_backup(&first, "first").unwrap();
+        write_file(&src.join("b"), &[2; 1000]);
+        let second = repo.create_backup_recursively(&src, None, &options).unwrap();
+        repo.save_backup(&second, "second").unwrap();
+        match repo.rename_backup("first", "second") {
+            Err(RepositoryError::Backup(BackupError::AlreadyExists(ref name))) => assert_eq!(name, "second"),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(()) => panic!("existing backup has been replaced")
+        }
+        assert_eq!(repo.get_backup("first").unwrap().file_count, 1);
+        assert_eq!(repo.get_backup("second").unwrap().file_count, 2);
+        repo.rename_backup("first", "old/first").unwrap();
+        assert!(!repo.has_backup("first"));
+        assert_eq!(repo.get_backup("old/first").unwrap().file_count, 1);
+        match repo.rename_backup("first", "third") {
+            Err(RepositoryError::Backup(BackupError::NoSuchBackup(_))) => (),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(()) => panic!("missing backup has been renamed")
+        }
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoints_in_large_files() {
+        let base = temp_dir("checkpoint-file");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..1_000_000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect();
+        write_file(&src.join("file"), &data);
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let mut options = BackupOptions::default();
+        options.checkpoint_interval = Some(0);
+        options.chunker = Some(ChunkerType::Fixed(16 * 1024));
+        let backup = repo.create_backup_recursively(&src, None, &options).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        // Every chunk of the file has been followed by a checkpoint
+        assert!(repo.info().bundle_count >= data.len() / (16 * 1024));
+        // A checkpoint is due once the given number of bundles has been written
+        let mut options = BackupOptions::default();
+        options.checkpoint_bundles = Some(1);
+        repo.set_overrides(&options);
+        repo.put_data(BundleMode::Data, &[7; 1000]).unwrap();
+        assert!(!repo.checkpoint_if_needed().unwrap());
+        repo.flush().unwrap();
+        assert!(repo.checkpoint_if_needed().unwrap());
+        assert!(!repo.checkpoint_if_needed().unwrap());
+        repo.reset_overrides();
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_empty_and_special_files() {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::FileTypeExt;
+        let base = temp_dir("empty-files");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        write_file(&src.join("empty"), &[]);
+        write_file(&src.join("tiny1"), b"tiny");
+        write_file(&src.join("tiny2"), b"tiny");
+        let fifo = ::std::ffi::CString::new(src.join("fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { ::libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        // Neither empty files nor special files have any contents
+        let empty = repo.get_backup_inode(&backup, "empty").unwrap();
+        assert_eq!((empty.file_type, empty.size), (FileType::File, 0));
+        assert!(empty.data.is_none());
+        let fifo = repo.get_backup_inode(&backup, "fifo").unwrap();
+        assert_eq!(fifo.file_type, FileType::NamedPipe);
+        assert!(fifo.data.is_none());
+        for name in &["tiny1", "tiny2"] {
+            match repo.get_backup_inode(&backup, name).unwrap().data {
+                Some(FileData::Inline(ref data)) => assert_eq!(&data[..], b"tiny"),
+                _ => panic!("tiny file has not been stored inline")
+            }
+        }
+        // Only inode metadata has been stored, no data bundle
+        assert!(repo.list_bundles().iter().all(|bundle| bundle.mode == BundleMode::Meta));
+        let root = repo.get_inode(&backup.root).unwrap();
+        fs::create_dir(base.join("restored")).unwrap();
+        repo.restore_inode_tree(&backup, root, base.join("restored"), RestoreOwner::Current, false).unwrap();
+        let meta = fs::symlink_metadata(base.join("restored/empty")).unwrap();
+        assert!(meta.is_file());
+        assert_eq!(meta.len(), 0);
+        assert!(fs::symlink_metadata(base.join("restored/fifo")).unwrap().file_type().is_fifo());
+        let mut data = vec![];
+        File::open(base.join("restored/tiny2")).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(&data[..], b"tiny");
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_directory_sizes() {
+        let base = temp_dir("directory-sizes");
+        let src = base.join("src");
+        fs::create_dir_all(src.join("a")).unwrap();
+        fs::create_dir_all(src.join("b")).unwrap();
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..8000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect();
+        write_file(&src.join("a/x"), &data[..5000]);
+        write_file(&src.join("b/y"), &data[..5000]);
+        write_file(&src.join("b/z"), &data[5000..]);
+        fs::create_dir(base.join("remote")).unwrap();
+        let config = Config::default();
+        let mut repo = Repository::create(base.join("repo"), &config, base.join("remote")).unwrap();
+        let options = BackupOptions::default();
+        let backup = repo.create_backup_recursively(&src, None, &options).unwrap();
+        let root = repo.get_inode(&backup.root).unwrap();
+        let sizes = repo.directory_sizes(&root, Path::new("/")).unwrap();
+        let sizes: Vec<_> = sizes.into_iter().map(|s| (s.path, s.size, s.dedup_size)).collect();
+        assert_eq!(sizes, vec![
+            (PathBuf::from("/"), 13000, 8000),
+            (PathBuf::from("/a"), 5000, 5000),
+            (PathBuf::from("/b"), 8000, 8000)
+        ]);
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_hard_links() {
+        let base = temp_dir("hard-links");
+        let src = base.join("src");
+        fs::create_dir_all(src.join("a")).unwrap();
+        fs::create_dir_all(src.join("b")).unwrap();
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..5000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect();
+        write_file(&src.join("a/file"), &data);
+        fs::hard_link(src.join("a/file"), src.join("a/link")).unwrap();
+        fs::hard_link(src.join("a/file"), src.join("b/link")).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        // Only the first link that has been seen is stored as a file
+        let mut links = 0;
+        for path in &["a/file", "a/link", "b/link"] {
+            if repo.get_backup_inode(&backup, path).unwrap().file_type == FileType::HardLink {
+                links += 1;
+            }
+        }
+        assert_eq!(links, 2);
+        let root = repo.get_inode(&backup.root).unwrap();
+        let sizes = repo.directory_sizes(&root, Path::new("/")).unwrap();
+        let sizes: Vec<_> = sizes.into_iter().map(|s| (s.path, s.size, s.dedup_size)).collect();
+        assert_eq!(sizes, vec![
+            (PathBuf::from("/"), 15000, 5000),
+            (PathBuf::from("/a"), 10000, 5000),
+            (PathBuf::from("/b"), 5000, 5000)
+        ]);
+        let (tested, failed) = repo.test_restore(&backup, root, 10, base.join("test")).unwrap();
+        assert_eq!((tested, failed.len()), (3, 0));
+        let root = repo.get_inode(&backup.root).unwrap();
+        fs::create_dir(base.join("restored")).unwrap();
+        repo.restore_inode_tree(&backup, root, base.join("restored"), RestoreOwner::Current, false).unwrap();
+        let restored = base.join("restored");
+        let ino = fs::metadata(restored.join("a/file")).unwrap().st_ino();
+        for path in &["a/file", "a/link", "b/link"] {
+            let meta = fs::symlink_metadata(restored.join(path)).unwrap();
+            assert!(meta.is_file());
+            assert_eq!((meta.st_ino(), meta.st_nlink()), (ino, 3));
+            let mut contents = vec![];
+            File::open(restored.join(path)).unwrap().read_to_end(&mut contents).unwrap();
+            assert!(contents == data);
+        }
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_hard_link_threshold() {
+        let base = temp_dir("hard-link-threshold");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        for name in &["x", "y"] {
+            write_file(&src.join(name), name.as_bytes());
+            fs::hard_link(src.join(name), src.join(format!("{}-link", name))).unwrap();
+        }
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let mut options = BackupOptions::default();
+        options.hard_link_threshold = Some(1);
+        let backup = repo.create_backup_recursively(&src, None, &options).unwrap();
+        // Only the first linked file is tracked, the links of the other one are stored as copies
+        let mut links = 0;
+        for path in &["x", "x-link", "y", "y-link"] {
+            if repo.get_backup_inode(&backup, path).unwrap().file_type == FileType::HardLink {
+                links += 1;
+            }
+        }
+        assert_eq!(links, 1);
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_interleaved_bundle_reads() {
+        let base = temp_dir("interleaved-reads");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut config = Config::default();
+        config.chunker = ChunkerType::Fixed(4096);
+        let mut repo = Repository::create(base.join("repo"), &config, base.join("remote")).unwrap();
+        let options = BackupOptions::default();
+        // Two files that end up in two different bundles
+        let mut parts = vec![];
+        for seed in 1..3u32 {
+            let mut state = seed;
+            let data: Vec<u8> = (0..16 * 4096).map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            }).collect();
+            write_file(&src.join("file"), &data);
+            repo.create_backup_recursively(&src, None, &options).unwrap();
+            repo.flush().unwrap();
+            parts.push(data);
+        }
+        // A file whose chunks alternate between both bundles
+        let mut data = vec![];
+        for i in 0..16 {
+            for part in &parts {
+                data.extend_from_slice(&part[i * 4096..(i + 1) * 4096]);
+            }
+        }
+        write_file(&src.join("file"), &data);
+        let backup = repo.create_backup_recursively(&src, None, &options).unwrap();
+        repo.flush().unwrap();
+        let inode = repo.get_backup_inode(&backup, "file").unwrap();
+        let chunks = repo.get_data_chunks(inode.data.as_ref().unwrap()).unwrap().unwrap();
+        // The fixed chunker ends the file with an empty chunk
+        let bundles: Vec<u32> = chunks.iter()
+            .filter(|&&(_, len)| len > 0)
+            .map(|&(hash, _)| repo.index.get(&hash).unwrap().bundle)
+            .collect();
+        assert_eq!(bundles.len(), 32);
+        for pair in bundles.windows(2) {
+            assert!(pair[0] != pair[1]);
+        }
+        let mut run = 0;
+        for &(threads, cache_size) in &[(1, 0), (1, 1 << 20), (4, 0), (4, 1 << 20)] {
+            repo.set_threads(threads);
+            repo.set_cache_size(cache_size);
+            run += 1;
+            let restored = base.join(format!("restored{}", run));
+            fs::create_dir(&restored).unwrap();
+            let root = repo.get_inode(&backup.root).unwrap();
+            repo.restore_inode_tree(&backup, root, &restored, RestoreOwner::Current, false).unwrap();
+            let mut contents = vec![];
+            File::open(restored.join("file")).unwrap().read_to_end(&mut contents).unwrap();
+            assert!(contents == data);
+        }
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_dangling_symlinks() {
+        let base = temp_dir("dangling-symlinks");
+        let src = base.join("src");
+        fs::create_dir_all(src.join("dir")).unwrap();
+        ::std::os::unix::fs::symlink("../missing", src.join("dir/relative")).unwrap();
+        ::std::os::unix::fs::symlink("/does/not/exist", src.join("absolute")).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        let backup = repo.get_backup("test").unwrap();
+        let inode = repo.get_backup_inode(&backup, "dir/relative").unwrap();
+        assert_eq!(inode.file_type, FileType::Symlink);
+        assert_eq!(inode.symlink_target, Some("../missing".to_string()));
+        let restored = base.join("restored");
+        fs::create_dir(&restored).unwrap();
+        let root = repo.get_inode(&backup.root).unwrap();
+        repo.restore_inode_tree(&backup, root, &restored, RestoreOwner::Current, false).unwrap();
+        for &(path, target) in &[("dir/relative", "../missing"), ("absolute", "/does/not/exist")] {
+            assert!(fs::symlink_metadata(restored.join(path)).unwrap().file_type().is_symlink());
+            assert!(!restored.join(path).exists());
+            assert_eq!(fs::read_link(restored.join(path)).unwrap(), Path::new(target));
+        }
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_restore_owner() {
+        let base = temp_dir("restore-owner");
+        let src = base.join("src");
+        fs::create_dir_all(src.join("dir")).unwrap();
+        write_file(&src.join("dir/file"), &[1; 1000]);
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+        let (uid, gid) = unsafe { (::libc::getuid(), ::libc::getgid()) };
+        // Only root can give files to other users
+        let (user, group) = if uid == 0 { (12345, 23456) } else { (uid, gid) };
+        let restored = base.join("restored");
+        fs::create_dir(&restored).unwrap();
+        let root = repo.get_inode(&backup.root).unwrap();
+        repo.restore_inode_tree(&backup, root, &restored, RestoreOwner::Fixed(user, group), false).unwrap();
+        for path in &["dir", "dir/file"] {
+            let meta = fs::symlink_metadata(restored.join(path)).unwrap();
+            assert_eq!((meta.st_uid(), meta.st_gid()), (user, group));
+        }
+        if uid != 0 {
+            let failed = base.join("failed");
+            fs::create_dir(&failed).unwrap();
+            let root = repo.get_inode(&backup.root).unwrap();
+            let res = repo.restore_inode_tree(&backup, root, &failed, RestoreOwner::Fixed(12345, 23456), false);
+            assert!(res.is_err());
+        }
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_prune_size_overrides_policy() {
+        let base = temp_dir("prune-size");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let now = Local::now().timestamp();
+        for i in 0..3u8 {
+            write_file(&src.join("file"), &[i; 10_000]);
+            let mut backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+            backup.timestamp = now - 3600 * i64::from(3 - i);
+            repo.save_backup(&backup, &format!("backup{}", i)).unwrap();
+        }
+        let mut policy = RetentionPolicy::default();
+        policy.keep_last = 3;
+        repo.prune_backups("", &policy, None, true).unwrap();
+        assert_eq!(repo.get_all_backups().unwrap().len(), 3);
+        repo.prune_backups("", &policy, Some(0), true).unwrap();
+        let names: Vec<_> = repo.get_all_backups().unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["backup2".to_string()]);
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_exclude_caches() {
+        let base = temp_dir("exclude-caches");
+        let src = base.join("src");
+        for dir in &["cache/sub", "wrong", "link"] {
+            fs::create_dir_all(src.join(dir)).unwrap();
+            write_file(&src.join(dir).join("data"), &[1; 100]);
+        }
+        write_file(&src.join("cache/CACHEDIR.TAG"), b"Signature: 8a477f597d28d172789f06886806bc55\n# comment");
+        write_file(&src.join("wrong/CACHEDIR.TAG"), b"Signature: 0000");
+        ::std::os::unix::fs::symlink("../cache/CACHEDIR.TAG", src.join("link/CACHEDIR.TAG")).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let config = Config::default();
+        let mut repo = Repository::create(base.join("repo"), &config, base.join("remote")).unwrap();
+        let mut options = BackupOptions::default();
+        options.exclude_caches = true;
+        let (entries, failed_paths) = repo.plan_backup(&src, None, &options).unwrap();
+        let mut paths: Vec<_> = entries.iter()
+            .map(|entry| entry.path.strip_prefix(&src).unwrap().to_path_buf())
+            .collect();
+        paths.sort();
+        let expected: Vec<PathBuf> = vec![
+            "", "cache", "cache/CACHEDIR.TAG", "link", "link/CACHEDIR.TAG", "link/data", "wrong",
+            "wrong/CACHEDIR.TAG", "wrong/data"
+        ].into_iter().map(PathBuf::from).collect();
+        assert_eq!(paths, expected);
+        assert!(failed_paths.is_empty());
+        assert_eq!(repo.cache_dirs(), &[src.join("cache")]);
+        let backup = repo.create_backup_recursively(&src, None, &options).unwrap();
+        assert_eq!((backup.file_count, backup.dir_count), (5, 4));
+        assert_eq!(repo.cache_dirs(), &[src.join("cache")]);
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_plan_with_reference() {
+        let base = temp_dir("plan-reference");
+        let src = base.join("src");
+        fs::create_dir_all(src.join("dir")).unwrap();
+        write_file(&src.join("dir/same"), &[1; 1000]);
+        write_file(&src.join("changed"), &[2; 1000]);
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let options = BackupOptions::default();
+        let reference = repo.create_backup_recursively(&src, None, &options).unwrap();
+        write_file(&src.join("changed"), &[2; 2000]);
+        write_file(&src.join("dir/new"), &[3; 1000]);
+        let (entries, failed_paths) = repo.plan_backup(&src, Some(&reference), &options).unwrap();
+        assert!(failed_paths.is_empty());
+        let mut changes: Vec<_> = entries.iter()
+            .filter(|entry| entry.file_type == FileType::File)
+            .map(|entry| {
+                let change = match entry.change {
+                    None => "unchanged",
+                    Some(DiffType::Add) => "new",
+                    Some(DiffType::Mod) => "modified",
+                    Some(DiffType::Del) => "deleted"
+                };
+                (entry.path.strip_prefix(&src).unwrap().to_path_buf(), change)
+            })
+            .collect();
+        changes.sort();
+        assert_eq!(changes, vec![
+            (PathBuf::from("changed"), "modified"),
+            (PathBuf::from("dir/new"), "new"),
+            (PathBuf::from("dir/same"), "unchanged")
+        ]);
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[allow(dead_code)]
+    fn fake_device_of(path: &Path) -> Result<u64, io::Error> {
+        try!(fs::symlink_metadata(path));
+        Ok(if path.ends_with("other") { 2 } else { 1 })
+    }
+
+    #[test]
+    fn test_device_boundary() {
+        let base = temp_dir("device-boundary");
+        let src = base.join("src");
+        for dir in &["keep", "keep/other", "other", "bind", "mnt"] {
+            fs::create_dir_all(src.join(dir)).unwrap();
+            write_file(&src.join(dir).join("file"), &[1; 100]);
+        }
+        fs::create_dir(base.join("remote")).unwrap();
+        let config = Config::default();
+        let mut repo = Repository::create(base.join("repo"), &config, base.join("remote")).unwrap();
+        // The bind mount has the same device as the source, the mount point of the source itself
+        // and mount points outside of it are ignored
+        let canonical_src = fs::canonicalize(&src).unwrap();
+        let mount_points = vec![canonical_src.join("bind"), canonical_src.clone(), base.join("mnt")];
+        let boundary = DeviceBoundary::with_device_of(&src, mount_points, fake_device_of).unwrap();
+        assert!(!boundary.includes(&src.join("bind")).unwrap());
+        assert!(!boundary.includes(&src.join("keep/other")).unwrap());
+        assert!(boundary.includes(&src.join("mnt")).unwrap());
+        repo.device_boundary = Some(boundary);
+        let options = BackupOptions::default();
+        let mut entries = vec![];
+        let mut failed_paths = vec![];
+        repo.plan_backup_recurse(&src, None, &options, &mut entries, &mut failed_paths).unwrap();
+        let mut paths: Vec<_> = entries.iter()
+            .map(|entry| entry.path.strip_prefix(&src).unwrap().to_path_buf())
+            .collect();
+        paths.sort();
+        let expected: Vec<PathBuf> = vec!["", "keep", "keep/file", "mnt", "mnt/file"]
+            .into_iter().map(PathBuf::from).collect();
+        assert_eq!(paths, expected);
+        assert!(failed_paths.is_empty());
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+}
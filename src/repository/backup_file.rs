@@ -3,7 +3,9 @@ use prelude::*;
 use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
+
+use serde_bytes::ByteBuf;
 
 
 static HEADER_STRING: [u8; 7] = *b"zvault\x03";
@@ -55,6 +57,15 @@ quick_error!{
             description(tr!("Encryption failed"))
             display("{}", tr_format!("Backup file error: encryption failed\n\tcaused by: {}", err))
         }
+        NotSigned {
+            description(tr!("Backup is not signed"))
+            display("{}", tr_format!("Backup file error: the backup is not signed"))
+        }
+        Signature(err: EncryptionError) {
+            cause(err)
+            description(tr!("Failed to check signature"))
+            display("{}", tr_format!("Backup file error: failed to check the signature\n\tcaused by: {}", err))
+        }
         PartialBackupsList(partial: HashMap<String, Backup>, failed: Vec<PathBuf>) {
             description(tr!("Some backups could not be loaded"))
             display("{}", tr_format!("Backup file error: some backups could not be loaded: {:?}", failed))
@@ -71,6 +82,21 @@ serde_impl!(BackupHeader(u8) {
 });
 
 
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct BackupSignature {
+    /// Public key of the key pair whose secret key made the signature
+    pub key: ByteBuf,
+    pub signature: ByteBuf,
+    /// Public key that checks the signature without needing the secret key
+    pub verify_key: ByteBuf
+}
+serde_impl!(BackupSignature(u8) {
+    key: ByteBuf => 0,
+    signature: ByteBuf => 1,
+    verify_key: ByteBuf => 2
+});
+
+
 #[derive(Default, Debug, Clone)]
 pub struct Backup {
     pub root: ChunkList,
@@ -90,7 +116,11 @@ pub struct Backup {
     pub config: Config,
     pub modified: bool,
     pub user_names: HashMap<u32, String>,
-    pub group_names: HashMap<u32, String>
+    pub group_names: HashMap<u32, String>,
+    pub metadata: BTreeMap<String, String>,
+    pub signature: Option<BackupSignature>,
+    /// Version of zVault that created the backup, unknown for older backups
+    pub version: Option<String>
 }
 serde_impl!(Backup(u8?) {
     root: ChunkList => 0,
@@ -110,10 +140,19 @@ serde_impl!(Backup(u8?) {
     config: Config => 14,
     modified: bool => 15,
     user_names: HashMap<u32, String> => 16,
-    group_names: HashMap<u32, String> => 17
+    group_names: HashMap<u32, String> => 17,
+    metadata: BTreeMap<String, String> => 18,
+    signature: Option<BackupSignature> => 19,
+    version: Option<String> => 20
 });
 
 impl Backup {
+    /// Returns the time when the backup was finished
+    #[inline]
+    pub fn end_timestamp(&self) -> i64 {
+        self.timestamp + self.duration.round() as i64
+    }
+
     pub fn read_from<P: AsRef<Path>>(crypto: &Crypto, path: P) -> Result<Self, BackupFileError> {
         let path = path.as_ref();
         let mut file = BufReader::new(try!(File::open(path).map_err(|err| {
@@ -172,6 +211,48 @@ impl Backup {
         Ok(())
     }
 
+    /// Returns the data that is covered by the signature
+    ///
+    /// The root chunks reference the whole inode tree by hashes, so the signature also
+    /// covers all files and directories in the backup.
+    fn signed_data(&self) -> Result<Vec<u8>, msgpack::EncodeError> {
+        msgpack::encode(&(&self.root, self.timestamp, &self.host, &self.path, &self.metadata))
+    }
+
+    /// Signs the backup with the signing key derived from the given key pair
+    pub fn sign(&mut self, crypto: &Crypto, key: &PublicKey) -> Result<(), BackupFileError> {
+        let data = try!(self.signed_data().map_err(|err| BackupFileError::Encode(err, PathBuf::new())));
+        let signature = try!(crypto.sign(key, &data).map_err(BackupFileError::Signature));
+        let verify_key = try!(crypto.verify_key(key).map_err(BackupFileError::Signature));
+        self.signature = Some(BackupSignature {
+            key: key[..].to_vec().into(),
+            signature: signature.into(),
+            verify_key: verify_key.into()
+        });
+        Ok(())
+    }
+
+    /// Checks the signature of the backup
+    ///
+    /// The signature is checked with the verify key that is stored with it, no secret key is
+    /// needed. As anyone can store a new signature with their own verify key, the signature
+    /// only proves that the backup is unchanged if the verify key is known to be the right
+    /// one, so a signature by a different key than `trusted_key` does not match.
+    pub fn verify_signature(&self, trusted_key: Option<&[u8]>) -> Result<bool, BackupFileError> {
+        let signature = match self.signature {
+            Some(ref signature) => signature,
+            None => return Err(BackupFileError::NotSigned)
+        };
+        if let Some(trusted_key) = trusted_key {
+            if &signature.verify_key[..] != trusted_key {
+                return Ok(false);
+            }
+        }
+        let data = try!(self.signed_data().map_err(|err| BackupFileError::Encode(err, PathBuf::new())));
+        Crypto::verify(&signature.verify_key, &data, &signature.signature)
+            .map_err(BackupFileError::Signature)
+    }
+
     pub fn get_all_from<P: AsRef<Path>>(
         crypto: &Crypto,
         path: P,
@@ -218,3 +299,28 @@ impl Backup {
         }
     }
 }
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        let mut crypto = Crypto::dummy();
+        let (public, secret) = Crypto::gen_keypair();
+        crypto.add_secret_key(public, secret);
+        let mut backup = Backup::default();
+        backup.path = "/home".to_string();
+        assert!(backup.verify_signature(None).is_err());
+        backup.sign(&crypto, &public).unwrap();
+        let verify_key = crypto.verify_key(&public).unwrap();
+        // Checking the signature does not need the secret key
+        assert!(backup.verify_signature(None).unwrap());
+        assert!(backup.verify_signature(Some(&verify_key[..])).unwrap());
+        assert!(!backup.verify_signature(Some(&public[..])).unwrap());
+        backup.path = "/root".to_string();
+        assert!(!backup.verify_signature(None).unwrap());
+    }
+}
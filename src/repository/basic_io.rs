@@ -4,6 +4,8 @@ use std::mem;
 use std::cmp::min;
 use std::collections::VecDeque;
 use std::io::{self, Read, Write, Cursor};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
 
 pub struct ChunkReader<'a> {
@@ -76,9 +78,49 @@ impl Repository {
         // Lookup bundle id from map
         let bundle_id = try!(self.get_bundle_id(found.bundle));
         // Get chunk from bundle
-        Ok(Some(try!(
-            self.bundles.get_chunk(&bundle_id, found.chunk as usize)
-        )))
+        let data = try!(self.bundles.get_chunk(&bundle_id, found.chunk as usize));
+        if self.verify_chunks && self.config.hash.hash(&data) != hash {
+            let (offset, _) = try!(self.bundles.get_chunk_position(
+                &bundle_id,
+                found.chunk as usize
+            ));
+            return Err(
+                IntegrityError::CorruptChunkInBundle(hash, bundle_id, found.chunk, offset).into()
+            );
+        }
+        Ok(Some(data))
+    }
+
+    pub fn get_chunk_source(
+        &self,
+        hash: Hash,
+    ) -> Result<Option<(BundleId, PathBuf, usize)>, RepositoryError> {
+        let found = if let Some(found) = self.index.get(&hash) {
+            found
+        } else {
+            return Ok(None);
+        };
+        let bundle_id = try!(self.get_bundle_id(found.bundle));
+        let path = try!(self.bundles.get_bundle_path(&bundle_id));
+        Ok(Some((bundle_id, path, found.chunk as usize)))
+    }
+
+    /// Returns the time spent in the stages of storing data so far
+    pub fn pipeline_times(&self) -> PipelineTimes {
+        let mut times = self.times.clone();
+        times.add(self.bundles.times());
+        times
+    }
+
+    #[inline]
+    pub fn get_crypto(&self) -> Arc<Mutex<Crypto>> {
+        self.crypto.clone()
+    }
+
+    /// Returns the cache of decoded bundles, reads of other threads should share it
+    #[inline]
+    pub fn get_decoded_bundles(&self) -> DecodedBundleCache {
+        self.bundles.decoded_bundles()
     }
 
     #[inline]
@@ -101,39 +143,73 @@ impl Repository {
         hash: Hash,
         data: &[u8],
     ) -> Result<(), RepositoryError> {
+        let location = try!(self.write_chunk_to_bundle(mode, hash, data));
+        try!(self.index.set(&hash, &location));
+        if let Some(ref mut written) = self.written_chunks {
+            written.push(hash);
+        }
+        Ok(())
+    }
+
+    /// Adds the chunk to the open bundle without adding it to the index
+    ///
+    /// Returns the location of the chunk, the bundle is only readable once it is finished.
+    fn write_chunk_to_bundle(
+        &mut self,
+        mode: BundleMode,
+        hash: Hash,
+        data: &[u8],
+    ) -> Result<Location, RepositoryError> {
+        // Incompressible data chunks go into their own uncompressed bundle
+        let raw = mode == BundleMode::Data && self.auto_compression &&
+            !timed(&mut self.times.compressing, || is_compressible(data));
         let writer = match mode {
+            BundleMode::Data if raw => &mut self.raw_data_bundle,
             BundleMode::Data => &mut self.data_bundle,
             BundleMode::Meta => &mut self.meta_bundle,
         };
         // ...alocate one if needed
         if writer.is_none() {
+            let compression = match (mode, &self.data_compression) {
+                (BundleMode::Data, _) if raw => None,
+                (BundleMode::Data, &Some(ref compression)) => compression.clone(),
+                _ => self.config.compression.clone()
+            };
             *writer = Some(try!(self.bundles.create_bundle(
                 mode,
                 self.config.hash,
-                self.config.compression.clone(),
+                compression,
                 self.config.encryption.clone()
             )));
         }
         debug_assert!(writer.is_some());
         // Add chunk to bundle writer and determine the size of the bundle
         let writer_obj = writer.as_mut().unwrap();
-        let chunk_id = try!(writer_obj.add(data, hash));
+        let chunk_id = try!(timed(&mut self.times.compressing, || writer_obj.add(data, hash)));
         let bundle_id = match mode {
+            BundleMode::Data if raw => self.next_raw_bundle,
             BundleMode::Data => self.next_data_bundle,
             BundleMode::Meta => self.next_meta_bundle,
         };
-        // Add location to the index
-        try!(self.index.set(
-            &hash,
-            &Location::new(bundle_id, chunk_id as u32)
-        ));
-        Ok(())
+        Ok(Location::new(bundle_id, chunk_id as u32))
     }
 
     fn finish_bundle(&mut self, mode: BundleMode) -> Result<(), RepositoryError> {
+        if mode == BundleMode::Data {
+            try!(self.finish_bundle_writer(mode, true));
+        }
+        self.finish_bundle_writer(mode, false)
+    }
+
+    fn finish_bundle_writer(
+        &mut self,
+        mode: BundleMode,
+        raw: bool,
+    ) -> Result<(), RepositoryError> {
         // Calculate the next free bundle id now (late lifetime prevents this)
         let next_free_bundle_id = self.next_free_bundle_id();
         let writer = match mode {
+            BundleMode::Data if raw => &mut self.raw_data_bundle,
             BundleMode::Data => &mut self.data_bundle,
             BundleMode::Meta => &mut self.meta_bundle,
         };
@@ -141,25 +217,41 @@ impl Repository {
             return Ok(());
         }
         let bundle_id = match mode {
+            BundleMode::Data if raw => self.next_raw_bundle,
             BundleMode::Data => self.next_data_bundle,
             BundleMode::Meta => self.next_meta_bundle,
         };
         let mut finished = None;
         mem::swap(writer, &mut finished);
         let bundle = try!(self.bundles.add_bundle(finished.unwrap()));
-        self.bundle_map.set(bundle_id, bundle.id.clone());
+        self.bundle_map.set(bundle_id, bundle);
         if self.next_meta_bundle == bundle_id {
             self.next_meta_bundle = next_free_bundle_id
         }
         if self.next_data_bundle == bundle_id {
             self.next_data_bundle = next_free_bundle_id
         }
+        if self.next_raw_bundle == bundle_id {
+            self.next_raw_bundle = next_free_bundle_id
+        }
         Ok(())
     }
 
     fn finish_bundle_if_needed(&mut self, mode: BundleMode) -> Result<(), RepositoryError> {
+        if mode == BundleMode::Data {
+            try!(self.finish_bundle_writer_if_needed(mode, true));
+        }
+        self.finish_bundle_writer_if_needed(mode, false)
+    }
+
+    fn finish_bundle_writer_if_needed(
+        &mut self,
+        mode: BundleMode,
+        raw: bool,
+    ) -> Result<(), RepositoryError> {
         let (size, raw_size) = {
             let writer = match mode {
+                BundleMode::Data if raw => &mut self.raw_data_bundle,
                 BundleMode::Data => &mut self.data_bundle,
                 BundleMode::Meta => &mut self.meta_bundle,
             };
@@ -174,11 +266,23 @@ impl Repository {
                 //First store the current data bundle as meta referrs to those chunks
                 try!(self.finish_bundle(BundleMode::Data))
             }
-            try!(self.finish_bundle(mode))
+            try!(self.finish_bundle_writer(mode, raw))
         }
         Ok(())
     }
 
+    /// Stores the chunk in a new bundle without changing the index
+    pub fn copy_chunk(
+        &mut self,
+        mode: BundleMode,
+        hash: Hash,
+        data: &[u8],
+    ) -> Result<Location, RepositoryError> {
+        let location = try!(self.write_chunk_to_bundle(mode, hash, data));
+        try!(self.finish_bundle_if_needed(mode));
+        Ok(location)
+    }
+
     #[inline]
     pub fn put_chunk_override(
         &mut self,
@@ -208,14 +312,33 @@ impl Repository {
         let avg_size = self.config.chunker.avg_size();
         let mut chunks = Vec::new();
         let mut chunk = Vec::with_capacity(avg_size * 2);
+        // The chunker reads the input itself, the time waiting for it is counted separately
+        let mut input = TimedReader::new(data);
         loop {
+            if is_interrupted() {
+                return Err(BackupError::Interrupted.into());
+            }
             chunk.clear();
             let mut output = Cursor::new(chunk);
-            let res = try!(self.chunker.chunk(data, &mut output));
+            let res = {
+                let chunker = &mut self.chunker;
+                let read_before = input.time;
+                let mut time = 0.0;
+                let res = try!(timed(&mut time, || chunker.chunk(&mut input, &mut output)));
+                let read_time = input.time - read_before;
+                self.times.reading += read_time;
+                self.times.chunking += time - read_time;
+                res
+            };
             chunk = output.into_inner();
-            let hash = self.config.hash.hash(&chunk);
+            let hash = {
+                let method = self.config.hash;
+                timed(&mut self.times.hashing, || method.hash(&chunk))
+            };
             try!(self.put_chunk(mode, hash, &chunk));
             chunks.push((hash, chunk.len() as u32));
+            // Large files should not delay the checkpoints of a backup
+            try!(self.write_checkpoint_if_due());
             if res == ChunkerStatus::Finished {
                 break;
             }
@@ -0,0 +1,359 @@
+use prelude::*;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::io::{self, BufReader, Read, Write, BufWriter};
+use std::fs::{self, File};
+
+
+static HEADER_STRING: [u8; 7] = *b"zcheckp";
+static HEADER_VERSION: u8 = 1;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum CheckpointError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description(tr!("Failed to read/write checkpoint"))
+        }
+        Decode(err: msgpack::DecodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to decode checkpoint"))
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to encode checkpoint"))
+        }
+        WrongHeader {
+            description(tr!("Wrong header"))
+        }
+        WrongVersion(version: u8) {
+            description(tr!("Wrong version"))
+            display("{}", tr_format!("Wrong version: {}", version))
+        }
+    }
+}
+
+
+/// Journal of an unfinished backup, written after every checkpoint
+///
+/// All folders listed in `done` have been completely backed up and their inodes only
+/// reference chunks in the bundles that were finished before the journal was written.
+#[derive(Default)]
+pub struct BackupCheckpoint {
+    /// Source path of the backup
+    pub path: String,
+    /// Bundles that have been written by the backup so far
+    pub bundles: Vec<BundleId>,
+    /// Finished folders and the chunks of their inodes, nested folders are left out
+    pub done: BTreeMap<String, ChunkList>,
+    pub user_names: HashMap<u32, String>,
+    pub group_names: HashMap<u32, String>
+}
+serde_impl!(BackupCheckpoint(u8?) {
+    path: String => 0,
+    bundles: Vec<BundleId> => 1,
+    done: BTreeMap<String, ChunkList> => 2,
+    user_names: HashMap<u32, String> => 3,
+    group_names: HashMap<u32, String> => 4
+});
+
+impl BackupCheckpoint {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CheckpointError> {
+        let mut file = BufReader::new(try!(File::open(path.as_ref())));
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header));
+        if header[..HEADER_STRING.len()] != HEADER_STRING {
+            return Err(CheckpointError::WrongHeader);
+        }
+        let version = header[HEADER_STRING.len()];
+        if version != HEADER_VERSION {
+            return Err(CheckpointError::WrongVersion(version));
+        }
+        Ok(try!(msgpack::decode_from_stream(&mut file)))
+    }
+
+    /// Saves the checkpoint, replacing the old file only after the new one is complete
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), CheckpointError> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("checkpoint.tmp");
+        {
+            let mut file = BufWriter::new(try!(File::create(&tmp_path)));
+            try!(file.write_all(&HEADER_STRING));
+            try!(file.write_all(&[HEADER_VERSION]));
+            try!(msgpack::encode_to_stream(self, &mut file));
+            try!(file.flush());
+            try!(file.get_ref().sync_all());
+        }
+        Ok(try!(fs::rename(&tmp_path, path)))
+    }
+
+    /// Marks the folder as finished, replacing all entries of nested folders
+    pub fn add_done(&mut self, path: String, chunks: ChunkList) {
+        // All nested paths start with "path/" and sort before "path0"
+        let nested: Vec<String> = self.done
+            .range(format!("{}/", path)..format!("{}0", path))
+            .map(|(nested, _)| nested.clone())
+            .collect();
+        for nested in nested {
+            self.done.remove(&nested);
+        }
+        self.done.insert(path, chunks);
+    }
+}
+
+
+/// Checkpoint state of the running backup
+pub struct CheckpointJournal {
+    name: String,
+    checkpoint: BackupCheckpoint,
+    /// Finished folders whose chunks might not be in finished bundles yet
+    pending: Vec<(String, ChunkList)>,
+    /// Bundles that existed before the backup started
+    old_bundles: HashSet<BundleId>
+}
+
+
+impl Repository {
+    /// Starts the checkpoint journal for the backup `name` of `path`
+    ///
+    /// If a checkpoint of an interrupted backup with the same name exists, it is used to skip
+    /// the finished folders when `resume` is set and discarded otherwise.
+    pub fn start_checkpoint_journal(
+        &mut self,
+        name: &str,
+        path: &Path,
+        resume: bool,
+    ) -> Result<(), RepositoryError> {
+        let path = path.to_string_lossy().to_string();
+        let file = self.layout.checkpoint_path(name);
+        let mut checkpoint = None;
+        if file.exists() {
+            match BackupCheckpoint::load(&file) {
+                Ok(old) => {
+                    if !resume {
+                        tr_warn!("Discarding the checkpoint of an interrupted backup, use --resume to continue it");
+                    } else if old.path != path {
+                        tr_warn!("Discarding the checkpoint of an interrupted backup of {}", old.path);
+                    } else if let Some(id) = old.bundles.iter().find(|id| self.bundle_map.find(id).is_none()) {
+                        tr_warn!("Discarding the checkpoint of an interrupted backup, bundle {} is missing", id);
+                    } else {
+                        let mut old = old;
+                        self.check_checkpoint_folders(&mut old);
+                        tr_info!("Resuming backup, skipping {} finished folders", old.done.len());
+                        checkpoint = Some(old);
+                    }
+                }
+                Err(err) => tr_warn!("Discarding invalid checkpoint {}: {}", escape(&file), err)
+            }
+            if checkpoint.is_none() {
+                try!(fs::remove_file(&file));
+            }
+        } else if resume {
+            tr_info!("No checkpoint found, starting the backup from the beginning");
+        }
+        let checkpoint = checkpoint.unwrap_or_else(|| {
+            BackupCheckpoint {
+                path,
+                ..Default::default()
+            }
+        });
+        let mut old_bundles: HashSet<BundleId> = self.bundle_map.bundles().into_iter().map(|(_, id)| id).collect();
+        for id in &checkpoint.bundles {
+            old_bundles.remove(id);
+        }
+        self.journal = Some(CheckpointJournal {
+            name: name.to_string(),
+            checkpoint,
+            pending: vec![],
+            old_bundles
+        });
+        Ok(())
+    }
+
+    /// Removes the finished folders that reference chunks which are not in the index anymore
+    ///
+    /// Chunks that were deduplicated are stored in bundles that existed before the backup, so
+    /// they are not covered by the bundles of the checkpoint and might have been removed since.
+    fn check_checkpoint_folders(&mut self, checkpoint: &mut BackupCheckpoint) {
+        let mut checked = Bitmap::new(self.index.capacity());
+        let mut broken = vec![];
+        for (path, chunks) in &checkpoint.done {
+            if let Err(err) = self.check_subtree_chunks(Path::new(path), chunks, &mut checked) {
+                tr_warn!("Backing up {} again, it is incomplete in the checkpoint: {}", escape(&path), err);
+                broken.push(path.clone());
+            }
+        }
+        for path in broken {
+            checkpoint.done.remove(&path);
+        }
+    }
+
+    /// Returns the inode of a folder that has been finished before the backup was interrupted
+    pub fn get_checkpoint_inode(&mut self, path: &Path) -> Option<(Inode, ChunkList)> {
+        let chunks = match (path.to_str(), self.journal.as_ref()) {
+            (Some(path), Some(journal)) => journal.checkpoint.done.get(path).cloned(),
+            _ => None
+        };
+        match chunks.map(|chunks| (self.get_inode(&chunks), chunks)) {
+            Some((Ok(inode), chunks)) => Some((inode, chunks)),
+            Some((Err(err), _)) => {
+                tr_warn!("Failed to load {} from the checkpoint, backing it up again: {}", escape(&path), err);
+                None
+            }
+            None => None
+        }
+    }
+
+    /// Records a finished folder, it is written to the journal with the next checkpoint
+    ///
+    /// Paths that are not valid UTF-8 are not recorded to avoid ambiguity.
+    pub fn add_checkpoint_folder(&mut self, path: &Path, chunks: &ChunkList) {
+        if let (Some(journal), Some(path)) = (self.journal.as_mut(), path.to_str()) {
+            journal.pending.push((path.to_string(), chunks.clone()));
+        }
+    }
+
+    /// Writes the journal, this must only be called right after all bundles have been flushed
+    pub fn write_checkpoint_journal(&mut self, backup: &Backup) -> Result<(), RepositoryError> {
+        let bundles = self.bundle_map.bundles();
+        let file = match self.journal.as_mut() {
+            Some(journal) => {
+                for (path, chunks) in journal.pending.drain(..) {
+                    journal.checkpoint.add_done(path, chunks);
+                }
+                journal.checkpoint.bundles = bundles.into_iter()
+                    .map(|(_, id)| id)
+                    .filter(|id| !journal.old_bundles.contains(id))
+                    .collect();
+                journal.checkpoint.user_names.extend(backup.user_names.iter().map(|(k, v)| (*k, v.clone())));
+                journal.checkpoint.group_names.extend(backup.group_names.iter().map(|(k, v)| (*k, v.clone())));
+                self.layout.checkpoint_path(&journal.name)
+            }
+            None => return Ok(())
+        };
+        try!(fs::create_dir_all(file.parent().unwrap()));
+        let journal = self.journal.as_ref().unwrap();
+        Ok(try!(journal.checkpoint.save(&file)))
+    }
+
+    /// Adds the owner names of the interrupted backup that has been resumed
+    pub fn restore_checkpoint_names(&self, backup: &mut Backup) {
+        if let Some(ref journal) = self.journal {
+            for (id, name) in &journal.checkpoint.user_names {
+                backup.user_names.entry(*id).or_insert_with(|| name.clone());
+            }
+            for (id, name) in &journal.checkpoint.group_names {
+                backup.group_names.entry(*id).or_insert_with(|| name.clone());
+            }
+        }
+    }
+
+    /// Removes the checkpoint of the backup `name` if there is one
+    pub fn remove_checkpoint(&self, name: &str) -> Result<(), RepositoryError> {
+        let file = self.layout.checkpoint_path(name);
+        if file.exists() {
+            try!(fs::remove_file(&file));
+        }
+        Ok(())
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use repository::tests::temp_dir;
+
+    #[allow(unused_imports)]
+    use std::path::PathBuf;
+
+
+    #[allow(dead_code)]
+    fn file_contents(repo: &mut Repository, backup: &Backup, path: &str) -> Vec<u8> {
+        let inode = repo.get_backup_inode(backup, path).unwrap();
+        let mut data = vec![];
+        repo.get_inode_contents(&inode, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_add_done() {
+        let mut checkpoint = BackupCheckpoint::default();
+        checkpoint.add_done("/src/a/x".to_string(), ChunkList::new());
+        checkpoint.add_done("/src/a/y/z".to_string(), ChunkList::new());
+        checkpoint.add_done("/src/ab".to_string(), ChunkList::new());
+        checkpoint.add_done("/src/a".to_string(), ChunkList::new());
+        let done: Vec<&str> = checkpoint.done.keys().map(|path| path as &str).collect();
+        assert_eq!(done, vec!["/src/a", "/src/ab"]);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let base = temp_dir("checkpoint-file");
+        let mut checkpoint = BackupCheckpoint::default();
+        checkpoint.path = "/src".to_string();
+        checkpoint.bundles = vec![BundleId::random()];
+        checkpoint.add_done("/src/a".to_string(), vec![(Hash::empty(), 10)].into());
+        checkpoint.user_names.insert(1000, "user".to_string());
+        checkpoint.save(base.join("test.checkpoint")).unwrap();
+        assert!(!base.join("test.checkpoint.tmp").exists());
+        let loaded = BackupCheckpoint::load(base.join("test.checkpoint")).unwrap();
+        assert_eq!(loaded.path, checkpoint.path);
+        assert_eq!(loaded.bundles, checkpoint.bundles);
+        assert_eq!(loaded.done, checkpoint.done);
+        assert_eq!(loaded.user_names, checkpoint.user_names);
+        File::create(base.join("invalid.checkpoint")).unwrap().write_all(b"zvault\x00\x01").unwrap();
+        match BackupCheckpoint::load(base.join("invalid.checkpoint")) {
+            Err(CheckpointError::WrongHeader) => (),
+            _ => panic!("invalid checkpoint has been loaded")
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let base = temp_dir("checkpoint-resume");
+        let src = base.join("src");
+        fs::create_dir_all(src.join("a")).unwrap();
+        fs::create_dir_all(src.join("b")).unwrap();
+        File::create(src.join("a/file")).unwrap().write_all(&[1; 1000]).unwrap();
+        File::create(src.join("b/file")).unwrap().write_all(&[2; 1000]).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let mut options = BackupOptions::default();
+        options.checkpoint_interval = Some(0);
+        options.journal = Some("test".to_string());
+        // The backup is not saved, like after an interruption
+        let backup = repo.create_backup_recursively(&src, None, &options).unwrap();
+        let checkpoint = BackupCheckpoint::load(repo.layout.checkpoint_path("test")).unwrap();
+        let done: Vec<&str> = checkpoint.done.keys().map(|path| path as &str).collect();
+        let (a, b) = (src.join("a"), src.join("b"));
+        assert_eq!(done, vec![a.to_str().unwrap(), b.to_str().unwrap()]);
+        // A chunk of the first folder is lost in the meantime
+        let hash = match repo.get_backup_inode(&backup, "a/file").unwrap().data {
+            Some(FileData::ChunkedDirect(ref chunks)) => chunks[0].0,
+            _ => panic!("file has not been chunked")
+        };
+        repo.index.delete(&hash).unwrap();
+        File::create(src.join("a/file")).unwrap().write_all(&[3; 1000]).unwrap();
+        File::create(src.join("b/file")).unwrap().write_all(&[4; 1000]).unwrap();
+        options.resume = true;
+        let backup = repo.create_backup_recursively(&src, None, &options).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        assert!(!repo.layout.checkpoint_path("test").exists());
+        // Only the intact folder has been taken from the checkpoint
+        assert_eq!(file_contents(&mut repo, &backup, "a/file"), vec![3; 1000]);
+        assert_eq!(file_contents(&mut repo, &backup, "b/file"), vec![2; 1000]);
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
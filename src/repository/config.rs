@@ -2,11 +2,14 @@ use prelude::*;
 
 use serde_yaml;
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::Path;
 use std::io;
 
 
+pub const CONFIG_VERSION: u8 = 1;
+
+
 quick_error!{
     #[derive(Debug)]
     pub enum ConfigError {
@@ -25,6 +28,14 @@ quick_error!{
             description(tr!("Yaml format error"))
             display("{}", tr_format!("Yaml format error: {}", err))
         }
+        UnsupportedVersion(version: u8) {
+            description(tr!("Unsupported config version"))
+            display("{}", tr_format!("Unsupported config version: {}, this version of zVault only supports repositories up to version {}, please update zVault", version, CONFIG_VERSION))
+        }
+        Corrupt(reason: &'static str) {
+            description(tr!("Config is corrupt"))
+            display("{}", tr_format!("Config is corrupt: {}", reason))
+        }
     }
 }
 
@@ -40,36 +51,59 @@ impl HashMethod {
 }
 
 
+#[derive(PartialEq)]
 struct ChunkerYaml {
     method: String,
     avg_size: usize,
-    seed: u64
+    seed: u64,
+    window_size: Option<usize>,
+    min_size: Option<usize>,
+    max_size: Option<usize>
 }
 impl Default for ChunkerYaml {
     fn default() -> Self {
         ChunkerYaml {
             method: "fastcdc".to_string(),
             avg_size: 16 * 1024,
-            seed: 0
+            seed: 0,
+            window_size: None,
+            min_size: None,
+            max_size: None
         }
     }
 }
-serde_impl!(ChunkerYaml(String) {
+serde_impl!(ChunkerYaml(String?) {
     method: String => "method",
     avg_size: usize => "avg_size",
-    seed: u64 => "seed"
+    seed: u64 => "seed",
+    window_size: Option<usize> => "window_size",
+    min_size: Option<usize> => "min_size",
+    max_size: Option<usize> => "max_size"
 });
 
 impl ChunkerType {
     fn from_yaml(yaml: &ChunkerYaml) -> Result<Self, ConfigError> {
-        ChunkerType::from(&yaml.method, yaml.avg_size, yaml.seed).map_err(ConfigError::Parse)
+        let mut chunker = try!(ChunkerType::from(&yaml.method, yaml.avg_size, yaml.seed));
+        match (yaml.min_size, yaml.max_size) {
+            (Some(min), Some(max)) => chunker = try!(chunker.with_bounds(min, max)),
+            (None, None) => (),
+            _ => return Err(ConfigError::Parse(tr!("Minimum and maximum chunk size must be set together")))
+        }
+        if let Some(window) = yaml.window_size {
+            Ok(try!(chunker.with_window_size(window)))
+        } else {
+            Ok(chunker)
+        }
     }
 
     fn to_yaml(&self) -> ChunkerYaml {
         ChunkerYaml {
             method: self.name().to_string(),
             avg_size: self.avg_size(),
-            seed: self.seed()
+            seed: self.seed(),
+            window_size: self.window_size(),
+            min_size: self.bounds().map(|b| b.0),
+            max_size: self.bounds().map(|b| b.1)
         }
     }
 }
@@ -102,6 +136,7 @@ impl EncryptionMethod {
 }
 
 
+#[derive(PartialEq)]
 struct EncryptionYaml {
     method: String,
     key: String
@@ -120,33 +155,127 @@ serde_impl!(EncryptionYaml(String) {
 });
 
 
+#[derive(PartialEq)]
+struct KeyDerivationYaml {
+    method: String,
+    salt: String,
+    ops_limit: usize,
+    mem_limit: usize
+}
+impl Default for KeyDerivationYaml {
+    fn default() -> Self {
+        KeyDerivationYaml {
+            method: "scrypt".to_string(),
+            salt: "".to_string(),
+            ops_limit: 0,
+            mem_limit: 0
+        }
+    }
+}
+serde_impl!(KeyDerivationYaml(String) {
+    method: String => "method",
+    salt: String => "salt",
+    ops_limit: usize => "ops_limit",
+    mem_limit: usize => "mem_limit"
+});
+
+impl KeyDerivation {
+    fn from_yaml(yaml: &KeyDerivationYaml) -> Result<Self, ConfigError> {
+        if yaml.method != "scrypt" {
+            return Err(ConfigError::Parse(tr!("Unsupported key derivation method")));
+        }
+        let salt = try!(parse_hex(&yaml.salt).map_err(|_| {
+            ConfigError::Parse(tr!("Invalid key derivation salt"))
+        }));
+        Ok(KeyDerivation {
+            salt: salt.into(),
+            ops_limit: yaml.ops_limit,
+            mem_limit: yaml.mem_limit
+        })
+    }
+
+    fn to_yaml(&self) -> KeyDerivationYaml {
+        KeyDerivationYaml {
+            method: "scrypt".to_string(),
+            salt: to_hex(&self.salt[..]),
+            ops_limit: self.ops_limit,
+            mem_limit: self.mem_limit
+        }
+    }
+
+    /// Loads the parameters from a separate file as written by `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let f = try!(File::open(path));
+        let yaml: KeyDerivationYaml = try!(serde_yaml::from_reader(f));
+        Self::from_yaml(&yaml)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        let mut f = try!(File::create(path));
+        try!(serde_yaml::to_writer(&mut f, &self.to_yaml()));
+        Ok(())
+    }
+}
+
+
 
 struct ConfigYaml {
+    version: u8,
     compression: Option<String>,
     encryption: Option<EncryptionYaml>,
     bundle_size: usize,
     chunker: ChunkerYaml,
-    hash: String
+    hash: String,
+    append_only: bool,
+    redundancy: Option<String>,
+    bundle_shard: Option<usize>,
+    key_source: Option<String>,
+    key_derivation: Option<KeyDerivationYaml>,
+    checksum: Option<String>
 }
 impl Default for ConfigYaml {
     fn default() -> Self {
         ConfigYaml {
+            version: 0,
             compression: Some("brotli/5".to_string()),
             encryption: None,
             bundle_size: 25 * 1024 * 1024,
             chunker: ChunkerYaml::default(),
-            hash: "blake2".to_string()
+            hash: "blake2".to_string(),
+            append_only: false,
+            redundancy: None,
+            bundle_shard: None,
+            key_source: None,
+            key_derivation: None,
+            checksum: None
         }
     }
 }
-serde_impl!(ConfigYaml(String) {
+serde_impl!(ConfigYaml(String?) {
+    version: u8 => "version",
     compression: Option<String> => "compression",
     encryption: Option<EncryptionYaml> => "encryption",
     bundle_size: usize => "bundle_size",
     chunker: ChunkerYaml => "chunker",
-    hash: String => "hash"
+    hash: String => "hash",
+    append_only: bool => "append_only",
+    redundancy: Option<String> => "redundancy",
+    bundle_shard: Option<usize> => "bundle_shard",
+    key_source: Option<String> => "key_source",
+    key_derivation: Option<KeyDerivationYaml> => "key_derivation",
+    checksum: Option<String> => "checksum"
 });
 
+impl ConfigYaml {
+    /// Calculates the checksum of all fields except the checksum itself
+    fn calculate_checksum(&mut self) -> Result<String, ConfigError> {
+        let checksum = self.checksum.take();
+        let data = serde_yaml::to_string(self);
+        self.checksum = checksum;
+        Ok(HashMethod::Blake2.hash(try!(data).as_bytes()).to_string())
+    }
+}
+
 
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -155,7 +284,16 @@ pub struct Config {
     pub encryption: Option<Encryption>,
     pub bundle_size: usize,
     pub chunker: ChunkerType,
-    pub hash: HashMethod
+    pub hash: HashMethod,
+    pub append_only: bool,
+    /// Number of parity bundles that protect groups of bundles, if any
+    pub redundancy: Option<Redundancy>,
+    /// Number of characters of the bundle names used as folder for the remote bundles
+    ///
+    /// Without it, the folders are created automatically as the number of bundles grows.
+    pub bundle_shard: Option<usize>,
+    /// Parameters to derive the key pair from a password, if it is not stored in a key file
+    pub key_derivation: Option<KeyDerivation>
 }
 impl Default for Config {
     fn default() -> Self {
@@ -164,16 +302,24 @@ impl Default for Config {
             encryption: None,
             bundle_size: 25 * 1024 * 1024,
             chunker: ChunkerType::from_string("fastcdc/16").unwrap(),
-            hash: HashMethod::Blake2
+            hash: HashMethod::Blake2,
+            append_only: false,
+            redundancy: None,
+            bundle_shard: None,
+            key_derivation: None
         }
     }
 }
-serde_impl!(Config(u64) {
+serde_impl!(Config(u64?) {
     compression: Option<Compression> => 0,
     encryption: Option<Encryption> => 1,
     bundle_size: usize => 2,
     chunker: ChunkerType => 3,
-    hash: HashMethod => 4
+    hash: HashMethod => 4,
+    append_only: bool => 5,
+    key_derivation: Option<KeyDerivation> => 6,
+    redundancy: Option<Redundancy> => 7,
+    bundle_shard: Option<usize> => 8
 });
 
 impl Config {
@@ -192,17 +338,36 @@ impl Config {
         } else {
             None
         };
+        // Repositories without a key source have been created with a key file
+        let key_source = yaml.key_source.as_ref().map(|s| s.as_str());
+        let key_derivation = match (key_source, yaml.key_derivation) {
+            (None, None) | (Some("keyfile"), None) => None,
+            (Some("password"), Some(ref d)) => Some(try!(KeyDerivation::from_yaml(d))),
+            (Some("password"), None) => {
+                return Err(ConfigError::Parse(tr!("Key derivation parameters are missing")))
+            }
+            _ => return Err(ConfigError::Parse(tr!("Invalid key source")))
+        };
+        let redundancy = match yaml.redundancy {
+            Some(ref r) => Some(try!(Redundancy::from_string(r).map_err(ConfigError::Parse))),
+            None => None
+        };
         Ok(Config {
             compression,
             encryption,
             bundle_size: yaml.bundle_size,
             chunker: try!(ChunkerType::from_yaml(&yaml.chunker)),
-            hash: try!(HashMethod::from_yaml(&yaml.hash))
+            hash: try!(HashMethod::from_yaml(&yaml.hash)),
+            append_only: yaml.append_only,
+            redundancy,
+            bundle_shard: yaml.bundle_shard,
+            key_derivation
         })
     }
 
     fn to_yaml(&self) -> ConfigYaml {
         ConfigYaml {
+            version: CONFIG_VERSION,
             compression: self.compression.as_ref().map(|c| c.to_yaml()),
             encryption: self.encryption.as_ref().map(|e| {
                 EncryptionYaml {
@@ -212,19 +377,65 @@ impl Config {
             }),
             bundle_size: self.bundle_size,
             chunker: self.chunker.to_yaml(),
-            hash: self.hash.to_yaml()
+            hash: self.hash.to_yaml(),
+            append_only: self.append_only,
+            redundancy: self.redundancy.map(|r| r.to_string()),
+            bundle_shard: self.bundle_shard,
+            key_source: self.key_derivation.as_ref().map(|_| "password".to_string()),
+            key_derivation: self.key_derivation.as_ref().map(|d| d.to_yaml()),
+            checksum: None
         }
     }
 
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+    /// Loads and validates the config, also returning the version of the file
+    ///
+    /// Configs without a version have been written before versioning was added and have no
+    /// checksum. They are accepted and should be saved again to upgrade them.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<(Self, u8), ConfigError> {
         let f = try!(File::open(path));
-        let config = try!(serde_yaml::from_reader(f));
-        Config::from_yaml(config)
+        let mut yaml: ConfigYaml = try!(serde_yaml::from_reader(f));
+        if yaml.version > CONFIG_VERSION {
+            return Err(ConfigError::UnsupportedVersion(yaml.version));
+        }
+        if yaml.version > 0 {
+            // The checksum is the last field, so a truncated file will not have it
+            let checksum = match yaml.checksum {
+                Some(ref checksum) => checksum.clone(),
+                None => return Err(ConfigError::Corrupt(tr!("checksum is missing")))
+            };
+            if try!(yaml.calculate_checksum()) != checksum {
+                return Err(ConfigError::Corrupt(tr!("checksum does not match")));
+            }
+        }
+        let version = yaml.version;
+        Ok((try!(Config::from_yaml(yaml)), version))
     }
 
+    /// Saves the config, keeping the previous file as backup
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        let path = path.as_ref();
+        if path.exists() {
+            try!(fs::copy(path, path.with_extension("yaml.bak")));
+        }
+        let mut yaml = self.to_yaml();
+        yaml.checksum = Some(try!(yaml.calculate_checksum()));
         let mut f = try!(File::create(path));
-        try!(serde_yaml::to_writer(&mut f, &self.to_yaml()));
+        try!(serde_yaml::to_writer(&mut f, &yaml));
         Ok(())
     }
+
+    /// Reads only the append-only flag from a config that might not be valid anymore
+    ///
+    /// Returns `None` if the file can not be read or does not contain the flag.
+    pub fn load_append_only<P: AsRef<Path>>(path: P) -> Option<bool> {
+        let f = match File::open(path) {
+            Ok(f) => f,
+            Err(_) => return None
+        };
+        let yaml: serde_yaml::Value = match serde_yaml::from_reader(f) {
+            Ok(yaml) => yaml,
+            Err(_) => return None
+        };
+        yaml.get("append_only").and_then(|v| v.as_bool())
+    }
 }
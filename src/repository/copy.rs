@@ -0,0 +1,371 @@
+use prelude::*;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+
+#[derive(Debug, Default)]
+pub struct CopyStatistics {
+    pub passed_bundles: usize,
+    pub passed_chunks: usize,
+    pub transcoded_chunks: usize,
+    pub existing_chunks: usize
+}
+
+
+#[derive(Debug, Default)]
+pub struct CloneStatistics {
+    pub copied_files: usize,
+    pub copied_size: u64,
+    pub existing_files: usize
+}
+
+
+/// Collects all files below `base` relative to it, the folders in `skip` are left out
+fn list_remote_files(base: &Path, dir: &Path, skip: &[&str], files: &mut Vec<(PathBuf, u64)>) -> Result<(), io::Error> {
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let path = entry.path();
+        let rel_path = path.strip_prefix(base).unwrap().to_path_buf();
+        let meta = try!(fs::metadata(&path));
+        if meta.is_dir() {
+            if !skip.iter().any(|s| rel_path == Path::new(s)) {
+                try!(list_remote_files(base, &path, skip, files));
+            }
+        } else {
+            files.push((rel_path, meta.len()));
+        }
+    }
+    Ok(())
+}
+
+/// Copies the file and returns the hash of the data that has been read
+fn copy_hashed(src: &Path, dst: &Path) -> Result<Hash, io::Error> {
+    let mut input = try!(File::open(src));
+    let mut output = try!(File::create(dst));
+    let mut hasher = Blake2Stream::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = try!(input.read(&mut buf));
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        try!(output.write_all(&buf[..len]));
+    }
+    try!(output.sync_all());
+    Ok(hasher.finish())
+}
+
+fn hash_file(path: &Path) -> Result<Hash, io::Error> {
+    let mut input = try!(File::open(path));
+    let mut hasher = Blake2Stream::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = try!(input.read(&mut buf));
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    Ok(hasher.finish())
+}
+
+
+impl Repository {
+    /// Collects all chunks that are referenced by the backup
+    pub fn get_backup_chunks(&mut self, backup: &Backup) -> Result<Vec<Chunk>, RepositoryError> {
+        let mut seen = HashSet::new();
+        let mut chunks = vec![];
+        let mut todo = VecDeque::new();
+        todo.push_back(backup.root.clone());
+        while let Some(inode_chunks) = todo.pop_back() {
+            for &chunk in inode_chunks.iter() {
+                if seen.insert(chunk.0) {
+                    chunks.push(chunk);
+                }
+            }
+            let inode = try!(self.get_inode(&inode_chunks));
+            match inode.data.as_ref().map(FileData::stored) {
+                None |
+                Some(&FileData::Inline(_)) |
+                Some(&FileData::Sparse(_)) => (),
+                Some(&FileData::ChunkedDirect(ref data_chunks)) => {
+                    for &chunk in data_chunks.iter() {
+                        if seen.insert(chunk.0) {
+                            chunks.push(chunk);
+                        }
+                    }
+                }
+                Some(&FileData::ChunkedIndirect(ref list_chunks)) => {
+                    for &chunk in list_chunks.iter() {
+                        if seen.insert(chunk.0) {
+                            chunks.push(chunk);
+                        }
+                    }
+                    let chunk_data = try!(self.get_data(list_chunks));
+                    for &chunk in ChunkList::read_from(&chunk_data).iter() {
+                        if seen.insert(chunk.0) {
+                            chunks.push(chunk);
+                        }
+                    }
+                }
+            }
+            if let Some(children) = inode.children {
+                for (_name, child_chunks) in children {
+                    todo.push_back(child_chunks);
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Checks whether a bundle can be copied into the other repository without decoding it
+    fn can_pass_through(&self, info: &BundleInfo, dst: &Repository) -> bool {
+        if info.compression != dst.config.compression || info.encryption != dst.config.encryption {
+            return false;
+        }
+        // The chunk list of the bundle must be readable to add it to the index
+        match info.encryption {
+            Some(ref encryption) => dst.crypto.lock().unwrap().can_decrypt(encryption),
+            None => true
+        }
+    }
+
+    /// Copies all chunks of the backup that are missing in the destination repository
+    ///
+    /// Bundles are copied as they are if all their chunks are needed and they use the same
+    /// compression and encryption as configured in the destination. All other chunks are
+    /// decoded and stored again using the configuration of the destination. The backup file
+    /// itself is not saved.
+    pub fn copy_backup_chunks(
+        &mut self,
+        backup: &Backup,
+        dst: &mut Repository,
+    ) -> Result<CopyStatistics, RepositoryError> {
+        if self.config.hash != dst.config.hash {
+            return Err(RepositoryError::HashMismatch(self.config.hash, dst.config.hash));
+        }
+        let chunks = try!(self.get_backup_chunks(backup));
+        try!(dst.write_mode());
+        let _lock = try!(dst.lock(false));
+        if dst.dirty {
+            return Err(RepositoryError::Dirty);
+        }
+        try!(dst.set_dirty());
+        let mut stats = CopyStatistics::default();
+        let mut missing: HashMap<u32, Vec<(usize, Hash)>> = HashMap::new();
+        for (hash, _len) in chunks {
+            if dst.index.contains(&hash) {
+                stats.existing_chunks += 1;
+                continue;
+            }
+            let found = try!(self.index.get(&hash).ok_or(IntegrityError::MissingChunk(hash)));
+            missing.entry(found.bundle).or_insert_with(Vec::new).push((found.chunk as usize, hash));
+        }
+        let mut bundles = Vec::with_capacity(missing.len());
+        for (id, chunks) in missing {
+            let bundle_id = try!(self.get_bundle_id(id));
+            let info = try!(self.bundles.get_bundle_info(&bundle_id).ok_or_else(|| {
+                IntegrityError::MissingBundle(bundle_id.clone())
+            })).info.clone();
+            bundles.push((info, chunks));
+        }
+        // Data bundles first, as meta data refers to those chunks
+        bundles.sort_by_key(|&(ref info, _)| info.mode == BundleMode::Meta);
+        for (info, chunks) in ProgressIter::new(tr!("copying bundles"), bundles.len(), bundles.into_iter()) {
+            if chunks.len() == info.chunk_count && self.can_pass_through(&info, dst) {
+                let path = try!(self.bundles.get_bundle_path(&info.id));
+                let imported = try!(dst.bundles.import_bundle(&path));
+                try!(dst.add_new_remote_bundle(&imported));
+                stats.passed_bundles += 1;
+                stats.passed_chunks += chunks.len();
+            } else {
+                for (chunk, hash) in chunks {
+                    let data = try!(self.bundles.get_chunk(&info.id, chunk));
+                    try!(dst.put_chunk(info.mode, hash, &data));
+                    stats.transcoded_chunks += 1;
+                }
+            }
+        }
+        try!(dst.flush());
+        dst.dirty = false;
+        Ok(stats)
+    }
+
+    /// Checks that the copy of a bundle can be loaded and its contents decoded
+    ///
+    /// Returns `false` if the contents can not be decrypted with the keys of this repository.
+    fn check_bundle_copy(&self, path: &Path) -> Result<bool, BundleReaderError> {
+        let reader = try!(BundleReader::load(path.to_path_buf(), self.get_crypto()));
+        if let Some(ref encryption) = reader.info.encryption {
+            if !self.crypto.lock().unwrap().can_decrypt(encryption) {
+                return Ok(false);
+            }
+        }
+        if try!(reader.load_contents()).len() != reader.info.raw_size {
+            return Err(BundleReaderError::Integrity(
+                reader.id(),
+                tr!("Decoded size does not match")
+            ));
+        }
+        Ok(true)
+    }
+
+    /// Copies the whole remote storage into a new repository at `path` using `remote`
+    ///
+    /// The new repository gets the same configuration, excludes and key files. Bundles,
+    /// parity files and backups are copied byte for byte. Every copied bundle is loaded and
+    /// decoded before it is moved into place, other files and bundles that can not be
+    /// decrypted with the available keys are read back and compared with the hash of the
+    /// original. Files that already exist in the destination with the same contents are
+    /// skipped, so an interrupted clone continues where it stopped when it is run again. The
+    /// backup files are copied last so that they never reference missing bundles.
+    ///
+    /// The new repository is not opened, the index is built when it is opened the next time.
+    pub fn clone_to<P: AsRef<Path>, R: AsRef<Path>>(
+        &self,
+        path: P,
+        remote: R,
+    ) -> Result<CloneStatistics, RepositoryError> {
+        let path = path.as_ref();
+        let _lock = try!(self.lock(false));
+        let layout = if RepositoryLayout::new(path).config_path().exists() {
+            tr_info!("Continuing the clone into {}", escape(&path));
+            RepositoryLayout::new(path)
+        } else {
+            try!(Self::create_layout(path, &self.config, remote))
+        };
+        try!(fs::copy(self.layout.excludes_path(), layout.excludes_path()));
+        for entry in try!(fs::read_dir(self.layout.keys_path())) {
+            let entry = try!(entry);
+            let dst = layout.keys_path().join(entry.file_name());
+            if !dst.exists() {
+                try!(fs::copy(entry.path(), dst));
+            }
+        }
+        let src_base = self.layout.remote_path();
+        let dst_base = layout.remote_path();
+        let mut files = vec![];
+        try!(list_remote_files(&src_base, &src_base, &["locks", "backups"], &mut files));
+        let data_count = files.len();
+        let backups_path = src_base.join("backups");
+        if backups_path.exists() {
+            try!(list_remote_files(&src_base, &backups_path, &[], &mut files));
+        }
+        files[..data_count].sort();
+        files[data_count..].sort();
+        let total: u64 = files.iter().map(|&(_, size)| size).sum();
+        let mut progress = if self.show_progress {
+            Some(ProgressReporter::new(tr!("clone"), total))
+        } else {
+            None
+        };
+        let mut stats = CloneStatistics::default();
+        for (rel_path, size) in files {
+            let src = src_base.join(&rel_path);
+            let dst = dst_base.join(&rel_path);
+            if let Some(ref mut progress) = progress {
+                progress.add(size, &rel_path);
+            }
+            if fs::metadata(&dst).map(|m| m.len() == size).unwrap_or(false) &&
+                try!(hash_file(&dst)) == try!(hash_file(&src))
+            {
+                stats.existing_files += 1;
+                continue;
+            }
+            tr_debug!("Copying {:?}", rel_path);
+            try!(fs::create_dir_all(dst.parent().unwrap()));
+            let mut tmp_name = dst.file_name().unwrap().to_os_string();
+            tmp_name.push(".tmp");
+            let tmp = dst.with_file_name(tmp_name);
+            let hash = try!(copy_hashed(&src, &tmp));
+            let checked = if rel_path.extension().map_or(false, |ext| ext == "bundle") {
+                match self.check_bundle_copy(&tmp) {
+                    Ok(checked) => checked,
+                    Err(err) => {
+                        try!(fs::remove_file(&tmp));
+                        return Err(BundleDbError::from(err).into());
+                    }
+                }
+            } else {
+                false
+            };
+            if !checked && try!(hash_file(&tmp)) != hash {
+                try!(fs::remove_file(&tmp));
+                return Err(IntegrityError::CorruptCopy(dst).into());
+            }
+            try!(fs::rename(&tmp, &dst));
+            stats.copied_files += 1;
+            stats.copied_size += size;
+        }
+        Ok(stats)
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use repository::tests::temp_dir;
+
+
+    #[test]
+    fn test_clone_to() {
+        let base = temp_dir("clone");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..100_000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect();
+        File::create(src.join("file")).unwrap().write_all(&data).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        fs::create_dir(base.join("clone-remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        repo.flush().unwrap();
+        let stats = repo.clone_to(base.join("clone"), base.join("clone-remote")).unwrap();
+        assert!(stats.copied_files > 1);
+        // Only the readme is written with the new layout
+        assert_eq!(stats.existing_files, 1);
+        let total = stats.copied_files + stats.existing_files;
+        // Running it again skips all files that have been copied
+        let again = repo.clone_to(base.join("clone"), base.join("clone-remote")).unwrap();
+        assert_eq!((again.copied_files, again.existing_files), (0, total));
+        // A damaged copy with the same size is copied again
+        let mut files = vec![];
+        list_remote_files(&base.join("clone-remote"), &base.join("clone-remote"), &[], &mut files).unwrap();
+        let (bundle, _) = files.into_iter()
+            .find(|&(ref path, _)| path.extension().map_or(false, |ext| ext == "bundle"))
+            .unwrap();
+        let bundle = base.join("clone-remote").join(bundle);
+        let mut contents = vec![];
+        File::open(&bundle).unwrap().read_to_end(&mut contents).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        File::create(&bundle).unwrap().write_all(&contents).unwrap();
+        let again = repo.clone_to(base.join("clone"), base.join("clone-remote")).unwrap();
+        assert_eq!((again.copied_files, again.existing_files), (1, total - 1));
+        drop(repo);
+        // The backup can be restored from the clone
+        let mut clone = Repository::open(base.join("clone"), true).unwrap();
+        let backup = clone.get_backup("test").unwrap();
+        let root = clone.get_inode(&backup.root).unwrap();
+        fs::create_dir(base.join("restored")).unwrap();
+        clone.restore_inode_tree(&backup, root, base.join("restored"), RestoreOwner::Current, false).unwrap();
+        let mut restored = vec![];
+        File::open(base.join("restored/file")).unwrap().read_to_end(&mut restored).unwrap();
+        assert!(restored == data);
+        drop(clone);
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -6,8 +6,11 @@ use std::path::PathBuf;
 use super::backup_file::BackupFileError;
 use super::backup::BackupError;
 use super::bundle_map::BundleMapError;
+use super::checkpoint::CheckpointError;
+use super::vacuum::VacuumError;
 use super::config::ConfigError;
 use super::metadata::InodeError;
+use super::archive::ArchiveError;
 
 
 quick_error!{
@@ -18,6 +21,10 @@ quick_error!{
             description(tr!("Remote storage not found"))
             display("{}", tr_format!("Repository error: The remote storage has not been found, may be it needs to be mounted?"))
         }
+        ConfigIntact {
+            description(tr!("Config is intact"))
+            display("{}", tr_format!("Repository error: the config can still be loaded, only a missing or corrupt config can be recovered"))
+        }
         Index(err: IndexError) {
             from()
             cause(err)
@@ -72,16 +79,50 @@ quick_error!{
             description(tr!("Bundle map error"))
             display("{}", tr_format!("Repository error: bundle map error\n\tcaused by: {}", err))
         }
+        Checkpoint(err: CheckpointError) {
+            from()
+            cause(err)
+            description(tr!("Checkpoint error"))
+            display("{}", tr_format!("Repository error: checkpoint error\n\tcaused by: {}", err))
+        }
+        Vacuum(err: VacuumError) {
+            from()
+            cause(err)
+            description(tr!("Vacuum error"))
+            display("{}", tr_format!("Repository error: vacuum error\n\tcaused by: {}", err))
+        }
+        Archive(err: ArchiveError) {
+            from()
+            cause(err)
+            description(tr!("Archive error"))
+            display("{}", tr_format!("Repository error: archive error\n\tcaused by: {}", err))
+        }
         Integrity(err: IntegrityError) {
             from()
             cause(err)
             description(tr!("Integrity error"))
             display("{}", tr_format!("Repository error: integrity error\n\tcaused by: {}", err))
         }
+        PasswordRequired {
+            description(tr!("Password required"))
+            display("{}", tr_format!("Repository error: the repository is encrypted with a password, please enter it"))
+        }
         Dirty {
             description(tr!("Dirty repository"))
             display("{}", tr_format!("The repository is dirty, please run a check"))
         }
+        HashMismatch(src: HashMethod, dst: HashMethod) {
+            description(tr!("Different hash methods"))
+            display("{}", tr_format!("Repository error: can not copy chunks hashed with {} into a repository using {}", src.name(), dst.name()))
+        }
+        MixedHashMethods(bundle: BundleId, found: HashMethod, configured: HashMethod) {
+            description(tr!("Mixed hash methods"))
+            display("{}", tr_format!("Repository error: bundle {} uses the hash method {} but the repository is configured to use {}", bundle, found.name(), configured.name()))
+        }
+        AppendOnly {
+            description(tr!("Append-only repository"))
+            display("{}", tr_format!("The repository is append-only, this operation requires the --admin flag"))
+        }
         Backup(err: BackupError) {
             from()
             cause(err)
@@ -0,0 +1,177 @@
+use std::path::Path;
+
+use regex::{self, RegexSet};
+
+
+struct ExcludeRule {
+    /// The pattern re-includes paths that have been excluded by an earlier pattern
+    negated: bool
+}
+
+
+/// Compiled list of gitignore-style exclude patterns
+///
+/// Patterns are checked in order and the last matching pattern decides whether a path is
+/// excluded. Patterns starting with `!` re-include paths that an earlier pattern excluded.
+/// Patterns starting with `/` are anchored and must match from the beginning of the path,
+/// other patterns can match at any component. A trailing `/` restricts a pattern to
+/// folders. In the patterns, `?` matches any single character and `*` any string within a
+/// path component while `**` also spans components. All other characters match literally.
+///
+/// A matching pattern also excludes all paths inside the matched folder.
+pub struct ExcludeMatcher {
+    rules: Vec<ExcludeRule>,
+    set: RegexSet
+}
+
+impl ExcludeMatcher {
+    /// Compiles the patterns, empty lines and lines starting with `#` are ignored
+    pub fn new<I: IntoIterator<Item = S>, S: AsRef<str>>(patterns: I) -> Result<Self, regex::Error> {
+        let mut rules = vec![];
+        let mut regexes = vec![];
+        for pattern in patterns {
+            let mut pattern = pattern.as_ref().trim_right();
+            if pattern.is_empty() || pattern.starts_with('#') {
+                continue;
+            }
+            let negated = pattern.starts_with('!');
+            if negated {
+                pattern = &pattern[1..];
+            } else if pattern.starts_with("\\!") || pattern.starts_with("\\#") {
+                pattern = &pattern[1..];
+            }
+            regexes.push(pattern_to_regex(pattern));
+            rules.push(ExcludeRule { negated });
+        }
+        Ok(ExcludeMatcher {
+            rules,
+            set: try!(RegexSet::new(regexes))
+        })
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns whether the path is excluded, `is_dir` tells whether the path is a folder
+    pub fn is_excluded<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> bool {
+        if self.rules.is_empty() {
+            return false;
+        }
+        let mut path = path.as_ref().to_string_lossy().into_owned();
+        // Folder patterns require a slash after the name
+        if is_dir {
+            path.push('/');
+        }
+        match self.set.matches(&path).iter().last() {
+            Some(index) => !self.rules[index].negated,
+            None => false
+        }
+    }
+}
+
+
+fn pattern_to_regex(pattern: &str) -> String {
+    let (mut regex, mut pattern) = if pattern.starts_with('/') {
+        ("^/".to_string(), &pattern[1..])
+    } else {
+        ("(^|/)".to_string(), pattern)
+    };
+    let dir_only = pattern.ends_with('/');
+    if dir_only {
+        pattern = pattern.trim_right_matches('/');
+    }
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                let at_start = i == 0 || chars[i - 1] == '/';
+                if at_start && chars.get(i + 2) == Some(&'/') {
+                    // `**/` matches any number of folders, including none
+                    regex.push_str("(.*/)?");
+                    i += 3;
+                    continue;
+                } else if at_start && i + 2 == chars.len() && i > 0 {
+                    // A trailing `/**` matches everything inside the folder
+                    regex.push_str(".+");
+                } else {
+                    regex.push_str(".*");
+                }
+                i += 2;
+                continue;
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string()))
+        }
+        i += 1;
+    }
+    regex.push_str(if dir_only { "/" } else { "(/|$)" });
+    regex
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_literal() {
+        let matcher = ExcludeMatcher::new(&["/tmp", "remote/bundles", "# comment", ""]).unwrap();
+        assert!(!matcher.is_empty());
+        assert!(matcher.is_excluded("/tmp", true));
+        assert!(matcher.is_excluded("/tmp/file", false));
+        assert!(!matcher.is_excluded("/var/tmp", true));
+        assert!(!matcher.is_excluded("/tmpfile", false));
+        assert!(matcher.is_excluded("/backup/remote/bundles", true));
+        assert!(!matcher.is_excluded("/backup/remote/bundles2", true));
+        assert!(!matcher.is_excluded("/# comment", false));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let matcher = ExcludeMatcher::new(&["**/*.tmp", "/home/*/.cache", "/usr/**bin", "file?.txt"]).unwrap();
+        assert!(matcher.is_excluded("/a.tmp", false));
+        assert!(matcher.is_excluded("/some/deep/folder/b.tmp", false));
+        assert!(!matcher.is_excluded("/some/b.tmp.bak", false));
+        assert!(matcher.is_excluded("/home/user/.cache", true));
+        assert!(!matcher.is_excluded("/home/user/sub/.cache", true));
+        assert!(matcher.is_excluded("/usr/local/sbin", true));
+        assert!(matcher.is_excluded("/data/file1.txt", false));
+        assert!(!matcher.is_excluded("/data/file10.txt", false));
+    }
+
+    #[test]
+    fn test_double_star() {
+        let matcher = ExcludeMatcher::new(&["/home/**/Trash", "/srv/**"]).unwrap();
+        assert!(matcher.is_excluded("/home/Trash", true));
+        assert!(matcher.is_excluded("/home/user/.local/Trash", true));
+        assert!(!matcher.is_excluded("/srv", true));
+        assert!(matcher.is_excluded("/srv/www", true));
+    }
+
+    #[test]
+    fn test_dir_only() {
+        let matcher = ExcludeMatcher::new(&["/cache/"]).unwrap();
+        assert!(matcher.is_excluded("/cache", true));
+        assert!(matcher.is_excluded("/cache/file", false));
+        assert!(!matcher.is_excluded("/cache", false));
+        assert!(!matcher.is_excluded("/data/cache", true));
+    }
+
+    #[test]
+    fn test_negation() {
+        let matcher = ExcludeMatcher::new(&["*.log", "!important.log", "\\!literal"]).unwrap();
+        assert!(matcher.is_excluded("/var/debug.log", false));
+        assert!(!matcher.is_excluded("/var/important.log", false));
+        assert!(matcher.is_excluded("/var/!literal", false));
+        let matcher = ExcludeMatcher::new(&["!important.log", "*.log"]).unwrap();
+        assert!(matcher.is_excluded("/var/important.log", false));
+    }
+
+}
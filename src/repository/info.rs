@@ -1,6 +1,6 @@
 use prelude::*;
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 
 pub struct BundleAnalysis {
@@ -35,7 +35,71 @@ pub struct RepositoryInfo {
     pub avg_chunk_size: f32,
     pub index_size: usize,
     pub index_capacity: usize,
-    pub index_entries: usize
+    pub index_entries: usize,
+    pub encryption_method: Option<String>,
+    pub default_key: Option<String>,
+    pub bundle_keys: Vec<(String, bool)>,
+    pub can_decrypt: bool,
+    /// Bundles grouped by their compression, sorted by the compression
+    pub compressions: Vec<CompressionUsage>
+}
+
+
+/// Bundles that use the same compression method and level
+pub struct CompressionUsage {
+    /// `None` for uncompressed bundles
+    pub compression: Option<String>,
+    pub bundle_count: usize,
+    pub raw_size: u64,
+    pub encoded_size: u64
+}
+
+impl CompressionUsage {
+    #[inline]
+    pub fn compression_ratio(&self) -> f32 {
+        self.encoded_size as f32 / self.raw_size as f32
+    }
+}
+
+
+/// Location of a chunk in its bundle as recorded in the index
+pub struct IndexEntry {
+    pub hash: Hash,
+    pub bundle: BundleId,
+    pub chunk: usize,
+    pub offset: usize,
+    pub size: usize
+}
+
+
+/// Deduplication of the file contents across all backups
+///
+/// References are counted once per distinct stored file, so files that are unchanged between
+/// backups are only counted once.
+pub struct DedupAnalysis {
+    /// Number of distinct chunks that are used for file contents
+    pub unique_chunks: usize,
+    pub unique_size: u64,
+    /// Number of times chunks are used for file contents
+    pub references: usize,
+    pub referenced_size: u64,
+    /// Number of chunks in the index, including metadata and unused chunks
+    pub index_chunks: usize,
+    /// The most referenced chunks with their size and number of references
+    pub top_chunks: Vec<(Hash, u32, usize)>
+}
+
+impl DedupAnalysis {
+    /// Ratio of the size of all references to the size of the distinct chunks
+    ///
+    /// Without any chunks, nothing is deduplicated and the factor is 1.
+    #[inline]
+    pub fn get_dedup_factor(&self) -> f32 {
+        if self.unique_size == 0 {
+            return 1.0;
+        }
+        self.referenced_size as f32 / self.unique_size as f32
+    }
 }
 
 
@@ -102,15 +166,16 @@ impl Repository {
             }
             let inode = try!(self.get_inode(&chunks));
             // Mark the content chunks as used
-            match inode.data {
+            match inode.data.as_ref().map(FileData::stored) {
                 None |
-                Some(FileData::Inline(_)) => (),
-                Some(FileData::ChunkedDirect(chunks)) => {
-                    try!(self.mark_used(&mut usage, &chunks));
+                Some(&FileData::Inline(_)) |
+                Some(&FileData::Sparse(_)) => (),
+                Some(&FileData::ChunkedDirect(ref chunks)) => {
+                    try!(self.mark_used(&mut usage, chunks));
                 }
-                Some(FileData::ChunkedIndirect(chunks)) => {
-                    if try!(self.mark_used(&mut usage, &chunks)) {
-                        let chunk_data = try!(self.get_data(&chunks));
+                Some(&FileData::ChunkedIndirect(ref chunks)) => {
+                    if try!(self.mark_used(&mut usage, chunks)) {
+                        let chunk_data = try!(self.get_data(chunks));
                         let chunks = ChunkList::read_from(&chunk_data);
                         try!(self.mark_used(&mut usage, &chunks));
                     }
@@ -127,6 +192,74 @@ impl Repository {
         Ok(usage)
     }
 
+    /// Counts how often the chunks are used for file contents in all backups
+    ///
+    /// Returns the `top` most referenced chunks along with the totals.
+    pub fn find_duplicate_chunks(&mut self, top: usize) -> Result<DedupAnalysis, RepositoryError> {
+        let _lock = try!(self.lock(false));
+        let mut counts: HashMap<Hash, (u32, usize)> = HashMap::new();
+        let mut visited = HashSet::new();
+        let mut todo = VecDeque::new();
+        let backup_map = match self.get_all_backups() {
+            Ok(backup_map) => backup_map,
+            Err(RepositoryError::BackupFile(BackupFileError::PartialBackupsList(backup_map,
+                                                                                _failed))) => {
+                tr_warn!("Some backups could not be read, ignoring them");
+                backup_map
+            }
+            Err(err) => return Err(err),
+        };
+        for (_name, backup) in backup_map {
+            todo.push_back(backup.root);
+        }
+        while let Some(chunks) = todo.pop_back() {
+            // Unchanged subtrees are shared between backups and only counted once
+            if !visited.insert(chunks.clone()) {
+                continue;
+            }
+            let inode = try!(self.get_inode(&chunks));
+            let content = match inode.data.as_ref().map(FileData::stored) {
+                Some(&FileData::ChunkedDirect(ref chunks)) => Some(chunks.clone()),
+                Some(&FileData::ChunkedIndirect(ref chunks)) => {
+                    let chunk_data = try!(self.get_data(chunks));
+                    Some(ChunkList::read_from(&chunk_data))
+                }
+                _ => None
+            };
+            if let Some(content) = content {
+                for &(hash, len) in content.iter() {
+                    counts.entry(hash).or_insert((len, 0)).1 += 1;
+                }
+            }
+            if let Some(children) = inode.children {
+                for (_name, chunks) in children {
+                    todo.push_back(chunks);
+                }
+            }
+        }
+        let mut analysis = DedupAnalysis {
+            unique_chunks: counts.len(),
+            unique_size: 0,
+            references: 0,
+            referenced_size: 0,
+            index_chunks: self.index.len(),
+            top_chunks: Vec::with_capacity(top)
+        };
+        for &(len, refs) in counts.values() {
+            analysis.unique_size += u64::from(len);
+            analysis.references += refs;
+            analysis.referenced_size += u64::from(len) * refs as u64;
+        }
+        let mut chunks: Vec<(Hash, u32, usize)> = counts
+            .into_iter()
+            .map(|(hash, (len, refs))| (hash, len, refs))
+            .collect();
+        chunks.sort_by(|a, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)));
+        chunks.truncate(top);
+        analysis.top_chunks = chunks;
+        Ok(analysis)
+    }
+
     #[inline]
     pub fn list_bundles(&self) -> Vec<&BundleInfo> {
         self.bundles.list_bundles()
@@ -142,7 +275,42 @@ impl Repository {
         let encoded_data_size = bundles.iter().map(|b| b.encoded_size as u64).sum();
         let raw_data_size = bundles.iter().map(|b| b.raw_size as u64).sum();
         let chunk_count = bundles.iter().map(|b| b.chunk_count).sum();
+        let crypto = self.crypto.lock().unwrap();
+        let mut seen_keys = HashSet::new();
+        let mut bundle_keys = vec![];
+        for bundle in &bundles {
+            if let Some(ref encryption) = bundle.encryption {
+                let key = to_hex(&encryption.1[..]);
+                if seen_keys.insert(key.clone()) {
+                    bundle_keys.push((key, crypto.can_decrypt(encryption)));
+                }
+            }
+        }
+        bundle_keys.sort();
+        let can_decrypt = bundle_keys.iter().all(|&(_, available)| available);
+        let mut compressions: HashMap<Option<String>, CompressionUsage> = HashMap::new();
+        for bundle in &bundles {
+            let compression = bundle.compression.as_ref().map(|c| c.to_string());
+            let usage = compressions.entry(compression.clone()).or_insert_with(|| {
+                CompressionUsage {
+                    compression,
+                    bundle_count: 0,
+                    raw_size: 0,
+                    encoded_size: 0
+                }
+            });
+            usage.bundle_count += 1;
+            usage.raw_size += bundle.raw_size as u64;
+            usage.encoded_size += bundle.encoded_size as u64;
+        }
+        let mut compressions: Vec<_> = compressions.into_iter().map(|(_, usage)| usage).collect();
+        compressions.sort_by(|a, b| a.compression.cmp(&b.compression));
         RepositoryInfo {
+            encryption_method: self.config.encryption.as_ref().map(|e| e.0.to_string()),
+            default_key: self.config.encryption.as_ref().map(|e| to_hex(&e.1[..])),
+            bundle_keys,
+            can_decrypt,
+            compressions,
             bundle_count: bundles.len(),
             chunk_count,
             encoded_data_size,
@@ -155,6 +323,65 @@ impl Repository {
         }
     }
 
+    /// Calls `f` for every entry of the index and returns the number of entries
+    ///
+    /// The offset is the position of the chunk in the decoded contents of its bundle. The
+    /// chunk lists of the bundles are loaded on demand and kept until the dump is done.
+    pub fn dump_index<F>(&self, mut f: F) -> Result<usize, RepositoryError>
+    where
+        F: FnMut(IndexEntry) -> Result<(), RepositoryError>,
+    {
+        let mut bundles: HashMap<u32, (BundleId, Vec<(usize, usize)>)> = HashMap::new();
+        let mut count = 0;
+        for (hash, location) in self.index.iter() {
+            if !bundles.contains_key(&location.bundle) {
+                let bundle_id = try!(self.get_bundle_id(location.bundle));
+                let chunk_list = try!(self.bundles.get_chunk_list(&bundle_id));
+                let mut offset = 0;
+                let mut chunks = Vec::with_capacity(chunk_list.len());
+                for &(_hash, len) in chunk_list.iter() {
+                    chunks.push((offset, len as usize));
+                    offset += len as usize;
+                }
+                bundles.insert(location.bundle, (bundle_id, chunks));
+            }
+            let (ref bundle_id, ref chunks) = bundles[&location.bundle];
+            let (offset, size) = try!(chunks.get(location.chunk as usize).cloned().ok_or_else(|| {
+                IntegrityError::NoSuchChunk(bundle_id.clone(), location.chunk)
+            }));
+            try!(f(IndexEntry {
+                hash: *hash,
+                bundle: bundle_id.clone(),
+                chunk: location.chunk as usize,
+                offset,
+                size
+            }));
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns all chunks of the bundle with their position in the decoded bundle contents
+    ///
+    /// Only the header and the chunk list of the bundle are read, so this also works without
+    /// fetching the data of remote bundles.
+    pub fn get_bundle_chunks(&self, bundle_id: &BundleId) -> Result<Vec<IndexEntry>, RepositoryError> {
+        let chunk_list = try!(self.bundles.get_chunk_list(bundle_id));
+        let mut offset = 0;
+        let mut chunks = Vec::with_capacity(chunk_list.len());
+        for (num, &(hash, len)) in chunk_list.iter().enumerate() {
+            chunks.push(IndexEntry {
+                hash,
+                bundle: bundle_id.clone(),
+                chunk: num,
+                offset,
+                size: len as usize
+            });
+            offset += len as usize;
+        }
+        Ok(chunks)
+    }
+
     #[allow(dead_code)]
     pub fn statistics(&self) -> RepositoryStatistics {
         RepositoryStatistics {
@@ -2,8 +2,9 @@ use prelude::*;
 
 use super::*;
 
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use pbr::ProgressBar;
 
@@ -23,6 +24,26 @@ quick_error!{
             description(tr!("Missing bundle"))
             display("{}", tr_format!("Missing bundle: {}", id))
         }
+        CorruptChunk(hash: Hash) {
+            description(tr!("Corrupt chunk"))
+            display("{}", tr_format!("The contents of chunk {} do not match its hash", hash))
+        }
+        CorruptChunkInBundle(hash: Hash, bundle: BundleId, chunk: u32, offset: usize) {
+            description(tr!("Corrupt chunk"))
+            display("{}", tr_format!("Checksum failed: the contents of chunk {} do not match its hash (bundle {}, chunk {} at offset {})", hash, bundle, chunk, offset))
+        }
+        WrongFileSize(path: PathBuf, expected: u64, size: u64) {
+            description(tr!("Wrong file size"))
+            display("{}", tr_format!("The data of {:?} has {} bytes instead of {}", path, size, expected))
+        }
+        CorruptFiles(paths: Vec<PathBuf>) {
+            description(tr!("Corrupt files"))
+            display("{}", tr_format!("{} files were skipped due to corrupt chunks", paths.len()))
+        }
+        CorruptCopy(path: PathBuf) {
+            description(tr!("Corrupt copy"))
+            display("{}", tr_format!("The copy {:?} differs from the original", path))
+        }
         NoSuchChunk(bundle: BundleId, chunk: u32) {
             description(tr!("No such chunk"))
             display("{}", tr_format!("Bundle {} does not contain the chunk {}", bundle, chunk))
@@ -101,13 +122,14 @@ impl Repository {
         inode: &Inode,
         checked: &mut Bitmap,
     ) -> Result<(), RepositoryError> {
-        match inode.data {
+        match inode.data.as_ref().map(FileData::stored) {
             None |
-            Some(FileData::Inline(_)) => (),
-            Some(FileData::ChunkedDirect(ref chunks)) => {
+            Some(&FileData::Inline(_)) |
+            Some(&FileData::Sparse(_)) => (),
+            Some(&FileData::ChunkedDirect(ref chunks)) => {
                 try!(self.check_chunks(checked, chunks, true));
             }
-            Some(FileData::ChunkedIndirect(ref chunks)) => {
+            Some(&FileData::ChunkedIndirect(ref chunks)) => {
                 if try!(self.check_chunks(checked, chunks, false)) {
                     let chunk_data = try!(self.get_data(chunks));
                     let chunks2 = ChunkList::read_from(&chunk_data);
@@ -137,8 +159,8 @@ impl Repository {
         if let Err(err) = self.check_inode_contents(&inode, checked) {
             if repair {
                 tr_warn!(
-                    "Problem detected: data of {:?} is corrupt\n\tcaused by: {}",
-                    path,
+                    "Problem detected: data of {} is corrupt\n\tcaused by: {}",
+                    escape(&path),
                     err
                 );
                 tr_info!("Removing inode data");
@@ -162,8 +184,8 @@ impl Repository {
                     Err(err) => {
                         if repair {
                             tr_warn!(
-                                "Problem detected: inode {:?} is corrupt\n\tcaused by: {}",
-                                path.join(name),
+                                "Problem detected: inode {} is corrupt\n\tcaused by: {}",
+                                escape(path.join(name)),
                                 err
                             );
                             tr_info!("Removing broken inode from backup");
@@ -187,6 +209,18 @@ impl Repository {
         }
     }
 
+    /// Checks that all chunks of the subtree are in the index without repairing anything
+    ///
+    /// Chunks that are marked in `checked` are not checked again.
+    pub fn check_subtree_chunks(
+        &mut self,
+        path: &Path,
+        chunks: &[Chunk],
+        checked: &mut Bitmap,
+    ) -> Result<(), RepositoryError> {
+        self.check_subtree(path.to_path_buf(), chunks, checked, false).map(|_| ())
+    }
+
     fn evacuate_broken_backup(&self, name: &str) -> Result<(), RepositoryError> {
         tr_warn!(
             "The backup {} was corrupted and needed to be modified.",
@@ -203,7 +237,7 @@ impl Repository {
             try!(fs::copy(&src, &dst));
             try!(fs::remove_file(&src));
         }
-        tr_info!("The original backup was renamed to {:?}", dst);
+        tr_info!("The original backup was renamed to {}", escape(&dst));
         Ok(())
     }
 
@@ -273,8 +307,8 @@ impl Repository {
         if let Err(err) = self.check_inode_contents(&inode, &mut checked) {
             if repair {
                 tr_warn!(
-                    "Problem detected: data of {:?} is corrupt\n\tcaused by: {}",
-                    path,
+                    "Problem detected: data of {} is corrupt\n\tcaused by: {}",
+                    escape(&path),
                     err
                 );
                 tr_info!("Removing inode data");
@@ -299,8 +333,8 @@ impl Repository {
                     Err(err) => {
                         if repair {
                             tr_warn!(
-                                "Problem detected: inode {:?} is corrupt\n\tcaused by: {}",
-                                path.join(name),
+                                "Problem detected: inode {} is corrupt\n\tcaused by: {}",
+                                escape(path.join(name)),
                                 err
                             );
                             tr_info!("Removing broken inode from backup");
@@ -439,6 +473,9 @@ impl Repository {
             if self.next_data_bundle == bundle_id {
                 self.next_data_bundle = self.next_free_bundle_id()
             }
+            if self.next_raw_bundle == bundle_id {
+                self.next_raw_bundle = self.next_free_bundle_id()
+            }
         }
         self.save_bundle_map()
     }
@@ -463,6 +500,92 @@ impl Repository {
         Ok(())
     }
 
+    /// Rebuilds the index from the chunk lists stored in the bundle files
+    ///
+    /// Unlike `rebuild_index`, all bundle files are scanned and the chunk lists are read from
+    /// the bundles themselves and not from the local bundle cache. Bundles that are missing in
+    /// the bundle map are added to it and unreadable bundles are skipped with a warning.
+    /// The new index is written to a separate file that replaces the index only when it is
+    /// complete, so an interrupted or failed rebuild keeps the old index.
+    /// Returns the number of indexed chunks.
+    pub fn rebuild_index_from_bundles(&mut self) -> Result<usize, RepositoryError> {
+        try!(self.write_mode());
+        let path = self.layout.index_path();
+        let tmp_path = path.with_extension("new");
+        let index = match self.write_index_from_bundles(&tmp_path) {
+            Ok(index) => index,
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(err);
+            }
+        };
+        try!(fs::rename(&tmp_path, &path).map_err(IndexError::Io));
+        let count = index.len();
+        self.index = index;
+        Ok(count)
+    }
+
+    fn write_index_from_bundles(&mut self, path: &Path) -> Result<Index<Hash, Location>, RepositoryError> {
+        let mut index = try!(Index::create(path, &INDEX_MAGIC, INDEX_VERSION));
+        let files = try!(self.bundles.list_bundle_files());
+        let mut numbers: HashMap<_, _> = self.bundle_map.bundles().into_iter()
+            .map(|(num, id)| (id, num)).collect();
+        let mut seen = HashSet::new();
+        let mut map_changed = false;
+        for bundle_path in ProgressIter::new(tr!("reading bundles"), files.len(), files.into_iter()) {
+            let mut bundle = match BundleReader::load(bundle_path.clone(), self.get_crypto()) {
+                Ok(bundle) => bundle,
+                Err(err) => {
+                    tr_warn!("Skipping unreadable bundle {}\n\tcaused by: {}", escape(&bundle_path), err);
+                    continue;
+                }
+            };
+            let id = bundle.info.id.clone();
+            if self.bundles.get_bundle_info(&id).is_none() {
+                tr_warn!("Skipping bundle {} that is not in the bundle list", escape(&bundle_path));
+                continue;
+            }
+            if !seen.insert(id.clone()) {
+                tr_warn!("Skipping duplicate of bundle {} in {}", id, escape(&bundle_path));
+                continue;
+            }
+            let chunks = match bundle.get_chunk_list() {
+                Ok(chunks) => chunks.clone(),
+                Err(err) => {
+                    tr_warn!("Skipping unreadable bundle {}\n\tcaused by: {}", escape(&bundle_path), err);
+                    continue;
+                }
+            };
+            let num = match numbers.get(&id).cloned() {
+                Some(num) => num,
+                None => {
+                    let num = self.next_free_bundle_id();
+                    self.bundle_map.set(num, id.clone());
+                    numbers.insert(id, num);
+                    map_changed = true;
+                    num
+                }
+            };
+            for (i, &(hash, _len)) in chunks.iter().enumerate() {
+                try!(index.set(
+                    &hash,
+                    &Location {
+                        bundle: num,
+                        chunk: i as u32
+                    }
+                ));
+            }
+        }
+        try!(index.sync());
+        if map_changed {
+            try!(self.save_bundle_map());
+        }
+        Ok(index)
+    }
+
+    /// Checks the structure of the index and that every entry points to an existing chunk
+    ///
+    /// No bundle data is read, so this is quick enough to run after a crash.
     #[inline]
     pub fn check_index(&mut self, repair: bool) -> Result<(), RepositoryError> {
         if repair {
@@ -495,6 +618,9 @@ impl Repository {
         Ok(())
     }
 
+    /// Checks the header, chunk list and size of all bundles
+    ///
+    /// With `full`, the contents of the bundles are also decoded and compared to the chunk list.
     #[inline]
     pub fn check_bundles(&mut self, full: bool, repair: bool) -> Result<(), RepositoryError> {
         if repair {
@@ -509,4 +635,153 @@ impl Repository {
         }
         Ok(())
     }
+
+    /// Reads the chunk from its bundle and checks that its contents match its hash
+    ///
+    /// Returns the length of the chunk data.
+    fn verify_chunk(&mut self, hash: Hash) -> Result<usize, RepositoryError> {
+        let found = try!(self.index.get(&hash).ok_or(IntegrityError::MissingChunk(hash)));
+        let bundle_id = try!(self.get_bundle_id(found.bundle));
+        let data = try!(self.bundles.get_chunk(&bundle_id, found.chunk as usize));
+        if self.config.hash.hash(&data) != hash {
+            let (offset, _) = try!(self.bundles.get_chunk_position(
+                &bundle_id,
+                found.chunk as usize
+            ));
+            return Err(
+                IntegrityError::CorruptChunkInBundle(hash, bundle_id, found.chunk, offset).into()
+            );
+        }
+        Ok(data.len())
+    }
+
+    /// Verifies all chunks of the list and returns the total length of their data
+    fn verify_chunks(
+        &mut self,
+        chunks: &[Chunk],
+        verified: &mut HashMap<Hash, usize>,
+    ) -> Result<u64, RepositoryError> {
+        let mut size = 0;
+        for &(hash, _) in chunks {
+            let len = match verified.get(&hash).cloned() {
+                Some(len) => len,
+                None => {
+                    let len = try!(self.verify_chunk(hash));
+                    verified.insert(hash, len);
+                    len
+                }
+            };
+            size += len as u64;
+        }
+        Ok(size)
+    }
+
+    fn verify_subtree(
+        &mut self,
+        path: &Path,
+        inode: &Inode,
+        verified: &mut HashMap<Hash, usize>,
+    ) -> Result<(), RepositoryError> {
+        let size = match inode.data.as_ref().map(FileData::stored) {
+            None => 0,
+            Some(&FileData::Inline(ref data)) => data.len() as u64,
+            Some(data) => {
+                if let FileData::ChunkedIndirect(ref chunks) = *data {
+                    try!(self.verify_chunks(chunks, verified));
+                }
+                match try!(self.get_data_chunks(data)) {
+                    Some(chunks) => try!(self.verify_chunks(&chunks, verified)),
+                    None => 0
+                }
+            }
+        };
+        if inode.file_type == FileType::File || inode.file_type == FileType::HardLink {
+            // Only the data regions of sparse files are stored
+            let expected = match inode.data {
+                Some(FileData::Sparse(ref sparse)) => sparse.regions.iter().map(|r| r.1).sum(),
+                _ => inode.size
+            };
+            if size != expected {
+                return Err(
+                    IntegrityError::WrongFileSize(path.to_path_buf(), expected, size).into()
+                );
+            }
+        }
+        if let Some(ref children) = inode.children {
+            for (name, chunks) in children {
+                try!(self.verify_chunks(chunks, verified));
+                let child = try!(self.get_inode(chunks));
+                try!(self.verify_subtree(&path.join(name), &child, verified));
+            }
+        }
+        Ok(())
+    }
+
+    /// Verifies the data of the backup, optionally only the subtree at `path`
+    ///
+    /// In contrast to the structural checks, all chunks of the backup are read, decrypted and
+    /// decompressed and their contents are hashed again and compared to their ids. The size
+    /// of the data of each file is compared to the size in its inode. The first chunk that
+    /// does not match is reported with its bundle and its offset in the bundle contents.
+    pub fn verify_backup(
+        &mut self,
+        backup: &Backup,
+        path: Option<&Path>,
+    ) -> Result<(), RepositoryError> {
+        let _lock = try!(self.lock(false));
+        tr_info!("Verifying backup data...");
+        let mut verified = HashMap::new();
+        let (path, inode) = match path {
+            Some(path) => {
+                let mut inodes = try!(self.get_backup_path(backup, path));
+                (path.to_path_buf(), inodes.pop().unwrap())
+            }
+            None => {
+                try!(self.verify_chunks(&backup.root, &mut verified));
+                (PathBuf::from("/"), try!(self.get_inode(&backup.root)))
+            }
+        };
+        try!(self.verify_subtree(&path, &inode, &mut verified));
+        let size: usize = verified.values().sum();
+        tr_info!(
+            "Verified {} chunks with {} of data",
+            verified.len(),
+            to_file_size(size as u64)
+        );
+        Ok(())
+    }
+
+    /// Reads back all chunks that have been written since the running backup started
+    ///
+    /// The bundles must have been flushed before. Every chunk is decrypted, decompressed and
+    /// compared to its hash. The first chunk that does not match is returned as an error.
+    pub fn verify_written_chunks(&mut self) -> Result<(), RepositoryError> {
+        let chunks = self.written_chunks.take().unwrap_or_default();
+        tr_info!("Verifying {} new chunks...", chunks.len());
+        let start = Instant::now();
+        let mut progress = if self.show_progress {
+            Some(ProgressReporter::new(tr!("verify"), 0))
+        } else {
+            None
+        };
+        let mut size = 0;
+        for hash in &chunks {
+            let len = try!(self.verify_chunk(*hash)) as u64;
+            size += len;
+            if let Some(ref mut progress) = progress {
+                progress.add(len, "");
+            }
+        }
+        drop(progress);
+        let elapsed = start.elapsed();
+        let duration = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1e9;
+        tr_info!(
+            "Verified {} new chunks with {} of data in {}, {}",
+            chunks.len(),
+            to_file_size(size),
+            to_duration(duration),
+            to_speed(size, duration)
+        );
+        Ok(())
+    }
 }
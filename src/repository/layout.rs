@@ -2,6 +2,10 @@ use prelude::*;
 
 use std::path::{Path, PathBuf};
 
+/// Name of the copy of the key derivation parameters in the remote storage
+pub const REMOTE_KEY_DERIVATION_FILE: &'static str = "key_derivation.yaml";
+
+
 #[derive(Clone)]
 pub struct RepositoryLayout(PathBuf);
 
@@ -55,11 +59,32 @@ impl RepositoryLayout {
         self.backups_path().join(format!("{}.backup", name))
     }
 
+    #[inline]
+    pub fn checkpoints_path(&self) -> PathBuf {
+        self.0.join("checkpoints")
+    }
+
+    #[inline]
+    pub fn checkpoint_path(&self, name: &str) -> PathBuf {
+        self.checkpoints_path().join(format!("{}.checkpoint", name))
+    }
+
+    #[inline]
+    pub fn vacuum_journal_path(&self) -> PathBuf {
+        self.0.join("vacuum.journal")
+    }
+
     #[inline]
     pub fn remote_path(&self) -> PathBuf {
         self.0.join("remote")
     }
 
+    /// Copy of the key derivation parameters, needed to import a repository with a password
+    #[inline]
+    pub fn remote_key_derivation_path(&self) -> PathBuf {
+        self.remote_path().join(REMOTE_KEY_DERIVATION_FILE)
+    }
+
     #[inline]
     pub fn remote_exists(&self) -> bool {
         self.remote_bundles_path().exists() && self.backups_path().exists() &&
@@ -81,6 +106,16 @@ impl RepositoryLayout {
         self.0.join("remote/bundles")
     }
 
+    #[inline]
+    pub fn remote_parity_path(&self) -> PathBuf {
+        self.0.join("remote/parity")
+    }
+
+    #[inline]
+    pub fn parity_path(&self, group: &BundleId, index: usize) -> PathBuf {
+        self.remote_parity_path().join(format!("{}.{}.parity", group, index))
+    }
+
     #[inline]
     pub fn local_bundles_path(&self) -> PathBuf {
         self.0.join("bundles/cached")
@@ -107,9 +142,22 @@ impl RepositoryLayout {
         (folder, file.into())
     }
 
+    /// Returns the folder and file name for a new remote bundle
+    ///
+    /// With a `shard` of `n`, the bundle is stored in a folder named after the first `n`
+    /// characters of its file name. Without it, the folders are nested deeper as the number of
+    /// bundles grows.
     #[inline]
-    pub fn remote_bundle_path(&self, count: usize) -> (PathBuf, PathBuf) {
-        self.bundle_path(&BundleId::random(), self.remote_bundles_path(), count)
+    pub fn remote_bundle_path(&self, count: usize, shard: Option<usize>) -> (PathBuf, PathBuf) {
+        let bundle = BundleId::random();
+        match shard {
+            Some(0) => (self.remote_bundles_path(), (bundle.to_string() + ".bundle").into()),
+            Some(shard) => {
+                let file = bundle.to_string() + ".bundle";
+                (self.remote_bundles_path().join(&file[..shard]), file.into())
+            }
+            None => self.bundle_path(&bundle, self.remote_bundles_path(), count)
+        }
     }
 
     #[inline]
@@ -145,3 +193,34 @@ impl RepositoryLayout {
         self.0.join("dirty")
     }
 }
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_remote_bundle_path() {
+        let layout = RepositoryLayout::new("/repo");
+        let base = layout.remote_bundles_path();
+        let (folder, file) = layout.remote_bundle_path(1000, Some(0));
+        assert_eq!(folder, base);
+        assert!(file.to_string_lossy().ends_with(".bundle"));
+        let (folder, file) = layout.remote_bundle_path(0, Some(3));
+        let file = file.to_string_lossy().to_string();
+        assert_eq!(folder, base.join(&file[..3]));
+        // Without a shard, the folders are nested deeper as the number of bundles grows
+        let (folder, _) = layout.remote_bundle_path(10, None);
+        assert_eq!(folder, base);
+        let (folder, file) = layout.remote_bundle_path(1000, None);
+        let file = file.to_string_lossy().to_string();
+        assert_eq!(folder, base.join(&file[..2]));
+        let (folder, file) = layout.remote_bundle_path(100_000, None);
+        let file = file.to_string_lossy().to_string();
+        assert_eq!(folder, base.join(&file[..2]).join(&file[2..4]));
+    }
+
+}
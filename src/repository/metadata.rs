@@ -1,9 +1,12 @@
 use prelude::*;
 
+use super::sparse::{self, RegionReader, ZeroSkipper, SparseWriter, HOLE_BLOCK_SIZE};
+
 use filetime::{self, FileTime};
 use xattr;
 use libc;
 
+use std::cmp::{max, min};
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::fs::{self, File, Permissions};
@@ -13,6 +16,16 @@ use std::io::{self, Read, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::fmt;
 use std::ffi;
+use std::time::UNIX_EPOCH;
+
+
+/// Files of at least this size are always chunked, regardless of the inline size
+///
+/// Inline data is part of the inode and is loaded whenever the inode is read.
+pub const MAX_INLINE_SIZE: u64 = HOLE_BLOCK_SIZE as u64;
+
+/// Files smaller than this are stored inline unless the backup options say otherwise
+pub const DEFAULT_INLINE_SIZE: u64 = 100;
 
 
 quick_error!{
@@ -42,6 +55,11 @@ quick_error!{
             description(tr!("Failed to create entity"))
             display("{}", tr_format!("Inode error: failed to create entity {:?}\n\tcaused by: {}", path, err))
         }
+        SetOwner(err: io::Error, path: PathBuf) {
+            cause(err)
+            description(tr!("Failed to set owner"))
+            display("{}", tr_format!("Inode error: failed to set the owner of {:?}\n\tcaused by: {}", path, err))
+        }
         Integrity(reason: &'static str) {
             description(tr!("Integrity error"))
             display("{}", tr_format!("Inode error: inode integrity error: {}", reason))
@@ -69,7 +87,10 @@ pub enum FileType {
     Symlink,
     BlockDevice,
     CharDevice,
-    NamedPipe
+    NamedPipe,
+    /// Additional link to a file seen before, `symlink_target` holds the path of the first
+    /// link relative to the directory containing this one
+    HardLink
 }
 serde_impl!(FileType(u8) {
     File => 0,
@@ -77,7 +98,8 @@ serde_impl!(FileType(u8) {
     Symlink => 2,
     BlockDevice => 3,
     CharDevice => 4,
-    NamedPipe => 5
+    NamedPipe => 5,
+    HardLink => 6
 });
 impl fmt::Display for FileType {
     fn fmt(&self, format: &mut fmt::Formatter) -> Result<(), fmt::Error> {
@@ -88,6 +110,7 @@ impl fmt::Display for FileType {
             FileType::BlockDevice => write!(format, "{}", tr!("block device")),
             FileType::CharDevice => write!(format, "{}", tr!("char device")),
             FileType::NamedPipe => write!(format, "{}", tr!("named pipe")),
+            FileType::HardLink => write!(format, "{}", tr!("hard link")),
         }
     }
 }
@@ -97,15 +120,66 @@ impl fmt::Display for FileType {
 pub enum FileData {
     Inline(msgpack::Bytes),
     ChunkedDirect(ChunkList),
-    ChunkedIndirect(ChunkList)
+    ChunkedIndirect(ChunkList),
+    Sparse(SparseData)
 }
 serde_impl!(FileData(u8) {
     Inline(ByteBuf) => 0,
     ChunkedDirect(ChunkList) => 1,
-    ChunkedIndirect(ChunkList) => 2
+    ChunkedIndirect(ChunkList) => 2,
+    Sparse(SparseData) => 3
+});
+
+impl FileData {
+    /// Returns the stored data, for sparse files this is the data of the regions without holes
+    ///
+    /// The result is never sparse itself.
+    #[inline]
+    pub fn stored(&self) -> &FileData {
+        match *self {
+            FileData::Sparse(ref sparse) => &sparse.data,
+            _ => self
+        }
+    }
+}
+
+
+/// Contents of a file with holes, i.e. regions of zeros that are not stored
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct SparseData {
+    /// Offsets and lengths of the data regions, the rest of the file is zero
+    pub regions: Vec<(u64, u64)>,
+    /// Contents of all data regions one after another
+    pub data: Box<FileData>
+}
+impl Default for SparseData {
+    fn default() -> Self {
+        SparseData {
+            regions: vec![],
+            data: Box::new(FileData::Inline(vec![].into()))
+        }
+    }
+}
+serde_impl!(SparseData(u8) {
+    regions: Vec<(u64, u64)> => 0,
+    data: Box<FileData> => 1
 });
 
 
+/// How the owners of restored files are chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreOwner {
+    /// Map the owners to the local users and groups with the same names
+    Names,
+    /// Use the numeric ids stored in the backup and fail if that is not permitted
+    Numeric,
+    /// Use these user and group ids for all files and fail if that is not permitted
+    Fixed(u32, u32),
+    /// Keep the user and group of the restoring process
+    Current
+}
+
+
 #[derive(Debug, Hash, Eq, PartialEq)]
 pub struct Inode {
     pub name: String,
@@ -122,7 +196,9 @@ pub struct Inode {
     pub cum_dirs: usize,
     pub cum_files: usize,
     pub xattrs: BTreeMap<String, msgpack::Bytes>,
-    pub device: Option<(u32, u32)>
+    pub device: Option<(u32, u32)>,
+    /// Creation time if the filesystem records it, it is not restored
+    pub birth_time: Option<i64>
 }
 impl Default for Inode {
     fn default() -> Self {
@@ -141,7 +217,8 @@ impl Default for Inode {
             cum_dirs: 0,
             cum_files: 0,
             xattrs: BTreeMap::new(),
-            device: None
+            device: None,
+            birth_time: None
         }
     }
 }
@@ -160,7 +237,8 @@ serde_impl!(Inode(u8?) {
     cum_dirs: usize => 13,
     cum_files: usize => 14,
     xattrs: BTreeMap<String, msgpack::Bytes> => 15,
-    device: Option<(u32, u32)> => 16
+    device: Option<(u32, u32)> => 16,
+    birth_time: Option<i64> => 17
 });
 
 
@@ -211,17 +289,26 @@ impl Inode {
         inode.user = meta.st_uid();
         inode.group = meta.st_gid();
         inode.timestamp = meta.st_mtime();
+        // Only available via statx on filesystems that record it
+        inode.birth_time = meta.created()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs() as i64);
         if xattr::SUPPORTED_PLATFORM {
             if let Ok(attrs) = xattr::list(path) {
                 for name in attrs {
-                    if let Some(data) = try!(xattr::get(path, &name).map_err(|e| {
-                        InodeError::ReadXattr(e, path.to_owned())
-                    }))
-                    {
-                        inode.xattrs.insert(
-                            name.to_string_lossy().to_string(),
-                            data.into()
-                        );
+                    match xattr::get(path, &name) {
+                        Ok(Some(data)) => {
+                            inode.xattrs.insert(
+                                name.to_string_lossy().to_string(),
+                                data.into()
+                            );
+                        }
+                        Ok(None) => (),
+                        // The filesystem does not support it or it has been removed in between
+                        Err(ref err) if err.raw_os_error() == Some(libc::ENOTSUP) ||
+                            err.raw_os_error() == Some(libc::ENODATA) => (),
+                        Err(err) => return Err(InodeError::ReadXattr(err, path.to_owned()))
                     }
                 }
             }
@@ -233,7 +320,8 @@ impl Inode {
         let full_path = path.as_ref().join(&self.name);
         let mut file = None;
         match self.file_type {
-            FileType::File => {
+            // Hard links are created by the caller, this is the fallback if the target is missing
+            FileType::File | FileType::HardLink => {
                 file = Some(try!(File::create(&full_path).map_err(|e| {
                     InodeError::Create(e, full_path.clone())
                 })));
@@ -289,39 +377,68 @@ impl Inode {
                 }
             }
         }
-        let time = FileTime::from_seconds_since_1970(self.timestamp as u64, 0);
-        if let Err(err) = filetime::set_file_times(&full_path, time, time) {
-            tr_warn!("Failed to set file time on {:?}: {}", full_path, err);
+        Ok(file)
+    }
+
+    /// Sets the permissions, owner, times and xattrs of the inode created at `path`
+    ///
+    /// This must be called after the contents have been written as writing and changing the
+    /// owner both clear capabilities stored in the `security.capability` xattr.
+    /// Failures are only warnings, except for setting the owner with `RestoreOwner::Numeric`
+    /// and `RestoreOwner::Fixed` where the exact owner was requested.
+    pub fn restore_meta_at<P: AsRef<Path>>(
+        &self,
+        path: P,
+        owner: RestoreOwner,
+    ) -> Result<(), InodeError> {
+        let full_path = path.as_ref().join(&self.name);
+        let is_symlink = self.file_type == FileType::Symlink;
+        // Symlinks have no permissions of their own, setting them would change the target
+        if !is_symlink {
+            if let Err(err) = fs::set_permissions(&full_path, Permissions::from_mode(self.mode)) {
+                tr_warn!(
+                    "Failed to set permissions {:o} on {}: {}",
+                    self.mode,
+                    escape(&full_path),
+                    err
+                );
+            }
+        }
+        if owner != RestoreOwner::Current {
+            if let Err(err) = chown(&full_path, self.user, self.group) {
+                if owner != RestoreOwner::Names {
+                    return Err(InodeError::SetOwner(err, full_path));
+                }
+                tr_warn!(
+                    "Failed to set user {} and group {} on {}: {}",
+                    self.user,
+                    self.group,
+                    escape(&full_path),
+                    err
+                );
+            }
         }
         if !self.xattrs.is_empty() {
             if xattr::SUPPORTED_PLATFORM {
                 for (name, data) in &self.xattrs {
                     if let Err(err) = xattr::set(&full_path, name, data) {
-                        tr_warn!("Failed to set xattr {} on {:?}: {}", name, full_path, err);
+                        tr_warn!("Failed to set xattr {} on {}: {}", name, escape(&full_path), err);
                     }
                 }
             } else {
-                tr_warn!("Not setting xattr on {:?}", full_path);
+                tr_warn!("Not setting xattr on {}", escape(&full_path));
             }
         }
-        if let Err(err) = fs::set_permissions(&full_path, Permissions::from_mode(self.mode)) {
-            tr_warn!(
-                "Failed to set permissions {:o} on {:?}: {}",
-                self.mode,
-                full_path,
-                err
-            );
-        }
-        if let Err(err) = chown(&full_path, self.user, self.group) {
-            tr_warn!(
-                "Failed to set user {} and group {} on {:?}: {}",
-                self.user,
-                self.group,
-                full_path,
-                err
-            );
+        let time = FileTime::from_seconds_since_1970(self.timestamp as u64, 0);
+        let result = if is_symlink {
+            filetime::set_symlink_file_times(&full_path, time, time)
+        } else {
+            filetime::set_file_times(&full_path, time, time)
+        };
+        if let Err(err) = result {
+            tr_warn!("Failed to set file time on {}: {}", escape(&full_path), err);
         }
-        Ok(file)
+        Ok(())
     }
 
     #[inline]
@@ -351,10 +468,76 @@ impl Inode {
 
 
 impl Repository {
+    pub fn put_file_data<R: Read>(
+        &mut self,
+        inode: &mut Inode,
+        input: &mut R,
+        inline_size: u64,
+    ) -> Result<(), RepositoryError> {
+        if inode.file_type != FileType::File || inode.size == 0 {
+            // Empty files and special files have no contents, just metadata
+            inode.data = None;
+            return Ok(());
+        }
+        if inode.size < min(inline_size, MAX_INLINE_SIZE) {
+            let mut data = Vec::with_capacity(inode.size as usize);
+            try!(input.read_to_end(&mut data));
+            inode.data = Some(FileData::Inline(data.into()));
+        } else {
+            inode.data = Some(try!(self.put_chunked_data(input)));
+        }
+        Ok(())
+    }
+
+    pub fn put_chunked_data<R: Read>(&mut self, input: &mut R) -> Result<FileData, RepositoryError> {
+        let mut chunks = try!(self.put_stream(BundleMode::Data, input));
+        if chunks.len() < 10 {
+            Ok(FileData::ChunkedDirect(chunks))
+        } else {
+            let mut chunk_data = Vec::with_capacity(chunks.encoded_size());
+            chunks.write_to(&mut chunk_data).unwrap();
+            chunks = try!(self.put_data(BundleMode::Meta, &chunk_data));
+            Ok(FileData::ChunkedIndirect(chunks))
+        }
+    }
+
+    /// Stores the contents of a file that might contain holes
+    ///
+    /// The data regions are determined via `SEEK_DATA` and `SEEK_HOLE` if the filesystem
+    /// supports that, otherwise by scanning the file for blocks of zeros. Only the data
+    /// regions are stored. Files without any holes are stored like with `put_file_data`.
+    pub fn put_sparse_file_data(
+        &mut self,
+        inode: &mut Inode,
+        file: &mut File,
+    ) -> Result<(), RepositoryError> {
+        let (data, regions) = match sparse::data_regions(file, inode.size) {
+            Some(regions) => {
+                let mut reader = RegionReader::new(&mut *file, regions.clone());
+                (try!(self.put_chunked_data(&mut reader)), regions)
+            }
+            None => {
+                let mut reader = ZeroSkipper::new(&mut *file);
+                let data = try!(self.put_chunked_data(&mut reader));
+                (data, reader.into_regions())
+            }
+        };
+        inode.data = Some(if regions.len() == 1 && regions[0] == (0, inode.size) {
+            data
+        } else {
+            FileData::Sparse(SparseData {
+                regions,
+                data: Box::new(data)
+            })
+        });
+        Ok(())
+    }
+
     pub fn create_inode<P: AsRef<Path>>(
         &mut self,
         path: P,
         reference: Option<&Inode>,
+        inline_size: u64,
     ) -> Result<Inode, RepositoryError> {
         let mut inode = try!(Inode::get_from(path.as_ref()));
         if inode.file_type == FileType::File && inode.size > 0 {
@@ -365,20 +548,10 @@ impl Repository {
                 }
             }
             let mut file = try!(File::open(path));
-            if inode.size < 100 {
-                let mut data = Vec::with_capacity(inode.size as usize);
-                try!(file.read_to_end(&mut data));
-                inode.data = Some(FileData::Inline(data.into()));
+            if inode.size >= max(inline_size, HOLE_BLOCK_SIZE as u64) {
+                try!(self.put_sparse_file_data(&mut inode, &mut file));
             } else {
-                let mut chunks = try!(self.put_stream(BundleMode::Data, &mut file));
-                if chunks.len() < 10 {
-                    inode.data = Some(FileData::ChunkedDirect(chunks));
-                } else {
-                    let mut chunk_data = Vec::with_capacity(chunks.encoded_size());
-                    chunks.write_to(&mut chunk_data).unwrap();
-                    chunks = try!(self.put_data(BundleMode::Meta, &chunk_data));
-                    inode.data = Some(FileData::ChunkedIndirect(chunks));
-                }
+                try!(self.put_file_data(&mut inode, &mut file, inline_size));
             }
         }
         Ok(inode)
@@ -394,28 +567,154 @@ impl Repository {
         Ok(try!(Inode::decode(&try!(self.get_data(chunks)))))
     }
 
+    pub fn get_inode_contents<W: Write>(
+        &mut self,
+        inode: &Inode,
+        w: &mut W,
+    ) -> Result<(), RepositoryError> {
+        match inode.data {
+            Some(FileData::Sparse(ref sparse)) => {
+                let mut writer = SparseWriter::new(w, &sparse.regions);
+                try!(self.get_stored_data(&sparse.data, &mut writer));
+                try!(writer.finish(inode.size));
+            }
+            Some(ref data) => try!(self.get_stored_data(data, w)),
+            None => ()
+        }
+        Ok(())
+    }
+
+    fn get_stored_data<W: Write>(
+        &mut self,
+        data: &FileData,
+        w: &mut W,
+    ) -> Result<(), RepositoryError> {
+        match *data {
+            FileData::Inline(ref data) => {
+                try!(w.write_all(data));
+            }
+            FileData::ChunkedDirect(ref chunks) => {
+                try!(self.get_stream(chunks, w));
+            }
+            FileData::ChunkedIndirect(ref chunks) => {
+                let chunk_data = try!(self.get_data(chunks));
+                let chunks = ChunkList::read_from(&chunk_data);
+                try!(self.get_stream(&chunks, w));
+            }
+            FileData::Sparse(_) => {
+                return Err(InodeError::Integrity(tr!("Nested sparse data")).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the list of data chunks, resolving indirect chunk lists
+    ///
+    /// Returns `None` for inline data that is not stored in chunks. For sparse files, these
+    /// are the chunks of the data regions.
+    pub fn get_data_chunks(
+        &mut self,
+        data: &FileData,
+    ) -> Result<Option<ChunkList>, RepositoryError> {
+        Ok(match *data.stored() {
+            FileData::ChunkedDirect(ref chunks) => Some(chunks.clone()),
+            FileData::ChunkedIndirect(ref chunks) => {
+                Some(ChunkList::read_from(&try!(self.get_data(chunks))))
+            }
+            _ => None
+        })
+    }
+
     pub fn save_inode_at<P: AsRef<Path>>(
         &mut self,
         inode: &Inode,
         path: P,
+        owner: RestoreOwner,
     ) -> Result<(), RepositoryError> {
         if let Some(mut file) = try!(inode.create_at(path.as_ref())) {
-            if let Some(ref contents) = inode.data {
-                match *contents {
-                    FileData::Inline(ref data) => {
-                        try!(file.write_all(data));
-                    }
-                    FileData::ChunkedDirect(ref chunks) => {
-                        try!(self.get_stream(chunks, &mut file));
-                    }
-                    FileData::ChunkedIndirect(ref chunks) => {
-                        let chunk_data = try!(self.get_data(chunks));
-                        let chunks = ChunkList::read_from(&chunk_data);
-                        try!(self.get_stream(&chunks, &mut file));
-                    }
+            if let Some(FileData::Sparse(ref sparse)) = inode.data {
+                // Seeking over the holes leaves them unallocated if the filesystem supports it
+                {
+                    let mut writer = SparseWriter::with_holes(&mut file, &sparse.regions);
+                    try!(self.get_stored_data(&sparse.data, &mut writer));
+                    try!(writer.finish(inode.size));
                 }
+                try!(file.set_len(inode.size));
+            } else {
+                try!(self.get_inode_contents(inode, &mut file));
             }
         }
+        try!(inode.restore_meta_at(path, owner));
         Ok(())
     }
 }
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use repository::tests::temp_dir;
+
+
+    #[test]
+    fn test_dangling_symlink_roundtrip() {
+        let src = temp_dir("symlink-src");
+        let dst = temp_dir("symlink-dst");
+        let target = "../does/not/exist";
+        symlink(target, src.join("link")).unwrap();
+        let inode = Inode::get_from(src.join("link")).unwrap();
+        assert_eq!(inode.file_type, FileType::Symlink);
+        assert_eq!(inode.symlink_target, Some(target.to_string()));
+        let decoded = Inode::decode(&inode.encode().unwrap()).unwrap();
+        assert!(decoded.create_at(&dst).unwrap().is_none());
+        decoded.restore_meta_at(&dst, RestoreOwner::Current).unwrap();
+        let meta = fs::symlink_metadata(dst.join("link")).unwrap();
+        assert!(meta.file_type().is_symlink());
+        assert_eq!(fs::read_link(dst.join("link")).unwrap(), Path::new(target));
+        fs::remove_dir_all(&src).unwrap();
+        fs::remove_dir_all(&dst).unwrap();
+    }
+
+    #[test]
+    fn test_restore_owner_current() {
+        let src = temp_dir("owner-src");
+        let dst = temp_dir("owner-dst");
+        File::create(src.join("file")).unwrap();
+        let mut inode = Inode::get_from(src.join("file")).unwrap();
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        inode.user = uid + 1;
+        inode.group = gid + 1;
+        inode.create_at(&dst).unwrap();
+        inode.restore_meta_at(&dst, RestoreOwner::Current).unwrap();
+        let meta = fs::metadata(dst.join("file")).unwrap();
+        assert_eq!((meta.st_uid(), meta.st_gid()), (uid, gid));
+        fs::remove_dir_all(&src).unwrap();
+        fs::remove_dir_all(&dst).unwrap();
+    }
+
+    #[test]
+    fn test_birth_time_roundtrip() {
+        let src = temp_dir("birth-time");
+        File::create(src.join("file")).unwrap();
+        let mut inode = Inode::get_from(src.join("file")).unwrap();
+        // Not all filesystems record the birth time
+        if let Ok(created) = fs::metadata(src.join("file")).unwrap().created() {
+            let secs = created.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+            assert_eq!(inode.birth_time, Some(secs));
+        } else {
+            assert_eq!(inode.birth_time, None);
+        }
+        inode.birth_time = Some(1_500_000_000);
+        let decoded = Inode::decode(&inode.encode().unwrap()).unwrap();
+        assert_eq!(decoded.birth_time, Some(1_500_000_000));
+        inode.birth_time = None;
+        let decoded = Inode::decode(&inode.encode().unwrap()).unwrap();
+        assert_eq!(decoded.birth_time, None);
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+}
@@ -10,26 +10,49 @@ mod vacuum;
 mod backup_file;
 mod tarfile;
 mod layout;
+mod copy;
+mod checkpoint;
+mod prune;
+mod exclude;
+mod sparse;
+mod stream;
+mod restore;
+mod archive;
 
 use prelude::*;
 
 use std::mem;
 use std::cmp::max;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::sync::{Arc, Mutex};
 use std::os::unix::fs::symlink;
 use std::io::Write;
+use std::time::Instant;
 
 pub use self::error::RepositoryError;
-pub use self::config::Config;
-pub use self::metadata::{Inode, FileType, FileData, InodeError};
-pub use self::backup::{BackupError, BackupOptions, DiffType};
+pub use self::config::{Config, ConfigError};
+pub use self::metadata::{Inode, FileType, FileData, SparseData, InodeError, RestoreOwner,
+                         MAX_INLINE_SIZE, DEFAULT_INLINE_SIZE};
+pub use self::backup::{BackupError, BackupOptions, DiffType, PathSelection, PlannedEntry,
+                       DirectorySize};
 pub use self::backup_file::{Backup, BackupFileError};
+use self::backup::DeviceBoundary;
 pub use self::integrity::IntegrityError;
-pub use self::info::{RepositoryInfo, BundleAnalysis, RepositoryStatistics};
+pub use self::info::{RepositoryInfo, BundleAnalysis, RepositoryStatistics, IndexEntry,
+                     DedupAnalysis, CompressionUsage};
 pub use self::layout::RepositoryLayout;
+pub use self::copy::{CopyStatistics, CloneStatistics};
+pub use self::archive::{ArchiveHeader, ArchiveEntry, ArchiveError};
+pub use self::checkpoint::{BackupCheckpoint, CheckpointError};
+pub use self::prune::{RetentionPolicy, RetentionSlot, KeepReason};
+pub use self::exclude::ExcludeMatcher;
+pub use self::vacuum::VacuumError;
+pub use self::bundle_map::BundleMapError;
+use self::checkpoint::CheckpointJournal;
 use self::bundle_map::BundleMap;
+use self::config::CONFIG_VERSION;
 
 
 const REPOSITORY_README: &[u8] = include_bytes!("../../docs/repository_readme.md");
@@ -78,14 +101,48 @@ pub struct Repository {
     bundle_map: BundleMap,
     next_data_bundle: u32,
     next_meta_bundle: u32,
+    next_raw_bundle: u32,
     bundles: BundleDb,
     data_bundle: Option<BundleWriter>,
     meta_bundle: Option<BundleWriter>,
+    /// Uncompressed data bundle for the incompressible chunks when `auto_compression` is set
+    raw_data_bundle: Option<BundleWriter>,
     chunker: Box<Chunker>,
+    /// Compression of new data bundles if it differs from the configuration
+    data_compression: Option<Option<Compression>>,
+    /// Store data chunks that look incompressible in separate uncompressed bundles
+    auto_compression: bool,
     remote_locks: LockFolder,
     local_locks: LockFolder,
     lock: LockHandle,
-    dirty: bool
+    dirty: bool,
+    admin: bool,
+    times: PipelineTimes,
+    last_checkpoint: Instant,
+    /// Number of bundles at the last checkpoint
+    last_checkpoint_bundles: usize,
+    /// Whether a checkpoint has been written that is not in the checkpoint journal yet
+    checkpoint_written: bool,
+    /// Finish all open bundles after this many seconds during a backup
+    checkpoint_interval: Option<u64>,
+    /// Finish all open bundles after this many new bundles during a backup
+    checkpoint_bundles: Option<usize>,
+    verify_chunks: bool,
+    /// Files with multiple links seen in the current backup, by device and inode number
+    hard_links: HashMap<(u64, u64), (PathBuf, Option<FileData>)>,
+    /// Device and mount points of the source of the running backup with `same_device`
+    device_boundary: Option<DeviceBoundary>,
+    /// Folders marked as caches whose contents the last backup has skipped
+    cache_dirs: Vec<PathBuf>,
+    show_progress: bool,
+    /// Progress of the running backup
+    progress: Option<ProgressReporter>,
+    /// Checkpoint journal of the running backup
+    journal: Option<CheckpointJournal>,
+    /// Hashes of the chunks written by the running backup if they are verified afterwards
+    written_chunks: Option<Vec<Hash>>,
+    /// Version of an outdated config, it is upgraded when the repository is first written to
+    pending_migration: Option<u8>
 }
 
 
@@ -95,7 +152,17 @@ impl Repository {
         config: &Config,
         remote: R,
     ) -> Result<Self, RepositoryError> {
-        let layout = RepositoryLayout::new(path.as_ref().to_path_buf());
+        try!(Self::create_layout(path.as_ref(), config, remote));
+        Self::open(path, true)
+    }
+
+    /// Creates the files and folders of a new repository without opening it
+    fn create_layout<R: AsRef<Path>>(
+        path: &Path,
+        config: &Config,
+        remote: R,
+    ) -> Result<RepositoryLayout, RepositoryError> {
+        let layout = RepositoryLayout::new(path.to_path_buf());
         try!(fs::create_dir(layout.base_path()));
         try!(File::create(layout.excludes_path()).and_then(|mut f| {
             f.write_all(DEFAULT_EXCLUDES)
@@ -118,22 +185,41 @@ impl Repository {
         ));
         try!(BundleMap::create().save(layout.bundle_map_path()));
         try!(fs::create_dir_all(layout.backups_path()));
-        Self::open(path, true)
+        Ok(layout)
     }
 
-    #[allow(unknown_lints, useless_let_if_seq)]
+    #[inline]
     pub fn open<P: AsRef<Path>>(path: P, online: bool) -> Result<Self, RepositoryError> {
+        Self::open_with_password(path, online, None)
+    }
+
+    /// Opens the repository, deriving the key pair from `password` if the repository uses one
+    ///
+    /// If the repository uses a password and the secret key is not stored as a key file,
+    /// `RepositoryError::PasswordRequired` is returned when no password is given.
+    #[allow(unknown_lints, useless_let_if_seq)]
+    pub fn open_with_password<P: AsRef<Path>>(
+        path: P,
+        online: bool,
+        password: Option<&str>,
+    ) -> Result<Self, RepositoryError> {
         let layout = RepositoryLayout::new(path.as_ref().to_path_buf());
         if !layout.remote_exists() {
             return Err(RepositoryError::NoRemote);
         }
-        let config = try!(Config::load(layout.config_path()));
+        let (config, config_version) = try!(Config::load(layout.config_path()));
         let remote_locks = LockFolder::new(layout.remote_locks_path());
         try!(fs::create_dir_all(layout.local_locks_path())); // Added after v0.1.0
         let local_locks = LockFolder::new(layout.local_locks_path());
         let lock = try!(local_locks.lock(false));
-        let crypto = Arc::new(Mutex::new(try!(Crypto::open(layout.keys_path()))));
+        let mut crypto = try!(Crypto::open(layout.keys_path()));
+        try!(Self::unlock(&config, &mut crypto, password));
+        let crypto = Arc::new(Mutex::new(crypto));
         let (bundles, new, gone) = try!(BundleDb::open(layout.clone(), crypto.clone(), online));
+        // Chunks hashed with different methods can not be deduplicated against each other
+        if let Some(info) = bundles.list_bundles().into_iter().find(|info| info.hash_method != config.hash) {
+            return Err(RepositoryError::MixedHashMethods(info.id.clone(), info.hash_method, config.hash));
+        }
         let (index, mut rebuild_index) =
             match unsafe { Index::open(layout.index_path(), &INDEX_MAGIC, INDEX_VERSION) } {
                 Ok(index) => (index, false),
@@ -161,19 +247,46 @@ impl Repository {
             layout,
             dirty: true,
             chunker: config.chunker.create(),
+            data_compression: None,
+            auto_compression: false,
             config,
             index,
             crypto,
             bundle_map,
             next_data_bundle: 0,
             next_meta_bundle: 0,
+            next_raw_bundle: 0,
             bundles,
             data_bundle: None,
             meta_bundle: None,
+            raw_data_bundle: None,
             lock,
             remote_locks,
-            local_locks
+            local_locks,
+            admin: false,
+            times: PipelineTimes::default(),
+            last_checkpoint: Instant::now(),
+            last_checkpoint_bundles: 0,
+            checkpoint_written: false,
+            checkpoint_interval: None,
+            checkpoint_bundles: None,
+            verify_chunks: false,
+            hard_links: HashMap::new(),
+            device_boundary: None,
+            cache_dirs: vec![],
+            show_progress: false,
+            progress: None,
+            journal: None,
+            written_chunks: None,
+            pending_migration: if config_version < CONFIG_VERSION {
+                Some(config_version)
+            } else {
+                None
+            }
         };
+        repo.bundles.set_append_only(repo.config.append_only);
+        repo.bundles.set_redundancy(repo.config.redundancy);
+        repo.bundles.set_bundle_shard(repo.config.bundle_shard);
         if !rebuild_bundle_map {
             let mut save_bundle_map = false;
             if !gone.is_empty() {
@@ -204,6 +317,7 @@ impl Repository {
         }
         repo.next_meta_bundle = repo.next_free_bundle_id();
         repo.next_data_bundle = repo.next_free_bundle_id();
+        repo.next_raw_bundle = repo.next_free_bundle_id();
         if rebuild_bundle_map {
             try!(repo.write_mode());
             try!(repo.rebuild_bundle_map());
@@ -213,33 +327,184 @@ impl Repository {
             try!(repo.write_mode());
             try!(repo.rebuild_index());
         }
+        if repo.layout.vacuum_journal_path().exists() {
+            try!(repo.write_mode());
+            try!(repo.recover_vacuum());
+        }
         repo.dirty = dirty;
         Ok(repo)
     }
 
+    /// Upgrades a repository that has been written by an older version of zVault
+    ///
+    /// Newer versions are rejected when loading the config. Older versions can still be read,
+    /// so this only runs with the exclusive lock once the repository is written to. Every
+    /// format change that needs more than saving the config again gets its own step here.
+    fn migrate(&mut self, version: u8) -> Result<(), RepositoryError> {
+        tr_info!(
+            "Upgrading the repository from version {} to version {}",
+            version,
+            CONFIG_VERSION
+        );
+        // Version 1 added the version and the checksum to the config, saving it adds both
+        self.save_config()
+    }
+
+    /// Adds the key pair derived from the password if the repository uses one
+    fn unlock(
+        config: &Config,
+        crypto: &mut Crypto,
+        password: Option<&str>,
+    ) -> Result<(), RepositoryError> {
+        let (derivation, public) = match (config.key_derivation.as_ref(), config.encryption.as_ref()) {
+            (Some(derivation), Some(&(_, ref public))) => (derivation, public),
+            _ => return Ok(())
+        };
+        let public = try!(PublicKey::from_slice(public).ok_or(EncryptionError::InvalidKey));
+        if crypto.contains_secret_key(&public) {
+            return Ok(());
+        }
+        let password = match password {
+            Some(password) => password,
+            None => return Err(RepositoryError::PasswordRequired)
+        };
+        let (derived, secret) = try!(derivation.derive_keypair(password));
+        if derived != public {
+            return Err(EncryptionError::WrongPassword.into());
+        }
+        crypto.add_secret_key(public, secret);
+        Ok(())
+    }
+
+    /// Checks whether the keys of the repository at the remote path are derived from a password
+    pub fn remote_uses_password<R: AsRef<Path>>(remote: R) -> bool {
+        remote.as_ref().join(layout::REMOTE_KEY_DERIVATION_FILE).exists()
+    }
+
+    /// Creates a repository for an existing remote storage
+    ///
+    /// The password is only needed if the keys of the repository are derived from a password.
     pub fn import<P: AsRef<Path>, R: AsRef<Path>>(
         path: P,
         remote: R,
         key_files: Vec<String>,
+        password: Option<&str>,
     ) -> Result<Self, RepositoryError> {
         let path = path.as_ref();
-        let mut repo = try!(Repository::create(path, &Config::default(), remote));
+        let layout = try!(Self::create_layout(path, &Config::default(), remote));
+        let mut crypto = try!(Crypto::open(layout.keys_path()));
         for file in key_files {
-            try!(repo.crypto.lock().unwrap().register_keyfile(file));
+            try!(crypto.register_keyfile(file));
+        }
+        // The bundles are checked against the config when opening, so it has to be taken
+        // from the existing backups or bundles first
+        {
+            let local_locks = LockFolder::new(layout.local_locks_path());
+            let _lock = try!(local_locks.lock(true));
+            let (config, _from_backup) = try!(Self::rebuild_config(&layout, password));
+            try!(config.save(layout.config_path()));
+        }
+        Repository::open_with_password(path, true, password)
+    }
+
+    /// Rebuilds a lost or corrupt config from the backups and bundles in the repository
+    ///
+    /// This refuses to replace a config that can still be loaded. The append-only flag is kept
+    /// from the damaged config or its backup copy, so recovering the config of an append-only
+    /// repository needs the `admin` flag. If the flag can not be found anywhere, the
+    /// repository is treated as append-only.
+    pub fn recover_config<P: AsRef<Path>>(
+        path: P,
+        password: Option<&str>,
+        admin: bool,
+    ) -> Result<Config, RepositoryError> {
+        let layout = RepositoryLayout::new(path.as_ref().to_path_buf());
+        if !layout.remote_exists() {
+            return Err(RepositoryError::NoRemote);
+        }
+        match Config::load(layout.config_path()) {
+            Ok(_) => return Err(RepositoryError::ConfigIntact),
+            Err(ConfigError::UnsupportedVersion(version)) => {
+                return Err(ConfigError::UnsupportedVersion(version).into())
+            }
+            Err(_) => ()
+        }
+        try!(fs::create_dir_all(layout.local_locks_path()));
+        let local_locks = LockFolder::new(layout.local_locks_path());
+        let _lock = try!(local_locks.lock(true));
+        let old_append_only = Config::load_append_only(layout.config_path()).or_else(|| {
+            Config::load_append_only(layout.config_path().with_extension("yaml.bak"))
+        });
+        let (mut config, from_backup) = try!(Self::rebuild_config(&layout, password));
+        config.append_only = match old_append_only {
+            Some(append_only) => append_only,
+            None if from_backup => config.append_only,
+            None => {
+                tr_warn!("The append-only flag could not be recovered, keeping the repository append-only");
+                true
+            }
+        };
+        if config.append_only && !admin {
+            return Err(RepositoryError::AppendOnly);
+        }
+        try!(config.save(layout.config_path()));
+        Ok(config)
+    }
+
+    /// Builds a config from the backups and bundles in the repository
+    ///
+    /// The config of the newest readable backup is used if there is one. Otherwise, the
+    /// compression, encryption and hash method are taken from the newest data bundle and all
+    /// other settings are set to their defaults. The key derivation is taken from the copy in
+    /// the remote storage, with the password the backups of such a repository can be read.
+    /// The returned flag tells whether the config has been taken from a backup.
+    fn rebuild_config(
+        layout: &RepositoryLayout,
+        password: Option<&str>,
+    ) -> Result<(Config, bool), RepositoryError> {
+        let mut crypto = try!(Crypto::open(layout.keys_path()));
+        let key_derivation = if layout.remote_key_derivation_path().exists() {
+            Some(try!(KeyDerivation::load(layout.remote_key_derivation_path())))
+        } else {
+            None
+        };
+        if let (Some(derivation), Some(password)) = (key_derivation.as_ref(), password) {
+            let (public, secret) = try!(derivation.derive_keypair(password));
+            crypto.add_secret_key(public, secret);
         }
-        repo = try!(Repository::open(path, true));
-        let mut backups: Vec<(String, Backup)> = try!(repo.get_all_backups()).into_iter().collect();
-        backups.sort_by_key(|&(_, ref b)| b.timestamp);
-        if let Some((name, backup)) = backups.pop() {
+        let backups = match Backup::get_all_from(&crypto, layout.backups_path()) {
+            Ok(backups) => backups,
+            Err(BackupFileError::PartialBackupsList(backups, _failed)) => backups,
+            Err(err) => return Err(err.into())
+        };
+        let newest_backup = backups.iter().max_by_key(|&(_, b)| b.timestamp);
+        let from_backup = newest_backup.is_some();
+        let mut config = if let Some((name, backup)) = newest_backup {
             tr_info!("Taking configuration from the last backup '{}'", name);
-            repo.config = backup.config;
-            try!(repo.save_config())
+            backup.config.clone()
         } else {
-            tr_warn!(
-                "No backup found in the repository to take configuration from, please set the configuration manually."
-            );
+            let crypto = Arc::new(Mutex::new(crypto));
+            let (bundles, _new, _gone) = try!(BundleDb::open(layout.clone(), crypto, true));
+            let mut config = Config::default();
+            let newest = bundles.list_bundles().into_iter()
+                .filter(|info| info.mode == BundleMode::Data)
+                .max_by_key(|info| info.timestamp);
+            if let Some(info) = newest {
+                tr_info!("Taking compression, encryption and hash method from bundle {}", info.id);
+                config.compression = info.compression.clone();
+                config.encryption = info.encryption.clone();
+                config.hash = info.hash_method;
+                config.bundle_shard = bundles.detect_bundle_shard();
+            } else {
+                tr_warn!("No backups or bundles found, using the default configuration");
+            }
+            tr_warn!("The bundle size and chunker could not be recovered, please check them");
+            config
+        };
+        if key_derivation.is_some() {
+            config.key_derivation = key_derivation;
         }
-        Ok(repo)
+        Ok((config, from_backup))
     }
 
     #[inline]
@@ -260,21 +525,121 @@ impl Repository {
     pub fn save_config(&mut self) -> Result<(), RepositoryError> {
         try!(self.write_mode());
         try!(self.config.save(self.layout.config_path()));
+        if let Some(ref derivation) = self.config.key_derivation {
+            try!(derivation.save(self.layout.remote_key_derivation_path()));
+        }
+        self.bundles.set_redundancy(self.config.redundancy);
+        self.bundles.set_bundle_shard(self.config.bundle_shard);
         Ok(())
     }
 
+    /// Sets the public key for new bundles, the encryption method of the repository is kept
     #[inline]
     pub fn set_encryption(&mut self, public: Option<&PublicKey>) {
         if let Some(key) = public {
             if !self.crypto.lock().unwrap().contains_secret_key(key) {
                 tr_warn!("The secret key for that public key is not stored in the repository.")
             }
+            let method = self.config.encryption.as_ref().map(|e| e.0.clone()).unwrap_or(
+                EncryptionMethod::Sodium
+            );
             let mut key_bytes = Vec::new();
             key_bytes.extend_from_slice(&key[..]);
-            self.config.encryption = Some((EncryptionMethod::Sodium, key_bytes.into()))
+            self.config.encryption = Some((method, key_bytes.into()))
         } else {
             self.config.encryption = None
         }
+        self.config.key_derivation = None;
+    }
+
+    /// Sets the cipher that is used to encrypt new bundles
+    ///
+    /// Existing bundles record their own method and are still decrypted with it. This has no
+    /// effect if encryption is not enabled.
+    pub fn set_encryption_method(&mut self, method: EncryptionMethod) {
+        if let Some(ref mut encryption) = self.config.encryption {
+            encryption.0 = method;
+        }
+    }
+
+    /// Enables encryption with a key pair derived from `password`
+    ///
+    /// Only the salt and the parameters are stored in the config, the secret key is not
+    /// written to a key file.
+    pub fn set_password_encryption(&mut self, password: &str) -> Result<PublicKey, RepositoryError> {
+        let derivation = KeyDerivation::new();
+        let (public, secret) = try!(derivation.derive_keypair(password));
+        self.crypto.lock().unwrap().add_secret_key(public, secret);
+        self.set_encryption(Some(&public));
+        self.config.key_derivation = Some(derivation);
+        Ok(public)
+    }
+
+    /// Allows operations that remove or modify data in append-only repositories
+    pub fn set_admin(&mut self, admin: bool) {
+        self.admin = admin;
+        self.bundles.set_append_only(self.config.append_only && !admin);
+    }
+
+    /// Checks the hash of every chunk that is read against its contents
+    pub fn set_verify_chunks(&mut self, verify: bool) {
+        self.verify_chunks = verify;
+    }
+
+    /// Sets the number of threads that compress and write bundles in parallel
+    ///
+    /// The index is still updated by the calling thread only. Restores write this many files
+    /// in parallel.
+    #[inline]
+    pub fn set_threads(&mut self, threads: usize) {
+        self.bundles.set_threads(threads);
+    }
+
+    #[inline]
+    pub fn threads(&self) -> usize {
+        self.bundles.threads()
+    }
+
+    /// Limits the upload of new bundles to the remote storage to `rate` bytes per second
+    #[inline]
+    pub fn set_rate_limit(&mut self, rate: Option<u64>) {
+        self.bundles.set_rate_limit(rate);
+    }
+
+    /// Sets how often and after which time without progress remote operations are retried
+    #[inline]
+    pub fn set_remote_retry(&mut self, retry: RetryPolicy) {
+        self.bundles.set_remote_retry(retry);
+    }
+
+    /// Sets the maximal size in bytes of the decoded bundles that are cached when reading
+    #[inline]
+    pub fn set_cache_size(&mut self, size: usize) {
+        self.bundles.set_cache_size(size);
+    }
+
+    #[inline]
+    pub fn cache_size(&self) -> usize {
+        self.bundles.cache_size()
+    }
+
+    /// Displays the progress of backups and restores on stderr
+    #[inline]
+    pub fn set_progress(&mut self, progress: bool) {
+        self.show_progress = progress;
+    }
+
+    /// Refuses operations that remove or modify data in append-only repositories unless the
+    /// admin mode is set
+    ///
+    /// This is a safeguard against mistakes only, clients can always set the admin mode.
+    #[inline]
+    pub fn check_append_only(&self) -> Result<(), RepositoryError> {
+        if self.config.append_only && !self.admin {
+            Err(RepositoryError::AppendOnly)
+        } else {
+            Ok(())
+        }
     }
 
     #[inline]
@@ -285,7 +650,10 @@ impl Repository {
 
     #[inline]
     fn next_free_bundle_id(&self) -> u32 {
-        let mut id = max(self.next_data_bundle, self.next_meta_bundle) + 1;
+        let mut id = max(
+            max(self.next_data_bundle, self.next_meta_bundle),
+            self.next_raw_bundle
+        ) + 1;
         while self.bundle_map.get(id).is_some() {
             id += 1;
         }
@@ -311,22 +679,25 @@ impl Repository {
             mem::swap(&mut self.data_bundle, &mut finished);
             {
                 let bundle = try!(self.bundles.add_bundle(finished.unwrap()));
-                self.bundle_map.set(
-                    self.next_data_bundle,
-                    bundle.id.clone()
-                );
+                self.bundle_map.set(self.next_data_bundle, bundle);
             }
             self.next_data_bundle = self.next_free_bundle_id()
         }
+        if self.raw_data_bundle.is_some() {
+            let mut finished = None;
+            mem::swap(&mut self.raw_data_bundle, &mut finished);
+            {
+                let bundle = try!(self.bundles.add_bundle(finished.unwrap()));
+                self.bundle_map.set(self.next_raw_bundle, bundle);
+            }
+            self.next_raw_bundle = self.next_free_bundle_id()
+        }
         if self.meta_bundle.is_some() {
             let mut finished = None;
             mem::swap(&mut self.meta_bundle, &mut finished);
             {
                 let bundle = try!(self.bundles.add_bundle(finished.unwrap()));
-                self.bundle_map.set(
-                    self.next_meta_bundle,
-                    bundle.id.clone()
-                );
+                self.bundle_map.set(self.next_meta_bundle, bundle);
             }
             self.next_meta_bundle = self.next_free_bundle_id()
         }
@@ -355,6 +726,9 @@ impl Repository {
         if self.next_data_bundle == bundle_id {
             self.next_data_bundle = self.next_free_bundle_id()
         }
+        if self.next_raw_bundle == bundle_id {
+            self.next_raw_bundle = self.next_free_bundle_id()
+        }
         for (i, (hash, _len)) in chunks.into_inner().into_iter().enumerate() {
             if let Some(old) = try!(self.index.set(
                 &hash,
@@ -387,6 +761,9 @@ impl Repository {
     #[inline]
     fn write_mode(&mut self) -> Result<(), RepositoryError> {
         try!(self.local_locks.upgrade(&mut self.lock));
+        if let Some(version) = self.pending_migration.take() {
+            try!(self.migrate(version));
+        }
         Ok(())
     }
 
@@ -409,3 +786,247 @@ impl Drop for Repository {
         }
     }
 }
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::io::{Read, Write};
+
+
+    /// Creates an empty folder for a test, shared by the tests of all repository modules
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let path = ::std::env::temp_dir().join(format!("zvault-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[allow(dead_code)]
+    fn rewrite_config<F: FnOnce(&mut ::serde_yaml::Mapping)>(path: &Path, change: F) {
+        let mut yaml: ::serde_yaml::Value = ::serde_yaml::from_reader(File::open(path).unwrap()).unwrap();
+        change(yaml.as_mapping_mut().unwrap());
+        ::serde_yaml::to_writer(&mut File::create(path).unwrap(), &yaml).unwrap();
+    }
+
+    #[test]
+    fn test_reject_newer_config() {
+        let base = temp_dir("newer-config");
+        fs::create_dir(base.join("remote")).unwrap();
+        drop(Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap());
+        rewrite_config(&base.join("repo/config.yaml"), |yaml| {
+            yaml.insert("version".to_string().into(), (CONFIG_VERSION as u64 + 1).into());
+        });
+        match Repository::open(base.join("repo"), true) {
+            Err(RepositoryError::Config(ConfigError::UnsupportedVersion(version))) => {
+                assert_eq!(version, CONFIG_VERSION + 1)
+            }
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("newer config has been accepted")
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_chunker_seed_in_config() {
+        let base = temp_dir("chunker-seed");
+        let path = base.join("config.yaml");
+        let chunkers = vec![
+            "rabin/16", "fastcdc/16", "buzhash/16", "rabin/16/64", "rabin/16:4:64", "fastcdc/16:4:64"
+        ];
+        for name in chunkers {
+            let chunker = ChunkerType::from_string(name).unwrap().with_seed(0x1234_5678).unwrap();
+            let mut config = Config::default();
+            config.chunker = chunker;
+            config.save(&path).unwrap();
+            let (loaded, _) = Config::load(&path).unwrap();
+            assert_eq!(loaded.chunker, chunker, "{}", name);
+            assert_eq!(loaded.chunker.seed(), 0x1234_5678, "{}", name);
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_unversioned_config() {
+        let base = temp_dir("migrate-config");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        File::create(src.join("file")).unwrap().write_all(&[1; 1000]).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let config_path = base.join("repo/config.yaml");
+        drop(Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap());
+        rewrite_config(&config_path, |yaml| {
+            yaml.remove(&"version".to_string().into());
+            yaml.remove(&"checksum".to_string().into());
+        });
+        assert_eq!(Config::load(&config_path).unwrap().1, 0);
+        // Reading the repository does not change the config
+        {
+            let repo = Repository::open(base.join("repo"), true).unwrap();
+            assert!(repo.get_all_backups().unwrap().is_empty());
+        }
+        assert_eq!(Config::load(&config_path).unwrap().1, 0);
+        // The first write upgrades it
+        {
+            let mut repo = Repository::open(base.join("repo"), true).unwrap();
+            let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+            repo.save_backup(&backup, "test").unwrap();
+        }
+        let (config, version) = Config::load(&config_path).unwrap();
+        assert_eq!(version, CONFIG_VERSION);
+        assert_eq!(config.hash, Config::default().hash);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_restore_from_parity() {
+        let base = temp_dir("parity");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..100_000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect();
+        File::create(src.join("file")).unwrap().write_all(&data).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut config = Config::default();
+        config.redundancy = Some(Redundancy { data: 2, parity: 1 });
+        let mut repo = Repository::create(base.join("repo"), &config, base.join("remote")).unwrap();
+        let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        repo.flush().unwrap();
+        drop(repo);
+        // Truncate the largest bundle, that is the one containing the file data
+        let mut bundles = vec![];
+        for entry in fs::read_dir(base.join("remote/bundles")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(false, |ext| ext == "bundle") {
+                bundles.push((path.metadata().unwrap().len(), path));
+            }
+        }
+        bundles.sort();
+        let (_, bundle) = bundles.pop().unwrap();
+        let mut contents = vec![];
+        File::open(&bundle).unwrap().read_to_end(&mut contents).unwrap();
+        File::create(&bundle).unwrap().write_all(&contents[..contents.len() - 100]).unwrap();
+        let mut repo = Repository::open(base.join("repo"), true).unwrap();
+        repo.check_bundles(true, true).unwrap();
+        assert!(bundle.with_extension("bundle.broken").exists());
+        let mut restored = vec![];
+        File::open(&bundle).unwrap().read_to_end(&mut restored).unwrap();
+        assert!(restored == contents);
+        let backup = repo.get_backup("test").unwrap();
+        let root = repo.get_inode(&backup.root).unwrap();
+        fs::create_dir(base.join("restored")).unwrap();
+        repo.restore_inode_tree(&backup, root, base.join("restored"), RestoreOwner::Current, false).unwrap();
+        let mut restored = vec![];
+        File::open(base.join("restored/file")).unwrap().read_to_end(&mut restored).unwrap();
+        assert!(restored == data);
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_find_duplicate_chunks() {
+        let base = temp_dir("dedup-analysis");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let analysis = repo.find_duplicate_chunks(10).unwrap();
+        assert_eq!((analysis.unique_chunks, analysis.references), (0, 0));
+        assert_eq!(analysis.get_dedup_factor(), 1.0);
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..100_000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect();
+        File::create(src.join("a")).unwrap().write_all(&data).unwrap();
+        File::create(src.join("b")).unwrap().write_all(&data).unwrap();
+        let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        // Backups that can not be read are ignored
+        File::create(repo.layout.backup_path("broken")).unwrap().write_all(b"garbage").unwrap();
+        let analysis = repo.find_duplicate_chunks(10).unwrap();
+        assert!(analysis.unique_chunks > 0);
+        assert_eq!(analysis.references, 2 * analysis.unique_chunks);
+        assert_eq!(analysis.get_dedup_factor(), 2.0);
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_bundle_writes() {
+        let base = temp_dir("parallel-writes");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let mut files = vec![];
+        for seed in 1..11u32 {
+            let mut state = seed;
+            let data: Vec<u8> = (0..100_000).map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            }).collect();
+            let name = format!("file{}", seed);
+            File::create(src.join(&name)).unwrap().write_all(&data).unwrap();
+            files.push((name, data));
+        }
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut config = Config::default();
+        config.bundle_size = 64 * 1024;
+        let mut repo = Repository::create(base.join("repo"), &config, base.join("remote")).unwrap();
+        repo.set_threads(4);
+        let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        repo.flush().unwrap();
+        assert!(repo.list_bundles().len() > 4);
+        drop(repo);
+        let mut repo = Repository::open(base.join("repo"), true).unwrap();
+        let backup = repo.get_backup("test").unwrap();
+        let root = repo.get_inode(&backup.root).unwrap();
+        fs::create_dir(base.join("restored")).unwrap();
+        repo.restore_inode_tree(&backup, root, base.join("restored"), RestoreOwner::Current, false).unwrap();
+        for (name, data) in files {
+            let mut restored = vec![];
+            File::open(base.join("restored").join(name)).unwrap().read_to_end(&mut restored).unwrap();
+            assert!(restored == data);
+        }
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_index_from_bundles() {
+        let base = temp_dir("rebuild-index");
+        let src = base.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        File::create(src.join("file")).unwrap().write_all(&data).unwrap();
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let backup = repo.create_backup_recursively(&src, None, &BackupOptions::default()).unwrap();
+        repo.save_backup(&backup, "test").unwrap();
+        repo.flush().unwrap();
+        // Lose one bundle from the map and add a file that is not a bundle
+        let (num, _) = repo.bundle_map.bundles()[0];
+        repo.bundle_map.remove(num);
+        File::create(base.join("remote/bundles/garbage.bundle")).unwrap().write_all(b"garbage").unwrap();
+        let chunks: usize = repo.bundles.list_bundles().iter().map(|info| info.chunk_count).sum();
+        assert_eq!(repo.rebuild_index_from_bundles().unwrap(), chunks);
+        assert_eq!(repo.bundle_map.len(), repo.bundles.len());
+        assert!(!repo.layout.index_path().with_extension("new").exists());
+        let root = repo.get_inode(&backup.root).unwrap();
+        fs::create_dir(base.join("restored")).unwrap();
+        repo.restore_inode_tree(&backup, root, base.join("restored"), RestoreOwner::Current, false).unwrap();
+        let mut restored = vec![];
+        File::open(base.join("restored/file")).unwrap().read_to_end(&mut restored).unwrap();
+        assert!(restored == data);
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
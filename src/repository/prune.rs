@@ -0,0 +1,240 @@
+use prelude::*;
+
+use std::fmt;
+
+use chrono::prelude::*;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetentionSlot {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly
+}
+
+impl RetentionSlot {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RetentionSlot::Daily => tr!("daily"),
+            RetentionSlot::Weekly => tr!("weekly"),
+            RetentionSlot::Monthly => tr!("monthly"),
+            RetentionSlot::Yearly => tr!("yearly")
+        }
+    }
+
+    /// Returns the period of the date, e.g. `2024-06` for monthly slots
+    pub fn period(&self, date: &DateTime<Local>) -> String {
+        match *self {
+            RetentionSlot::Daily => date.format("%Y-%m-%d").to_string(),
+            RetentionSlot::Weekly => {
+                let week = date.iso_week();
+                format!("{}-W{:02}", week.year(), week.week())
+            }
+            RetentionSlot::Monthly => date.format("%Y-%m").to_string(),
+            RetentionSlot::Yearly => date.format("%Y").to_string()
+        }
+    }
+}
+
+
+/// The rule of the retention policy that keeps a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeepReason {
+    /// Newest backup in a period, e.g. the daily slot `2024-06-01`
+    Slot(RetentionSlot, String),
+    /// One of the given number of most recent backups
+    Last(usize),
+    /// Newer than the given number of seconds
+    Within(u64)
+}
+
+impl fmt::Display for KeepReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeepReason::Slot(slot, ref period) => {
+                write!(f, "{}", tr_format!("{} slot {}", slot.name(), period))
+            }
+            KeepReason::Last(count) => write!(f, "{}", tr_format!("last {} backups", count)),
+            KeepReason::Within(secs) => {
+                write!(f, "{}", tr_format!("within {}", format_duration(secs)))
+            }
+        }
+    }
+}
+
+
+/// Rules to select the backups that are kept when pruning
+///
+/// A backup is kept if any of the rules keeps it.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    pub yearly: usize,
+    /// Number of most recent backups that are always kept
+    pub keep_last: usize,
+    /// All backups newer than this number of seconds are kept
+    pub keep_within: Option<u64>
+}
+
+impl RetentionPolicy {
+    /// Returns whether the policy keeps any backups at all
+    pub fn is_empty(&self) -> bool {
+        self.daily + self.weekly + self.monthly + self.yearly + self.keep_last == 0 &&
+            self.keep_within.is_none()
+    }
+
+    /// Selects the backups to keep
+    ///
+    /// The dates must be sorted with the newest backup first. For each of the last `daily` days
+    /// (`weekly` weeks, etc.) that have backups, the newest backup of that period is kept. In
+    /// addition, the `keep_last` newest backups and all backups newer than `keep_within`
+    /// seconds before `now` are kept. The result contains the reasons for keeping each backup,
+    /// backups with no reasons are to be removed.
+    pub fn select(&self, dates: &[DateTime<Local>], now: DateTime<Local>) -> Vec<Vec<KeepReason>> {
+        let mut reasons = vec![vec![]; dates.len()];
+        for &(slot, max) in &[
+            (RetentionSlot::Daily, self.daily),
+            (RetentionSlot::Weekly, self.weekly),
+            (RetentionSlot::Monthly, self.monthly),
+            (RetentionSlot::Yearly, self.yearly),
+        ]
+        {
+            let mut kept = 0;
+            let mut last = None;
+            for (i, date) in dates.iter().enumerate() {
+                let period = slot.period(date);
+                if last.as_ref() != Some(&period) {
+                    if kept >= max {
+                        break;
+                    }
+                    last = Some(period.clone());
+                    reasons[i].push(KeepReason::Slot(slot, period));
+                    kept += 1;
+                }
+            }
+        }
+        for reasons in reasons.iter_mut().take(self.keep_last) {
+            reasons.push(KeepReason::Last(self.keep_last));
+        }
+        if let Some(secs) = self.keep_within {
+            let limit = now.timestamp().saturating_sub(secs as i64);
+            for (i, date) in dates.iter().enumerate() {
+                if date.timestamp() >= limit {
+                    reasons[i].push(KeepReason::Within(secs));
+                }
+            }
+        }
+        reasons
+    }
+}
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[allow(dead_code)]
+    fn date(year: i32, month: u32, day: u32, hour: u32) -> DateTime<Local> {
+        Local.ymd(year, month, day).and_hms(hour, 0, 0)
+    }
+
+    #[allow(dead_code)]
+    fn kept(reasons: &[Vec<KeepReason>]) -> Vec<usize> {
+        reasons.iter().enumerate().filter(|&(_, r)| !r.is_empty()).map(|(i, _)| i).collect()
+    }
+
+    #[allow(dead_code)]
+    fn policy(daily: usize, weekly: usize, monthly: usize, yearly: usize) -> RetentionPolicy {
+        RetentionPolicy {
+            daily,
+            weekly,
+            monthly,
+            yearly,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_nothing_kept() {
+        let now = date(2024, 6, 3, 0);
+        let dates = vec![date(2024, 6, 2, 12), date(2024, 6, 1, 12)];
+        assert!(RetentionPolicy::default().is_empty());
+        assert_eq!(kept(&RetentionPolicy::default().select(&dates, now)), Vec::<usize>::new());
+        assert!(policy(3, 3, 3, 3).select(&[], now).is_empty());
+    }
+
+    #[test]
+    fn test_daily_keeps_newest_per_day() {
+        let dates = vec![
+            date(2024, 6, 3, 18),
+            date(2024, 6, 3, 6),
+            date(2024, 6, 2, 18),
+            date(2024, 6, 1, 18),
+        ];
+        let reasons = policy(2, 0, 0, 0).select(&dates, date(2024, 6, 4, 0));
+        assert_eq!(kept(&reasons), vec![0, 2]);
+        assert_eq!(reasons[2], vec![KeepReason::Slot(RetentionSlot::Daily, "2024-06-02".to_string())]);
+    }
+
+    #[test]
+    fn test_slots_combine() {
+        let dates = vec![
+            date(2024, 6, 3, 12),
+            date(2024, 6, 1, 12),
+            date(2024, 5, 20, 12),
+            date(2024, 4, 10, 12),
+            date(2023, 12, 24, 12),
+        ];
+        let reasons = policy(1, 0, 3, 2).select(&dates, date(2024, 6, 4, 0));
+        assert_eq!(kept(&reasons), vec![0, 2, 3, 4]);
+        assert_eq!(reasons[0].len(), 3);
+        assert_eq!(reasons[3], vec![KeepReason::Slot(RetentionSlot::Monthly, "2024-04".to_string())]);
+        assert_eq!(reasons[4], vec![KeepReason::Slot(RetentionSlot::Yearly, "2023".to_string())]);
+    }
+
+    #[test]
+    fn test_keep_last_and_within() {
+        let dates = vec![
+            date(2024, 6, 3, 12),
+            date(2024, 6, 2, 12),
+            date(2024, 5, 30, 12),
+            date(2024, 5, 1, 12),
+        ];
+        let now = date(2024, 6, 4, 12);
+        let keep_last = RetentionPolicy {
+            keep_last: 2,
+            ..Default::default()
+        };
+        assert_eq!(kept(&keep_last.select(&dates, now)), vec![0, 1]);
+        let keep_within = RetentionPolicy {
+            keep_within: Some(7 * 24 * 60 * 60),
+            ..Default::default()
+        };
+        let reasons = keep_within.select(&dates, now);
+        assert_eq!(kept(&reasons), vec![0, 1, 2]);
+        assert_eq!(reasons[2], vec![KeepReason::Within(7 * 24 * 60 * 60)]);
+        // The rules are combined as a union
+        let combined = RetentionPolicy {
+            keep_last: 1,
+            yearly: 1,
+            keep_within: Some(24 * 60 * 60),
+            ..Default::default()
+        };
+        let reasons = combined.select(&dates, now);
+        assert_eq!(kept(&reasons), vec![0]);
+        assert_eq!(reasons[0].len(), 3);
+    }
+
+    #[test]
+    fn test_weekly_period() {
+        // 2024-12-30 belongs to the first ISO week of 2025
+        assert_eq!(RetentionSlot::Weekly.period(&date(2024, 12, 30, 12)), "2025-W01");
+        assert_eq!(RetentionSlot::Weekly.period(&date(2024, 6, 3, 12)), "2024-W23");
+    }
+
+}
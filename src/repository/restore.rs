@@ -0,0 +1,227 @@
+use prelude::*;
+
+use super::sparse::SparseWriter;
+
+use std::sync::atomic::{Ordering, AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::path::PathBuf;
+use std::io::Write;
+use std::{fs, mem, thread};
+
+
+/// A chunk of a file and the bundle that stores it
+pub struct RestoreChunk {
+    pub hash: Hash,
+    pub bundle_id: BundleId,
+    pub path: PathBuf,
+    pub chunk: usize
+}
+
+struct RestoreJob {
+    inode: Inode,
+    path: PathBuf,
+    chunks: Vec<RestoreChunk>
+}
+
+/// Worker threads that restore regular files in parallel
+///
+/// The chunks of the files are looked up by the calling thread, the workers only load the
+/// bundles, write the files and apply their metadata. Decoded bundles are kept in the bundle
+/// cache of the repository, so they are shared with the calling thread and between all
+/// workers. The first error stops all workers and is returned by `queue` or `finish`.
+pub struct RestorePool {
+    capacity: usize,
+    crypto: Arc<Mutex<Crypto>>,
+    /// Hash method to verify the chunks with
+    verify: Option<HashMethod>,
+    owner: RestoreOwner,
+    skip_corrupt: bool,
+    corrupt_paths: Mutex<Vec<PathBuf>>,
+    error_present: AtomicBool,
+    error: Mutex<Option<RepositoryError>>,
+    waiting: AtomicUsize,
+    jobs: Mutex<Sender<Option<RestoreJob>>>,
+    queue: Mutex<Receiver<Option<RestoreJob>>>,
+    wait: (Condvar, Mutex<()>),
+    cache: DecodedBundleCache,
+    handles: Mutex<Vec<thread::JoinHandle<()>>>
+}
+
+impl RestorePool {
+    pub fn new(
+        threads: usize,
+        crypto: Arc<Mutex<Crypto>>,
+        cache: DecodedBundleCache,
+        verify: Option<HashMethod>,
+        owner: RestoreOwner,
+        skip_corrupt: bool,
+    ) -> Arc<Self> {
+        let (jobs, queue) = mpsc::channel();
+        let self_ = Arc::new(RestorePool {
+            capacity: threads * 2,
+            crypto,
+            verify,
+            owner,
+            skip_corrupt,
+            corrupt_paths: Mutex::new(vec![]),
+            error_present: AtomicBool::new(false),
+            error: Mutex::new(None),
+            waiting: AtomicUsize::new(0),
+            jobs: Mutex::new(jobs),
+            queue: Mutex::new(queue),
+            wait: (Condvar::new(), Mutex::new(())),
+            cache,
+            handles: Mutex::new(vec![])
+        });
+        for num in 0..threads {
+            let self2 = self_.clone();
+            let handle = thread::Builder::new()
+                .name(format!("restore-{}", num))
+                .spawn(move || self2.worker_thread())
+                .unwrap();
+            self_.handles.lock().unwrap().push(handle);
+        }
+        self_
+    }
+
+    fn get_status(&self) -> Result<(), RepositoryError> {
+        if self.error_present.load(Ordering::SeqCst) {
+            if let Some(err) = self.error.lock().unwrap().take() {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the file `inode` to be restored in the folder `path`
+    ///
+    /// This blocks while the workers are busy, so only a few files are waiting at any time.
+    pub fn queue(
+        &self,
+        inode: Inode,
+        path: PathBuf,
+        chunks: Vec<RestoreChunk>,
+    ) -> Result<(), RepositoryError> {
+        {
+            let mut guard = self.wait.1.lock().unwrap();
+            while self.waiting.load(Ordering::SeqCst) >= self.capacity {
+                guard = self.wait.0.wait(guard).unwrap();
+            }
+        }
+        try!(self.get_status());
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        // The pool keeps the receiving end, so sending never fails
+        self.jobs.lock().unwrap().send(Some(RestoreJob {
+            inode,
+            path,
+            chunks
+        })).unwrap();
+        Ok(())
+    }
+
+    /// Waits for all queued files and stops the workers
+    ///
+    /// Returns the paths of the files that were skipped due to corrupt chunks.
+    pub fn finish(&self) -> Result<Vec<PathBuf>, RepositoryError> {
+        let handles = mem::replace(&mut *self.handles.lock().unwrap(), vec![]);
+        for _ in 0..handles.len() {
+            self.jobs.lock().unwrap().send(None).unwrap();
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        try!(self.get_status());
+        Ok(mem::replace(&mut *self.corrupt_paths.lock().unwrap(), vec![]))
+    }
+
+    fn get_bundle(&self, chunk: &RestoreChunk) -> Result<Arc<DecodedBundle>, BundleDbError> {
+        self.cache.get_or_load(&chunk.bundle_id, || -> Result<_, BundleDbError> {
+            let mut reader = try!(BundleReader::load(chunk.path.clone(), self.crypto.clone()));
+            let (offset, _) = try!(reader.get_chunk_position(chunk.chunk));
+            let data = try!(reader.load_contents().map_err(|err| {
+                BundleDbError::ReadChunk(err, chunk.bundle_id.clone(), chunk.hash, offset)
+            }));
+            Ok((reader, data))
+        })
+    }
+
+    fn write_chunks<W: Write>(
+        &self,
+        chunks: &[RestoreChunk],
+        w: &mut W,
+    ) -> Result<(), RepositoryError> {
+        for chunk in chunks {
+            let bundle = try!(self.get_bundle(chunk));
+            let (pos, len) = try!(bundle.chunk_position(chunk.chunk).map_err(BundleDbError::from));
+            let data = &bundle.data[pos..pos + len];
+            if let Some(method) = self.verify {
+                if method.hash(data) != chunk.hash {
+                    return Err(IntegrityError::CorruptChunkInBundle(
+                        chunk.hash,
+                        chunk.bundle_id.clone(),
+                        chunk.chunk as u32,
+                        pos
+                    ).into());
+                }
+            }
+            try!(w.write_all(data));
+        }
+        Ok(())
+    }
+
+    fn restore_file(&self, job: &RestoreJob) -> Result<(), RepositoryError> {
+        if let Some(mut file) = try!(job.inode.create_at(&job.path)) {
+            if let Some(FileData::Sparse(ref sparse)) = job.inode.data {
+                {
+                    let mut writer = SparseWriter::with_holes(&mut file, &sparse.regions);
+                    try!(self.write_chunks(&job.chunks, &mut writer));
+                    try!(writer.finish(job.inode.size));
+                }
+                try!(file.set_len(job.inode.size));
+            } else {
+                try!(self.write_chunks(&job.chunks, &mut file));
+            }
+        }
+        try!(job.inode.restore_meta_at(&job.path, self.owner));
+        Ok(())
+    }
+
+    fn worker_thread(&self) {
+        loop {
+            // Only lock the queue while waiting for the next job
+            let next = self.queue.lock().unwrap().recv().unwrap();
+            let job = match next {
+                Some(job) => job,
+                None => break
+            };
+            if !self.error_present.load(Ordering::SeqCst) {
+                let file_path = job.path.join(&job.inode.name);
+                match self.restore_file(&job) {
+                    Ok(()) => (),
+                    Err(RepositoryError::Integrity(IntegrityError::CorruptChunk(hash))) |
+                    Err(RepositoryError::Integrity(
+                        IntegrityError::CorruptChunkInBundle(hash, ..)
+                    )) if self.skip_corrupt => {
+                        tr_error!("File {} contains the corrupt chunk {}", escape(&file_path), hash);
+                        fs::remove_file(&file_path).ok();
+                        self.corrupt_paths.lock().unwrap().push(file_path);
+                    }
+                    Err(err) => {
+                        tr_error!("Failed to restore {}: {}", escape(&file_path), err);
+                        let mut error = self.error.lock().unwrap();
+                        if error.is_none() {
+                            *error = Some(err);
+                        }
+                        self.error_present.store(true, Ordering::SeqCst);
+                    }
+                }
+            }
+            {
+                let _guard = self.wait.1.lock().unwrap();
+                self.waiting.fetch_sub(1, Ordering::SeqCst);
+            }
+            self.wait.0.notify_all();
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use libc;
+
+use std::cmp::min;
+use std::fs::File;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+
+
+/// Size of the blocks that are checked for zeros if the filesystem can not report holes
+pub const HOLE_BLOCK_SIZE: usize = 64 * 1024;
+
+// Not defined by the libc crate for all platforms, these are the values used by Linux
+const SEEK_DATA: libc::c_int = 3;
+const SEEK_HOLE: libc::c_int = 4;
+
+static ZEROS: [u8; 4096] = [0; 4096];
+
+
+/// Returns the data regions of the file as offset and length via `SEEK_DATA` and `SEEK_HOLE`
+///
+/// Returns `None` if the filesystem does not support this. Filesystems that do not keep track
+/// of holes report the whole file as a single data region. The file position is reset to the
+/// start of the file.
+pub fn data_regions(file: &File, size: u64) -> Option<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut regions = vec![];
+    let mut offset = 0;
+    while offset < size {
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, SEEK_DATA) };
+        if start < 0 {
+            if io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+                // No more data until the end of the file
+                break;
+            }
+            return None;
+        }
+        let end = unsafe { libc::lseek(fd, start, SEEK_HOLE) };
+        if end < 0 {
+            return None;
+        }
+        let (start, end) = (start as u64, min(end as u64, size));
+        if end <= start {
+            break;
+        }
+        regions.push((start, end - start));
+        offset = end;
+    }
+    if unsafe { libc::lseek(fd, 0, libc::SEEK_SET) } < 0 {
+        return None;
+    }
+    Some(regions)
+}
+
+
+/// Reads the given regions of the input one after another
+pub struct RegionReader<R> {
+    inner: R,
+    regions: Vec<(u64, u64)>,
+    next: usize,
+    /// Bytes left in the current region
+    remaining: u64
+}
+
+impl<R: Read + Seek> RegionReader<R> {
+    pub fn new(inner: R, regions: Vec<(u64, u64)>) -> Self {
+        RegionReader {
+            inner,
+            regions,
+            next: 0,
+            remaining: 0
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for RegionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        while self.remaining == 0 {
+            if self.next == self.regions.len() || buf.is_empty() {
+                return Ok(0);
+            }
+            let (offset, len) = self.regions[self.next];
+            try!(self.inner.seek(SeekFrom::Start(offset)));
+            self.remaining = len;
+            self.next += 1;
+        }
+        let len = min(buf.len() as u64, self.remaining) as usize;
+        let read = try!(self.inner.read(&mut buf[..len]));
+        if read == 0 {
+            // The file has been truncated while reading
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "region ends after the input"));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+
+/// Leaves out all blocks of the input that only contain zeros
+///
+/// The input is checked in blocks of `HOLE_BLOCK_SIZE` bytes and the offsets and lengths of
+/// the data that has been passed on are recorded as regions.
+pub struct ZeroSkipper<R> {
+    inner: R,
+    regions: Vec<(u64, u64)>,
+    /// Offset of the next block in the input
+    offset: u64,
+    block: Vec<u8>,
+    pos: usize
+}
+
+impl<R: Read> ZeroSkipper<R> {
+    pub fn new(inner: R) -> Self {
+        ZeroSkipper {
+            inner,
+            regions: vec![],
+            offset: 0,
+            block: Vec::with_capacity(HOLE_BLOCK_SIZE),
+            pos: 0
+        }
+    }
+
+    /// Returns the data regions of all input that has been read
+    pub fn into_regions(self) -> Vec<(u64, u64)> {
+        self.regions
+    }
+
+    fn read_block(&mut self) -> Result<bool, io::Error> {
+        loop {
+            self.block.clear();
+            self.pos = 0;
+            try!((&mut self.inner).take(HOLE_BLOCK_SIZE as u64).read_to_end(&mut self.block));
+            if self.block.is_empty() {
+                return Ok(false);
+            }
+            let start = self.offset;
+            let len = self.block.len() as u64;
+            self.offset += len;
+            if self.block.len() == HOLE_BLOCK_SIZE && self.block.iter().all(|b| *b == 0) {
+                continue;
+            }
+            match self.regions.last_mut() {
+                Some(&mut (offset, ref mut size)) if offset + *size == start => *size += len,
+                _ => self.regions.push((start, len))
+            }
+            return Ok(true);
+        }
+    }
+}
+
+impl<R: Read> Read for ZeroSkipper<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.pos == self.block.len() && !try!(self.read_block()) {
+            return Ok(0);
+        }
+        let len = min(buf.len(), self.block.len() - self.pos);
+        buf[..len].copy_from_slice(&self.block[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+
+/// Reads the data regions of a sparse file from the input and fills the holes with zeros
+pub struct SparseReader<R> {
+    inner: R,
+    regions: Vec<(u64, u64)>,
+    next: usize,
+    /// Bytes left in the current region
+    remaining: u64,
+    pos: u64,
+    size: u64
+}
+
+impl<R: Read> SparseReader<R> {
+    pub fn new(inner: R, regions: Vec<(u64, u64)>, size: u64) -> Self {
+        SparseReader {
+            inner,
+            regions,
+            next: 0,
+            remaining: 0,
+            pos: 0,
+            size
+        }
+    }
+}
+
+impl<R: Read> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.remaining > 0 {
+                let len = min(buf.len() as u64, self.remaining) as usize;
+                let read = try!(self.inner.read(&mut buf[..len]));
+                if read == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing sparse data"));
+                }
+                self.remaining -= read as u64;
+                self.pos += read as u64;
+                return Ok(read);
+            }
+            let hole_end = self.regions.get(self.next).map_or(self.size, |r| r.0);
+            if self.pos < hole_end {
+                let len = min(buf.len() as u64, hole_end - self.pos) as usize;
+                for b in &mut buf[..len] {
+                    *b = 0;
+                }
+                self.pos += len as u64;
+                return Ok(len);
+            }
+            if self.next == self.regions.len() {
+                return Ok(0);
+            }
+            self.remaining = self.regions[self.next].1;
+            self.next += 1;
+        }
+    }
+}
+
+
+fn write_zeros<W: Write>(w: &mut W, mut len: u64) -> Result<(), io::Error> {
+    while len > 0 {
+        let n = min(len, ZEROS.len() as u64) as usize;
+        try!(w.write_all(&ZEROS[..n]));
+        len -= n as u64;
+    }
+    Ok(())
+}
+
+fn seek_forward<W: Seek>(w: &mut W, len: u64) -> Result<(), io::Error> {
+    try!(w.seek(SeekFrom::Current(len as i64)));
+    Ok(())
+}
+
+
+/// Writes the data regions of a sparse file to their place in the output
+///
+/// The data written to this writer is the contents of all data regions one after another.
+/// The holes are either filled with zeros or, for seekable outputs, skipped so that the
+/// filesystem can leave them unallocated.
+pub struct SparseWriter<'a, W> {
+    inner: W,
+    regions: &'a [(u64, u64)],
+    next: usize,
+    /// Bytes left in the current region
+    remaining: u64,
+    pos: u64,
+    skip: fn(&mut W, u64) -> Result<(), io::Error>
+}
+
+impl<'a, W: Write> SparseWriter<'a, W> {
+    /// Creates a writer that fills the holes with zeros
+    pub fn new(inner: W, regions: &'a [(u64, u64)]) -> Self {
+        SparseWriter {
+            inner,
+            regions,
+            next: 0,
+            remaining: 0,
+            pos: 0,
+            skip: write_zeros::<W>
+        }
+    }
+
+    /// Finishes the hole at the end of the file
+    ///
+    /// Skipping the end only moves the position, the size of files has to be set afterwards.
+    pub fn finish(mut self, size: u64) -> Result<W, io::Error> {
+        if self.remaining > 0 || self.next < self.regions.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing sparse data"));
+        }
+        if size > self.pos {
+            try!((self.skip)(&mut self.inner, size - self.pos));
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<'a, W: Write + Seek> SparseWriter<'a, W> {
+    /// Creates a writer that seeks over the holes instead of writing them
+    pub fn with_holes(inner: W, regions: &'a [(u64, u64)]) -> Self {
+        SparseWriter {
+            skip: seek_forward::<W>,
+            ..SparseWriter::new(inner, regions)
+        }
+    }
+}
+
+impl<'a, W: Write> Write for SparseWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.remaining == 0 {
+            if self.next == self.regions.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "data after the last region"));
+            }
+            let (offset, len) = self.regions[self.next];
+            if offset > self.pos {
+                try!((self.skip)(&mut self.inner, offset - self.pos));
+                self.pos = offset;
+            }
+            self.remaining = len;
+            self.next += 1;
+        }
+        let len = min(buf.len() as u64, self.remaining) as usize;
+        let written = try!(self.inner.write(&buf[..len]));
+        self.remaining -= written as u64;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::io::Cursor;
+
+
+    #[allow(dead_code)]
+    fn sparse_file() -> Vec<u8> {
+        let mut data = vec![0; 4 * HOLE_BLOCK_SIZE + 100];
+        for b in &mut data[HOLE_BLOCK_SIZE..HOLE_BLOCK_SIZE + 10] {
+            *b = 1;
+        }
+        for b in &mut data[4 * HOLE_BLOCK_SIZE..] {
+            *b = 2;
+        }
+        data
+    }
+
+    #[test]
+    fn test_zero_skipper() {
+        let data = sparse_file();
+        let mut skipper = ZeroSkipper::new(Cursor::new(&data));
+        let mut stored = vec![];
+        skipper.read_to_end(&mut stored).unwrap();
+        let block = HOLE_BLOCK_SIZE as u64;
+        assert_eq!(skipper.into_regions(), vec![(block, block), (4 * block, 100)]);
+        assert_eq!(stored.len(), HOLE_BLOCK_SIZE + 100);
+    }
+
+    #[test]
+    fn test_zero_skipper_merges_regions() {
+        let data = vec![1; 3 * HOLE_BLOCK_SIZE];
+        let mut skipper = ZeroSkipper::new(Cursor::new(&data));
+        let mut stored = vec![];
+        skipper.read_to_end(&mut stored).unwrap();
+        assert_eq!(skipper.into_regions(), vec![(0, data.len() as u64)]);
+        assert_eq!(stored, data);
+    }
+
+    #[test]
+    fn test_region_reader() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut reader = RegionReader::new(Cursor::new(&data), vec![(10, 5), (50, 3)]);
+        let mut stored = vec![];
+        reader.read_to_end(&mut stored).unwrap();
+        assert_eq!(stored, vec![10, 11, 12, 13, 14, 50, 51, 52]);
+        let mut reader = RegionReader::new(Cursor::new(&data), vec![(90, 20)]);
+        assert!(reader.read_to_end(&mut stored).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = sparse_file();
+        let mut skipper = ZeroSkipper::new(Cursor::new(&data));
+        let mut stored = vec![];
+        skipper.read_to_end(&mut stored).unwrap();
+        let regions = skipper.into_regions();
+        let mut restored = vec![];
+        SparseReader::new(Cursor::new(&stored), regions.clone(), data.len() as u64)
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert!(restored == data);
+        let mut writer = SparseWriter::new(vec![], &regions);
+        writer.write_all(&stored).unwrap();
+        assert!(writer.finish(data.len() as u64).unwrap() == data);
+        let mut writer = SparseWriter::with_holes(Cursor::new(vec![]), &regions);
+        writer.write_all(&stored).unwrap();
+        let written = writer.finish(data.len() as u64).unwrap().into_inner();
+        assert!(written[..] == data[..written.len()]);
+    }
+
+    #[test]
+    fn test_data_regions() {
+        let file = File::open("/proc/self/exe").unwrap();
+        let size = file.metadata().unwrap().len();
+        if let Some(regions) = data_regions(&file, size) {
+            let total: u64 = regions.iter().map(|r| r.1).sum();
+            assert!(total <= size);
+        }
+    }
+
+}
@@ -0,0 +1,172 @@
+use prelude::*;
+
+use std::io::{self, Read};
+
+use chrono::prelude::*;
+use libc;
+
+
+/// Reader that counts the bytes that have been read and reports them as progress
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+    progress: Option<ProgressReporter>,
+    name: String
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = try!(self.inner.read(buf));
+        self.count += len as u64;
+        if let Some(ref mut progress) = self.progress {
+            progress.update(self.count, &self.name);
+        }
+        Ok(len)
+    }
+}
+
+
+impl Repository {
+    /// Stores all data from the input as a backup that consists of a single file
+    ///
+    /// The file gets the given name, the current time and the current user as owner. As the
+    /// size of the stream is not known in advance, the progress only shows the amount of data
+    /// that has been read so far.
+    pub fn backup_stream<R: Read>(
+        &mut self,
+        input: R,
+        name: &str,
+        options: &BackupOptions,
+    ) -> Result<Backup, RepositoryError> {
+        try!(self.write_mode());
+        let _lock = try!(self.lock(false));
+        if self.dirty {
+            return Err(RepositoryError::Dirty);
+        }
+        try!(self.set_dirty());
+        let mut backup = Backup::default();
+        backup.config = self.config.clone();
+        backup.host = get_hostname().unwrap_or_else(|_| "".to_string());
+        backup.path = "-".to_string();
+        backup.version = Some(env!("CARGO_PKG_VERSION").to_string());
+        let info_before = self.info();
+        let start = Local::now();
+        let mut inode = Inode {
+            name: name.to_string(),
+            file_type: FileType::File,
+            mode: 0o600,
+            user: unsafe { libc::getuid() },
+            group: unsafe { libc::getgid() },
+            timestamp: start.timestamp(),
+            cum_files: 1,
+            ..Default::default()
+        };
+        let mut reader = CountingReader {
+            inner: input,
+            count: 0,
+            progress: if self.show_progress {
+                Some(ProgressReporter::new(tr!("backup"), 0))
+            } else {
+                None
+            },
+            name: name.to_string()
+        };
+        self.written_chunks = if options.verify { Some(vec![]) } else { None };
+        self.set_overrides(options);
+        let data = self.put_chunked_data(&mut reader);
+        self.reset_overrides();
+        let data = try!(data);
+        inode.size = reader.count;
+        inode.cum_size = reader.count;
+        if inode.size > 0 {
+            if let FileData::ChunkedIndirect(ref chunks) = data {
+                for &(_, len) in chunks.iter() {
+                    inode.cum_size += u64::from(len);
+                }
+            }
+            inode.data = Some(data);
+        }
+        backup.root = try!(self.put_inode(&inode));
+        try!(self.flush());
+        let elapsed = Local::now().signed_duration_since(start);
+        backup.timestamp = start.timestamp();
+        backup.total_data_size = inode.cum_size;
+        for &(_, len) in backup.root.iter() {
+            backup.total_data_size += u64::from(len);
+        }
+        backup.file_count = 1;
+        backup.dir_count = 0;
+        backup.duration = elapsed.num_milliseconds() as f32 / 1_000.0;
+        let info_after = self.info();
+        backup.deduplicated_data_size = info_after.raw_data_size - info_before.raw_data_size;
+        backup.encoded_data_size = info_after.encoded_data_size - info_before.encoded_data_size;
+        backup.bundle_count = info_after.bundle_count - info_before.bundle_count;
+        backup.chunk_count = info_after.chunk_count - info_before.chunk_count;
+        // Backups of data that is already stored add no chunks
+        backup.avg_chunk_size = if backup.chunk_count > 0 {
+            backup.deduplicated_data_size as f32 / backup.chunk_count as f32
+        } else {
+            0.0
+        };
+        if options.verify {
+            try!(self.verify_written_chunks());
+        }
+        self.dirty = false;
+        Ok(backup)
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use repository::tests::temp_dir;
+
+    #[allow(unused_imports)]
+    use std::fs::{self, File};
+    #[allow(unused_imports)]
+    use std::io::Cursor;
+    #[allow(unused_imports)]
+    use std::path::PathBuf;
+
+
+    #[test]
+    fn test_backup_stream() {
+        let base = temp_dir("backup-stream");
+        fs::create_dir(base.join("remote")).unwrap();
+        let mut repo = Repository::create(base.join("repo"), &Config::default(), base.join("remote")).unwrap();
+        let options = BackupOptions::default();
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..100_000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect();
+        let backup = repo.backup_stream(Cursor::new(&data), "dump", &options).unwrap();
+        repo.save_backup(&backup, "first").unwrap();
+        assert_eq!((backup.file_count, backup.dir_count), (1, 0));
+        assert!(backup.chunk_count > 0 && backup.avg_chunk_size > 0.0);
+        let inode = repo.get_inode(&backup.root).unwrap();
+        assert_eq!((inode.name.as_str(), inode.size), ("dump", data.len() as u64));
+        let restored = base.join("restored");
+        fs::create_dir(&restored).unwrap();
+        repo.restore_inode_tree(&backup, inode, &restored, RestoreOwner::Current, false).unwrap();
+        let mut contents = vec![];
+        File::open(restored.join("dump")).unwrap().read_to_end(&mut contents).unwrap();
+        assert!(contents == data);
+        // All chunks of the same data are already stored, at most the inode is new
+        let backup = repo.backup_stream(Cursor::new(&data), "dump", &options).unwrap();
+        assert!(backup.deduplicated_data_size < 1000);
+        assert!(backup.avg_chunk_size.is_finite());
+        let backup = repo.backup_stream(Cursor::new(Vec::<u8>::new()), "empty", &options).unwrap();
+        let inode = repo.get_inode(&backup.root).unwrap();
+        assert_eq!(inode.size, 0);
+        assert!(inode.data.is_none());
+        drop(repo);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+}
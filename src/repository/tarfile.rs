@@ -1,5 +1,7 @@
 use prelude::*;
 
+use super::sparse::SparseReader;
+
 use std::collections::{HashMap, HashSet, BTreeMap};
 use std::path::{Path, PathBuf};
 use std::io::{self, Read, Write, Cursor};
@@ -137,23 +139,10 @@ impl Repository {
     fn import_tar_entry<R: Read>(
         &mut self,
         entry: &mut tar::Entry<R>,
+        inline_size: u64,
     ) -> Result<Inode, RepositoryError> {
         let mut inode = try!(inode_from_entry(entry));
-        if inode.size < 100 {
-            let mut data = Vec::with_capacity(inode.size as usize);
-            try!(entry.read_to_end(&mut data));
-            inode.data = Some(FileData::Inline(data.into()));
-        } else {
-            let mut chunks = try!(self.put_stream(BundleMode::Data, entry));
-            if chunks.len() < 10 {
-                inode.data = Some(FileData::ChunkedDirect(chunks));
-            } else {
-                let mut chunk_data = Vec::with_capacity(chunks.encoded_size());
-                chunks.write_to(&mut chunk_data).unwrap();
-                chunks = try!(self.put_data(BundleMode::Meta, &chunk_data));
-                inode.data = Some(FileData::ChunkedIndirect(chunks));
-            }
-        }
+        try!(self.put_file_data(&mut inode, entry, inline_size));
         Ok(inode)
     }
 
@@ -161,6 +150,7 @@ impl Repository {
         &mut self,
         backup: &mut Backup,
         input: R,
+        options: &BackupOptions,
         failed_paths: &mut Vec<PathBuf>,
     ) -> Result<(Inode, ChunkList), RepositoryError> {
         let mut tarfile = tar::Archive::new(input);
@@ -169,7 +159,7 @@ impl Repository {
         for entry in try!(tarfile.entries()) {
             let mut entry = try!(entry);
             let path = try!(entry.path()).to_path_buf();
-            match self.import_tar_entry(&mut entry) {
+            match self.import_tar_entry(&mut entry, options.inline_size) {
                 Ok(mut inode) => {
                     inode.cum_size = inode.size;
                     if inode.file_type == FileType::Directory {
@@ -194,11 +184,12 @@ impl Repository {
                         backup.group_names.insert(inode.group, name.to_string());
                     }
                     inodes.insert(path, (inode, HashSet::new()));
+                    try!(self.write_checkpoint_if_due());
                 }
                 Err(RepositoryError::Inode(_)) |
                 Err(RepositoryError::Chunker(_)) |
                 Err(RepositoryError::Io(_)) => {
-                    tr_info!("Failed to backup {:?}", path);
+                    tr_info!("Failed to backup {}", escape(&path));
                     failed_paths.push(path);
                     continue;
                 }
@@ -272,6 +263,7 @@ impl Repository {
     pub fn import_tarfile<P: AsRef<Path>>(
         &mut self,
         tarfile: P,
+        options: &BackupOptions,
     ) -> Result<Backup, RepositoryError> {
         try!(self.write_mode());
         let _lock = try!(self.lock(false));
@@ -283,23 +275,21 @@ impl Repository {
         backup.config = self.config.clone();
         backup.host = get_hostname().unwrap_or_else(|_| "".to_string());
         backup.path = tarfile.as_ref().to_string_lossy().to_string();
+        backup.version = Some(env!("CARGO_PKG_VERSION").to_string());
         let info_before = self.info();
         let start = Local::now();
         let mut failed_paths = vec![];
         let tarfile = tarfile.as_ref();
-        let (root_inode, chunks) = if tarfile == Path::new("-") {
-            try!(self.import_tarfile_as_inode(
-                &mut backup,
-                io::stdin(),
-                &mut failed_paths
-            ))
+        let input: Box<Read> = if tarfile == Path::new("-") {
+            Box::new(io::stdin())
         } else {
-            try!(self.import_tarfile_as_inode(
-                &mut backup,
-                try!(File::open(tarfile)),
-                &mut failed_paths
-            ))
+            Box::new(try!(File::open(tarfile)))
         };
+        self.written_chunks = if options.verify { Some(vec![]) } else { None };
+        self.set_overrides(options);
+        let result = self.import_tarfile_as_inode(&mut backup, input, options, &mut failed_paths);
+        self.reset_overrides();
+        let (root_inode, chunks) = try!(result);
         backup.root = chunks;
         try!(self.flush());
         let elapsed = Local::now().signed_duration_since(start);
@@ -313,7 +303,15 @@ impl Repository {
         backup.encoded_data_size = info_after.encoded_data_size - info_before.encoded_data_size;
         backup.bundle_count = info_after.bundle_count - info_before.bundle_count;
         backup.chunk_count = info_after.chunk_count - info_before.chunk_count;
-        backup.avg_chunk_size = backup.deduplicated_data_size as f32 / backup.chunk_count as f32;
+        // Backups of data that is already stored add no chunks
+        backup.avg_chunk_size = if backup.chunk_count > 0 {
+            backup.deduplicated_data_size as f32 / backup.chunk_count as f32
+        } else {
+            0.0
+        };
+        if options.verify {
+            try!(self.verify_written_chunks());
+        }
         self.dirty = false;
         if failed_paths.is_empty() {
             Ok(backup)
@@ -362,12 +360,15 @@ impl Repository {
             } else {
                 try!(header.set_path(&path));
             }
-            if let Some(target) = inode.symlink_target {
-                if target.len() >= MAX_LINK_LEN {
-                    try!(tarfile.append_long_link(Path::new(&target)));
-                } else {
-                    try!(header.set_link_name(target));
+            match inode.symlink_target {
+                Some(ref target) if inode.file_type != FileType::HardLink => {
+                    if target.len() >= MAX_LINK_LEN {
+                        try!(tarfile.append_long_link(Path::new(target)));
+                    } else {
+                        try!(header.set_link_name(target));
+                    }
                 }
+                _ => ()
             }
             if let Some((major, minor)) = inode.device {
                 try!(header.set_device_major(major));
@@ -384,7 +385,8 @@ impl Repository {
             }
             header.set_mtime(inode.timestamp as u64);
             header.set_entry_type(match inode.file_type {
-                FileType::File => tar::EntryType::Regular,
+                // Hard links are exported with their contents so that subtrees are complete
+                FileType::File | FileType::HardLink => tar::EntryType::Regular,
                 FileType::Symlink => tar::EntryType::Symlink,
                 FileType::Directory => tar::EntryType::Directory,
                 FileType::BlockDevice => tar::EntryType::Block,
@@ -402,6 +404,16 @@ impl Repository {
                     let chunks = ChunkList::read_from(&try!(self.get_data(&chunks)));
                     try!(tarfile.append(&header, self.get_reader(chunks)))
                 }
+                Some(FileData::Sparse(sparse)) => {
+                    let chunks = try!(try!(self.get_data_chunks(&sparse.data)).ok_or_else(|| {
+                        InodeError::Integrity(tr!("Sparse file with inline data"))
+                    }));
+                    let size = inode.size;
+                    try!(tarfile.append(
+                        &header,
+                        SparseReader::new(self.get_reader(chunks), sparse.regions, size)
+                    ))
+                }
             }
         }
         if let Some(children) = inode.children {
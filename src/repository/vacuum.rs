@@ -1,6 +1,94 @@
 use prelude::*;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::io::{self, BufReader, Read, Write, BufWriter};
+use std::fs::{self, File};
+use std::mem;
+
+
+static HEADER_STRING: [u8; 7] = *b"zvacuum";
+static HEADER_VERSION: u8 = 1;
+
+
+quick_error!{
+    #[derive(Debug)]
+    pub enum VacuumError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            description(tr!("Failed to read/write vacuum journal"))
+        }
+        Decode(err: msgpack::DecodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to decode vacuum journal"))
+        }
+        Encode(err: msgpack::EncodeError) {
+            from()
+            cause(err)
+            description(tr!("Failed to encode vacuum journal"))
+        }
+        WrongHeader {
+            description(tr!("Wrong header"))
+        }
+        WrongVersion(version: u8) {
+            description(tr!("Wrong version"))
+            display("{}", tr_format!("Wrong version: {}", version))
+        }
+    }
+}
+
+
+/// Journal of a running vacuum or repack, used to finish or undo it after an interruption
+///
+/// The index is only changed once all used chunks of the old bundles have been copied into
+/// complete new bundles. Until the old bundles are deleted, both copies are readable.
+#[derive(Default)]
+struct VacuumJournal {
+    /// Bundles that are being rewritten
+    old: Vec<BundleId>,
+    /// Bundles that contain the used chunks of the old bundles
+    new: Vec<BundleId>,
+    /// Whether all new bundles have been written
+    complete: bool
+}
+serde_impl!(VacuumJournal(u8?) {
+    old: Vec<BundleId> => 0,
+    new: Vec<BundleId> => 1,
+    complete: bool => 2
+});
+
+impl VacuumJournal {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Self, VacuumError> {
+        let mut file = BufReader::new(try!(File::open(path.as_ref())));
+        let mut header = [0u8; 8];
+        try!(file.read_exact(&mut header));
+        if header[..HEADER_STRING.len()] != HEADER_STRING {
+            return Err(VacuumError::WrongHeader);
+        }
+        let version = header[HEADER_STRING.len()];
+        if version != HEADER_VERSION {
+            return Err(VacuumError::WrongVersion(version));
+        }
+        Ok(try!(msgpack::decode_from_stream(&mut file)))
+    }
+
+    /// Saves the journal, replacing the old file only after the new one is complete
+    fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), VacuumError> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("journal.tmp");
+        {
+            let mut file = BufWriter::new(try!(File::create(&tmp_path)));
+            try!(file.write_all(&HEADER_STRING));
+            try!(file.write_all(&[HEADER_VERSION]));
+            try!(msgpack::encode_to_stream(self, &mut file));
+            try!(file.flush());
+            try!(file.get_ref().sync_all());
+        }
+        Ok(try!(fs::rename(&tmp_path, path)))
+    }
+}
 
 
 impl Repository {
@@ -19,6 +107,9 @@ impl Repository {
         combine: bool,
         force: bool,
     ) -> Result<(), RepositoryError> {
+        if force {
+            try!(self.check_append_only());
+        }
         try!(self.flush());
         tr_info!("Locking repository");
         try!(self.write_mode());
@@ -80,44 +171,179 @@ impl Repository {
             self.dirty = false;
             return Ok(());
         }
-        for id in ProgressIter::new(
-            tr!("rewriting bundles"),
-            rewrite_bundles.len(),
-            rewrite_bundles.iter()
-        )
-        {
+        let rewrite_bundles: Vec<u32> = rewrite_bundles.into_iter().collect();
+        try!(self.rewrite_bundles(&usage, &rewrite_bundles, tr!("rewriting bundles")));
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Copies the used chunks of the bundles into new bundles and deletes the old bundles
+    ///
+    /// Bundles without used chunks are just deleted. The whole process is recorded in the
+    /// vacuum journal, so that it is undone or finished after an interruption.
+    fn rewrite_bundles(
+        &mut self,
+        usage: &HashMap<u32, BundleAnalysis>,
+        bundles: &[u32],
+        msg: &str,
+    ) -> Result<(), RepositoryError> {
+        let journal_path = self.layout.vacuum_journal_path();
+        let mut journal = VacuumJournal {
+            old: bundles.iter().map(|id| self.bundle_map.get(*id).unwrap()).collect(),
+            new: vec![],
+            complete: false
+        };
+        try!(journal.save(&journal_path));
+        // The index still points to the old bundles while the used chunks are copied
+        let mut copied = HashSet::new();
+        let mut new_bundles = HashSet::new();
+        for id in ProgressIter::new(msg, bundles.len(), bundles.iter()) {
             let bundle = &usage[id];
+            if bundle.used_raw_size == 0 {
+                continue;
+            }
             let bundle_id = self.bundle_map.get(*id).unwrap();
             let chunks = try!(self.bundles.get_chunk_list(&bundle_id));
-            let mode = usage[id].info.mode;
+            let mode = bundle.info.mode;
             for (chunk, &(hash, _len)) in chunks.into_iter().enumerate() {
-                if !bundle.chunk_usage.get(chunk) {
-                    try!(self.index.delete(&hash));
+                if !bundle.chunk_usage.get(chunk) || !copied.insert(hash) {
                     continue;
                 }
                 let data = try!(self.bundles.get_chunk(&bundle_id, chunk));
-                try!(self.put_chunk_override(mode, hash, &data));
+                new_bundles.insert(try!(self.copy_chunk(mode, hash, &data)).bundle);
             }
         }
         try!(self.flush());
-        tr_info!("Checking index");
-        for (hash, location) in self.index.iter() {
-            let loc_bundle = location.bundle;
-            let loc_chunk = location.chunk;
-            if rewrite_bundles.contains(&loc_bundle) {
-                tr_panic!(
-                    "Removed bundle is still referenced in index: hash:{}, bundle:{}, chunk:{}",
-                    hash,
-                    loc_bundle,
-                    loc_chunk
-                );
+        journal.new = new_bundles.into_iter().map(|id| self.bundle_map.get(id).unwrap()).collect();
+        journal.complete = true;
+        try!(journal.save(&journal_path));
+        self.finish_vacuum(&journal)
+    }
+
+    /// Points the index to the new bundles of the vacuum and deletes the old bundles
+    ///
+    /// This can be repeated any number of times, e.g. after an interruption.
+    fn finish_vacuum(&mut self, journal: &VacuumJournal) -> Result<(), RepositoryError> {
+        for bundle in ProgressIter::new(
+            tr!("updating index"),
+            journal.new.len(),
+            journal.new.iter()
+        )
+        {
+            let id = try!(self.bundle_map.find(bundle).ok_or_else(|| {
+                IntegrityError::MissingBundle(bundle.clone())
+            }));
+            let chunks = try!(self.bundles.get_chunk_list(bundle));
+            for (chunk, (hash, _len)) in chunks.into_inner().into_iter().enumerate() {
+                try!(self.index.set(&hash, &Location::new(id, chunk as u32)));
             }
         }
-        tr_info!("Deleting {} bundles", rewrite_bundles.len());
-        for id in rewrite_bundles {
+        let old: HashSet<u32> = journal.old.iter().filter_map(|b| self.bundle_map.find(b)).collect();
+        // Only the unused chunks still point to the old bundles
+        try!(self.index.filter(|_hash, location| !old.contains(&location.bundle)));
+        for id in ProgressIter::new(tr!("deleting bundles"), old.len(), old.into_iter()) {
             try!(self.delete_bundle(id));
         }
         try!(self.save_bundle_map());
+        try!(fs::remove_file(self.layout.vacuum_journal_path()));
+        Ok(())
+    }
+
+    /// Finishes or undoes a vacuum that has been interrupted
+    ///
+    /// If the new bundles were not complete yet, the index still points to the old bundles
+    /// and the vacuum is undone by just dropping the journal. Otherwise the index is updated
+    /// and the old bundles are deleted.
+    pub fn recover_vacuum(&mut self) -> Result<(), RepositoryError> {
+        let path = self.layout.vacuum_journal_path();
+        let journal = try!(VacuumJournal::load(&path));
+        if !journal.complete {
+            tr_warn!("Undoing an interrupted vacuum, the old bundles are kept");
+            try!(fs::remove_file(&path));
+            return Ok(());
+        }
+        let _lock = match self.lock(true) {
+            Ok(lock) => lock,
+            Err(err) => {
+                tr_warn!("An interrupted vacuum can not be finished now\n\tcaused by: {}", err);
+                return Ok(());
+            }
+        };
+        tr_warn!("Finishing an interrupted vacuum");
+        // The vacuum has already been allowed when it was started
+        self.bundles.set_append_only(false);
+        let result = self.finish_vacuum(&journal);
+        self.bundles.set_append_only(self.config.append_only && !self.admin);
+        result
+    }
+
+    pub fn repack(&mut self, bundle_size: usize, force: bool) -> Result<(), RepositoryError> {
+        if force {
+            try!(self.check_append_only());
+        }
+        try!(self.flush());
+        tr_info!("Locking repository");
+        try!(self.write_mode());
+        let _lock = try!(self.lock(true));
+        // analyze_usage will set the dirty flag
+        tr_info!("Analyzing chunk usage");
+        let usage = try!(self.analyze_usage());
+        let mut unused = 0;
+        let mut oversized = 0;
+        let mut rewrite = vec![];
+        let mut small_meta = vec![];
+        let mut small_data = vec![];
+        let mut rewrite_size = 0;
+        for (id, bundle) in &usage {
+            let size = bundle.info.encoded_size;
+            if bundle.used_raw_size == 0 {
+                // Nothing to copy, the bundle is just deleted
+                unused += 1;
+                rewrite.push(*id);
+            } else if size > 2 * bundle_size {
+                oversized += 1;
+                rewrite_size += bundle.get_used_size();
+                rewrite.push(*id);
+            } else if size * 2 < bundle_size {
+                match bundle.info.mode {
+                    BundleMode::Meta => small_meta.push((*id, bundle.get_used_size())),
+                    BundleMode::Data => small_data.push((*id, bundle.get_used_size())),
+                }
+            }
+        }
+        let mut undersized = 0;
+        // A single small bundle can not be merged with anything
+        if small_meta.len() >= 2 {
+            for (id, size) in small_meta {
+                undersized += 1;
+                rewrite.push(id);
+                rewrite_size += size;
+            }
+        }
+        if small_data.len() >= 2 {
+            for (id, size) in small_data {
+                undersized += 1;
+                rewrite.push(id);
+                rewrite_size += size;
+            }
+        }
+        rewrite.sort();
+        tr_info!(
+            "Repacking {} oversized and {} undersized bundles ({}) to {} each",
+            oversized,
+            undersized,
+            to_file_size(rewrite_size as u64),
+            to_file_size(bundle_size as u64)
+        );
+        tr_info!("Removing {} bundles without used chunks", unused);
+        if !force {
+            self.dirty = false;
+            return Ok(());
+        }
+        let old_bundle_size = mem::replace(&mut self.config.bundle_size, bundle_size);
+        let result = self.rewrite_bundles(&usage, &rewrite, tr!("repacking bundles"));
+        self.config.bundle_size = old_bundle_size;
+        try!(result);
         self.dirty = false;
         Ok(())
     }
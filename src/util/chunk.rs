@@ -1,5 +1,6 @@
 use std::io::{self, Write, Read, Cursor};
 use std::ops::{Deref, DerefMut};
+use std::cmp::min;
 
 use serde::{self, Serialize, Deserialize};
 use serde_bytes::{Bytes, ByteBuf};
@@ -7,7 +8,7 @@ use serde::de::Error;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use super::Hash;
+use super::{Hash, HashMethod, Blake2Stream};
 
 pub type Chunk = (Hash, u32);
 
@@ -138,11 +139,141 @@ impl<'a> Deserialize<'a> for ChunkList {
 }
 
 
+/// Verifies a stream of chunk data against a chunk list with a bounded buffer
+///
+/// Blake2 hashes are calculated incrementally, so no data is buffered. For the other hash
+/// methods, at most the data of the current chunk is buffered. Data after the last chunk is
+/// only counted.
+pub struct ChunkVerifier<'a> {
+    chunks: &'a [Chunk],
+    method: HashMethod,
+    stream: Option<Blake2Stream>,
+    buffer: Vec<u8>,
+    /// Index of the current chunk and the number of its bytes that have been written
+    next: usize,
+    current: usize,
+    size: usize,
+    offset: usize,
+    extra: usize,
+    invalid: Option<(usize, usize)>
+}
+
+impl<'a> ChunkVerifier<'a> {
+    pub fn new(chunks: &'a [Chunk], method: HashMethod) -> Self {
+        ChunkVerifier {
+            chunks,
+            method,
+            stream: if method == HashMethod::Blake2 {
+                Some(Blake2Stream::new())
+            } else {
+                None
+            },
+            buffer: Vec::new(),
+            next: 0,
+            current: 0,
+            size: 0,
+            offset: 0,
+            extra: 0,
+            invalid: None
+        }
+    }
+
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    #[inline]
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.invalid.is_none()
+    }
+
+    /// Returns the index and offset of the first chunk that does not match its hash
+    #[inline]
+    pub fn first_invalid(&self) -> Option<(usize, usize)> {
+        self.invalid
+    }
+
+    /// Returns whether all chunks have been written completely and no data after them
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.next == self.chunks.len() && self.current == 0 && self.extra == 0
+    }
+
+    fn finish_chunk(&mut self) {
+        let hash = match self.stream.take() {
+            Some(stream) => {
+                self.stream = Some(Blake2Stream::new());
+                stream.finish()
+            }
+            None => {
+                let hash = self.method.hash(&self.buffer);
+                self.buffer.clear();
+                hash
+            }
+        };
+        if self.invalid.is_none() && hash != self.chunks[self.next].0 {
+            self.invalid = Some((self.next, self.offset));
+        }
+        self.offset += self.current;
+        self.current = 0;
+        self.next += 1;
+    }
+}
+
+impl<'a> Write for ChunkVerifier<'a> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, io::Error> {
+        self.size += data.len();
+        let mut rest = data;
+        loop {
+            let len = match self.chunks.get(self.next) {
+                Some(&(_, len)) => len as usize,
+                None => {
+                    self.extra += rest.len();
+                    break;
+                }
+            };
+            if self.current == len {
+                self.finish_chunk();
+                continue;
+            }
+            if rest.is_empty() {
+                break;
+            }
+            // Only take the data of the current chunk, so the buffer never exceeds its length
+            let part = min(len - self.current, rest.len());
+            match self.stream {
+                Some(ref mut stream) => stream.update(&rest[..part]),
+                None => self.buffer.extend_from_slice(&rest[..part])
+            }
+            self.current += part;
+            rest = &rest[part..];
+        }
+        Ok(data.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
 
 mod tests {
 
     #[allow(unused_imports)]
-    use super::ChunkList;
+    use super::{ChunkList, ChunkVerifier};
+
+    #[allow(unused_imports)]
+    use super::super::HashMethod;
+
+    #[allow(unused_imports)]
+    use std::io::Write;
 
     #[allow(unused_imports)]
     use super::super::Hash;
@@ -278,4 +409,70 @@ mod tests {
         assert_eq!(msgpack::decode::<ChunkList>(&buf).unwrap(), list);
     }
 
+    #[test]
+    fn test_verifier() {
+        let method = HashMethod::Blake2;
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+        let mut list = ChunkList::new();
+        list.push((method.hash(&data[..1000]), 1000));
+        list.push((method.hash(&data[1000..]), 9000));
+        let mut verifier = ChunkVerifier::new(&list, method);
+        for part in data.chunks(333) {
+            verifier.write_all(part).unwrap();
+        }
+        assert!(verifier.is_valid());
+        assert!(verifier.is_complete());
+        assert_eq!(verifier.size(), data.len());
+        let mut verifier = ChunkVerifier::new(&list, method);
+        verifier.write_all(&data[..9999]).unwrap();
+        verifier.write_all(&[0]).unwrap();
+        assert!(!verifier.is_valid());
+        assert_eq!(verifier.first_invalid(), Some((1, 1000)));
+    }
+
+    #[test]
+    fn test_verifier_bounded_memory() {
+        let method = HashMethod::Blake2;
+        let chunk: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 13) as u8).collect();
+        let hash = method.hash(&chunk);
+        let mut list = ChunkList::new();
+        for _ in 0..1024 {
+            list.push((hash, chunk.len() as u32));
+        }
+        // 64 MiB of data must never be buffered at once
+        let mut verifier = ChunkVerifier::new(&list, method);
+        for _ in 0..1024 {
+            for part in chunk.chunks(10_000) {
+                verifier.write_all(part).unwrap();
+                assert!(verifier.buffered() < chunk.len() + 10_000);
+            }
+        }
+        assert!(verifier.is_valid());
+        assert!(verifier.is_complete());
+        assert_eq!(verifier.buffered(), 0);
+    }
+
+    #[test]
+    fn test_verifier_buffer_limit() {
+        // Hashes that can not be calculated incrementally buffer at most one chunk
+        let method = HashMethod::Murmur3;
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+        let mut list = ChunkList::new();
+        list.push((method.hash(&data[..1000]), 1000));
+        list.push((method.hash(&data[1000..3000]), 2000));
+        list.push((method.hash(&data[3000..]), 7000));
+        let mut verifier = ChunkVerifier::new(&list, method);
+        verifier.write_all(&data[..2500]).unwrap();
+        assert_eq!(verifier.buffered(), 1500);
+        verifier.write_all(&data[2500..]).unwrap();
+        assert_eq!(verifier.buffered(), 0);
+        assert!(verifier.is_valid());
+        assert!(verifier.is_complete());
+        // Data after the last chunk is not buffered
+        verifier.write_all(&data).unwrap();
+        assert_eq!(verifier.buffered(), 0);
+        assert_eq!(verifier.size(), 2 * data.len());
+        assert!(verifier.is_valid());
+        assert!(!verifier.is_complete());
+    }
 }
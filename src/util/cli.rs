@@ -1,6 +1,6 @@
 use pbr;
 use std::io::Stdout;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub fn to_file_size(size: u64) -> String {
     let mut size = size as f32;
@@ -41,11 +41,134 @@ pub fn to_duration(dur: f32) -> String {
     format!("{}:{:02}:{:04.1}", hours, mins, secs)
 }
 
+/// Parses a duration like `30s`, `15m`, `12h`, `7d`, `2w` or `1y` into seconds
+pub fn parse_duration(val: &str) -> Result<u64, String> {
+    let split = match val.char_indices().last() {
+        Some((pos, _)) => pos,
+        None => return Err(tr!("The duration must not be empty").to_string())
+    };
+    let (num, unit) = val.split_at(split);
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => return Err(tr_format!("Invalid duration '{}': the unit must be one of s, m, h, d, w, y", val))
+    };
+    let num = try!(num.parse::<u64>().map_err(|_| {
+        tr_format!("Invalid duration '{}': '{}' is not a number", val, num)
+    }));
+    num.checked_mul(factor).ok_or_else(|| tr_format!("Invalid duration '{}': too large", val))
+}
+
+/// Formats a duration in seconds with the largest unit of `parse_duration` that fits exactly
+pub fn format_duration(secs: u64) -> String {
+    for &(factor, unit) in &[
+        (365 * 24 * 60 * 60, "y"),
+        (7 * 24 * 60 * 60, "w"),
+        (24 * 60 * 60, "d"),
+        (60 * 60, "h"),
+        (60, "m"),
+    ]
+    {
+        if secs > 0 && secs % factor == 0 {
+            return format!("{}{}", secs / factor, unit);
+        }
+    }
+    format!("{}s", secs)
+}
+
+pub fn to_eta(dur: f64) -> String {
+    let secs = dur.round() as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+
+/// Half-life of the rate smoothing in seconds
+const ETA_RATE_HALFLIFE: f64 = 10.0;
+/// Maximal relative change of the ETA per second
+const ETA_MAX_CHANGE: f64 = 0.1;
+/// Minimal time between two rate samples in seconds
+const ETA_MIN_INTERVAL: f64 = 0.1;
+
+/// Estimates the remaining time of an operation using an exponentially
+/// weighted moving average of the processing rate.
+pub struct EtaEstimator {
+    start: Instant,
+    last_time: f64,
+    last_done: u64,
+    rate: Option<f64>,
+    eta: Option<f64>
+}
+
+impl EtaEstimator {
+    pub fn new() -> Self {
+        EtaEstimator {
+            start: Instant::now(),
+            last_time: 0.0,
+            last_done: 0,
+            rate: None,
+            eta: None
+        }
+    }
+
+    #[inline]
+    pub fn update(&mut self, done: u64, total: u64) -> Option<f64> {
+        let elapsed = self.start.elapsed();
+        let now = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.update_at(done, total, now)
+    }
+
+    pub fn update_at(&mut self, done: u64, total: u64, now: f64) -> Option<f64> {
+        let dt = now - self.last_time;
+        if dt < ETA_MIN_INTERVAL {
+            return self.eta;
+        }
+        let sample = done.saturating_sub(self.last_done) as f64 / dt;
+        self.last_time = now;
+        self.last_done = done;
+        // Weigh the new sample by the time it covers
+        let alpha = 1.0 - (-dt * 2f64.ln() / ETA_RATE_HALFLIFE).exp();
+        let rate = match self.rate {
+            Some(rate) => rate + alpha * (sample - rate),
+            None => sample,
+        };
+        self.rate = Some(rate);
+        if rate <= 0.0 {
+            return self.eta;
+        }
+        let estimate = total.saturating_sub(done) as f64 / rate;
+        // Limit how fast the ETA can jump away from its expected value
+        let eta = match self.eta {
+            Some(eta) => {
+                let expected = (eta - dt).max(0.0);
+                let max_change = (expected * ETA_MAX_CHANGE * dt).max(dt);
+                estimate.max(expected - max_change).min(expected + max_change)
+            }
+            None => estimate,
+        };
+        self.eta = Some(eta);
+        self.eta
+    }
+}
+
+impl Default for EtaEstimator {
+    #[inline]
+    fn default() -> Self {
+        EtaEstimator::new()
+    }
+}
+
 
 pub struct ProgressIter<T> {
     inner: T,
     msg: String,
-    bar: pbr::ProgressBar<Stdout>
+    bar: pbr::ProgressBar<Stdout>,
+    eta: EtaEstimator,
+    done: u64,
+    total: u64
 }
 
 impl<T> ProgressIter<T> {
@@ -55,10 +178,15 @@ impl<T> ProgressIter<T> {
         let msg = format!("{}: ", msg);
         bar.message(&msg);
         bar.set_max_refresh_rate(Some(Duration::from_millis(100)));
+        // The builtin time left is based on the average rate since start
+        bar.show_time_left = false;
         ProgressIter {
             inner,
             bar,
-            msg
+            msg,
+            eta: EtaEstimator::new(),
+            done: 0,
+            total: max as u64
         }
     }
 }
@@ -78,6 +206,11 @@ impl<T: Iterator> Iterator for ProgressIter<T> {
                 None
             }
             Some(item) => {
+                self.done += 1;
+                if let Some(eta) = self.eta.update(self.done, self.total) {
+                    let msg = format!("{}{} {} ", self.msg, tr!("ETA"), to_eta(eta));
+                    self.bar.message(&msg);
+                }
                 self.bar.inc();
                 Some(item)
             }
@@ -121,6 +254,33 @@ mod tests {
         assert_eq!("3.9 TiB/s", to_speed(4312534553170, 1.0));
     }
 
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Ok(30));
+        assert_eq!(parse_duration("15m"), Ok(900));
+        assert_eq!(parse_duration("12h"), Ok(43200));
+        assert_eq!(parse_duration("7d"), Ok(604800));
+        assert_eq!(parse_duration("2w"), Ok(1209600));
+        assert_eq!(parse_duration("1y"), Ok(31536000));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("7x").is_err());
+        assert!(parse_duration("-7d").is_err());
+        assert!(parse_duration("7ä").is_err());
+        assert!(parse_duration("99999999999999999y").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(45), "45s");
+        assert_eq!(format_duration(900), "15m");
+        assert_eq!(format_duration(604800), "1w");
+        assert_eq!(format_duration(90000), "25h");
+        assert_eq!(parse_duration(&format_duration(1209600)), Ok(1209600));
+    }
+
     #[test]
     fn test_to_duration() {
         assert_eq!("0:00:00.0", to_duration(0.0));
@@ -131,5 +291,36 @@ mod tests {
         assert_eq!("2:02:02.2", to_duration(7322.2));
     }
 
+    #[test]
+    fn test_to_eta() {
+        assert_eq!("0:00:00", to_eta(0.0));
+        assert_eq!("0:00:01", to_eta(0.6));
+        assert_eq!("0:01:00", to_eta(60.0));
+        assert_eq!("2:02:02", to_eta(7322.2));
+    }
+
+    #[test]
+    fn test_eta_constant_rate() {
+        let mut eta = EtaEstimator::new();
+        let mut last = None;
+        for i in 1..100 {
+            last = eta.update_at(i * 10, 1000, i as f64);
+        }
+        let last = last.unwrap();
+        assert!((last - 1.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_eta_jitter() {
+        let mut eta = EtaEstimator::new();
+        for i in 1..20 {
+            eta.update_at(i * 10, 1000, i as f64);
+        }
+        let before = eta.update_at(200, 1000, 20.0).unwrap();
+        // A sudden stall must not make the ETA explode
+        let after = eta.update_at(200, 1000, 21.0).unwrap();
+        assert!(after <= before * (1.0 + ETA_MAX_CHANGE) + 1.0);
+    }
+
 
 }
@@ -39,15 +39,20 @@ pub enum CompressionMethod {
     Deflate, // Standardized
     Brotli, // Good speed and ratio
     Lzma, // Very good ratio, slow
-    Lz4 // Very fast, low ratio
+    Lz4, // Very fast, low ratio
+    Zstd // Good speed and ratio, wide range of levels
 }
 serde_impl!(CompressionMethod(u8) {
     Deflate => 0,
     Brotli => 1,
     Lzma => 2,
-    Lz4 => 3
+    Lz4 => 3,
+    Zstd => 4
 });
 
+/// The highest supported zstd compression level
+pub const MAX_ZSTD_LEVEL: u8 = 22;
+
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Compression {
@@ -89,8 +94,12 @@ impl Compression {
             "brotli" => CompressionMethod::Brotli,
             "lzma" | "lzma2" | "xz" => CompressionMethod::Lzma,
             "lz4" => CompressionMethod::Lz4,
+            "zstd" => CompressionMethod::Zstd,
             _ => return Err(CompressionError::UnsupportedCodec(name.to_string())),
         };
+        if method == CompressionMethod::Zstd && (level < 1 || level > MAX_ZSTD_LEVEL) {
+            return Err(CompressionError::UnsupportedCodec(format!("{}/{}", name, level)));
+        }
         Ok(Compression {
             method,
             level
@@ -103,6 +112,7 @@ impl Compression {
             CompressionMethod::Brotli => "brotli",
             CompressionMethod::Lzma => "lzma",
             CompressionMethod::Lz4 => "lz4",
+            CompressionMethod::Zstd => "zstd",
         }
     }
 
@@ -224,11 +234,46 @@ impl Compression {
 }
 
 
+/// Size of the sample at the start of a chunk that its compressibility is estimated from
+const ENTROPY_SAMPLE_SIZE: usize = 16 * 1024;
+
+/// Data with more bits of entropy per byte hardly gets smaller when compressed
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+/// Returns the entropy of the byte values in bits per byte
+pub fn byte_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts.iter().filter(|&&count| count > 0).map(|&count| {
+        let p = count as f64 / len;
+        -p * p.log2()
+    }).sum()
+}
+
+/// Estimates whether compressing the data is worth the time
+///
+/// Already compressed or encrypted data has nearly 8 bits of entropy per byte. Only a sample at
+/// the start of the data is checked, so this is much faster than a trial compression.
+pub fn is_compressible(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(ENTROPY_SAMPLE_SIZE)];
+    byte_entropy(sample) < INCOMPRESSIBLE_ENTROPY
+}
+
+
 pub struct CompressionStream {
     stream: *mut SquashStream,
     buffer: [u8; 16 * 1024]
 }
 
+// The stream is owned exclusively, so it can be moved to a compression thread
+unsafe impl Send for CompressionStream {}
+
 impl CompressionStream {
     #[inline]
     fn new(stream: *mut SquashStream) -> Self {
@@ -315,6 +360,10 @@ mod tests {
         assert_eq!(("lzma", 3), (method.name(), method.level()));
         let method = Compression::from_string("lz4/1").unwrap();
         assert_eq!(("lz4", 1), (method.name(), method.level()));
+        let method = Compression::from_string("zstd/10").unwrap();
+        assert_eq!(("zstd", 10), (method.name(), method.level()));
+        assert!(Compression::from_string("zstd/0").is_err());
+        assert!(Compression::from_string("zstd/23").is_err());
     }
 
     #[test]
@@ -327,6 +376,10 @@ mod tests {
             "deflate/1",
             Compression::from_string("gzip/1").unwrap().to_string()
         );
+        assert_eq!(
+            "zstd/22",
+            Compression::from_string("zstd/22").unwrap().to_string()
+        );
     }
 
     #[allow(dead_code, needless_range_loop)]
@@ -373,6 +426,17 @@ mod tests {
         test_compression("lz4", 1, 11)
     }
 
+    #[test]
+    fn test_compression_zstd() {
+        let input = test_data(1024 * 1024);
+        for level in &[1, 3, 10, 19, 22] {
+            let method = Compression::from_string(&format!("zstd/{}", level)).unwrap();
+            let compressed = method.compress(&input).unwrap();
+            assert!(compressed.len() < input.len());
+            assert_eq!(input, method.decompress(&compressed).unwrap());
+        }
+    }
+
     #[allow(dead_code)]
     fn test_stream_compression(method: &str, min_lvl: u8, max_lvl: u8) {
         let input = test_data(512 * 1024);
@@ -416,6 +480,26 @@ mod tests {
         test_stream_compression("lz4", 1, 11)
     }
 
+    #[test]
+    fn test_stream_compression_zstd() {
+        test_stream_compression("zstd", 1, 22)
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible(&[]));
+        assert!(is_compressible(&[0; 100_000]));
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(1000);
+        assert!(is_compressible(text.as_bytes()));
+        let mut state = 1u32;
+        let random: Vec<u8> = (0..100_000).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect();
+        assert!(byte_entropy(&random) > 7.9);
+        assert!(!is_compressible(&random));
+    }
+
 }
 
 
@@ -0,0 +1,27 @@
+use libc;
+
+
+/// Returns the number of online processors, or 1 if it can not be determined
+pub fn get_cpu_count() -> usize {
+    let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if count < 1 {
+        1
+    } else {
+        count as usize
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_get_cpu_count() {
+        assert!(get_cpu_count() >= 1);
+    }
+
+}
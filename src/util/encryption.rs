@@ -7,11 +7,14 @@ use std::sync::{Once, ONCE_INIT};
 use serde_yaml;
 use serde_bytes::ByteBuf;
 
+use libc;
 use libsodium_sys;
 use sodiumoxide;
 use sodiumoxide::crypto::sealedbox;
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::sign;
+use blake2::blake2b::blake2b;
 pub use sodiumoxide::crypto::box_::{SecretKey, PublicKey};
 
 use util::*;
@@ -19,6 +22,37 @@ use util::*;
 
 static INIT: Once = ONCE_INIT;
 
+// AES-256-GCM is part of libsodium but not of the bindings
+extern "C" {
+    fn crypto_aead_aes256gcm_is_available() -> libc::c_int;
+    fn crypto_aead_aes256gcm_encrypt(
+        c: *mut u8,
+        clen: *mut libc::c_ulonglong,
+        m: *const u8,
+        mlen: libc::c_ulonglong,
+        ad: *const u8,
+        adlen: libc::c_ulonglong,
+        nsec: *const u8,
+        npub: *const u8,
+        k: *const u8
+    ) -> libc::c_int;
+    fn crypto_aead_aes256gcm_decrypt(
+        m: *mut u8,
+        mlen: *mut libc::c_ulonglong,
+        nsec: *mut u8,
+        c: *const u8,
+        clen: libc::c_ulonglong,
+        ad: *const u8,
+        adlen: libc::c_ulonglong,
+        npub: *const u8,
+        k: *const u8
+    ) -> libc::c_int;
+}
+
+const AES_KEYBYTES: usize = 32;
+const AES_NPUBBYTES: usize = 12;
+const AES_ABYTES: usize = 16;
+
 fn sodium_init() {
     INIT.call_once(|| if !sodiumoxide::init() {
         tr_panic!("Failed to initialize sodiumoxide");
@@ -31,6 +65,9 @@ quick_error!{
         InvalidKey {
             description(tr!("Invalid key"))
         }
+        WrongPassword {
+            description(tr!("Wrong password"))
+        }
         MissingKey(key: PublicKey) {
             description(tr!("Missing key"))
             display("{}", tr_format!("Missing key: {}", to_hex(&key[..])))
@@ -58,16 +95,19 @@ quick_error!{
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 #[allow(unknown_lints, non_camel_case_types)]
 pub enum EncryptionMethod {
-    Sodium
+    Sodium,
+    Aes256Gcm
 }
 serde_impl!(EncryptionMethod(u64) {
-    Sodium => 0
+    Sodium => 0,
+    Aes256Gcm => 1
 });
 
 impl EncryptionMethod {
     pub fn from_string(val: &str) -> Result<Self, &'static str> {
         match val {
             "sodium" => Ok(EncryptionMethod::Sodium),
+            "aes256gcm" => Ok(EncryptionMethod::Aes256Gcm),
             _ => Err(tr!("Unsupported encryption method")),
         }
     }
@@ -75,14 +115,142 @@ impl EncryptionMethod {
     pub fn to_string(&self) -> String {
         match *self {
             EncryptionMethod::Sodium => "sodium".to_string(),
+            EncryptionMethod::Aes256Gcm => "aes256gcm".to_string(),
         }
     }
+
+    /// Checks whether the method can be used on this machine
+    ///
+    /// AES-256-GCM is only supported on CPUs with hardware AES instructions.
+    pub fn is_available(&self) -> bool {
+        match *self {
+            EncryptionMethod::Sodium => true,
+            EncryptionMethod::Aes256Gcm => {
+                sodium_init();
+                unsafe { crypto_aead_aes256gcm_is_available() == 1 }
+            }
+        }
+    }
+}
+
+
+/// Encrypts the data with AES-256-GCM, the caller must never use the same key and nonce twice
+fn aes256gcm_encrypt(key: &[u8], nonce: &[u8], data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    if key.len() != AES_KEYBYTES || nonce.len() != AES_NPUBBYTES {
+        return Err(EncryptionError::InvalidKey);
+    }
+    if !EncryptionMethod::Aes256Gcm.is_available() {
+        return Err(EncryptionError::Operation(tr!("AES-256-GCM is not supported by this CPU")));
+    }
+    let mut out = vec![0; data.len() + AES_ABYTES];
+    let mut len = 0;
+    if unsafe {
+        crypto_aead_aes256gcm_encrypt(
+            out.as_mut_ptr(),
+            &mut len,
+            data.as_ptr(),
+            data.len() as libc::c_ulonglong,
+            ::std::ptr::null(),
+            0,
+            ::std::ptr::null(),
+            nonce.as_ptr(),
+            key.as_ptr()
+        )
+    } != 0 {
+        return Err(EncryptionError::Operation(tr!("Encryption failed")));
+    }
+    out.truncate(len as usize);
+    Ok(out)
+}
+
+/// Decrypts and authenticates data encrypted by `aes256gcm_encrypt`
+fn aes256gcm_decrypt(key: &[u8], nonce: &[u8], data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    if key.len() != AES_KEYBYTES || nonce.len() != AES_NPUBBYTES {
+        return Err(EncryptionError::InvalidKey);
+    }
+    if !EncryptionMethod::Aes256Gcm.is_available() {
+        return Err(EncryptionError::Operation(tr!("AES-256-GCM is not supported by this CPU")));
+    }
+    if data.len() < AES_ABYTES {
+        return Err(EncryptionError::Operation(tr!("Decryption failed")));
+    }
+    let mut out = vec![0; data.len() - AES_ABYTES];
+    let mut len = 0;
+    if unsafe {
+        crypto_aead_aes256gcm_decrypt(
+            out.as_mut_ptr(),
+            &mut len,
+            ::std::ptr::null_mut(),
+            data.as_ptr(),
+            data.len() as libc::c_ulonglong,
+            ::std::ptr::null(),
+            0,
+            nonce.as_ptr(),
+            key.as_ptr()
+        )
+    } != 0 {
+        return Err(EncryptionError::Operation(tr!("Decryption failed")));
+    }
+    out.truncate(len as usize);
+    Ok(out)
 }
 
 
 pub type Encryption = (EncryptionMethod, ByteBuf);
 
 
+/// Parameters to derive a key pair from a password with scrypt
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyDerivation {
+    pub salt: ByteBuf,
+    pub ops_limit: usize,
+    pub mem_limit: usize
+}
+serde_impl!(KeyDerivation(u8) {
+    salt: ByteBuf => 0,
+    ops_limit: usize => 1,
+    mem_limit: usize => 2
+});
+
+impl Default for KeyDerivation {
+    fn default() -> Self {
+        KeyDerivation {
+            salt: ByteBuf::new(),
+            ops_limit: pwhash::OPSLIMIT_INTERACTIVE.0,
+            mem_limit: pwhash::MEMLIMIT_INTERACTIVE.0
+        }
+    }
+}
+
+impl KeyDerivation {
+    /// Creates new parameters with a random salt
+    pub fn new() -> Self {
+        sodium_init();
+        KeyDerivation {
+            salt: pwhash::gen_salt().0[..].to_vec().into(),
+            ..Default::default()
+        }
+    }
+
+    /// Derives the key pair, the same password and parameters always give the same keys
+    pub fn derive_keypair(&self, password: &str) -> Result<(PublicKey, SecretKey), EncryptionError> {
+        sodium_init();
+        let salt = try!(pwhash::Salt::from_slice(&self.salt).ok_or(
+            EncryptionError::Operation(tr!("Invalid salt"))
+        ));
+        let mut key = [0u8; pwhash::HASHEDPASSWORDBYTES];
+        let key = try!(pwhash::derive_key(
+            &mut key,
+            password.as_bytes(),
+            &salt,
+            pwhash::OpsLimit(self.ops_limit),
+            pwhash::MemLimit(self.mem_limit)
+        ).map_err(|_| EncryptionError::Operation(tr!("Key derivation failed"))));
+        Ok(Crypto::keypair_from_key(key))
+    }
+}
+
+
 pub struct KeyfileYaml {
     public: String,
     secret: String
@@ -228,12 +396,68 @@ impl Crypto {
         self.keys.contains_key(public)
     }
 
+    /// Checks whether the secret key needed to decrypt data with this encryption is known
+    pub fn can_decrypt(&self, enc: &Encryption) -> bool {
+        match PublicKey::from_slice(&enc.1) {
+            Some(public) => self.keys.contains_key(&public),
+            None => false,
+        }
+    }
+
     fn get_secret_key(&self, public: &PublicKey) -> Result<&SecretKey, EncryptionError> {
         self.keys.get(public).ok_or_else(
             || EncryptionError::MissingKey(*public)
         )
     }
 
+    /// Derives the AES key and nonce from the shared secret of the ephemeral and the
+    /// recipient key pair
+    ///
+    /// The ephemeral public key is part of the input, so every message gets its own key and
+    /// nonce as long as the ephemeral key pair is never reused.
+    fn aes256gcm_key_nonce(
+        shared: &box_::PrecomputedKey,
+        ephemeral: &PublicKey,
+        public: &PublicKey,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let mut input = Vec::with_capacity(2 * box_::PUBLICKEYBYTES);
+        input.extend_from_slice(&ephemeral[..]);
+        input.extend_from_slice(&public[..]);
+        let hash = blake2b(AES_KEYBYTES + AES_NPUBBYTES, &shared[..], &input);
+        let (key, nonce) = hash.as_bytes().split_at(AES_KEYBYTES);
+        (key.to_vec(), nonce.to_vec())
+    }
+
+    /// Encrypts the data for the owner of `public` with a fresh ephemeral key pair
+    ///
+    /// The result consists of the ephemeral public key followed by the ciphertext and the tag,
+    /// like a sealed box but with AES-256-GCM as cipher.
+    fn aes256gcm_seal(data: &[u8], public: &PublicKey) -> Result<Vec<u8>, EncryptionError> {
+        let (ephemeral_public, ephemeral_secret) = box_::gen_keypair();
+        let shared = box_::precompute(public, &ephemeral_secret);
+        let (key, nonce) = Self::aes256gcm_key_nonce(&shared, &ephemeral_public, public);
+        let mut out = Vec::with_capacity(box_::PUBLICKEYBYTES + data.len() + AES_ABYTES);
+        out.extend_from_slice(&ephemeral_public[..]);
+        out.extend_from_slice(&try!(aes256gcm_encrypt(&key, &nonce, data)));
+        Ok(out)
+    }
+
+    fn aes256gcm_open(
+        data: &[u8],
+        public: &PublicKey,
+        secret: &SecretKey,
+    ) -> Result<Vec<u8>, EncryptionError> {
+        if data.len() < box_::PUBLICKEYBYTES {
+            return Err(EncryptionError::Operation(tr!("Decryption failed")));
+        }
+        let (ephemeral_public, data) = data.split_at(box_::PUBLICKEYBYTES);
+        let ephemeral_public = PublicKey::from_slice(ephemeral_public).unwrap();
+        // The shared secret is the same when computed from the other side
+        let shared = box_::precompute(&ephemeral_public, secret);
+        let (key, nonce) = Self::aes256gcm_key_nonce(&shared, &ephemeral_public, public);
+        aes256gcm_decrypt(&key, &nonce, data)
+    }
+
     #[inline]
     pub fn encrypt(&self, enc: &Encryption, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
         let &(ref method, ref public) = enc;
@@ -242,6 +466,7 @@ impl Crypto {
         ));
         match *method {
             EncryptionMethod::Sodium => Ok(sealedbox::seal(data, &public)),
+            EncryptionMethod::Aes256Gcm => Self::aes256gcm_seal(data, &public),
         }
     }
 
@@ -258,9 +483,48 @@ impl Crypto {
                     EncryptionError::Operation(tr!("Decryption failed"))
                 })
             }
+            EncryptionMethod::Aes256Gcm => Self::aes256gcm_open(data, &public, secret),
         }
     }
 
+    /// Derives a signing key pair from the secret key of an encryption key pair
+    fn signing_keypair(secret: &SecretKey) -> (sign::PublicKey, sign::SecretKey) {
+        let seed = blake2b(sign::SEEDBYTES, b"zvault signing key", &secret[..]);
+        sign::keypair_from_seed(&sign::Seed::from_slice(seed.as_bytes()).unwrap())
+    }
+
+    /// Signs the data with the signing key derived from the secret key of the given public key
+    pub fn sign(&self, public: &PublicKey, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let secret = try!(self.get_secret_key(public));
+        let (_, sign_secret) = Self::signing_keypair(secret);
+        Ok(sign::sign_detached(data, &sign_secret)[..].to_vec())
+    }
+
+    /// Returns the public key that checks the signatures made by `sign` with the given key
+    ///
+    /// Creating the verify key requires the secret key, checking signatures does not.
+    pub fn verify_key(&self, public: &PublicKey) -> Result<Vec<u8>, EncryptionError> {
+        let secret = try!(self.get_secret_key(public));
+        let (sign_public, _) = Self::signing_keypair(secret);
+        Ok(sign_public[..].to_vec())
+    }
+
+    /// Checks a signature created by `sign` with the verify key of the signing key
+    pub fn verify(
+        verify_key: &[u8],
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, EncryptionError> {
+        sodium_init();
+        let verify_key = try!(sign::PublicKey::from_slice(verify_key).ok_or(
+            EncryptionError::InvalidKey
+        ));
+        Ok(match sign::Signature::from_slice(signature) {
+            Some(signature) => sign::verify_detached(&signature, data, &verify_key),
+            None => false
+        })
+    }
+
     #[inline]
     pub fn gen_keypair() -> (PublicKey, SecretKey) {
         sodium_init();
@@ -277,6 +541,11 @@ impl Crypto {
             pwhash::OPSLIMIT_INTERACTIVE,
             pwhash::MEMLIMIT_INTERACTIVE
         ).unwrap();
+        Self::keypair_from_key(key)
+    }
+
+    /// Creates a key pair using the last bytes of a derived key as seed
+    fn keypair_from_key(key: &[u8]) -> (PublicKey, SecretKey) {
         let mut seed = [0u8; 32];
         let offset = key.len() - seed.len();
         for (i, b) in seed.iter_mut().enumerate() {
@@ -295,7 +564,6 @@ impl Crypto {
 }
 
 
-
 mod tests {
 
     #[allow(unused_imports)]
@@ -318,6 +586,18 @@ mod tests {
         assert!(key1.0 != key3.0);
     }
 
+    #[test]
+    fn test_key_derivation() {
+        let derivation = KeyDerivation::new();
+        let key1 = derivation.derive_keypair("foo").unwrap();
+        let key2 = derivation.derive_keypair("foo").unwrap();
+        assert_eq!(key1.0, key2.0);
+        let key3 = derivation.derive_keypair("bar").unwrap();
+        assert!(key1.0 != key3.0);
+        let key4 = KeyDerivation::new().derive_keypair("foo").unwrap();
+        assert!(key1.0 != key4.0);
+    }
+
     #[test]
     fn test_add_keypair() {
         let mut crypto = Crypto::dummy();
@@ -355,6 +635,75 @@ mod tests {
         assert_eq!(&cleartext[..] as &[u8], &unciphered as &[u8]);
     }
 
+    #[test]
+    fn test_aes256gcm_known_answer() {
+        if !EncryptionMethod::Aes256Gcm.is_available() {
+            return;
+        }
+        // Test cases 13 and 14 of the GCM specification
+        let key = [0u8; 32];
+        let nonce = [0u8; 12];
+        assert_eq!(
+            to_hex(&aes256gcm_encrypt(&key, &nonce, &[]).unwrap()),
+            "530f8afbc74536b9a963b4f1c4cb738b"
+        );
+        let ciphertext = aes256gcm_encrypt(&key, &nonce, &[0u8; 16]).unwrap();
+        assert_eq!(
+            to_hex(&ciphertext),
+            "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919"
+        );
+        assert_eq!(aes256gcm_decrypt(&key, &nonce, &ciphertext).unwrap(), vec![0u8; 16]);
+        let mut modified = ciphertext.clone();
+        modified[3] ^= 1;
+        assert!(aes256gcm_decrypt(&key, &nonce, &modified).is_err());
+    }
+
+    #[test]
+    fn test_aes256gcm_encrypt_decrypt() {
+        if !EncryptionMethod::Aes256Gcm.is_available() {
+            return;
+        }
+        let mut crypto = Crypto::dummy();
+        let (pk, sk) = Crypto::gen_keypair();
+        crypto.add_secret_key(pk, sk);
+        let encryption = (EncryptionMethod::Aes256Gcm, ByteBuf::from(&pk[..]));
+        let cleartext = b"test123";
+        let ciphertext1 = crypto.encrypt(&encryption, cleartext).unwrap();
+        let ciphertext2 = crypto.encrypt(&encryption, cleartext).unwrap();
+        // Every message gets a new ephemeral key and therefore a new key and nonce
+        assert!(ciphertext1[..32] != ciphertext2[..32]);
+        assert!(ciphertext1 != ciphertext2);
+        assert_eq!(crypto.decrypt(&encryption, &ciphertext1).unwrap(), cleartext.to_vec());
+        assert_eq!(crypto.decrypt(&encryption, &ciphertext2).unwrap(), cleartext.to_vec());
+        let mut modified = ciphertext1.clone();
+        modified[40] ^= 53;
+        assert!(crypto.decrypt(&encryption, &modified).is_err());
+        // The method is part of the encryption, data is not decrypted with the wrong cipher
+        let sodium = (EncryptionMethod::Sodium, ByteBuf::from(&pk[..]));
+        assert!(crypto.decrypt(&sodium, &ciphertext1).is_err());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let mut crypto = Crypto::dummy();
+        let (pk, sk) = Crypto::gen_keypair();
+        crypto.add_secret_key(pk, sk);
+        let data = b"test123";
+        let signature = crypto.sign(&pk, data).unwrap();
+        let verify_key = crypto.verify_key(&pk).unwrap();
+        assert!(verify_key != &pk[..]);
+        assert!(Crypto::verify(&verify_key, data, &signature).unwrap());
+        assert!(!Crypto::verify(&verify_key, b"test124", &signature).unwrap());
+        assert!(!Crypto::verify(&verify_key, data, &signature[1..]).unwrap());
+        let (pk2, sk2) = Crypto::gen_keypair();
+        crypto.add_secret_key(pk2, sk2);
+        let verify_key2 = crypto.verify_key(&pk2).unwrap();
+        assert!(!Crypto::verify(&verify_key2, data, &signature).unwrap());
+        assert!(Crypto::verify(&verify_key[1..], data, &signature).is_err());
+        assert!(Crypto::dummy().sign(&pk, data).is_err());
+        assert!(Crypto::dummy().verify_key(&pk).is_err());
+    }
+
     #[test]
     fn test_wrong_key() {
         let mut crypto = Crypto::dummy();
@@ -0,0 +1,173 @@
+use std::fmt::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::str;
+use std::sync::RwLock;
+
+
+/// Way of displaying names and paths that contain special characters or invalid UTF-8
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EscapeMode {
+    /// Invalid UTF-8 is replaced, everything else is printed as is
+    None,
+    /// Backslashes, control characters and invalid bytes are escaped like in C strings
+    C,
+    /// The output can be pasted into a POSIX shell as a single word
+    Shell
+}
+
+impl EscapeMode {
+    pub fn from_string(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "none" => Ok(EscapeMode::None),
+            "c" => Ok(EscapeMode::C),
+            "shell" => Ok(EscapeMode::Shell),
+            _ => Err(tr!("Unsupported escape mode"))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EscapeMode::None => "none",
+            EscapeMode::C => "c",
+            EscapeMode::Shell => "shell"
+        }
+    }
+
+    pub fn escape_bytes(&self, data: &[u8]) -> String {
+        match *self {
+            EscapeMode::None => String::from_utf8_lossy(data).into_owned(),
+            EscapeMode::C => escape_c(data, false),
+            EscapeMode::Shell => escape_shell(data)
+        }
+    }
+
+    #[inline]
+    pub fn escape_str(&self, data: &str) -> String {
+        self.escape_bytes(data.as_bytes())
+    }
+
+    #[inline]
+    pub fn escape_path<P: AsRef<Path>>(&self, path: P) -> String {
+        self.escape_bytes(path.as_ref().as_os_str().as_bytes())
+    }
+}
+
+
+lazy_static! {
+    static ref ESCAPE_MODE: RwLock<EscapeMode> = RwLock::new(EscapeMode::C);
+}
+
+/// Sets the escape mode that is used by `escape` for all output
+pub fn set_escape_mode(mode: EscapeMode) {
+    *ESCAPE_MODE.write().unwrap() = mode;
+}
+
+/// Formats a name or path for the output using the configured escape mode
+pub fn escape<P: AsRef<Path>>(path: P) -> String {
+    ESCAPE_MODE.read().unwrap().escape_path(path)
+}
+
+
+fn escape_c(data: &[u8], quote: bool) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (valid, invalid) = match str::from_utf8(rest) {
+            Ok(valid) => (valid, 0),
+            Err(err) => {
+                let len = err.valid_up_to();
+                let invalid = err.error_len().unwrap_or(rest.len() - len);
+                (unsafe { str::from_utf8_unchecked(&rest[..len]) }, invalid)
+            }
+        };
+        for c in valid.chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                '\'' if quote => escaped.push_str("\\'"),
+                c if c.is_control() && (c as u32) < 0x80 => {
+                    write!(escaped, "\\x{:02x}", c as u32).unwrap()
+                }
+                c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+                c => escaped.push(c)
+            }
+        }
+        for b in &rest[valid.len()..valid.len() + invalid] {
+            write!(escaped, "\\x{:02x}", b).unwrap();
+        }
+        rest = &rest[valid.len() + invalid..];
+    }
+    escaped
+}
+
+fn escape_shell(data: &[u8]) -> String {
+    let is_safe = |b: &u8| match *b {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' => true,
+        b'_' | b'-' | b'+' | b'=' | b'.' | b',' | b'/' | b':' | b'@' | b'%' => true,
+        _ => false
+    };
+    if !data.is_empty() && data.iter().all(is_safe) {
+        return String::from_utf8_lossy(data).into_owned();
+    }
+    match str::from_utf8(data) {
+        Ok(data) if !data.chars().any(|c| c.is_control()) => {
+            format!("'{}'", data.replace('\'', "'\\''"))
+        }
+        _ => format!("$'{}'", escape_c(data, true))
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_parse() {
+        for mode in &[EscapeMode::None, EscapeMode::C, EscapeMode::Shell] {
+            assert_eq!(*mode, EscapeMode::from_string(mode.name()).unwrap());
+        }
+        assert!(EscapeMode::from_string("xml").is_err());
+    }
+
+    #[test]
+    fn test_none() {
+        assert_eq!(EscapeMode::None.escape_str("a\nb"), "a\nb");
+        assert_eq!(EscapeMode::None.escape_bytes(b"a\xffb"), "a\u{fffd}b");
+    }
+
+    #[test]
+    fn test_c() {
+        assert_eq!(EscapeMode::C.escape_str("some/path.txt"), "some/path.txt");
+        assert_eq!(EscapeMode::C.escape_str("äöü ß"), "äöü ß");
+        assert_eq!(EscapeMode::C.escape_str("a\nb\tc\\d"), "a\\nb\\tc\\\\d");
+        assert_eq!(EscapeMode::C.escape_str("a\x1b"), "a\\x1b");
+        assert_eq!(EscapeMode::C.escape_bytes(b"a\xff\xfeb"), "a\\xff\\xfeb");
+        assert_eq!(EscapeMode::C.escape_bytes(b"\xc3"), "\\xc3");
+    }
+
+    #[test]
+    fn test_shell() {
+        assert_eq!(EscapeMode::Shell.escape_str("some/path.txt"), "some/path.txt");
+        assert_eq!(EscapeMode::Shell.escape_str(""), "''");
+        assert_eq!(EscapeMode::Shell.escape_str("a b"), "'a b'");
+        assert_eq!(EscapeMode::Shell.escape_str("it's"), "'it'\\''s'");
+        assert_eq!(EscapeMode::Shell.escape_str("a\nb"), "$'a\\nb'");
+        assert_eq!(EscapeMode::Shell.escape_bytes(b"it's\xff"), "$'it\\'s\\xff'");
+    }
+
+    #[test]
+    fn test_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"/tmp/\xff"));
+        assert_eq!(EscapeMode::C.escape_path(path), "/tmp/\\xff");
+    }
+
+}
@@ -24,4 +24,129 @@ mod linux {
 
 pub use self::linux::*;
 
-// Not testing since this requires root
+use std::path::{Component, Path, PathBuf};
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::fs::File;
+use std::io::{self, Read};
+
+
+/// Removes `.` and `..` components without accessing the filesystem
+///
+/// Leading `..` components of relative paths are kept, `..` at the root is dropped.
+pub fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    let mut depth = 0;
+    for c in path.as_ref().components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if depth > 0 {
+                    normalized.pop();
+                    depth -= 1;
+                } else if !normalized.has_root() {
+                    normalized.push("..");
+                }
+            }
+            Component::Normal(name) => {
+                normalized.push(name);
+                depth += 1;
+            }
+            c => normalized.push(c.as_os_str())
+        }
+    }
+    normalized
+}
+
+/// Returns the path that leads from the directory `from` to `to`
+///
+/// Both paths must be normalized and either both absolute or both relative to the same base.
+pub fn relative_path<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> PathBuf {
+    let mut from = from.as_ref().components().peekable();
+    let mut to = to.as_ref().components().peekable();
+    while from.peek().is_some() && from.peek() == to.peek() {
+        from.next();
+        to.next();
+    }
+    let mut path = PathBuf::new();
+    for _ in from {
+        path.push("..");
+    }
+    for c in to {
+        path.push(c.as_os_str());
+    }
+    path
+}
+
+/// Decodes the octal escapes (`\040` for a space) of paths in `/proc/self/mountinfo`
+fn unescape_mount_path(path: &str) -> PathBuf {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = &bytes[i + 1..bytes.len().min(i + 4)];
+        if bytes[i] == b'\\' && escape.len() == 3 && b'0' <= escape[0] && escape[0] <= b'3' &&
+            escape.iter().all(|b| b'0' <= *b && *b <= b'7')
+        {
+            decoded.push(escape.iter().fold(0, |value, b| value * 8 + (b - b'0')));
+            i += 4;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    PathBuf::from(OsString::from_vec(decoded))
+}
+
+fn parse_mountinfo(data: &str) -> Vec<PathBuf> {
+    data.lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape_mount_path)
+        .collect()
+}
+
+/// Returns the mount points of all filesystems that are visible to this process
+pub fn mount_points() -> Result<Vec<PathBuf>, io::Error> {
+    let mut data = String::new();
+    try!(try!(File::open("/proc/self/mountinfo")).read_to_string(&mut data));
+    Ok(parse_mountinfo(&data))
+}
+
+
+// Not testing chown since this requires root
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/a/b/../c/./d"), Path::new("/a/c/d"));
+        assert_eq!(normalize_path("/../a"), Path::new("/a"));
+        assert_eq!(normalize_path("a/../../b"), Path::new("../b"));
+        assert_eq!(normalize_path("a/b/.."), Path::new("a"));
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(relative_path("/a/b", "/a/c/d"), Path::new("../c/d"));
+        assert_eq!(relative_path("/a", "/a/b"), Path::new("b"));
+        assert_eq!(relative_path("/a/b/c", "/d"), Path::new("../../../d"));
+        assert_eq!(normalize_path(Path::new("/x/y").join(relative_path("/x/y", "/x/z/f"))), Path::new("/x/z/f"));
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let data = "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+                    35 22 8:1 /srv/data /mnt/my\\040data rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+                    36 22 0:5 / /proc rw,nosuid - proc proc rw\n";
+        assert_eq!(parse_mountinfo(data), vec![
+            PathBuf::from("/"),
+            PathBuf::from("/mnt/my data"),
+            PathBuf::from("/proc")
+        ]);
+        assert_eq!(unescape_mount_path("a\\134b\\0"), Path::new("a\\b\\0"));
+    }
+
+}
@@ -3,8 +3,9 @@ use serde::de::Error;
 use serde_bytes::{ByteBuf, Bytes};
 
 use murmurhash3::murmurhash3_x64_128;
-use blake2::blake2b::blake2b;
-use byteorder::{LittleEndian, ByteOrder, WriteBytesExt, ReadBytesExt};
+use blake2::blake2b::{blake2b, Blake2b};
+use sodiumoxide::crypto::hash::sha256;
+use byteorder::{LittleEndian, BigEndian, ByteOrder, WriteBytesExt, ReadBytesExt};
 
 use std::mem;
 use std::fmt;
@@ -105,14 +106,125 @@ impl<'a> Deserialize<'a> for Hash {
 }
 
 
+const PRIME64_1: u64 = 11_400_714_785_074_694_791;
+const PRIME64_2: u64 = 14_029_467_366_897_019_727;
+const PRIME64_3: u64 = 1_609_587_929_392_839_161;
+const PRIME64_4: u64 = 9_650_029_242_287_828_579;
+const PRIME64_5: u64 = 2_870_177_450_012_600_261;
+
+#[inline]
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+#[inline]
+fn xxh64_merge(acc: u64, val: u64) -> u64 {
+    (acc ^ xxh64_round(0, val))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+/// Calculates the 64 bit xxHash (XXH64) of the data
+pub fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let mut rest = data;
+    let mut hash = if data.len() >= 32 {
+        let mut v1 = seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2);
+        let mut v2 = seed.wrapping_add(PRIME64_2);
+        let mut v3 = seed;
+        let mut v4 = seed.wrapping_sub(PRIME64_1);
+        while rest.len() >= 32 {
+            v1 = xxh64_round(v1, LittleEndian::read_u64(&rest[0..]));
+            v2 = xxh64_round(v2, LittleEndian::read_u64(&rest[8..]));
+            v3 = xxh64_round(v3, LittleEndian::read_u64(&rest[16..]));
+            v4 = xxh64_round(v4, LittleEndian::read_u64(&rest[24..]));
+            rest = &rest[32..];
+        }
+        let mut hash = v1.rotate_left(1)
+            .wrapping_add(v2.rotate_left(7))
+            .wrapping_add(v3.rotate_left(12))
+            .wrapping_add(v4.rotate_left(18));
+        hash = xxh64_merge(hash, v1);
+        hash = xxh64_merge(hash, v2);
+        hash = xxh64_merge(hash, v3);
+        xxh64_merge(hash, v4)
+    } else {
+        seed.wrapping_add(PRIME64_5)
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+    while rest.len() >= 8 {
+        hash ^= xxh64_round(0, LittleEndian::read_u64(rest));
+        hash = hash.rotate_left(27).wrapping_mul(PRIME64_1).wrapping_add(PRIME64_4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        hash ^= u64::from(LittleEndian::read_u32(rest)).wrapping_mul(PRIME64_1);
+        hash = hash.rotate_left(23).wrapping_mul(PRIME64_2).wrapping_add(PRIME64_3);
+        rest = &rest[4..];
+    }
+    for &byte in rest {
+        hash ^= u64::from(byte).wrapping_mul(PRIME64_5);
+        hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME64_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME64_3);
+    hash ^ (hash >> 32)
+}
+
+
+#[inline]
+fn blake2_to_hash(hash: &[u8]) -> Hash {
+    let hash = unsafe { &*mem::transmute::<_, *const (u64, u64)>(hash.as_ptr()) };
+    Hash {
+        high: u64::from_be(hash.0),
+        low: u64::from_be(hash.1)
+    }
+}
+
+
+/// Calculates the same hash as `HashMethod::Blake2` for data that is given in parts
+pub struct Blake2Stream(Blake2b);
+
+impl Blake2Stream {
+    #[inline]
+    pub fn new() -> Self {
+        Blake2Stream(Blake2b::new(16))
+    }
+
+    #[inline]
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data)
+    }
+
+    #[inline]
+    pub fn finish(self) -> Hash {
+        blake2_to_hash(self.0.finalize().as_bytes())
+    }
+}
+
+impl Default for Blake2Stream {
+    #[inline]
+    fn default() -> Self {
+        Blake2Stream::new()
+    }
+}
+
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum HashMethod {
     Blake2,
-    Murmur3
+    Murmur3,
+    Sha256,
+    XxHash64
 }
 serde_impl!(HashMethod(u64) {
     Blake2 => 1,
-    Murmur3 => 2
+    Murmur3 => 2,
+    Sha256 => 3,
+    XxHash64 => 4
 });
 
 
@@ -120,19 +232,25 @@ impl HashMethod {
     #[inline]
     pub fn hash(&self, data: &[u8]) -> Hash {
         match *self {
-            HashMethod::Blake2 => {
-                let hash = blake2b(16, &[], data);
-                let hash =
-                    unsafe { &*mem::transmute::<_, *const (u64, u64)>(hash.as_bytes().as_ptr()) };
-                Hash {
-                    high: u64::from_be(hash.0),
-                    low: u64::from_be(hash.1)
-                }
-            }
+            HashMethod::Blake2 => blake2_to_hash(blake2b(16, &[], data).as_bytes()),
             HashMethod::Murmur3 => {
                 let (a, b) = murmurhash3_x64_128(data, 0);
                 Hash { high: a, low: b }
             }
+            HashMethod::Sha256 => {
+                let hash = sha256::hash(data);
+                Hash {
+                    high: BigEndian::read_u64(&hash.0[..8]),
+                    low: BigEndian::read_u64(&hash.0[8..16])
+                }
+            }
+            HashMethod::XxHash64 => {
+                // Two differently seeded hashes to fill the 128 bits
+                Hash {
+                    high: xxh64(data, 0),
+                    low: xxh64(data, 1)
+                }
+            }
         }
     }
 
@@ -141,6 +259,8 @@ impl HashMethod {
         match name {
             "blake2" => Ok(HashMethod::Blake2),
             "murmur3" => Ok(HashMethod::Murmur3),
+            "sha256" => Ok(HashMethod::Sha256),
+            "xxh64" => Ok(HashMethod::XxHash64),
             _ => Err(tr!("Unsupported hash method")),
         }
     }
@@ -150,6 +270,16 @@ impl HashMethod {
         match *self {
             HashMethod::Blake2 => "blake2",
             HashMethod::Murmur3 => "murmur3",
+            HashMethod::Sha256 => "sha256",
+            HashMethod::XxHash64 => "xxh64",
+        }
+    }
+
+    #[inline]
+    pub fn implementation(&self) -> &'static str {
+        match *self {
+            HashMethod::Blake2 if cfg!(feature = "simd") => "simd",
+            _ => "portable",
         }
     }
 }
@@ -166,6 +296,8 @@ mod tests {
     fn test_parse() {
         assert_eq!(HashMethod::from("blake2"), Ok(HashMethod::Blake2));
         assert_eq!(HashMethod::from("murmur3"), Ok(HashMethod::Murmur3));
+        assert_eq!(HashMethod::from("sha256"), Ok(HashMethod::Sha256));
+        assert_eq!(HashMethod::from("xxh64"), Ok(HashMethod::XxHash64));
         assert!(HashMethod::from("foo").is_err());
     }
 
@@ -173,6 +305,8 @@ mod tests {
     fn test_to_str() {
         assert_eq!(HashMethod::Blake2.name(), "blake2");
         assert_eq!(HashMethod::Murmur3.name(), "murmur3");
+        assert_eq!(HashMethod::Sha256.name(), "sha256");
+        assert_eq!(HashMethod::XxHash64.name(), "xxh64");
     }
 
     #[test]
@@ -186,6 +320,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_blake2_stream() {
+        let mut stream = Blake2Stream::new();
+        stream.update(b"a");
+        stream.update(b"bc");
+        assert_eq!(stream.finish(), HashMethod::Blake2.hash(b"abc"));
+    }
+
     #[test]
     fn test_murmur3() {
         assert_eq!(
@@ -197,6 +339,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            HashMethod::Sha256.hash(b"abc"),
+            Hash {
+                high: 0xba7816bf8f01cfea,
+                low: 0x414140de5dae2223
+            }
+        );
+    }
+
+    #[test]
+    fn test_xxh64() {
+        assert_eq!(xxh64(b"", 0), 0xef46db3751d8e999);
+        assert_eq!(xxh64(b"abc", 0), 0x44bc2cf5ad770999);
+        assert_eq!(xxh64(b"Nobody inspects the spammish repetition", 0), 0xfbcea83c8a378bf1);
+        assert_eq!(
+            HashMethod::XxHash64.hash(b"123"),
+            Hash {
+                high: 4353148100880623749,
+                low: 5440451180712653975
+            }
+        );
+    }
+
+    #[test]
+    fn test_stable_roundtrip() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * i * i) as u8).collect();
+        for method in &[HashMethod::Blake2, HashMethod::Murmur3, HashMethod::Sha256, HashMethod::XxHash64] {
+            let method = HashMethod::from(method.name()).unwrap();
+            let hash = method.hash(&data);
+            assert_eq!(hash, method.hash(&data));
+            assert_eq!(Hash::from_string(&hash.to_string()), Ok(hash));
+            assert!(hash != method.hash(&data[1..]));
+        }
+    }
+
 }
 
 
@@ -233,4 +412,18 @@ mod benches {
         b.iter(|| HashMethod::Murmur3.hash(&data));
     }
 
+    #[bench]
+    fn bench_sha256(b: &mut Bencher) {
+        let data = test_data(16 * 1024);
+        b.bytes = data.len() as u64;
+        b.iter(|| HashMethod::Sha256.hash(&data));
+    }
+
+    #[bench]
+    fn bench_xxh64(b: &mut Bencher) {
+        let data = test_data(16 * 1024);
+        b.bytes = data.len() as u64;
+        b.iter(|| HashMethod::XxHash64.hash(&data));
+    }
+
 }
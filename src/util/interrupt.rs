@@ -0,0 +1,60 @@
+use libc;
+
+use std::{io, mem, process, ptr, thread};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+
+static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+
+/// Blocks SIGINT and SIGTERM in the calling thread and passes them to `handler` in a new thread
+///
+/// The handler is called with the number of every received signal until it returns `false`.
+/// Threads inherit the signal mask of the thread that starts them, so this must be called
+/// before any other threads are started.
+pub fn handle_signals<F>(name: &str, mut handler: F) -> Result<(), io::Error>
+where
+    F: FnMut(i32) -> bool + Send + 'static,
+{
+    let mut signals: libc::sigset_t = unsafe { mem::zeroed() };
+    let res = unsafe {
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut())
+    };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    try!(thread::Builder::new().name(name.to_string()).spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 || !handler(signal) {
+            return;
+        }
+    }));
+    Ok(())
+}
+
+/// Records SIGINT and SIGTERM instead of terminating the process
+///
+/// Long running operations check `is_interrupted` regularly and stop at the next point where
+/// they can leave the repository in a consistent state. A second signal terminates the process
+/// immediately.
+///
+/// Like `handle_signals`, this must be called before any other threads are started.
+pub fn catch_interrupts() -> Result<(), io::Error> {
+    handle_signals("signals", |signal| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            tr_error!("Interrupted again, aborting immediately");
+            process::exit(128 + signal);
+        }
+        tr_warn!("Interrupted, finishing the current chunk and storing the data written so far...");
+        true
+    })
+}
+
+/// Checks whether SIGINT or SIGTERM has been received since `catch_interrupts` was called
+#[inline]
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -1,39 +1,87 @@
-
 use std::hash::Hash;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::usize;
 
+/// Cache that drops the least recently used items
+///
+/// The cache is limited in the number of items and in the total weight of the items. If there
+/// are more than `max_size` items, the cache shrinks to the `min_size` most recently used items.
+/// If the total weight exceeds the maximal weight, the least recently used items are dropped
+/// one by one, but the most recent item is always kept.
 pub struct LruCache<K, V> {
-    items: HashMap<K, (V, u64)>,
+    items: HashMap<K, (V, u64, usize)>,
+    /// Keys of the items by the time of their last use, so the oldest item is found quickly
+    order: BTreeMap<u64, K>,
     min_size: usize,
     max_size: usize,
+    weight: usize,
+    max_weight: usize,
     next: u64
 }
 
 
-impl<K: Eq + Hash, V> LruCache<K, V> {
+impl<K: Eq + Hash + Clone, V> LruCache<K, V> {
     #[inline]
     pub fn new(min_size: usize, max_size: usize) -> Self {
         LruCache {
             items: HashMap::default(),
+            order: BTreeMap::new(),
             min_size,
             max_size,
+            weight: 0,
+            max_weight: usize::MAX,
             next: 0
         }
     }
 
+    /// Creates a cache that is only limited by the total weight of its items
+    #[inline]
+    pub fn with_max_weight(max_weight: usize) -> Self {
+        let mut cache = Self::new(0, usize::MAX);
+        cache.max_weight = max_weight;
+        cache
+    }
+
+    #[inline]
+    pub fn max_weight(&self) -> usize {
+        self.max_weight
+    }
+
+    pub fn set_max_weight(&mut self, max_weight: usize) {
+        self.max_weight = max_weight;
+        self.evict();
+    }
+
     #[inline]
     pub fn put(&mut self, key: K, value: V) {
-        self.items.insert(key, (value, self.next));
+        self.put_weighted(key, value, 0)
+    }
+
+    pub fn put_weighted(&mut self, key: K, value: V, weight: usize) {
+        self.order.insert(self.next, key.clone());
+        if let Some((_, n, old)) = self.items.insert(key, (value, self.next, weight)) {
+            self.order.remove(&n);
+            self.weight -= old;
+        }
+        self.weight += weight;
         self.next += 1;
         if self.items.len() > self.max_size {
             self.shrink()
         }
+        self.evict();
     }
 
     #[inline]
     pub fn get(&mut self, key: &K) -> Option<&V> {
-        if let Some(&mut (ref item, ref mut n)) = self.items.get_mut(key) {
+        self.get_mut(key).map(|item| &*item)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        if let Some(&mut (ref mut item, ref mut n, _)) = self.items.get_mut(key) {
+            let order_key = self.order.remove(&*n).unwrap();
             *n = self.next;
+            self.order.insert(self.next, order_key);
             self.next += 1;
             Some(item)
         } else {
@@ -41,23 +89,127 @@ impl<K: Eq + Hash, V> LruCache<K, V> {
         }
     }
 
-    #[inline]
-    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        if let Some(&mut (ref mut item, ref mut n)) = self.items.get_mut(key) {
-            *n = self.next;
-            self.next += 1;
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        if let Some((item, n, weight)) = self.items.remove(key) {
+            self.order.remove(&n);
+            self.weight -= weight;
             Some(item)
         } else {
             None
         }
     }
 
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Total weight of all items in the cache
+    #[inline]
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    /// Drops the least recently used item
+    fn remove_oldest(&mut self) {
+        let oldest = *self.order.keys().next().unwrap();
+        let key = self.order.remove(&oldest).unwrap();
+        // The dropped item is freed right away
+        let (_, _, weight) = self.items.remove(&key).unwrap();
+        self.weight -= weight;
+    }
+
     fn shrink(&mut self) {
-        let mut tags: Vec<u64> = self.items.values().map(|&(_, n)| n).collect();
-        tags.sort();
-        let min = tags[tags.len() - self.min_size];
-        let mut new = HashMap::with_capacity(self.min_size);
-        new.extend(self.items.drain().filter(|&(_, (_, n))| n >= min));
-        self.items = new;
+        while self.items.len() > self.min_size {
+            self.remove_oldest();
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.weight > self.max_weight && self.items.len() > 1 {
+            self.remove_oldest();
+        }
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    /// Reads the bundles in order through the cache and returns how often a bundle was loaded
+    #[allow(dead_code)]
+    fn count_loads(cache: &mut LruCache<usize, Vec<u8>>, bundles: &[usize], size: usize) -> usize {
+        let mut loads = 0;
+        for bundle in bundles {
+            if cache.get(bundle).is_none() {
+                loads += 1;
+                cache.put_weighted(*bundle, vec![0; size], size);
+            }
+        }
+        loads
     }
+
+    #[test]
+    fn test_count_limit() {
+        let mut cache = LruCache::new(2, 3);
+        cache.put(1, "a");
+        cache.put(2, "b");
+        cache.put(3, "c");
+        assert_eq!(cache.get(&1), Some(&"a"));
+        cache.put(4, "d");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&1), Some(&"a"));
+        assert_eq!(cache.get(&4), Some(&"d"));
+        assert_eq!(cache.get(&2), None);
+    }
+
+    #[test]
+    fn test_weight_limit() {
+        let mut cache = LruCache::with_max_weight(100);
+        cache.put_weighted(1, "a", 40);
+        cache.put_weighted(2, "b", 40);
+        assert_eq!(cache.get(&1), Some(&"a"));
+        cache.put_weighted(3, "c", 40);
+        assert_eq!(cache.weight(), 80);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"a"));
+        // A single item that is too large is still kept
+        cache.put_weighted(4, "d", 200);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&4), Some(&"d"));
+        cache.set_max_weight(0);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut cache = LruCache::with_max_weight(100);
+        cache.put_weighted(1, "a", 30);
+        cache.put_weighted(1, "b", 20);
+        assert_eq!(cache.weight(), 20);
+        assert_eq!(cache.remove(&1), Some("b"));
+        assert_eq!(cache.remove(&1), None);
+        assert!(cache.is_empty());
+        assert_eq!(cache.weight(), 0);
+    }
+
+    #[test]
+    fn test_interleaved_reads() {
+        // The chunks of a file alternate between three bundles
+        let reads: Vec<usize> = (0..30).map(|i| i % 3).collect();
+        let mut small = LruCache::with_max_weight(2 * 1024);
+        assert_eq!(count_loads(&mut small, &reads, 1024), 30);
+        let mut large = LruCache::with_max_weight(3 * 1024);
+        assert_eq!(count_loads(&mut large, &reads, 1024), 3);
+    }
+
 }
@@ -7,9 +7,17 @@ mod bitmap;
 mod hex;
 mod cli;
 mod hostname;
+mod cpu;
 mod fs;
 mod lock;
 mod statistics;
+mod timing;
+mod escape;
+mod progress;
+mod reed_solomon;
+mod rate_limit;
+mod retry;
+mod interrupt;
 pub mod msgpack;
 
 pub use self::fs::*;
@@ -22,5 +30,13 @@ pub use self::bitmap::*;
 pub use self::hex::*;
 pub use self::cli::*;
 pub use self::hostname::*;
+pub use self::cpu::*;
 pub use self::lock::*;
-pub use self::statistics::*;
\ No newline at end of file
+pub use self::statistics::*;
+pub use self::timing::*;
+pub use self::escape::*;
+pub use self::progress::*;
+pub use self::reed_solomon::*;
+pub use self::rate_limit::*;
+pub use self::retry::*;
+pub use self::interrupt::*;
@@ -0,0 +1,201 @@
+use super::*;
+
+use libc;
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::time::{Duration, Instant};
+
+
+/// Minimal time between two redraws of the progress line
+const REDRAW_INTERVAL_MS: u64 = 100;
+/// Width of the bar itself in characters
+const BAR_WIDTH: usize = 20;
+/// Terminal width used if it can not be determined
+const DEFAULT_TERMINAL_WIDTH: usize = 80;
+
+/// Whether a progress line is currently displayed on stderr
+static LINE_SHOWN: AtomicBool = ATOMIC_BOOL_INIT;
+
+
+/// Returns whether stderr is connected to a terminal
+pub fn stderr_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDERR_FILENO) == 1 }
+}
+
+fn terminal_width() -> usize {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0
+    };
+    if unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 &&
+        size.ws_col > 0
+    {
+        size.ws_col as usize
+    } else {
+        DEFAULT_TERMINAL_WIDTH
+    }
+}
+
+/// Removes the progress line from the terminal so that other messages can be printed
+///
+/// The line will be drawn again with the next update.
+pub fn clear_progress_line() {
+    if LINE_SHOWN.swap(false, Ordering::SeqCst) {
+        eprint!("\r\x1b[K");
+    }
+}
+
+
+/// Displays the progress of an operation as a single line on stderr
+///
+/// The line shows the processed and total bytes, the throughput, the estimated time left
+/// and the path that is currently processed. If the total is not known, it is given as 0
+/// and only the processed bytes and the throughput are shown. The line is removed when
+/// the reporter is dropped.
+pub struct ProgressReporter {
+    msg: String,
+    done: u64,
+    total: u64,
+    start: Instant,
+    last_draw: Option<Instant>,
+    eta: EtaEstimator
+}
+
+impl ProgressReporter {
+    pub fn new(msg: &str, total: u64) -> Self {
+        ProgressReporter {
+            msg: msg.to_string(),
+            done: 0,
+            total,
+            start: Instant::now(),
+            last_draw: None,
+            eta: EtaEstimator::new()
+        }
+    }
+
+    /// Adds `bytes` to the processed bytes, `path` is the path that is being processed
+    #[inline]
+    pub fn add<P: AsRef<Path>>(&mut self, bytes: u64, path: P) {
+        let done = self.done + bytes;
+        self.update(done, path)
+    }
+
+    /// Sets the processed bytes, `path` is the path that is being processed
+    pub fn update<P: AsRef<Path>>(&mut self, done: u64, path: P) {
+        self.done = done;
+        let eta = if self.total > 0 {
+            self.eta.update(done, self.total)
+        } else {
+            None
+        };
+        let now = Instant::now();
+        if let Some(last) = self.last_draw {
+            if now.duration_since(last) < Duration::from_millis(REDRAW_INTERVAL_MS) {
+                return
+            }
+        }
+        self.last_draw = Some(now);
+        let elapsed = now.duration_since(self.start);
+        let secs = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1e9;
+        let line = self.format_line(secs, eta, path.as_ref(), terminal_width());
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        write!(stderr, "\r{}\x1b[K", line).ok();
+        stderr.flush().ok();
+        LINE_SHOWN.store(true, Ordering::SeqCst);
+    }
+
+    fn format_line(&self, elapsed: f32, eta: Option<f64>, path: &Path, width: usize) -> String {
+        let mut line = format!("{}: ", self.msg);
+        if self.total > 0 {
+            let ratio = (self.done as f64 / self.total as f64).min(1.0);
+            let filled = (ratio * BAR_WIDTH as f64) as usize;
+            line.push('[');
+            line.push_str(&"=".repeat(filled));
+            if filled < BAR_WIDTH {
+                line.push('>');
+                line.push_str(&" ".repeat(BAR_WIDTH - filled - 1));
+            }
+            line.push_str(&format!(
+                "] {} / {} ({:.0}%)",
+                to_file_size(self.done),
+                to_file_size(self.total),
+                ratio * 100.0
+            ));
+        } else {
+            line.push_str(&to_file_size(self.done));
+        }
+        if elapsed > 0.0 {
+            line.push_str(&format!(", {}", to_speed(self.done, elapsed)));
+        }
+        if let Some(eta) = eta {
+            line.push_str(&format!(", {} {}", tr!("ETA"), to_eta(eta)));
+        }
+        // Only the end of long paths is shown as the file name is the most important part
+        let path = EscapeMode::C.escape_path(path);
+        let used = line.chars().count() + 1;
+        if used + 4 <= width {
+            let space = width - used - 1;
+            let len = path.chars().count();
+            line.push(' ');
+            if len <= space {
+                line.push_str(&path);
+            } else {
+                line.push_str("...");
+                line.extend(path.chars().skip(len - space + 3));
+            }
+        }
+        line
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        if self.last_draw.is_some() {
+            clear_progress_line();
+        }
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_format_with_total() {
+        let mut progress = ProgressReporter::new("backup", 4096);
+        progress.done = 1024;
+        let line = progress.format_line(1.0, Some(3.0), Path::new("/a/b"), 200);
+        assert_eq!(
+            line,
+            "backup: [=====>              ] 1.0 KiB / 4.0 KiB (25%), 1.0 KiB/s, ETA 0:00:03 /a/b"
+        );
+    }
+
+    #[test]
+    fn test_format_without_total() {
+        let mut progress = ProgressReporter::new("backup", 0);
+        progress.done = 100;
+        let line = progress.format_line(2.0, None, Path::new("file"), 200);
+        assert_eq!(line, "backup: 100 Byte, 50 Byte/s file");
+    }
+
+    #[test]
+    fn test_format_long_path() {
+        let mut progress = ProgressReporter::new("restore", 0);
+        progress.done = 100;
+        let line = progress.format_line(2.0, None, Path::new("/some/very/long/path/file"), 40);
+        assert_eq!(line.chars().count(), 39);
+        assert!(line.ends_with("/file"));
+        assert!(line.contains(" ..."));
+    }
+
+}
@@ -0,0 +1,98 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Token bucket that limits the throughput to a fixed number of bytes per second
+///
+/// The bucket holds at most the bytes of `burst` seconds, so after a pause only a short burst
+/// is allowed before the rate is enforced again.
+pub struct RateLimiter {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant
+}
+
+impl RateLimiter {
+    /// Creates a limiter for `rate` bytes per second that allows bursts of `burst` seconds
+    pub fn new(rate: u64, burst: f64) -> Self {
+        let rate = rate.max(1) as f64;
+        let capacity = (rate * burst).max(1.0);
+        RateLimiter {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now()
+        }
+    }
+
+    #[inline]
+    pub fn rate(&self) -> u64 {
+        self.rate as u64
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + secs * self.rate).min(self.capacity);
+    }
+
+    /// Blocks until `bytes` may be written
+    ///
+    /// Requests that are larger than the bucket leave it in debt, so the next call waits
+    /// longer instead of blocking forever.
+    pub fn acquire(&mut self, bytes: usize) {
+        self.refill();
+        self.tokens -= bytes as f64;
+        if self.tokens < 0.0 {
+            let wait = -self.tokens / self.rate;
+            thread::sleep(Duration::new(wait as u64, (wait.fract() * 1e9) as u32));
+        }
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_no_wait_within_burst() {
+        let mut limiter = RateLimiter::new(1_000_000, 1.0);
+        let start = Instant::now();
+        limiter.acquire(500_000);
+        limiter.acquire(400_000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_throughput_stays_under_rate() {
+        let rate = 1_000_000;
+        let mut limiter = RateLimiter::new(rate, 0.1);
+        let start = Instant::now();
+        let mut written = 0;
+        while written < 500_000 {
+            limiter.acquire(16 * 1024);
+            written += 16 * 1024;
+        }
+        let elapsed = start.elapsed();
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        // Only the initial burst may exceed the rate
+        assert!((written as f64 - 0.1 * rate as f64) / secs <= rate as f64 * 1.01);
+        assert!(secs >= 0.35);
+    }
+
+    #[test]
+    fn test_large_request() {
+        let mut limiter = RateLimiter::new(1_000_000, 0.01);
+        let start = Instant::now();
+        limiter.acquire(200_000);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+}
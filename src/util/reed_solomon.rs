@@ -0,0 +1,285 @@
+quick_error!{
+    #[derive(Debug)]
+    pub enum ReedSolomonError {
+        InvalidParameters(data: usize, parity: usize) {
+            description(tr!("Invalid number of shards"))
+            display("{}", tr_format!("Reed-Solomon error: invalid number of shards: {} data and {} parity shards", data, parity))
+        }
+        TooFewShards(available: usize, needed: usize) {
+            description(tr!("Too few shards"))
+            display("{}", tr_format!("Reed-Solomon error: only {} shards are available but {} are needed", available, needed))
+        }
+        ShardSize {
+            description(tr!("Shards have different sizes"))
+            display("{}", tr!("Reed-Solomon error: all shards must have the same size"))
+        }
+    }
+}
+
+
+struct Tables {
+    exp: [u8; 512],
+    log: [u8; 256]
+}
+
+impl Tables {
+    fn new() -> Self {
+        let mut exp = [0u8; 512];
+        let mut log = [0u8; 256];
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            exp[i] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                // Reduce by the primitive polynomial x^8 + x^4 + x^3 + x^2 + 1
+                x ^= 0x11d;
+            }
+        }
+        for i in 255..512 {
+            exp[i] = exp[i - 255];
+        }
+        Tables { exp, log }
+    }
+}
+
+lazy_static! {
+    static ref TABLES: Tables = Tables::new();
+}
+
+#[inline]
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        0
+    } else {
+        TABLES.exp[TABLES.log[a as usize] as usize + TABLES.log[b as usize] as usize]
+    }
+}
+
+#[inline]
+fn inv(a: u8) -> u8 {
+    debug_assert!(a != 0);
+    TABLES.exp[255 - TABLES.log[a as usize] as usize]
+}
+
+/// Adds `coef * src` to `dst`
+fn mul_add(coef: u8, src: &[u8], dst: &mut [u8]) {
+    if coef == 0 {
+        return;
+    }
+    let mut table = [0u8; 256];
+    for (i, t) in table.iter_mut().enumerate() {
+        *t = mul(coef, i as u8);
+    }
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= table[*s as usize];
+    }
+}
+
+/// Inverts the square matrix via Gauss-Jordan elimination
+fn invert(mut matrix: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    let n = matrix.len();
+    let mut result: Vec<Vec<u8>> = (0..n).map(|i| (0..n).map(|j| (i == j) as u8).collect()).collect();
+    for col in 0..n {
+        let pivot = match (col..n).find(|&row| matrix[row][col] != 0) {
+            Some(pivot) => pivot,
+            None => return None
+        };
+        matrix.swap(col, pivot);
+        result.swap(col, pivot);
+        let factor = inv(matrix[col][col]);
+        for j in 0..n {
+            matrix[col][j] = mul(matrix[col][j], factor);
+            result[col][j] = mul(result[col][j], factor);
+        }
+        for row in 0..n {
+            let factor = matrix[row][col];
+            if row == col || factor == 0 {
+                continue;
+            }
+            for j in 0..n {
+                let (m, r) = (mul(factor, matrix[col][j]), mul(factor, result[col][j]));
+                matrix[row][j] ^= m;
+                result[row][j] ^= r;
+            }
+        }
+    }
+    Some(result)
+}
+
+
+/// Reed-Solomon erasure code over GF(2^8)
+///
+/// The code is systematic: the data shards are stored unmodified and the parity shards are
+/// calculated from them via a Cauchy matrix. Any `data` of the `data + parity` shards are
+/// enough to reconstruct all others.
+pub struct ReedSolomon {
+    data: usize,
+    parity: usize,
+    /// Coefficients of the data shards for each parity shard
+    matrix: Vec<Vec<u8>>
+}
+
+impl ReedSolomon {
+    pub fn new(data: usize, parity: usize) -> Result<Self, ReedSolomonError> {
+        if data == 0 || parity == 0 || data + parity > 256 {
+            return Err(ReedSolomonError::InvalidParameters(data, parity));
+        }
+        // Cauchy matrix with x_i = data + i and y_j = j, all its square submatrices are invertible
+        let matrix = (0..parity)
+            .map(|i| (0..data).map(|j| inv(((data + i) ^ j) as u8)).collect())
+            .collect();
+        Ok(ReedSolomon {
+            data,
+            parity,
+            matrix
+        })
+    }
+
+    #[inline]
+    pub fn data_shards(&self) -> usize {
+        self.data
+    }
+
+    #[inline]
+    pub fn parity_shards(&self) -> usize {
+        self.parity
+    }
+
+    /// Calculates the parity shards from the data shards which must all have the same size
+    pub fn encode(&self, data: &[&[u8]], parity: &mut [Vec<u8>]) -> Result<(), ReedSolomonError> {
+        if data.len() != self.data || parity.len() != self.parity {
+            return Err(ReedSolomonError::InvalidParameters(data.len(), parity.len()));
+        }
+        let size = data[0].len();
+        if data.iter().any(|shard| shard.len() != size) {
+            return Err(ReedSolomonError::ShardSize);
+        }
+        for (row, shard) in self.matrix.iter().zip(parity.iter_mut()) {
+            shard.clear();
+            shard.resize(size, 0);
+            for (&coef, src) in row.iter().zip(data) {
+                mul_add(coef, src, shard);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills in the missing shards, the data shards come first followed by the parity shards
+    pub fn reconstruct(&self, shards: &mut [Option<Vec<u8>>]) -> Result<(), ReedSolomonError> {
+        if shards.len() != self.data + self.parity {
+            return Err(ReedSolomonError::InvalidParameters(shards.len(), 0));
+        }
+        let available: Vec<usize> = (0..shards.len()).filter(|&i| shards[i].is_some()).collect();
+        if available.len() < self.data {
+            return Err(ReedSolomonError::TooFewShards(available.len(), self.data));
+        }
+        let size = shards[available[0]].as_ref().unwrap().len();
+        if available.iter().any(|&i| shards[i].as_ref().unwrap().len() != size) {
+            return Err(ReedSolomonError::ShardSize);
+        }
+        if available.len() == shards.len() {
+            return Ok(());
+        }
+        if available[self.data - 1] >= self.data {
+            // Some data shards are missing, they are calculated from any `data` other shards
+            let rows = &available[..self.data];
+            let matrix = rows.iter()
+                .map(|&i| if i < self.data {
+                    (0..self.data).map(|j| (i == j) as u8).collect()
+                } else {
+                    self.matrix[i - self.data].clone()
+                })
+                .collect();
+            let decode = invert(matrix).expect("Cauchy submatrices are invertible");
+            for j in 0..self.data {
+                if shards[j].is_some() {
+                    continue;
+                }
+                let mut shard = vec![0; size];
+                for (&coef, &i) in decode[j].iter().zip(rows) {
+                    mul_add(coef, shards[i].as_ref().unwrap(), &mut shard);
+                }
+                shards[j] = Some(shard);
+            }
+        }
+        for i in 0..self.parity {
+            if shards[self.data + i].is_some() {
+                continue;
+            }
+            let mut shard = vec![0; size];
+            for (&coef, src) in self.matrix[i].iter().zip(&shards[..self.data]) {
+                mul_add(coef, src.as_ref().unwrap(), &mut shard);
+            }
+            shards[self.data + i] = Some(shard);
+        }
+        Ok(())
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[allow(dead_code)]
+    fn test_shards(count: usize, size: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|i| (0..size).map(|j| (i * 31 + j * 7 + j / 3) as u8).collect()).collect()
+    }
+
+    #[test]
+    fn test_field() {
+        for a in 1..256 {
+            assert_eq!(mul(a as u8, inv(a as u8)), 1);
+            assert_eq!(mul(a as u8, 1), a as u8);
+        }
+        assert_eq!(mul(0, 17), 0);
+        assert_eq!(mul(2, 0x80), 0x1d);
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        assert!(ReedSolomon::new(0, 2).is_err());
+        assert!(ReedSolomon::new(2, 0).is_err());
+        assert!(ReedSolomon::new(200, 57).is_err());
+        assert!(ReedSolomon::new(200, 56).is_ok());
+    }
+
+    #[test]
+    fn test_reconstruct_all_combinations() {
+        let rs = ReedSolomon::new(4, 2).unwrap();
+        let data = test_shards(4, 100);
+        let mut parity = vec![vec![]; 2];
+        {
+            let refs: Vec<&[u8]> = data.iter().map(|d| &d[..]).collect();
+            rs.encode(&refs, &mut parity).unwrap();
+        }
+        let all: Vec<Vec<u8>> = data.iter().chain(parity.iter()).cloned().collect();
+        for a in 0..6 {
+            for b in a..6 {
+                let mut shards: Vec<Option<Vec<u8>>> = all.iter().cloned().map(Some).collect();
+                shards[a] = None;
+                shards[b] = None;
+                rs.reconstruct(&mut shards).unwrap();
+                let shards: Vec<Vec<u8>> = shards.into_iter().map(Option::unwrap).collect();
+                assert_eq!(shards, all);
+            }
+        }
+    }
+
+    #[test]
+    fn test_too_few_shards() {
+        let rs = ReedSolomon::new(3, 1).unwrap();
+        let mut shards = vec![Some(vec![1, 2]), None, None, Some(vec![3, 4])];
+        match rs.reconstruct(&mut shards) {
+            Err(ReedSolomonError::TooFewShards(2, 3)) => (),
+            res => panic!("Unexpected result: {:?}", res.err())
+        }
+        let mut shards = vec![Some(vec![1, 2]), Some(vec![1]), None, Some(vec![3, 4])];
+        assert!(rs.reconstruct(&mut shards).is_err());
+    }
+
+}
@@ -0,0 +1,326 @@
+use libc;
+
+use std::{fmt, io};
+use std::cmp::min;
+use std::thread;
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+
+
+pub const DEFAULT_REMOTE_RETRIES: usize = 3;
+/// Seconds without any progress after which an operation on the remote storage is aborted
+pub const DEFAULT_REMOTE_TIMEOUT: u64 = 30;
+
+
+/// Progress marker of a running operation
+///
+/// Long operations like copying a bundle call `touch` regularly, so they are only aborted when
+/// they stall and not when they are just slow.
+///
+/// An aborted attempt keeps running in the background, so it must check `is_abandoned` before
+/// making its result visible, e.g. before renaming a written file into place.
+#[derive(Clone, Default)]
+pub struct Activity {
+    progress: Arc<AtomicUsize>,
+    abandoned: Arc<AtomicBool>
+}
+
+impl Activity {
+    #[inline]
+    pub fn touch(&self) {
+        self.progress.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn get(&self) -> usize {
+        self.progress.load(Ordering::Relaxed)
+    }
+
+    /// Checks whether the attempt has been given up and its result will be ignored
+    #[inline]
+    pub fn is_abandoned(&self) -> bool {
+        self.abandoned.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn abandon(&self) {
+        self.abandoned.store(true, Ordering::SeqCst);
+    }
+}
+
+
+/// Checks whether the error might go away when the operation is repeated
+///
+/// Timeouts, interruptions and network errors are retried, all other errors like missing files
+/// or denied permissions are fatal.
+pub fn is_retriable(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::Interrupted |
+        io::ErrorKind::WouldBlock |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::NotConnected |
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::UnexpectedEof => true,
+        _ => match err.raw_os_error() {
+            Some(code) => [
+                libc::EIO,
+                libc::EAGAIN,
+                libc::ETIMEDOUT,
+                libc::ESTALE,
+                libc::ENETDOWN,
+                libc::ENETUNREACH,
+                libc::ENETRESET,
+                libc::EHOSTDOWN,
+                libc::EHOSTUNREACH,
+                libc::ECOMM,
+                libc::ENOLINK
+            ].contains(&code),
+            None => false
+        }
+    }
+}
+
+
+/// Number of retries and timeout of the operations on the remote storage
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: usize,
+    /// An attempt fails if it makes no progress within this time
+    pub timeout: Duration,
+    /// Delay before the first retry, it is doubled for every further retry
+    pub delay: Duration
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(DEFAULT_REMOTE_RETRIES, Duration::from_secs(DEFAULT_REMOTE_TIMEOUT))
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(retries: usize, timeout: Duration) -> Self {
+        RetryPolicy {
+            retries,
+            timeout,
+            delay: Duration::from_secs(1)
+        }
+    }
+
+    /// Runs the operation once in its own thread and waits while it makes progress
+    ///
+    /// A stalled operation is left running in the background as blocking calls can not be
+    /// interrupted, it is marked as abandoned so that it does not make its result visible.
+    fn attempt<T, F>(&self, op: &Arc<F>) -> Result<T, io::Error>
+    where
+        T: Send + 'static,
+        F: Fn(&Activity) -> Result<T, io::Error> + Send + Sync + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let activity = Activity::default();
+        let (op, thread_activity) = (op.clone(), activity.clone());
+        try!(thread::Builder::new().name("remote".to_string()).spawn(move || {
+            tx.send(op(&thread_activity)).ok();
+        }));
+        let mut last = activity.get();
+        loop {
+            match rx.recv_timeout(self.timeout) {
+                Ok(result) => return result,
+                Err(RecvTimeoutError::Timeout) => {
+                    let current = activity.get();
+                    if current == last {
+                        activity.abandon();
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            tr_format!("no progress within {} seconds", self.timeout.as_secs())
+                        ));
+                    }
+                    last = current;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, tr!("remote operation panicked")))
+                }
+            }
+        }
+    }
+
+    fn wait_before_retry<E: fmt::Display>(&self, retry: usize, err: &E) {
+        let delay = self.delay * (1u32 << min(retry, 16));
+        tr_warn!(
+            "Remote operation failed, retrying in {} ms ({}/{}): {}",
+            delay.as_secs() * 1000 + u64::from(delay.subsec_nanos() / 1_000_000),
+            retry + 1,
+            self.retries,
+            err
+        );
+        thread::sleep(delay);
+    }
+
+    /// Runs the operation and repeats it with increasing delays while it fails with a
+    /// retriable error
+    pub fn run<T, F>(&self, op: F) -> Result<T, io::Error>
+    where
+        T: Send + 'static,
+        F: Fn(&Activity) -> Result<T, io::Error> + Send + Sync + 'static,
+    {
+        let op = Arc::new(op);
+        let mut retry = 0;
+        loop {
+            match self.attempt(&op) {
+                Err(ref err) if retry < self.retries && is_retriable(err) => {
+                    self.wait_before_retry(retry, err);
+                    retry += 1;
+                }
+                result => return result
+            }
+        }
+    }
+
+    /// Runs the operation in the calling thread and repeats it with increasing delays while
+    /// `retriable` accepts its error
+    ///
+    /// Unlike `run`, stalled attempts are not aborted, so this is meant for reads that have no
+    /// visible effects and whose errors are not `io::Error`.
+    pub fn repeat<T, E, F, R>(&self, mut op: F, retriable: R) -> Result<T, E>
+    where
+        E: fmt::Display,
+        F: FnMut() -> Result<T, E>,
+        R: Fn(&E) -> bool,
+    {
+        let mut retry = 0;
+        loop {
+            match op() {
+                Err(ref err) if retry < self.retries && retriable(err) => {
+                    self.wait_before_retry(retry, err);
+                    retry += 1;
+                }
+                result => return result
+            }
+        }
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::sync::Mutex;
+
+
+    #[allow(dead_code)]
+    fn fast_policy(retries: usize) -> RetryPolicy {
+        let mut policy = RetryPolicy::new(retries, Duration::from_millis(100));
+        policy.delay = Duration::from_millis(1);
+        policy
+    }
+
+    #[test]
+    fn test_is_retriable() {
+        assert!(is_retriable(&io::Error::from_raw_os_error(libc::EIO)));
+        assert!(is_retriable(&io::Error::from_raw_os_error(libc::ETIMEDOUT)));
+        assert!(is_retriable(&io::Error::new(io::ErrorKind::ConnectionReset, "reset")));
+        assert!(!is_retriable(&io::Error::from_raw_os_error(libc::ENOENT)));
+        assert!(!is_retriable(&io::Error::from_raw_os_error(libc::EACCES)));
+        assert!(!is_retriable(&io::Error::new(io::ErrorKind::InvalidData, "corrupt")));
+    }
+
+    #[test]
+    fn test_retry_until_success() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = calls.clone();
+        let result = fast_policy(3).run(move |_| {
+            if calls2.fetch_add(1, Ordering::SeqCst) < 2 {
+                Err(io::Error::from_raw_os_error(libc::EIO))
+            } else {
+                Ok(42)
+            }
+        });
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_give_up_after_retries() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = calls.clone();
+        let result: Result<(), _> = fast_policy(2).run(move |_| {
+            calls2.fetch_add(1, Ordering::SeqCst);
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        });
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EIO));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_fatal_errors_are_not_retried() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls2 = calls.clone();
+        let result: Result<(), _> = fast_policy(3).run(move |_| {
+            calls2.fetch_add(1, Ordering::SeqCst);
+            Err(io::Error::from_raw_os_error(libc::ENOENT))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_timeout_without_progress() {
+        // The operation blocks until the test releases it, so it can only end by the timeout
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (activity_tx, activity_rx) = mpsc::channel();
+        let (release_rx, activity_tx) = (Mutex::new(release_rx), Mutex::new(activity_tx));
+        let result: Result<(), _> = fast_policy(0).run(move |activity| {
+            activity_tx.lock().unwrap().send(activity.clone()).unwrap();
+            release_rx.lock().unwrap().recv().ok();
+            Ok(())
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        let activity = activity_rx.recv().unwrap();
+        assert!(activity.is_abandoned());
+        drop(release_tx);
+    }
+
+    #[test]
+    fn test_repeat() {
+        let mut calls = 0;
+        let result: Result<usize, String> = fast_policy(3).repeat(
+            || {
+                calls += 1;
+                if calls < 3 { Err("retry".to_string()) } else { Ok(calls) }
+            },
+            |err| err == "retry"
+        );
+        assert_eq!(result.unwrap(), 3);
+        let mut calls = 0;
+        let result: Result<(), String> = fast_policy(3).repeat(
+            || {
+                calls += 1;
+                Err("fatal".to_string())
+            },
+            |err| err == "retry"
+        );
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_slow_operation_with_progress() {
+        let result = fast_policy(0).run(|activity| {
+            for _ in 0..10 {
+                thread::sleep(Duration::from_millis(30));
+                activity.touch();
+            }
+            Ok(1)
+        });
+        assert_eq!(result.unwrap(), 1);
+    }
+
+}
@@ -0,0 +1,157 @@
+use std::io::{self, Read};
+use std::time::Instant;
+
+
+/// Time spent in the stages of the data pipeline, in seconds
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PipelineTimes {
+    /// Time spent waiting for the input data, this is not included in chunking
+    pub reading: f64,
+    pub chunking: f64,
+    pub hashing: f64,
+    pub compressing: f64,
+    pub encrypting: f64,
+    pub writing: f64
+}
+
+impl PipelineTimes {
+    #[inline]
+    pub fn total(&self) -> f64 {
+        self.reading + self.chunking + self.hashing + self.compressing + self.encrypting +
+            self.writing
+    }
+
+    pub fn add(&mut self, other: &PipelineTimes) {
+        self.reading += other.reading;
+        self.chunking += other.chunking;
+        self.hashing += other.hashing;
+        self.compressing += other.compressing;
+        self.encrypting += other.encrypting;
+        self.writing += other.writing;
+    }
+
+    /// Returns all stages together with their share of the total time in percent
+    pub fn breakdown(&self) -> Vec<(&'static str, f64)> {
+        let total = self.total();
+        let percent = |time: f64| if total > 0.0 { time / total * 100.0 } else { 0.0 };
+        vec![
+            (tr!("reading"), percent(self.reading)),
+            (tr!("chunking"), percent(self.chunking)),
+            (tr!("hashing"), percent(self.hashing)),
+            (tr!("compressing"), percent(self.compressing)),
+            (tr!("encrypting"), percent(self.encrypting)),
+            (tr!("writing"), percent(self.writing)),
+        ]
+    }
+}
+
+
+/// Adds the time passed since `start` to `time`
+#[inline]
+pub fn add_elapsed(time: &mut f64, start: Instant) {
+    let elapsed = start.elapsed();
+    *time += elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+}
+
+/// Runs the given closure and adds the time it took to `time`
+#[inline]
+pub fn timed<T, F: FnOnce() -> T>(time: &mut f64, f: F) -> T {
+    let start = Instant::now();
+    let res = f();
+    add_elapsed(time, start);
+    res
+}
+
+
+/// Reader that adds the time spent reading from the inner reader to `time`
+pub struct TimedReader<R> {
+    inner: R,
+    pub time: f64
+}
+
+impl<R: Read> TimedReader<R> {
+    #[inline]
+    pub fn new(inner: R) -> Self {
+        TimedReader { inner, time: 0.0 }
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let res = self.inner.read(buf);
+        add_elapsed(&mut self.time, start);
+        res
+    }
+}
+
+
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+
+    #[test]
+    fn test_total() {
+        let times = PipelineTimes {
+            reading: 6.0,
+            chunking: 1.0,
+            hashing: 2.0,
+            compressing: 3.0,
+            encrypting: 4.0,
+            writing: 5.0
+        };
+        assert_eq!(times.total(), 21.0);
+        let mut sum = times.clone();
+        sum.add(&times);
+        assert_eq!(sum.total(), 42.0);
+        assert_eq!(sum.reading, 12.0);
+        assert_eq!(sum.compressing, 6.0);
+    }
+
+    #[test]
+    fn test_breakdown() {
+        let times = PipelineTimes {
+            chunking: 1.0,
+            compressing: 3.0,
+            ..Default::default()
+        };
+        let breakdown = times.breakdown();
+        assert_eq!(breakdown.len(), 6);
+        assert_eq!(breakdown[0].1, 0.0);
+        assert_eq!(breakdown[1].1, 25.0);
+        assert_eq!(breakdown[2].1, 0.0);
+        assert_eq!(breakdown[3].1, 75.0);
+        assert_eq!(breakdown.iter().map(|s| s.1).sum::<f64>(), 100.0);
+    }
+
+    #[test]
+    fn test_breakdown_empty() {
+        let times = PipelineTimes::default();
+        assert!(times.breakdown().iter().all(|s| s.1 == 0.0));
+    }
+
+    #[test]
+    fn test_timed() {
+        let mut time = 0.0;
+        assert_eq!(timed(&mut time, || 42), 42);
+        assert!(time >= 0.0);
+        let before = time;
+        timed(&mut time, || ::std::thread::sleep(::std::time::Duration::from_millis(10)));
+        assert!(time - before >= 0.01);
+    }
+
+    #[test]
+    fn test_timed_reader() {
+        let data = vec![1u8; 1000];
+        let mut reader = TimedReader::new(&data[..]);
+        let mut output = vec![];
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+        assert!(reader.time >= 0.0);
+    }
+
+}